@@ -0,0 +1,993 @@
+use crate::connection::Request;
+use crate::resp;
+use crate::snapshot;
+use crate::storage::{Snapshot, Storage, Value, now_millis};
+use crate::stream::{PendingEntry, Stream, StreamId};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// When the append-only file is flushed to disk with fsync.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FsyncPolicy {
+    /// After every write command, before replying to the client.
+    Always,
+    /// Once per second from a background task.
+    EverySec,
+    /// Never explicitly; the OS decides.
+    No,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(FsyncPolicy::Always),
+            "everysec" => Ok(FsyncPolicy::EverySec),
+            "no" => Ok(FsyncPolicy::No),
+            _ => Err(format!(
+                "Invalid fsync policy '{}', expected always, everysec or no",
+                s
+            )),
+        }
+    }
+}
+
+// Elements per command when rewriting big lists and hashes, as in Redis.
+const REWRITE_ITEMS_PER_CMD: usize = 64;
+
+/// The write side of the append-only file.
+///
+/// Every write command is appended as a RESP array once it has been applied,
+/// so replaying the file from the start rebuilds the dataset.
+pub struct AppendOnlyFile {
+    file: File,
+    path: PathBuf,
+    policy: FsyncPolicy,
+    // Whether anything has been written since the last fsync.
+    unsynced: bool,
+    size: u64,
+    // Size right after the last rewrite (or at startup), to measure growth.
+    base_size: u64,
+    // Writes made while a rewrite is running. They are copied onto the end of
+    // the rewritten file before it replaces this one.
+    rewrite_buffer: Option<Vec<u8>>,
+    // Whether the last append succeeded, for INFO.
+    last_write_ok: bool,
+}
+
+impl AppendOnlyFile {
+    pub fn open(path: impl Into<PathBuf>, policy: FsyncPolicy) -> io::Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(AppendOnlyFile {
+            file,
+            path,
+            policy,
+            unsynced: false,
+            size,
+            base_size: size,
+            rewrite_buffer: None,
+            last_write_ok: true,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends a command that has just been applied to the dataset.
+    pub fn append(&mut self, args: &[Vec<u8>]) -> io::Result<()> {
+        let result = self.write(args);
+        self.last_write_ok = result.is_ok();
+        result
+    }
+
+    /// Whether the last command appended made it to the file.
+    pub fn last_write_ok(&self) -> bool {
+        self.last_write_ok
+    }
+
+    fn write(&mut self, args: &[Vec<u8>]) -> io::Result<()> {
+        let mut buf = Vec::new();
+        resp::encode_request(&with_absolute_expiry(args), &mut buf);
+        self.file.write_all(&buf)?;
+        self.size += buf.len() as u64;
+
+        if let Some(rewrite_buffer) = &mut self.rewrite_buffer {
+            rewrite_buffer.extend_from_slice(&buf);
+        }
+
+        if self.policy == FsyncPolicy::Always {
+            self.file.sync_data()?;
+        } else {
+            self.unsynced = true;
+        }
+        Ok(())
+    }
+
+    /// Returns a handle to fsync when the `everysec` policy has unsynced
+    /// writes. The fsync itself can then run without holding on to `self`.
+    pub fn take_pending_sync(&mut self) -> io::Result<Option<File>> {
+        if self.policy != FsyncPolicy::EverySec || !self.unsynced {
+            return Ok(None);
+        }
+
+        self.unsynced = false;
+        self.file.try_clone().map(Some)
+    }
+
+    pub fn is_rewriting(&self) -> bool {
+        self.rewrite_buffer.is_some()
+    }
+
+    /// Whether the log has grown enough since the last rewrite to warrant a
+    /// new one: by at least `percentage` percent, and to at least `min_size`
+    /// bytes. A percentage of 0 disables automatic rewrites.
+    pub fn needs_rewrite(&self, percentage: u64, min_size: u64) -> bool {
+        if percentage == 0 || self.is_rewriting() || self.size < min_size {
+            return false;
+        }
+
+        let base = self.base_size.max(1);
+        (self.size - base.min(self.size)) * 100 / base >= percentage
+    }
+
+    /// Where a rewrite writes the new log before it replaces the current one.
+    pub fn rewrite_path(&self) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(".rewrite");
+        self.path.with_file_name(name)
+    }
+
+    /// Starts buffering writes for a rewrite. Returns false if one is already
+    /// in progress.
+    pub fn start_rewrite(&mut self) -> bool {
+        if self.is_rewriting() {
+            return false;
+        }
+
+        self.rewrite_buffer = Some(Vec::new());
+        true
+    }
+
+    /// Completes a rewrite whose base has been written to `rewrite_path`:
+    /// the writes buffered meanwhile are appended to it, and it atomically
+    /// replaces the current log.
+    pub fn finish_rewrite(&mut self) -> io::Result<()> {
+        let buffer = self.rewrite_buffer.take().unwrap_or_default();
+        let tmp_path = self.rewrite_path();
+
+        let mut file = OpenOptions::new().append(true).open(&tmp_path)?;
+        file.write_all(&buffer)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+
+        self.size = file.metadata()?.len();
+        self.base_size = self.size;
+        self.file = file;
+        self.unsynced = false;
+        // The new log is in place whether or not this works, so it is only
+        // tried once the above points at it.
+        snapshot::sync_parent_dir(&self.path)
+    }
+
+    /// Gives up on a rewrite, leaving the current log in place.
+    pub fn abort_rewrite(&mut self) {
+        self.rewrite_buffer = None;
+        let _ = fs::remove_file(self.rewrite_path());
+    }
+}
+
+/// Writes a copy of the snapshot file at `snapshot` to `path`, as the base of
+/// a rewritten log that replays the writes made since on top of it. Swapping
+/// the log for one that starts with the snapshot is what makes dropping the
+/// writes the snapshot holds safe: a crash leaves either the old log, whole,
+/// or the new one.
+///
+/// It is framed like a bulk string, `$<length>\r\n<snapshot>\r\n`, ahead of
+/// the commands.
+pub fn write_snapshot_rewrite(snapshot: &Path, path: &Path) -> io::Result<()> {
+    let mut snapshot = File::open(snapshot)?;
+    let len = snapshot.metadata()?.len();
+    let mut out = BufWriter::new(File::create(path)?);
+
+    out.write_all(format!("${}\r\n", len).as_bytes())?;
+    if io::copy(&mut snapshot, &mut out)? != len {
+        return Err(io::Error::other("The snapshot changed while it was copied"));
+    }
+    out.write_all(b"\r\n")?;
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()
+}
+
+/// Writes the shortest list of commands that rebuilds `snapshot` to `path`.
+pub fn write_rewrite(snapshot: &Snapshot, path: &Path) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    let now = now_millis();
+    let mut buf = Vec::new();
+
+    for (key, store_value) in snapshot.entries() {
+        if store_value.expiry.is_some_and(|at| at <= now) {
+            continue;
+        }
+
+        match &store_value.data {
+            Value::String(value) => {
+                let mut args = vec![b"SET".to_vec(), key.to_vec(), value.clone()];
+                if let Some(at) = store_value.expiry {
+                    args.push(b"PXAT".to_vec());
+                    args.push(at.to_string().into_bytes());
+                }
+                resp::encode_request(&args, &mut buf);
+            }
+            Value::List(list) => {
+                let items = list.iter().cloned().collect::<Vec<_>>();
+                for chunk in items.chunks(REWRITE_ITEMS_PER_CMD) {
+                    let mut args = vec![b"RPUSH".to_vec(), key.to_vec()];
+                    args.extend_from_slice(chunk);
+                    resp::encode_request(&args, &mut buf);
+                }
+            }
+            Value::Hash(hash) => {
+                let pairs = hash
+                    .iter()
+                    .filter(|(field, _)| hash.field_expiry(field).is_none_or(|at| at > now))
+                    .collect::<Vec<_>>();
+                if pairs.is_empty() {
+                    continue;
+                }
+                for chunk in pairs.chunks(REWRITE_ITEMS_PER_CMD) {
+                    let mut args = vec![b"HSET".to_vec(), key.to_vec()];
+                    for (field, value) in chunk {
+                        args.push(field.to_vec());
+                        args.push(value.to_vec());
+                    }
+                    resp::encode_request(&args, &mut buf);
+                }
+                for (field, at) in hash.expiring_fields().filter(|&(_, at)| at > now) {
+                    let args = [
+                        b"HPEXPIREAT".to_vec(),
+                        key.to_vec(),
+                        at.to_string().into_bytes(),
+                        b"FIELDS".to_vec(),
+                        b"1".to_vec(),
+                        field.clone(),
+                    ];
+                    resp::encode_request(&args, &mut buf);
+                }
+            }
+            Value::Set(set) => {
+                let members = set.iter().map(<[u8]>::to_vec).collect::<Vec<_>>();
+                for chunk in members.chunks(REWRITE_ITEMS_PER_CMD) {
+                    let mut args = vec![b"SADD".to_vec(), key.to_vec()];
+                    args.extend_from_slice(chunk);
+                    resp::encode_request(&args, &mut buf);
+                }
+            }
+            Value::Stream(stream) => write_stream(key, stream, &mut buf),
+            Value::SortedSet(set) => {
+                let pairs = set.iter().collect::<Vec<_>>();
+                for chunk in pairs.chunks(REWRITE_ITEMS_PER_CMD) {
+                    let mut args = vec![b"ZADD".to_vec(), key.to_vec()];
+                    for (member, score) in chunk {
+                        args.push(score.to_string().into_bytes());
+                        args.push(member.to_vec());
+                    }
+                    resp::encode_request(&args, &mut buf);
+                }
+            }
+        }
+
+        if let Some(at) = store_value.expiry
+            && !matches!(store_value.data, Value::String(_))
+        {
+            let args = [
+                b"PEXPIREAT".to_vec(),
+                key.to_vec(),
+                at.to_string().into_bytes(),
+            ];
+            resp::encode_request(&args, &mut buf);
+        }
+
+        out.write_all(&buf)?;
+        buf.clear();
+    }
+
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()
+}
+
+// A stream is rebuilt one entry at a time, then its last ID is restored in
+// case the newest entries were deleted. Pending entries are claimed back
+// for their consumers, except those whose entries are gone, which XCLAIM
+// can't recreate.
+fn write_stream(key: &[u8], stream: &Stream, buf: &mut Vec<u8>) {
+    let id_arg = |id: &StreamId| id.to_string().into_bytes();
+
+    let mut last_added = None;
+    for (id, fields) in stream.entries() {
+        last_added = Some(*id);
+        let mut args = vec![b"XADD".to_vec(), key.to_vec(), id_arg(id)];
+        for (field, value) in fields {
+            args.push(field.clone());
+            args.push(value.clone());
+        }
+        resp::encode_request(&args, buf);
+    }
+    if stream.is_empty() {
+        // XADD only creates a stream along with an entry, so one is added
+        // and trimmed away straight after.
+        let id = StreamId::new(0, 1).max(stream.last_id());
+        let args = [
+            b"XADD".to_vec(),
+            key.to_vec(),
+            b"MAXLEN".to_vec(),
+            b"0".to_vec(),
+            id_arg(&id),
+            b"x".to_vec(),
+            b"y".to_vec(),
+        ];
+        resp::encode_request(&args, buf);
+        last_added = Some(id);
+    }
+    if last_added != Some(stream.last_id()) {
+        let args = [b"XSETID".to_vec(), key.to_vec(), id_arg(&stream.last_id())];
+        resp::encode_request(&args, buf);
+    }
+
+    for (name, group) in stream.groups() {
+        let args = [
+            b"XGROUP".to_vec(),
+            b"CREATE".to_vec(),
+            key.to_vec(),
+            name.clone(),
+            id_arg(&group.last_delivered),
+        ];
+        resp::encode_request(&args, buf);
+        for consumer in group.consumers() {
+            let args = [
+                b"XGROUP".to_vec(),
+                b"CREATECONSUMER".to_vec(),
+                key.to_vec(),
+                name.clone(),
+                consumer.clone(),
+            ];
+            resp::encode_request(&args, buf);
+        }
+        let live = group.pending().iter().filter(|(id, _)| stream.contains(id));
+        for (id, entry) in live {
+            resp::encode_request(&claim_request(key, name, id, entry), buf);
+        }
+    }
+}
+
+/// The XCLAIM that gives `entry`, pending in the consumer group `group` of the
+/// stream at `key`, back to its consumer exactly as it is.
+pub fn claim_request(
+    key: &[u8],
+    group: &[u8],
+    id: &StreamId,
+    entry: &PendingEntry,
+) -> Vec<Vec<u8>> {
+    vec![
+        b"XCLAIM".to_vec(),
+        key.to_vec(),
+        group.to_vec(),
+        entry.consumer.clone(),
+        b"0".to_vec(),
+        id.to_string().into_bytes(),
+        b"TIME".to_vec(),
+        entry.delivered_at.to_string().into_bytes(),
+        b"RETRYCOUNT".to_vec(),
+        entry.deliveries.to_string().into_bytes(),
+        b"FORCE".to_vec(),
+        b"JUSTID".to_vec(),
+    ]
+}
+
+/// The commands read back from an append-only file.
+#[derive(Debug)]
+pub struct LoadedLog {
+    /// The snapshot the log starts with, if it was last rewritten from one
+    /// (see `write_snapshot_rewrite`). The commands go on top of it.
+    pub base: Option<Storage>,
+    pub commands: Vec<Request>,
+    /// Bytes of an incomplete trailing command or transaction that were cut
+    /// off.
+    pub truncated: usize,
+}
+
+/// Reads every command in the append-only file at `path`. A missing file is
+/// an empty log. A command cut short at the end of the file, as left behind
+/// by a crash mid-write, is dropped and the file truncated to the last
+/// complete command; any other damage is an error.
+///
+/// The commands of a transaction are logged between MULTI and EXEC, which
+/// are left out of the result. If the file ends before the EXEC, the whole
+/// transaction is dropped the same way, so it is never half applied.
+pub fn load(path: &Path) -> io::Result<LoadedLog> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(LoadedLog {
+                base: None,
+                commands: Vec::new(),
+                truncated: 0,
+            });
+        }
+        Err(e) => return Err(e),
+    };
+
+    let (base, mut pos) = match data.first() {
+        Some(b'$') => read_base(&data).map(|(base, end)| (Some(base), end))?,
+        _ => (None, 0),
+    };
+    let mut commands = Vec::new();
+    // Where the open transaction starts, in the file and in `commands`.
+    let mut multi = None;
+    while pos < data.len() {
+        if data[pos] != b'*' {
+            return Err(bad_format(pos, "expected '*'"));
+        }
+        match resp::parse_request(&data[pos..]) {
+            Ok(Some((args, consumed))) => {
+                match args.as_slice() {
+                    [name] if name.eq_ignore_ascii_case(b"MULTI") => {
+                        multi = Some((pos, commands.len()));
+                    }
+                    // A rewrite can start in the middle of a transaction and
+                    // leave the EXEC without its MULTI.
+                    [name] if name.eq_ignore_ascii_case(b"EXEC") => multi = None,
+                    _ => commands.push(args),
+                }
+                pos += consumed;
+            }
+            Ok(None) => break,
+            Err(e) => return Err(bad_format(pos, &e.to_string())),
+        }
+    }
+
+    if let Some((start, queued)) = multi {
+        commands.truncate(queued);
+        pos = start;
+    }
+
+    let truncated = data.len() - pos;
+    if truncated > 0 {
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(pos as u64)?;
+    }
+
+    Ok(LoadedLog {
+        base,
+        commands,
+        truncated,
+    })
+}
+
+// Reads the snapshot at the start of `data`, and returns it with where the
+// commands after it start. The log only ever gets one by being replaced
+// whole, so unlike a command it can't be cut short by a crash.
+fn read_base(data: &[u8]) -> io::Result<(Storage, usize)> {
+    let start = data
+        .iter()
+        .position(|&b| b == b'\n')
+        .ok_or_else(|| bad_format(0, "unfinished snapshot length"))?
+        + 1;
+    let len = std::str::from_utf8(&data[1..start - 1])
+        .ok()
+        .and_then(|len| len.trim_end_matches('\r').parse::<usize>().ok())
+        .ok_or_else(|| bad_format(0, "invalid snapshot length"))?;
+
+    let end = start.saturating_add(len);
+    if data.len() < end.saturating_add(2) || &data[end..end + 2] != b"\r\n" {
+        return Err(bad_format(0, "the snapshot is truncated"));
+    }
+    let base =
+        snapshot::decode(&data[start..end]).map_err(|e| bad_format(start, &e.to_string()))?;
+    Ok((base, end + 2))
+}
+
+fn bad_format(offset: usize, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "Bad file format reading the append only file at offset {}: {}",
+            offset, reason
+        ),
+    )
+}
+
+/// Rewrites relative TTLs into absolute ones, so replaying the log later
+/// doesn't push the expiry further into the future.
+fn with_absolute_expiry(args: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    match args {
+        [cmd, key, value, options @ ..] if cmd.eq_ignore_ascii_case(b"SET") => {
+            let mut absolute = vec![b"SET".to_vec(), key.clone(), value.clone()];
+            let mut options = options.iter();
+
+            while let Some(option) = options.next() {
+                let (scale, relative) = match option.to_ascii_uppercase().as_slice() {
+                    b"EX" => (1000, true),
+                    b"PX" => (1, true),
+                    b"EXAT" => (1000, false),
+                    // Only affects the reply.
+                    b"GET" => continue,
+                    _ => {
+                        absolute.push(option.clone());
+                        continue;
+                    }
+                };
+
+                let mut at =
+                    parse_time(options.next().map_or(&[][..], Vec::as_slice)).saturating_mul(scale);
+                if relative {
+                    at = at.saturating_add(now_millis() as i64);
+                }
+                absolute.push(b"PXAT".to_vec());
+                absolute.push(at.to_string().into_bytes());
+            }
+            absolute
+        }
+        // HEXPIRE and friends take the same arguments, followed by the fields.
+        [cmd, key, time, options @ ..]
+            if [
+                &b"EXPIRE"[..],
+                b"PEXPIRE",
+                b"EXPIREAT",
+                b"HEXPIRE",
+                b"HPEXPIRE",
+                b"HEXPIREAT",
+            ]
+            .iter()
+            .any(|name| cmd.eq_ignore_ascii_case(name)) =>
+        {
+            let cmd = cmd.to_ascii_uppercase();
+            let (absolute_cmd, unit) = match cmd.strip_prefix(b"H") {
+                Some(unit) => (&b"HPEXPIREAT"[..], unit),
+                None => (&b"PEXPIREAT"[..], &cmd[..]),
+            };
+            let time = parse_time(time);
+            let at = match unit {
+                b"EXPIRE" => (now_millis() as i64).saturating_add(time.saturating_mul(1000)),
+                b"PEXPIRE" => (now_millis() as i64).saturating_add(time),
+                _ => time.saturating_mul(1000),
+            };
+
+            let mut absolute = vec![
+                absolute_cmd.to_vec(),
+                key.clone(),
+                at.to_string().into_bytes(),
+            ];
+            absolute.extend_from_slice(options);
+            absolute
+        }
+        // The value read doesn't matter on replay, only the expiry change.
+        [cmd, key, option, time @ ..] if cmd.eq_ignore_ascii_case(b"GETEX") => {
+            let option = option.to_ascii_uppercase();
+            if option == b"PERSIST" {
+                return vec![b"PERSIST".to_vec(), key.clone()];
+            }
+
+            let time = parse_time(time.first().map_or(&[][..], Vec::as_slice));
+            let at = match option.as_slice() {
+                b"EX" => (now_millis() as i64).saturating_add(time.saturating_mul(1000)),
+                b"PX" => (now_millis() as i64).saturating_add(time),
+                b"EXAT" => time.saturating_mul(1000),
+                _ => time,
+            };
+            vec![
+                b"PEXPIREAT".to_vec(),
+                key.clone(),
+                at.to_string().into_bytes(),
+            ]
+        }
+        _ => args.to_vec(),
+    }
+}
+
+fn parse_time(arg: &[u8]) -> i64 {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Storage;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    fn temp_path(name: &str) -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::SeqCst);
+        std::env::temp_dir().join(format!("iron_cache_{}_{}_{}", std::process::id(), n, name))
+    }
+
+    fn args(parts: &[&str]) -> Request {
+        parts.iter().map(|p| p.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_append_and_load() {
+        let path = temp_path("append.aof");
+        let mut aof = AppendOnlyFile::open(&path, FsyncPolicy::Always).unwrap();
+
+        aof.append(&args(&["SET", "k", "v"])).unwrap();
+        aof.append(&args(&["RPUSH", "l", "a", "b"])).unwrap();
+
+        let log = load(&path).unwrap();
+        assert_eq!(
+            log.commands,
+            vec![args(&["SET", "k", "v"]), args(&["RPUSH", "l", "a", "b"])]
+        );
+        assert_eq!(log.truncated, 0);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_failed_append_is_remembered() {
+        // Every write to /dev/full fails for lack of space.
+        let mut aof = AppendOnlyFile::open("/dev/full", FsyncPolicy::No).unwrap();
+        assert!(aof.last_write_ok());
+        assert!(
+            aof.append(&[b"SET".to_vec(), b"k".to_vec(), b"v".to_vec()])
+                .is_err()
+        );
+        assert!(!aof.last_write_ok());
+    }
+
+    #[test]
+    fn test_load_missing_file() {
+        let log = load(&temp_path("missing.aof")).unwrap();
+        assert!(log.commands.is_empty());
+    }
+
+    #[test]
+    fn test_load_truncated_tail() {
+        let path = temp_path("truncated.aof");
+        fs::write(
+            &path,
+            b"*2\r\n$3\r\nDEL\r\n$1\r\nk\r\n*3\r\n$3\r\nSET\r\n$1\r\nk",
+        )
+        .unwrap();
+
+        let log = load(&path).unwrap();
+        assert_eq!(log.commands, vec![args(&["DEL", "k"])]);
+        assert_eq!(log.truncated, 18);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 20);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_load_unfinished_transaction() {
+        let path = temp_path("multi.aof");
+        let mut aof = AppendOnlyFile::open(&path, FsyncPolicy::Always).unwrap();
+
+        aof.append(&args(&["MULTI"])).unwrap();
+        aof.append(&args(&["SET", "k", "v"])).unwrap();
+        aof.append(&args(&["EXEC"])).unwrap();
+        let complete = fs::metadata(&path).unwrap().len();
+        // Cut off by a crash before the EXEC.
+        aof.append(&args(&["MULTI"])).unwrap();
+        aof.append(&args(&["DEL", "k"])).unwrap();
+
+        let log = load(&path).unwrap();
+        assert_eq!(log.commands, vec![args(&["SET", "k", "v"])]);
+        assert_eq!(log.truncated, 35);
+        assert_eq!(fs::metadata(&path).unwrap().len(), complete);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_load_corrupt_file() {
+        let path = temp_path("corrupt.aof");
+        fs::write(&path, b"*1\r\n$4\r\nPING\r\ngarbage\r\n").unwrap();
+
+        let err = load(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_relative_expiry_is_logged_as_absolute() {
+        let path = temp_path("expiry.aof");
+        let mut aof = AppendOnlyFile::open(&path, FsyncPolicy::No).unwrap();
+        let before = now_millis();
+
+        aof.append(&args(&["set", "k", "v", "ex", "10"])).unwrap();
+
+        let log = load(&path).unwrap();
+        let entry = &log.commands[0];
+        assert_eq!(entry[..4], args(&["SET", "k", "v", "PXAT"])[..]);
+        let at: u64 = std::str::from_utf8(&entry[4]).unwrap().parse().unwrap();
+        assert!(at >= before + 10_000 && at <= now_millis() + 10_000);
+
+        aof.append(&args(&["SET", "k", "v", "NX", "PX", "10000", "GET"]))
+            .unwrap();
+        let log = load(&path).unwrap();
+        let entry = &log.commands[1];
+        assert_eq!(entry[..5], args(&["SET", "k", "v", "NX", "PXAT"])[..]);
+        let at: u64 = std::str::from_utf8(&entry[5]).unwrap().parse().unwrap();
+        assert!(at >= before + 10_000 && at <= now_millis() + 10_000);
+        assert_eq!(entry.len(), 6);
+
+        aof.append(&args(&["expire", "k", "10", "GT"])).unwrap();
+        aof.append(&args(&["EXPIREAT", "k", "1700000000"])).unwrap();
+
+        let log = load(&path).unwrap();
+        let entry = &log.commands[2];
+        assert_eq!(entry[..2], args(&["PEXPIREAT", "k"])[..]);
+        assert_eq!(entry[3], b"GT");
+        let at: u64 = std::str::from_utf8(&entry[2]).unwrap().parse().unwrap();
+        assert!(at >= before + 10_000 && at <= now_millis() + 10_000);
+        assert_eq!(log.commands[3], args(&["PEXPIREAT", "k", "1700000000000"]));
+
+        aof.append(&args(&["getex", "k", "exat", "1700000000"]))
+            .unwrap();
+        aof.append(&args(&["GETEX", "k", "PERSIST"])).unwrap();
+        let log = load(&path).unwrap();
+        assert_eq!(log.commands[4], args(&["PEXPIREAT", "k", "1700000000000"]));
+        assert_eq!(log.commands[5], args(&["PERSIST", "k"]));
+
+        aof.append(&args(&[
+            "hexpireat",
+            "h",
+            "1700000000",
+            "NX",
+            "FIELDS",
+            "1",
+            "f",
+        ]))
+        .unwrap();
+        let log = load(&path).unwrap();
+        assert_eq!(
+            log.commands[6],
+            args(&["HPEXPIREAT", "h", "1700000000000", "NX", "FIELDS", "1", "f"])
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_rewrite_keeps_ttls_of_lists_and_hashes() {
+        let path = temp_path("rewrite_ttl.aof");
+        let mut storage = Storage::new();
+        let at = now_millis() + 60_000;
+        storage.rpush(b"l", vec![b"a".to_vec()]).unwrap();
+        storage.expire_at(b"l", at as i64, Default::default());
+
+        write_rewrite(&storage.snapshot(), &path).unwrap();
+
+        let commands = load(&path).unwrap().commands;
+        assert_eq!(commands[0], args(&["RPUSH", "l", "a"]));
+        assert_eq!(commands[1], args(&["PEXPIREAT", "l", &at.to_string()]));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_rewrite_keeps_ttls_of_hash_fields() {
+        let path = temp_path("rewrite_field_ttl.aof");
+        let mut storage = Storage::new();
+        let at = now_millis() + 60_000;
+        storage
+            .hset(b"h".to_vec(), vec![(b"f".to_vec(), b"v".to_vec())])
+            .unwrap();
+        storage
+            .hexpire(b"h", &[b"f".to_vec()], at as i64, Default::default())
+            .unwrap();
+
+        write_rewrite(&storage.snapshot(), &path).unwrap();
+
+        let commands = load(&path).unwrap().commands;
+        assert_eq!(commands[0], args(&["HSET", "h", "f", "v"]));
+        assert_eq!(
+            commands[1],
+            args(&["HPEXPIREAT", "h", &at.to_string(), "FIELDS", "1", "f"])
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_rewrite_keeps_streams() {
+        use crate::stream::{NewId, ReadFrom};
+
+        let path = temp_path("rewrite_stream.aof");
+        let mut storage = Storage::new();
+        let fields = || vec![(b"f".to_vec(), b"v".to_vec())];
+        for ms in 1..=3 {
+            let id = NewId::Exact(StreamId::new(ms, 0));
+            storage.xadd(b"s", id, fields(), false, None).unwrap();
+        }
+        storage
+            .xgroup_create(b"s", b"g", ReadFrom::After(StreamId::MIN), false)
+            .unwrap();
+        let keys = [b"s".to_vec()];
+        storage
+            .xreadgroup(b"g", b"c", &keys, &[ReadFrom::Undelivered], Some(2), false)
+            .unwrap();
+        // Neither the newest entry nor the pending one can be recreated.
+        storage
+            .xdel(b"s", &[StreamId::new(2, 0), StreamId::new(3, 0)])
+            .unwrap();
+        storage
+            .xgroup_create(b"empty", b"g", ReadFrom::Last, true)
+            .unwrap();
+
+        write_rewrite(&storage.snapshot(), &path).unwrap();
+
+        let delivered_at = storage.xgroup(b"s", b"g").unwrap().pending()[&StreamId::new(1, 0)]
+            .delivered_at
+            .to_string();
+        let mut commands = load(&path).unwrap().commands;
+        // The keys come in no particular order.
+        if commands[0][1] == b"s" {
+            commands.rotate_left(5);
+        }
+        assert_eq!(
+            commands,
+            [
+                args(&["XADD", "empty", "MAXLEN", "0", "0-1", "x", "y"]),
+                args(&["XSETID", "empty", "0-0"]),
+                args(&["XGROUP", "CREATE", "empty", "g", "0-0"]),
+                args(&["XADD", "s", "1-0", "f", "v"]),
+                args(&["XSETID", "s", "3-0"]),
+                args(&["XGROUP", "CREATE", "s", "g", "2-0"]),
+                args(&["XGROUP", "CREATECONSUMER", "s", "g", "c"]),
+                args(&[
+                    "XCLAIM",
+                    "s",
+                    "g",
+                    "c",
+                    "0",
+                    "1-0",
+                    "TIME",
+                    &delivered_at,
+                    "RETRYCOUNT",
+                    "1",
+                    "FORCE",
+                    "JUSTID",
+                ]),
+            ]
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_pending_sync_only_for_everysec() {
+        let path = temp_path("sync.aof");
+        let mut aof = AppendOnlyFile::open(&path, FsyncPolicy::EverySec).unwrap();
+
+        assert!(aof.take_pending_sync().unwrap().is_none());
+        aof.append(&args(&["DEL", "k"])).unwrap();
+        assert!(aof.take_pending_sync().unwrap().is_some());
+        assert!(aof.take_pending_sync().unwrap().is_none());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_write_rewrite() {
+        let path = temp_path("rewrite.aof");
+        let mut storage = Storage::new();
+        storage.set(b"k".to_vec(), b"old".to_vec(), None).unwrap();
+        storage.set(b"k".to_vec(), b"new".to_vec(), None).unwrap();
+        storage
+            .set(b"gone".to_vec(), b"v".to_vec(), Some(Duration::ZERO))
+            .unwrap();
+        let items = (0..100).map(|i| i.to_string().into_bytes()).collect();
+        storage.rpush(b"l", items).unwrap();
+        storage
+            .hset(b"h".to_vec(), vec![(b"f".to_vec(), b"v".to_vec())])
+            .unwrap();
+
+        write_rewrite(&storage.snapshot(), &path).unwrap();
+
+        let mut commands = load(&path).unwrap().commands;
+        commands.sort();
+        assert_eq!(commands.len(), 4);
+        assert_eq!(commands[0], args(&["HSET", "h", "f", "v"]));
+        assert_eq!(commands[1].len(), 2 + REWRITE_ITEMS_PER_CMD);
+        assert_eq!(commands[2].len(), 2 + 100 - REWRITE_ITEMS_PER_CMD);
+        assert_eq!(commands[3], args(&["SET", "k", "new"]));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_rewrite_keeps_writes_made_meanwhile() {
+        let path = temp_path("swap.aof");
+        let mut aof = AppendOnlyFile::open(&path, FsyncPolicy::No).unwrap();
+        for _ in 0..10 {
+            aof.append(&args(&["SET", "k", "v"])).unwrap();
+        }
+
+        assert!(aof.start_rewrite());
+        assert!(!aof.start_rewrite());
+        let mut storage = Storage::new();
+        storage.set(b"k".to_vec(), b"v".to_vec(), None).unwrap();
+        write_rewrite(&storage.snapshot(), &aof.rewrite_path()).unwrap();
+
+        aof.append(&args(&["DEL", "k"])).unwrap();
+        aof.finish_rewrite().unwrap();
+        aof.append(&args(&["SET", "j", "1"])).unwrap();
+
+        assert!(!aof.is_rewriting());
+        assert_eq!(
+            load(&path).unwrap().commands,
+            vec![
+                args(&["SET", "k", "v"]),
+                args(&["DEL", "k"]),
+                args(&["SET", "j", "1"])
+            ]
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_needs_rewrite() {
+        let path = temp_path("growth.aof");
+        let mut aof = AppendOnlyFile::open(&path, FsyncPolicy::No).unwrap();
+        aof.append(&args(&["SET", "k", "v"])).unwrap();
+
+        // Everything written since startup counts as growth over an empty base.
+        assert!(aof.needs_rewrite(100, 0));
+        assert!(!aof.needs_rewrite(100, 1024));
+        assert!(!aof.needs_rewrite(0, 0));
+
+        drop(aof);
+        let mut aof = AppendOnlyFile::open(&path, FsyncPolicy::No).unwrap();
+        assert!(!aof.needs_rewrite(100, 0));
+        aof.append(&args(&["SET", "k", "v"])).unwrap();
+        assert!(aof.needs_rewrite(100, 0));
+        assert!(!aof.needs_rewrite(101, 0));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_rewrite() {
+        let path = temp_path("reset.aof");
+        let mut aof = AppendOnlyFile::open(&path, FsyncPolicy::No).unwrap();
+
+        aof.append(&args(&["DEL", "k"])).unwrap();
+        assert!(aof.start_rewrite());
+        let mut storage = Storage::new();
+        storage.set(b"k".to_vec(), b"v".to_vec(), None).unwrap();
+        let snapshot_path = temp_path("reset.db");
+        snapshot::save(&storage.snapshot(), &snapshot_path).unwrap();
+        aof.append(&args(&["DEL", "j"])).unwrap();
+        write_snapshot_rewrite(&snapshot_path, &aof.rewrite_path()).unwrap();
+        aof.finish_rewrite().unwrap();
+
+        // The log starts with the snapshot, and only holds the writes made
+        // after it was taken.
+        let log = load(&path).unwrap();
+        let mut base = log.base.unwrap();
+        assert_eq!(base.get_string(b"k"), Ok(Some(&b"v".to_vec())));
+        assert_eq!(log.commands, vec![args(&["DEL", "j"])]);
+
+        // Appends after the rewrite follow the snapshot.
+        aof.append(&args(&["DEL", "i"])).unwrap();
+        assert_eq!(load(&path).unwrap().commands.len(), 2);
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&snapshot_path).unwrap();
+    }
+}
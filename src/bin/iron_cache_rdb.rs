@@ -0,0 +1,61 @@
+//! Converts between Redis RDB files and IronCache snapshots.
+//!
+//!     iron_cache_rdb import <dump.rdb> <dump.db>
+//!     iron_cache_rdb export <dump.db> <dump.rdb>
+
+use iron_cache::{rdb, snapshot};
+use std::path::Path;
+use std::process::ExitCode;
+
+const USAGE: &str = "Usage: iron_cache_rdb import <rdb file> <snapshot>\n       iron_cache_rdb export <snapshot> <rdb file>";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let result = match args.as_slice() {
+        [mode, from, to] if mode == "import" => import(Path::new(from), Path::new(to)),
+        [mode, from, to] if mode == "export" => export(Path::new(from), Path::new(to)),
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match result {
+        Ok(keys) => {
+            println!("Converted {} keys", keys);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn import(from: &Path, to: &Path) -> Result<usize, Box<dyn std::error::Error>> {
+    let storage = rdb::load(from)
+        .map_err(|e| format!("Failed to read RDB file {}: {}", from.display(), e))?;
+    snapshot::save(&storage.snapshot(), to)?;
+    Ok(storage.entries().count())
+}
+
+fn export(from: &Path, to: &Path) -> Result<usize, Box<dyn std::error::Error>> {
+    let storage = snapshot::load(from)
+        .map_err(|e| format!("Failed to read snapshot {}: {}", from.display(), e))?
+        .ok_or_else(|| format!("Snapshot {} does not exist", from.display()))?;
+    let losses = rdb::save(&storage, to)?;
+    for key in &losses.streams {
+        eprintln!(
+            "Warning: left out {}, streams can't be exported",
+            String::from_utf8_lossy(key)
+        );
+    }
+    for key in &losses.field_expiries {
+        eprintln!(
+            "Warning: exported {} without the expiry of its fields",
+            String::from_utf8_lossy(key)
+        );
+    }
+    Ok(storage.entries().count() - losses.streams.len())
+}
@@ -0,0 +1,242 @@
+//! Clients parked by BLPOP, BRPOP and BLMOVE until a list they wait on gets
+//! an element, and by XREAD and XREADGROUP until a stream gets new entries.
+//!
+//! Clients are only added and served with the storage lock held: a client
+//! blocks under the same lock it found its keys empty under, and a push
+//! serves the clients waiting on its key before the lock is released. So no
+//! push can slip in between and be missed, and a newly arriving client can't
+//! take an element ahead of those already waiting for it.
+//!
+//! A client that times out or disconnects unblocks itself without the
+//! storage lock, only taking the registry's own. That can't lose anything:
+//! either it is removed before a push gets to it, or the push has already
+//! served it and `unblock` says so, leaving the reply to be picked up.
+
+use crate::resp::Frame;
+use crate::storage::ListEnd;
+use crate::stream::ReadFrom;
+use std::collections::{HashMap, VecDeque};
+use tokio::sync::oneshot;
+
+/// What to do with the element once a blocked client is served.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockingOp {
+    /// BLPOP or BRPOP.
+    Pop(ListEnd),
+    /// BLMOVE.
+    Move {
+        destination: Vec<u8>,
+        from: ListEnd,
+        to: ListEnd,
+    },
+    /// XREAD, or XREADGROUP with a `group` and consumer, reading each key
+    /// from the matching entry of `from`. XREAD's `$` is resolved to the
+    /// last ID when the client blocks.
+    ReadStreams {
+        from: Vec<ReadFrom>,
+        count: Option<usize>,
+        group: Option<(Vec<u8>, Vec<u8>)>,
+        no_ack: bool,
+    },
+}
+
+/// A client waiting for one of `keys` to get an element.
+pub struct Blocked {
+    pub keys: Vec<Vec<u8>>,
+    pub op: BlockingOp,
+    reply: oneshot::Sender<Frame>,
+}
+
+impl Blocked {
+    /// Sends the reply that unblocks the client.
+    pub fn reply(self, frame: Frame) {
+        // The client may have gone away since, there is nobody to tell then.
+        let _ = self.reply.send(frame);
+    }
+}
+
+#[derive(Default)]
+pub struct BlockedClients {
+    next_id: u64,
+    clients: HashMap<u64, Blocked>,
+    // The clients waiting on each key, in the order they started waiting.
+    queues: HashMap<Vec<u8>, VecDeque<u64>>,
+}
+
+impl BlockedClients {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    /// The number of clients currently blocked.
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Parks a client on `keys`. Returns an id to `unblock` it with and where
+    /// its reply will arrive once it is served.
+    pub fn block(&mut self, keys: Vec<Vec<u8>>, op: BlockingOp) -> (u64, oneshot::Receiver<Frame>) {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        for key in &keys {
+            let queue = self.queues.entry(key.clone()).or_default();
+            // BLPOP may name the same key twice, but it only waits once.
+            if !queue.contains(&id) {
+                queue.push_back(id);
+            }
+        }
+
+        let (reply, receiver) = oneshot::channel();
+        self.clients.insert(id, Blocked { keys, op, reply });
+        (id, receiver)
+    }
+
+    /// Stops waiting, after a timeout or a disconnect. Returns false if the
+    /// client has already been served, in which case its reply is on the way.
+    pub fn unblock(&mut self, id: u64) -> bool {
+        match self.clients.remove(&id) {
+            Some(client) => {
+                self.forget(id, &client.keys);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Offers `key` to the clients waiting on it, longest waiting first.
+    /// Those `serve` returns a reply for get it and are no longer blocked on
+    /// any of their keys, the others keep waiting.
+    pub fn serve(&mut self, key: &[u8], mut serve: impl FnMut(&Blocked) -> Option<Frame>) {
+        let Some(queue) = self.queues.get(key) else {
+            return;
+        };
+
+        for id in queue.clone() {
+            let client = &self.clients[&id];
+            // Nobody is listening for the reply any more, so whatever it was
+            // given would be lost. Leave it for the next client, the one
+            // that went away unblocks itself.
+            if client.reply.is_closed() {
+                continue;
+            }
+            if let Some(frame) = serve(client) {
+                let client = self
+                    .clients
+                    .remove(&id)
+                    .expect("queued clients are blocked");
+                self.forget(id, &client.keys);
+                client.reply(frame);
+            }
+        }
+    }
+
+    fn forget(&mut self, id: u64, keys: &[Vec<u8>]) {
+        for key in keys {
+            if let Some(queue) = self.queues.get_mut(key) {
+                queue.retain(|&queued| queued != id);
+                if queue.is_empty() {
+                    self.queues.remove(key);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(names: &[&str]) -> Vec<Vec<u8>> {
+        names.iter().map(|name| name.as_bytes().to_vec()).collect()
+    }
+
+    // Serves every client waiting on `key`, replying with the number of
+    // clients served before it, and returns their ops in that order.
+    fn serve_all(blocked: &mut BlockedClients, key: &[u8]) -> Vec<BlockingOp> {
+        let mut served = Vec::new();
+        blocked.serve(key, |client| {
+            served.push(client.op.clone());
+            Some(Frame::Integer(served.len() as i64 - 1))
+        });
+        served
+    }
+
+    #[test]
+    fn test_served_in_fifo_order() {
+        let mut blocked = BlockedClients::new();
+        let (_, mut first) = blocked.block(keys(&["q"]), BlockingOp::Pop(ListEnd::Left));
+        let (_, mut second) = blocked.block(keys(&["q"]), BlockingOp::Pop(ListEnd::Right));
+
+        assert_eq!(
+            serve_all(&mut blocked, b"q"),
+            [
+                BlockingOp::Pop(ListEnd::Left),
+                BlockingOp::Pop(ListEnd::Right)
+            ]
+        );
+        assert_eq!(first.try_recv().unwrap(), Frame::Integer(0));
+        assert_eq!(second.try_recv().unwrap(), Frame::Integer(1));
+        assert!(serve_all(&mut blocked, b"q").is_empty());
+        assert!(blocked.is_empty());
+    }
+
+    #[test]
+    fn test_served_client_leaves_every_queue() {
+        let mut blocked = BlockedClients::new();
+        let (id, mut receiver) = blocked.block(keys(&["a", "b"]), BlockingOp::Pop(ListEnd::Left));
+
+        assert_eq!(serve_all(&mut blocked, b"b").len(), 1);
+        assert!(serve_all(&mut blocked, b"a").is_empty());
+        assert!(!blocked.unblock(id));
+        assert_eq!(receiver.try_recv().unwrap(), Frame::Integer(0));
+    }
+
+    #[test]
+    fn test_unserved_clients_keep_waiting() {
+        let mut blocked = BlockedClients::new();
+        let (_, mut list) = blocked.block(keys(&["k"]), BlockingOp::Pop(ListEnd::Left));
+        let reader = BlockingOp::ReadStreams {
+            from: vec![ReadFrom::Last],
+            count: None,
+            group: None,
+            no_ack: false,
+        };
+        let (_, mut stream) = blocked.block(keys(&["k"]), reader);
+
+        blocked.serve(b"k", |client| {
+            matches!(client.op, BlockingOp::ReadStreams { .. }).then_some(Frame::Null)
+        });
+        assert_eq!(stream.try_recv().unwrap(), Frame::Null);
+        assert!(list.try_recv().is_err());
+        assert_eq!(blocked.len(), 1);
+    }
+
+    #[test]
+    fn test_unblock() {
+        let mut blocked = BlockedClients::new();
+        let (id, _receiver) = blocked.block(keys(&["q", "q"]), BlockingOp::Pop(ListEnd::Left));
+        assert_eq!(blocked.len(), 1);
+
+        assert!(blocked.unblock(id));
+        assert!(serve_all(&mut blocked, b"q").is_empty());
+        assert!(blocked.queues.is_empty());
+    }
+
+    #[test]
+    fn test_skips_clients_that_went_away() {
+        let mut blocked = BlockedClients::new();
+        let (_, receiver) = blocked.block(keys(&["q"]), BlockingOp::Pop(ListEnd::Left));
+        let (_, _alive) = blocked.block(keys(&["q"]), BlockingOp::Pop(ListEnd::Right));
+        drop(receiver);
+
+        assert_eq!(
+            serve_all(&mut blocked, b"q"),
+            [BlockingOp::Pop(ListEnd::Right)]
+        );
+    }
+}
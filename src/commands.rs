@@ -1,3 +1,8 @@
+use crate::sorted_set::{Aggregate, RangeBy};
+use crate::storage::{ExpireCondition, Expiry, ListEnd, Pairs, SetOp, SetOptions, ZAddOptions};
+use crate::stream::{ClaimOptions, DeliveredAt, Fields, NewId, ReadFrom, StreamId, Trim, TrimBy};
+use std::fmt;
+use std::ops::Bound;
 use std::time::Duration;
 
 #[derive(Debug)]
@@ -6,52 +11,527 @@ pub enum ParseError {
     InvalidArgument(String), // Can hold a message about what went wrong
 }
 
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnknownCommand => write!(f, "ERR unknown command"),
+            ParseError::InvalidArgument(msg) => write!(f, "ERR {}", msg),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Command {
     Get {
-        key: String,
+        key: Vec<u8>,
     },
     Set {
-        key: String,
-        value: String,
-        expiry: Option<Duration>,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        options: SetOptions,
     },
     Del {
-        key: String,
+        keys: Vec<Vec<u8>>,
+    },
+    MGet {
+        keys: Vec<Vec<u8>>,
+    },
+    MSet {
+        pairs: Pairs,
+    },
+    MSetNx {
+        pairs: Pairs,
+    },
+    SetNx {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    GetDel {
+        key: Vec<u8>,
+    },
+    /// GET, optionally changing the key's expiry.
+    GetEx {
+        key: Vec<u8>,
+        expiry: Option<ExpiryChange>,
+    },
+    Append {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    StrLen {
+        key: Vec<u8>,
+    },
+    GetRange {
+        key: Vec<u8>,
+        start: i64,
+        end: i64,
+    },
+    SetRange {
+        key: Vec<u8>,
+        offset: usize,
+        value: Vec<u8>,
+    },
+    /// INCR, DECR, INCRBY and DECRBY.
+    IncrBy {
+        key: Vec<u8>,
+        delta: i64,
+    },
+    IncrByFloat {
+        key: Vec<u8>,
+        delta: f64,
     },
     LPush {
-        key: String,
-        values: Vec<String>,
+        key: Vec<u8>,
+        values: Vec<Vec<u8>>,
     },
     RPush {
-        key: String,
-        values: Vec<String>,
+        key: Vec<u8>,
+        values: Vec<Vec<u8>>,
     },
     LRange {
-        key: String,
+        key: Vec<u8>,
+        start: i64,
+        stop: i64,
+    },
+    /// LPOP or RPOP. Without a count a single element is popped and replied
+    /// with on its own rather than in an array.
+    Pop {
+        key: Vec<u8>,
+        end: ListEnd,
+        count: Option<usize>,
+    },
+    /// BLPOP or BRPOP. A timeout of `None` waits forever.
+    BPop {
+        keys: Vec<Vec<u8>>,
+        end: ListEnd,
+        timeout: Option<Duration>,
+    },
+    /// BLMOVE, and BRPOPLPUSH which is BLMOVE RIGHT LEFT.
+    BLMove {
+        source: Vec<u8>,
+        destination: Vec<u8>,
+        from: ListEnd,
+        to: ListEnd,
+        timeout: Option<Duration>,
+    },
+    LLen {
+        key: Vec<u8>,
+    },
+    LIndex {
+        key: Vec<u8>,
+        index: i64,
+    },
+    LSet {
+        key: Vec<u8>,
+        index: i64,
+        value: Vec<u8>,
+    },
+    LInsert {
+        key: Vec<u8>,
+        before: bool,
+        pivot: Vec<u8>,
+        value: Vec<u8>,
+    },
+    LRem {
+        key: Vec<u8>,
+        count: i64,
+        value: Vec<u8>,
+    },
+    LTrim {
+        key: Vec<u8>,
         start: i64,
         stop: i64,
     },
+    /// LPOS. Without a count only the first match is replied with, on its
+    /// own.
+    LPos {
+        key: Vec<u8>,
+        element: Vec<u8>,
+        rank: i64,
+        count: Option<usize>,
+        max_len: usize,
+    },
+    /// LMOVE, and RPOPLPUSH which is LMOVE RIGHT LEFT.
+    LMove {
+        source: Vec<u8>,
+        destination: Vec<u8>,
+        from: ListEnd,
+        to: ListEnd,
+    },
     HSet {
-        key: String,
-        field: String,
-        value: String,
+        key: Vec<u8>,
+        pairs: Pairs,
+    },
+    /// The deprecated HMSET, which is HSET replying with OK.
+    HMSet {
+        key: Vec<u8>,
+        pairs: Pairs,
+    },
+    HSetNx {
+        key: Vec<u8>,
+        field: Vec<u8>,
+        value: Vec<u8>,
     },
     HGet {
-        key: String,
-        field: String,
+        key: Vec<u8>,
+        field: Vec<u8>,
     },
     HDel {
-        key: String,
-        fields: Vec<String>,
+        key: Vec<u8>,
+        fields: Vec<Vec<u8>>,
     },
     HLen {
-        key: String,
+        key: Vec<u8>,
     },
     HGetAll {
-        key: String,
+        key: Vec<u8>,
+    },
+    HMGet {
+        key: Vec<u8>,
+        fields: Vec<Vec<u8>>,
+    },
+    HExists {
+        key: Vec<u8>,
+        field: Vec<u8>,
+    },
+    HKeys {
+        key: Vec<u8>,
+    },
+    HVals {
+        key: Vec<u8>,
+    },
+    HIncrBy {
+        key: Vec<u8>,
+        field: Vec<u8>,
+        delta: i64,
+    },
+    HIncrByFloat {
+        key: Vec<u8>,
+        field: Vec<u8>,
+        delta: f64,
+    },
+    HStrLen {
+        key: Vec<u8>,
+        field: Vec<u8>,
+    },
+    /// HRANDFIELD. Without a count a single field is replied with on its own.
+    HRandField {
+        key: Vec<u8>,
+        count: Option<i64>,
+        with_values: bool,
+    },
+    SAdd {
+        key: Vec<u8>,
+        members: Vec<Vec<u8>>,
+    },
+    SRem {
+        key: Vec<u8>,
+        members: Vec<Vec<u8>>,
+    },
+    SMembers {
+        key: Vec<u8>,
+    },
+    SIsMember {
+        key: Vec<u8>,
+        member: Vec<u8>,
+    },
+    SCard {
+        key: Vec<u8>,
+    },
+    /// SPOP. Without a count a single member is replied with on its own.
+    SPop {
+        key: Vec<u8>,
+        count: Option<usize>,
+    },
+    /// SRANDMEMBER. Without a count a single member is replied with on its
+    /// own.
+    SRandMember {
+        key: Vec<u8>,
+        count: Option<i64>,
+    },
+    SMove {
+        source: Vec<u8>,
+        destination: Vec<u8>,
+        member: Vec<u8>,
+    },
+    /// SINTER, SUNION or SDIFF.
+    SetOp {
+        op: SetOp,
+        keys: Vec<Vec<u8>>,
+    },
+    /// SINTERSTORE, SUNIONSTORE or SDIFFSTORE.
+    SetOpStore {
+        op: SetOp,
+        destination: Vec<u8>,
+        keys: Vec<Vec<u8>>,
+    },
+    /// ZADD. With `incr` it takes a single pair and acts like ZINCRBY.
+    ZAdd {
+        key: Vec<u8>,
+        pairs: Vec<(f64, Vec<u8>)>,
+        options: ZAddOptions,
+        incr: bool,
+    },
+    ZIncrBy {
+        key: Vec<u8>,
+        delta: f64,
+        member: Vec<u8>,
+    },
+    ZRem {
+        key: Vec<u8>,
+        members: Vec<Vec<u8>>,
+    },
+    ZScore {
+        key: Vec<u8>,
+        member: Vec<u8>,
+    },
+    ZCard {
+        key: Vec<u8>,
+    },
+    /// ZRANK, or ZREVRANK when `rev` is set.
+    ZRank {
+        key: Vec<u8>,
+        member: Vec<u8>,
+        rev: bool,
+    },
+    /// ZRANGE, and the ZREVRANGE, ZRANGEBYSCORE, ZREVRANGEBYSCORE,
+    /// ZRANGEBYLEX and ZREVRANGEBYLEX it replaced. `limit` is LIMIT's offset
+    /// and count.
+    ZRange {
+        key: Vec<u8>,
+        by: RangeBy,
+        rev: bool,
+        limit: Option<(i64, i64)>,
+        with_scores: bool,
+    },
+    /// ZCOUNT or ZLEXCOUNT.
+    ZCount {
+        key: Vec<u8>,
+        by: RangeBy,
+    },
+    /// ZPOPMIN, or ZPOPMAX when `max` is set.
+    ZPop {
+        key: Vec<u8>,
+        max: bool,
+        count: usize,
+    },
+    /// ZUNIONSTORE, ZINTERSTORE or ZDIFFSTORE.
+    ZStore {
+        op: SetOp,
+        destination: Vec<u8>,
+        keys: Vec<Vec<u8>>,
+        weights: Vec<f64>,
+        aggregate: Aggregate,
+    },
+    /// XADD. `trim` is MAXLEN or MINID, applied once the entry is added.
+    XAdd {
+        key: Vec<u8>,
+        id: NewId,
+        fields: Fields,
+        no_mkstream: bool,
+        trim: Option<Trim>,
+    },
+    XLen {
+        key: Vec<u8>,
+    },
+    /// XRANGE, or XREVRANGE when `rev` is set.
+    XRange {
+        key: Vec<u8>,
+        start: Bound<StreamId>,
+        end: Bound<StreamId>,
+        rev: bool,
+        count: Option<usize>,
+    },
+    XTrim {
+        key: Vec<u8>,
+        trim: Trim,
+    },
+    XDel {
+        key: Vec<u8>,
+        ids: Vec<StreamId>,
+    },
+    XSetId {
+        key: Vec<u8>,
+        last_id: StreamId,
+    },
+    /// XREAD, reading each of `keys` from the matching ID in `ids`. `block`
+    /// is BLOCK's timeout, where `Some(None)` waits forever.
+    XRead {
+        keys: Vec<Vec<u8>>,
+        ids: Vec<ReadFrom>,
+        count: Option<usize>,
+        block: Option<Option<Duration>>,
+    },
+    /// XREADGROUP, which reads like XREAD on behalf of a consumer.
+    XReadGroup {
+        group: Vec<u8>,
+        consumer: Vec<u8>,
+        keys: Vec<Vec<u8>>,
+        ids: Vec<ReadFrom>,
+        count: Option<usize>,
+        block: Option<Option<Duration>>,
+        no_ack: bool,
+    },
+    XGroupCreate {
+        key: Vec<u8>,
+        group: Vec<u8>,
+        id: ReadFrom,
+        mkstream: bool,
+    },
+    XGroupSetId {
+        key: Vec<u8>,
+        group: Vec<u8>,
+        id: ReadFrom,
+    },
+    XGroupDestroy {
+        key: Vec<u8>,
+        group: Vec<u8>,
+    },
+    XGroupCreateConsumer {
+        key: Vec<u8>,
+        group: Vec<u8>,
+        consumer: Vec<u8>,
+    },
+    XGroupDelConsumer {
+        key: Vec<u8>,
+        group: Vec<u8>,
+        consumer: Vec<u8>,
+    },
+    XAck {
+        key: Vec<u8>,
+        group: Vec<u8>,
+        ids: Vec<StreamId>,
+    },
+    /// XPENDING: a summary of the group's pending entries, or the entries
+    /// themselves when given a `range`.
+    XPending {
+        key: Vec<u8>,
+        group: Vec<u8>,
+        range: Option<PendingRange>,
+    },
+    /// XCLAIM, with IDLE or TIME kept as a `DeliveredAt`, which is only
+    /// turned into a time when the command runs.
+    XClaim {
+        key: Vec<u8>,
+        group: Vec<u8>,
+        consumer: Vec<u8>,
+        min_idle: u64,
+        ids: Vec<StreamId>,
+        options: ClaimOptions,
+    },
+    XAutoClaim {
+        key: Vec<u8>,
+        group: Vec<u8>,
+        consumer: Vec<u8>,
+        min_idle: u64,
+        start: StreamId,
+        count: usize,
+        just_id: bool,
+    },
+    /// HEXPIRE, HPEXPIRE, HEXPIREAT and HPEXPIREAT, with the time in
+    /// milliseconds. It may be in the past, which deletes the fields.
+    HExpire {
+        key: Vec<u8>,
+        fields: Vec<Vec<u8>>,
+        at: Expiry,
+        condition: ExpireCondition,
+    },
+    /// HTTL, or HPTTL when `millis` is set.
+    HTtl {
+        key: Vec<u8>,
+        fields: Vec<Vec<u8>>,
+        millis: bool,
+    },
+    /// HEXPIRETIME, or HPEXPIRETIME when `millis` is set.
+    HExpireTime {
+        key: Vec<u8>,
+        fields: Vec<Vec<u8>>,
+        millis: bool,
+    },
+    HPersist {
+        key: Vec<u8>,
+        fields: Vec<Vec<u8>>,
+    },
+    /// EXPIRE, PEXPIRE, EXPIREAT and PEXPIREAT, with the time in
+    /// milliseconds. It may be in the past, which deletes the key.
+    Expire {
+        key: Vec<u8>,
+        at: Expiry,
+        condition: ExpireCondition,
+    },
+    /// TTL, or PTTL when `millis` is set.
+    Ttl {
+        key: Vec<u8>,
+        millis: bool,
+    },
+    /// EXPIRETIME, or PEXPIRETIME when `millis` is set.
+    ExpireTime {
+        key: Vec<u8>,
+        millis: bool,
+    },
+    Persist {
+        key: Vec<u8>,
+    },
+    Type {
+        key: Vec<u8>,
     },
     Save,
+    BgSave,
+    LastSave,
+    BgRewriteAof,
+    Info {
+        section: Option<Vec<u8>>,
+    },
+    Ping {
+        message: Option<Vec<u8>>,
+    },
+    Echo {
+        message: Vec<u8>,
+    },
+    /// SUBSCRIBE, or PSUBSCRIBE when `patterns` is set.
+    Subscribe {
+        channels: Vec<Vec<u8>>,
+        patterns: bool,
+    },
+    /// UNSUBSCRIBE, or PUNSUBSCRIBE when `patterns` is set. No channels
+    /// means every one subscribed to.
+    Unsubscribe {
+        channels: Vec<Vec<u8>>,
+        patterns: bool,
+    },
+    Publish {
+        channel: Vec<u8>,
+        message: Vec<u8>,
+    },
+    PubSubChannels {
+        pattern: Option<Vec<u8>>,
+    },
+    PubSubNumSub {
+        channels: Vec<Vec<u8>>,
+    },
+    PubSubNumPat,
+    Multi,
+    Exec,
+    Discard,
+}
+
+/// How GETEX changes the expiry of the key it reads.
+#[derive(Debug, PartialEq)]
+pub enum ExpiryChange {
+    Expire(Expiry),
+    Persist,
+}
+
+/// Which pending entries the extended form of XPENDING lists.
+#[derive(Debug, PartialEq)]
+pub struct PendingRange {
+    /// Only those idle for at least this many milliseconds.
+    pub min_idle: u64,
+    pub start: Bound<StreamId>,
+    pub end: Bound<StreamId>,
+    pub count: usize,
+    /// Only those pending for this consumer.
+    pub consumer: Option<Vec<u8>>,
 }
 
 #[derive(Debug)]
@@ -63,38 +543,186 @@ pub enum CommandParseError {
 
 impl Command {
     pub fn parse(buffer: &[u8]) -> Result<Command, ParseError> {
-        let parts = std::str::from_utf8(buffer)
-            .unwrap_or("")
-            .split_whitespace()
-            .collect::<Vec<&str>>();
+        let parts = buffer
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<&[u8]>>();
+
+        Self::from_parts(&parts)
+    }
+
+    /// Builds a command from the arguments of an already framed request.
+    pub fn from_args(args: &[Vec<u8>]) -> Result<Command, ParseError> {
+        let parts = args.iter().map(Vec::as_slice).collect::<Vec<&[u8]>>();
+
+        Self::from_parts(&parts)
+    }
+
+    /// Whether the command modifies the dataset and so must be logged to the
+    /// append-only file.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Command::Set { .. }
+                | Command::Del { .. }
+                | Command::MSet { .. }
+                | Command::MSetNx { .. }
+                | Command::SetNx { .. }
+                | Command::GetDel { .. }
+                | Command::GetEx {
+                    expiry: Some(_),
+                    ..
+                }
+                | Command::Append { .. }
+                | Command::SetRange { .. }
+                | Command::IncrBy { .. }
+                | Command::IncrByFloat { .. }
+                | Command::LPush { .. }
+                | Command::RPush { .. }
+                | Command::Pop { .. }
+                | Command::LSet { .. }
+                | Command::LInsert { .. }
+                | Command::LRem { .. }
+                | Command::LTrim { .. }
+                | Command::LMove { .. }
+                | Command::HSet { .. }
+                | Command::HMSet { .. }
+                | Command::HSetNx { .. }
+                | Command::HDel { .. }
+                | Command::HIncrBy { .. }
+                | Command::HIncrByFloat { .. }
+                | Command::SAdd { .. }
+                | Command::SRem { .. }
+                | Command::SPop { .. }
+                | Command::SMove { .. }
+                | Command::SetOpStore { .. }
+                | Command::ZAdd { .. }
+                | Command::ZIncrBy { .. }
+                | Command::ZRem { .. }
+                | Command::ZPop { .. }
+                | Command::ZStore { .. }
+                | Command::XAdd { .. }
+                | Command::XTrim { .. }
+                | Command::XDel { .. }
+                | Command::XSetId { .. }
+                | Command::XReadGroup { .. }
+                | Command::XGroupCreate { .. }
+                | Command::XGroupSetId { .. }
+                | Command::XGroupDestroy { .. }
+                | Command::XGroupCreateConsumer { .. }
+                | Command::XGroupDelConsumer { .. }
+                | Command::XAck { .. }
+                | Command::XClaim { .. }
+                | Command::XAutoClaim { .. }
+                | Command::HExpire { .. }
+                | Command::HPersist { .. }
+                | Command::Expire { .. }
+                | Command::Persist { .. }
+        )
+    }
+
+    fn from_parts(parts: &[&[u8]]) -> Result<Command, ParseError> {
+        // Command names are case-insensitive, so match on the upper-cased one.
+        let name = parts.first().map(|name| name.to_ascii_uppercase());
+        let mut parts = parts.to_vec();
+        if let Some(name) = &name {
+            parts[0] = name;
+        }
 
         match parts.as_slice() {
-            ["SET" | "set", key, value, "EX" | "ex", seconds] => {
-                let seconds = seconds.parse::<u64>().map_err(|_| {
-                    ParseError::InvalidArgument(
-                        "Expiry time must be a positive integer.".to_string(),
-                    )
+            [b"SET", key, value, options @ ..] => Ok(Command::Set {
+                key: key.to_vec(),
+                value: value.to_vec(),
+                options: parse_set_options(options)?,
+            }),
+
+            [b"GET", key] => Ok(Command::Get { key: key.to_vec() }),
+            [b"DEL", keys @ ..] if !keys.is_empty() => Ok(Command::Del {
+                keys: keys.iter().map(|s| s.to_vec()).collect(),
+            }),
+            [b"MGET", keys @ ..] if !keys.is_empty() => Ok(Command::MGet {
+                keys: keys.iter().map(|s| s.to_vec()).collect(),
+            }),
+            [b"MSET", pairs @ ..] => Ok(Command::MSet {
+                pairs: parse_pairs(pairs, "MSET")?,
+            }),
+            [b"MSETNX", pairs @ ..] => Ok(Command::MSetNx {
+                pairs: parse_pairs(pairs, "MSETNX")?,
+            }),
+            [b"SETNX", key, value] => Ok(Command::SetNx {
+                key: key.to_vec(),
+                value: value.to_vec(),
+            }),
+            [b"GETSET", key, value] => Ok(Command::Set {
+                key: key.to_vec(),
+                value: value.to_vec(),
+                options: SetOptions {
+                    get: true,
+                    ..SetOptions::default()
+                },
+            }),
+            [b"GETDEL", key] => Ok(Command::GetDel { key: key.to_vec() }),
+            [b"GETEX", key, options @ ..] => Ok(Command::GetEx {
+                key: key.to_vec(),
+                expiry: parse_getex_options(options)?,
+            }),
+            [b"APPEND", key, value] => Ok(Command::Append {
+                key: key.to_vec(),
+                value: value.to_vec(),
+            }),
+            [b"STRLEN", key] => Ok(Command::StrLen { key: key.to_vec() }),
+            [b"GETRANGE", key, start, end] => Ok(Command::GetRange {
+                key: key.to_vec(),
+                start: parse_number(start).map_err(|_| not_an_integer())?,
+                end: parse_number(end).map_err(|_| not_an_integer())?,
+            }),
+            [b"SETRANGE", key, offset, value] => {
+                let offset = parse_number::<usize>(offset).map_err(|_| {
+                    ParseError::InvalidArgument("offset is out of range".to_string())
                 })?;
 
-                Ok(Command::Set {
-                    key: key.to_string(),
-                    value: value.to_string(),
-                    expiry: Some(Duration::from_secs(seconds)),
+                Ok(Command::SetRange {
+                    key: key.to_vec(),
+                    offset,
+                    value: value.to_vec(),
                 })
             }
-            ["SET" | "set", key, value] => Ok(Command::Set {
-                key: key.to_string(),
-                value: value.to_string(),
-                expiry: None,
+            [b"INCR", key] => Ok(Command::IncrBy {
+                key: key.to_vec(),
+                delta: 1,
             }),
-
-            ["GET" | "get", key] => Ok(Command::Get {
-                key: key.to_string(),
+            [b"DECR", key] => Ok(Command::IncrBy {
+                key: key.to_vec(),
+                delta: -1,
             }),
-            ["DEL" | "del", key] => Ok(Command::Del {
-                key: key.to_string(),
+            [b"INCRBY", key, delta] => Ok(Command::IncrBy {
+                key: key.to_vec(),
+                delta: parse_number(delta).map_err(|_| not_an_integer())?,
             }),
-            ["LPUSH" | "lpush", key, values @ ..] => {
+            [b"DECRBY", key, delta] => {
+                let delta = parse_number::<i64>(delta).map_err(|_| not_an_integer())?;
+
+                Ok(Command::IncrBy {
+                    key: key.to_vec(),
+                    delta: delta.checked_neg().ok_or_else(|| {
+                        ParseError::InvalidArgument("decrement would overflow".to_string())
+                    })?,
+                })
+            }
+            [b"INCRBYFLOAT", key, delta] => {
+                let delta = parse_number::<f64>(delta)
+                    .ok()
+                    .filter(|delta| delta.is_finite())
+                    .ok_or_else(|| {
+                        ParseError::InvalidArgument("value is not a valid float".to_string())
+                    })?;
+
+                Ok(Command::IncrByFloat {
+                    key: key.to_vec(),
+                    delta,
+                })
+            }
+            [b"LPUSH", key, values @ ..] => {
                 if values.is_empty() {
                     return Err(ParseError::InvalidArgument(
                         "Usage: LPUSH <key> <value> [value ...]".to_string(),
@@ -102,11 +730,11 @@ impl Command {
                 }
 
                 Ok(Command::LPush {
-                    key: key.to_string(),
-                    values: values.iter().map(|s| s.to_string()).collect(),
+                    key: key.to_vec(),
+                    values: values.iter().map(|s| s.to_vec()).collect(),
                 })
             }
-            ["RPUSH" | "rpush", key, values @ ..] => {
+            [b"RPUSH", key, values @ ..] => {
                 if values.is_empty() {
                     return Err(ParseError::InvalidArgument(
                         "Usage: RPUSH <key> <value> [value ...]".to_string(),
@@ -114,79 +742,1753 @@ impl Command {
                 }
 
                 Ok(Command::RPush {
-                    key: key.to_string(),
-                    values: values.iter().map(|s| s.to_string()).collect(),
+                    key: key.to_vec(),
+                    values: values.iter().map(|s| s.to_vec()).collect(),
                 })
             }
-            ["LRANGE" | "lrange", key, start, stop] => {
-                let start = start.parse::<i64>().map_err(|_| {
+            [b"LRANGE", key, start, stop] => {
+                let start = parse_number::<i64>(start).map_err(|_| {
                     ParseError::InvalidArgument("start index must be an integer.".to_string())
                 })?;
-                let stop = stop.parse::<i64>().map_err(|_| {
+                let stop = parse_number::<i64>(stop).map_err(|_| {
                     ParseError::InvalidArgument("stop index must be an integer.".to_string())
                 })?;
 
                 Ok(Command::LRange {
-                    key: key.to_string(),
+                    key: key.to_vec(),
                     start,
                     stop,
                 })
             }
-            ["HSET" | "hset", key, field, value] => Ok(Command::HSet {
-                key: key.to_string(),
-                field: field.to_string(),
-                value: value.to_string(),
+            [b"LPOP" | b"RPOP", key, count @ ..] if count.len() <= 1 => {
+                let count = match count.first() {
+                    Some(count) => Some(parse_number::<usize>(count).map_err(|_| {
+                        ParseError::InvalidArgument(
+                            "value is out of range, must be positive".to_string(),
+                        )
+                    })?),
+                    None => None,
+                };
+
+                Ok(Command::Pop {
+                    key: key.to_vec(),
+                    end: if parts[0].eq_ignore_ascii_case(b"LPOP") {
+                        ListEnd::Left
+                    } else {
+                        ListEnd::Right
+                    },
+                    count,
+                })
+            }
+            [b"LLEN", key] => Ok(Command::LLen { key: key.to_vec() }),
+            [b"LINDEX", key, index] => Ok(Command::LIndex {
+                key: key.to_vec(),
+                index: parse_number(index).map_err(|_| not_an_integer())?,
+            }),
+            [b"LSET", key, index, value] => Ok(Command::LSet {
+                key: key.to_vec(),
+                index: parse_number(index).map_err(|_| not_an_integer())?,
+                value: value.to_vec(),
+            }),
+            [b"LINSERT", key, position, pivot, value] => {
+                let before = match position.to_ascii_uppercase().as_slice() {
+                    b"BEFORE" => true,
+                    b"AFTER" => false,
+                    _ => return Err(ParseError::InvalidArgument("syntax error".to_string())),
+                };
+
+                Ok(Command::LInsert {
+                    key: key.to_vec(),
+                    before,
+                    pivot: pivot.to_vec(),
+                    value: value.to_vec(),
+                })
+            }
+            [b"LREM", key, count, value] => Ok(Command::LRem {
+                key: key.to_vec(),
+                count: parse_number(count).map_err(|_| not_an_integer())?,
+                value: value.to_vec(),
+            }),
+            [b"LTRIM", key, start, stop] => Ok(Command::LTrim {
+                key: key.to_vec(),
+                start: parse_number(start).map_err(|_| not_an_integer())?,
+                stop: parse_number(stop).map_err(|_| not_an_integer())?,
+            }),
+            [b"LPOS", key, element, options @ ..] => parse_lpos(key, element, options),
+            [b"LMOVE", source, destination, from, to] => Ok(Command::LMove {
+                source: source.to_vec(),
+                destination: destination.to_vec(),
+                from: parse_list_end(from)?,
+                to: parse_list_end(to)?,
+            }),
+            [b"RPOPLPUSH", source, destination] => Ok(Command::LMove {
+                source: source.to_vec(),
+                destination: destination.to_vec(),
+                from: ListEnd::Right,
+                to: ListEnd::Left,
+            }),
+            [b"BLPOP" | b"BRPOP", keys @ .., timeout] if !keys.is_empty() => Ok(Command::BPop {
+                keys: keys.iter().map(|s| s.to_vec()).collect(),
+                end: if parts[0].eq_ignore_ascii_case(b"BLPOP") {
+                    ListEnd::Left
+                } else {
+                    ListEnd::Right
+                },
+                timeout: parse_timeout(timeout)?,
+            }),
+            [b"BLMOVE", source, destination, from, to, timeout] => Ok(Command::BLMove {
+                source: source.to_vec(),
+                destination: destination.to_vec(),
+                from: parse_list_end(from)?,
+                to: parse_list_end(to)?,
+                timeout: parse_timeout(timeout)?,
+            }),
+            [b"BRPOPLPUSH", source, destination, timeout] => Ok(Command::BLMove {
+                source: source.to_vec(),
+                destination: destination.to_vec(),
+                from: ListEnd::Right,
+                to: ListEnd::Left,
+                timeout: parse_timeout(timeout)?,
+            }),
+            [b"HSET", key, pairs @ ..] => Ok(Command::HSet {
+                key: key.to_vec(),
+                pairs: parse_pairs(pairs, "HSET")?,
+            }),
+            [b"HMSET", key, pairs @ ..] => Ok(Command::HMSet {
+                key: key.to_vec(),
+                pairs: parse_pairs(pairs, "HMSET")?,
+            }),
+            [b"HSETNX", key, field, value] => Ok(Command::HSetNx {
+                key: key.to_vec(),
+                field: field.to_vec(),
+                value: value.to_vec(),
+            }),
+            [b"HGET", key, field] => Ok(Command::HGet {
+                key: key.to_vec(),
+                field: field.to_vec(),
+            }),
+            [b"HDEL", key, fields @ ..] if !fields.is_empty() => Ok(Command::HDel {
+                key: key.to_vec(),
+                fields: fields.iter().map(|s| s.to_vec()).collect(),
+            }),
+            [b"HLEN", key] => Ok(Command::HLen { key: key.to_vec() }),
+            [b"HGETALL", key] => Ok(Command::HGetAll { key: key.to_vec() }),
+            [b"HMGET", key, fields @ ..] if !fields.is_empty() => Ok(Command::HMGet {
+                key: key.to_vec(),
+                fields: fields.iter().map(|s| s.to_vec()).collect(),
+            }),
+            [b"HEXISTS", key, field] => Ok(Command::HExists {
+                key: key.to_vec(),
+                field: field.to_vec(),
+            }),
+            [b"HKEYS", key] => Ok(Command::HKeys { key: key.to_vec() }),
+            [b"HVALS", key] => Ok(Command::HVals { key: key.to_vec() }),
+            [b"HINCRBY", key, field, delta] => Ok(Command::HIncrBy {
+                key: key.to_vec(),
+                field: field.to_vec(),
+                delta: parse_number(delta).map_err(|_| not_an_integer())?,
+            }),
+            [b"HINCRBYFLOAT", key, field, delta] => {
+                let delta = parse_number::<f64>(delta)
+                    .ok()
+                    .filter(|delta| delta.is_finite())
+                    .ok_or_else(|| {
+                        ParseError::InvalidArgument("value is not a valid float".to_string())
+                    })?;
+
+                Ok(Command::HIncrByFloat {
+                    key: key.to_vec(),
+                    field: field.to_vec(),
+                    delta,
+                })
+            }
+            [b"HSTRLEN", key, field] => Ok(Command::HStrLen {
+                key: key.to_vec(),
+                field: field.to_vec(),
+            }),
+            [b"HRANDFIELD", key] => Ok(Command::HRandField {
+                key: key.to_vec(),
+                count: None,
+                with_values: false,
+            }),
+            [b"HRANDFIELD", key, count, options @ ..] if options.len() <= 1 => {
+                let with_values = match options.first() {
+                    Some(option) if option.eq_ignore_ascii_case(b"WITHVALUES") => true,
+                    Some(_) => {
+                        return Err(ParseError::InvalidArgument("syntax error".to_string()));
+                    }
+                    None => false,
+                };
+
+                Ok(Command::HRandField {
+                    key: key.to_vec(),
+                    count: Some(parse_number(count).map_err(|_| not_an_integer())?),
+                    with_values,
+                })
+            }
+            [b"SADD", key, members @ ..] if !members.is_empty() => Ok(Command::SAdd {
+                key: key.to_vec(),
+                members: members.iter().map(|member| member.to_vec()).collect(),
+            }),
+            [b"SREM", key, members @ ..] if !members.is_empty() => Ok(Command::SRem {
+                key: key.to_vec(),
+                members: members.iter().map(|member| member.to_vec()).collect(),
+            }),
+            [b"SMEMBERS", key] => Ok(Command::SMembers { key: key.to_vec() }),
+            [b"SISMEMBER", key, member] => Ok(Command::SIsMember {
+                key: key.to_vec(),
+                member: member.to_vec(),
+            }),
+            [b"SCARD", key] => Ok(Command::SCard { key: key.to_vec() }),
+            [b"SPOP", key, count @ ..] if count.len() <= 1 => {
+                let count = match count.first() {
+                    Some(count) => Some(parse_number::<usize>(count).map_err(|_| {
+                        ParseError::InvalidArgument(
+                            "value is out of range, must be positive".to_string(),
+                        )
+                    })?),
+                    None => None,
+                };
+
+                Ok(Command::SPop {
+                    key: key.to_vec(),
+                    count,
+                })
+            }
+            [b"SRANDMEMBER", key, count @ ..] if count.len() <= 1 => {
+                let count = match count.first() {
+                    Some(count) => Some(parse_number(count).map_err(|_| not_an_integer())?),
+                    None => None,
+                };
+
+                Ok(Command::SRandMember {
+                    key: key.to_vec(),
+                    count,
+                })
+            }
+            [b"SMOVE", source, destination, member] => Ok(Command::SMove {
+                source: source.to_vec(),
+                destination: destination.to_vec(),
+                member: member.to_vec(),
+            }),
+            [name @ (b"SINTER" | b"SUNION" | b"SDIFF"), keys @ ..] if !keys.is_empty() => {
+                Ok(Command::SetOp {
+                    op: parse_set_op(name),
+                    keys: keys.iter().map(|key| key.to_vec()).collect(),
+                })
+            }
+            [
+                name @ (b"SINTERSTORE" | b"SUNIONSTORE" | b"SDIFFSTORE"),
+                destination,
+                keys @ ..,
+            ] if !keys.is_empty() => Ok(Command::SetOpStore {
+                op: parse_set_op(name),
+                destination: destination.to_vec(),
+                keys: keys.iter().map(|key| key.to_vec()).collect(),
+            }),
+            [b"ZADD", key, args @ ..] => parse_zadd(key, args),
+            [b"ZINCRBY", key, delta, member] => Ok(Command::ZIncrBy {
+                key: key.to_vec(),
+                delta: parse_score(delta)?,
+                member: member.to_vec(),
+            }),
+            [b"ZREM", key, members @ ..] if !members.is_empty() => Ok(Command::ZRem {
+                key: key.to_vec(),
+                members: members.iter().map(|member| member.to_vec()).collect(),
+            }),
+            [b"ZSCORE", key, member] => Ok(Command::ZScore {
+                key: key.to_vec(),
+                member: member.to_vec(),
+            }),
+            [b"ZCARD", key] => Ok(Command::ZCard { key: key.to_vec() }),
+            [b"ZRANK", key, member] => Ok(Command::ZRank {
+                key: key.to_vec(),
+                member: member.to_vec(),
+                rev: false,
+            }),
+            [b"ZREVRANK", key, member] => Ok(Command::ZRank {
+                key: key.to_vec(),
+                member: member.to_vec(),
+                rev: true,
+            }),
+            [b"ZRANGE", key, start, stop, options @ ..] => {
+                parse_zrange(key, start, stop, options, None)
+            }
+            [b"ZREVRANGE", key, start, stop, options @ ..] => {
+                parse_zrange(key, start, stop, options, Some((RangeKind::Rank, true)))
+            }
+            [b"ZRANGEBYSCORE", key, min, max, options @ ..] => {
+                parse_zrange(key, min, max, options, Some((RangeKind::Score, false)))
+            }
+            [b"ZREVRANGEBYSCORE", key, max, min, options @ ..] => {
+                parse_zrange(key, max, min, options, Some((RangeKind::Score, true)))
+            }
+            [b"ZRANGEBYLEX", key, min, max, options @ ..] => {
+                parse_zrange(key, min, max, options, Some((RangeKind::Lex, false)))
+            }
+            [b"ZREVRANGEBYLEX", key, max, min, options @ ..] => {
+                parse_zrange(key, max, min, options, Some((RangeKind::Lex, true)))
+            }
+            [b"ZCOUNT", key, min, max] => Ok(Command::ZCount {
+                key: key.to_vec(),
+                by: RangeBy::Score(parse_score_bound(min)?, parse_score_bound(max)?),
+            }),
+            [b"ZLEXCOUNT", key, min, max] => Ok(Command::ZCount {
+                key: key.to_vec(),
+                by: parse_lex_range(min, max)?,
+            }),
+            [name @ (b"ZPOPMIN" | b"ZPOPMAX"), key, count @ ..] if count.len() <= 1 => {
+                let count = match count.first() {
+                    Some(count) => parse_number::<usize>(count).map_err(|_| {
+                        ParseError::InvalidArgument(
+                            "value is out of range, must be positive".to_string(),
+                        )
+                    })?,
+                    None => 1,
+                };
+
+                Ok(Command::ZPop {
+                    key: key.to_vec(),
+                    max: name.eq_ignore_ascii_case(b"ZPOPMAX"),
+                    count,
+                })
+            }
+            [b"ZUNIONSTORE", destination, numkeys, args @ ..] => {
+                parse_zstore(SetOp::Union, destination, numkeys, args)
+            }
+            [b"ZINTERSTORE", destination, numkeys, args @ ..] => {
+                parse_zstore(SetOp::Inter, destination, numkeys, args)
+            }
+            [b"ZDIFFSTORE", destination, numkeys, args @ ..] => {
+                parse_zstore(SetOp::Diff, destination, numkeys, args)
+            }
+            [b"XADD", key, args @ ..] => parse_xadd(key, args),
+            [b"XLEN", key] => Ok(Command::XLen { key: key.to_vec() }),
+            [b"XRANGE", key, start, end, options @ ..] => {
+                parse_xrange(key, start, end, options, false)
+            }
+            [b"XREVRANGE", key, end, start, options @ ..] => {
+                parse_xrange(key, start, end, options, true)
+            }
+            [b"XTRIM", key, strategy, args @ ..] if is_trim_strategy(strategy) => {
+                match parse_trim(strategy, args)? {
+                    (trim, []) => Ok(Command::XTrim {
+                        key: key.to_vec(),
+                        trim,
+                    }),
+                    _ => Err(ParseError::InvalidArgument("syntax error".to_string())),
+                }
+            }
+            [b"XDEL", key, ids @ ..] if !ids.is_empty() => Ok(Command::XDel {
+                key: key.to_vec(),
+                ids: parse_stream_ids(ids)?,
+            }),
+            [b"XSETID", key, last_id] => Ok(Command::XSetId {
+                key: key.to_vec(),
+                last_id: parse_stream_id(last_id, 0)?,
+            }),
+            [b"XREAD", args @ ..] => parse_xread(args, None),
+            [b"XREADGROUP", option, group, consumer, args @ ..]
+                if option.eq_ignore_ascii_case(b"GROUP") =>
+            {
+                parse_xread(args, Some((group, consumer)))
+            }
+            [b"XGROUP", subcommand, args @ ..] => parse_xgroup(subcommand, args),
+            [b"XACK", key, group, ids @ ..] if !ids.is_empty() => Ok(Command::XAck {
+                key: key.to_vec(),
+                group: group.to_vec(),
+                ids: parse_stream_ids(ids)?,
+            }),
+            [b"XPENDING", key, group, args @ ..] => parse_xpending(key, group, args),
+            [b"XCLAIM", key, group, consumer, min_idle, args @ ..] if !args.is_empty() => {
+                parse_xclaim(key, group, consumer, min_idle, args)
+            }
+            [
+                b"XAUTOCLAIM",
+                key,
+                group,
+                consumer,
+                min_idle,
+                start,
+                options @ ..,
+            ] => parse_xautoclaim(key, group, consumer, min_idle, start, options),
+            [b"HEXPIRE", key, time, options @ ..] => {
+                parse_hexpire(key, time, options, Expiry::In, 1000)
+            }
+            [b"HPEXPIRE", key, time, options @ ..] => {
+                parse_hexpire(key, time, options, Expiry::In, 1)
+            }
+            [b"HEXPIREAT", key, time, options @ ..] => {
+                parse_hexpire(key, time, options, Expiry::At, 1000)
+            }
+            [b"HPEXPIREAT", key, time, options @ ..] => {
+                parse_hexpire(key, time, options, Expiry::At, 1)
+            }
+            [b"HTTL", key, fields @ ..] => Ok(Command::HTtl {
+                key: key.to_vec(),
+                fields: parse_fields(fields)?,
+                millis: false,
             }),
-            ["HGET" | "hget", key, field] => Ok(Command::HGet {
-                key: key.to_string(),
-                field: field.to_string(),
+            [b"HPTTL", key, fields @ ..] => Ok(Command::HTtl {
+                key: key.to_vec(),
+                fields: parse_fields(fields)?,
+                millis: true,
             }),
-            ["HDEL" | "hdel", key, fields @ ..] if !fields.is_empty() => Ok(Command::HDel {
-                key: key.to_string(),
-                fields: fields.iter().map(|s| s.to_string()).collect(),
+            [b"HEXPIRETIME", key, fields @ ..] => Ok(Command::HExpireTime {
+                key: key.to_vec(),
+                fields: parse_fields(fields)?,
+                millis: false,
             }),
-            ["HLEN" | "hlen", key] => Ok(Command::HLen {
-                key: key.to_string(),
+            [b"HPEXPIRETIME", key, fields @ ..] => Ok(Command::HExpireTime {
+                key: key.to_vec(),
+                fields: parse_fields(fields)?,
+                millis: true,
             }),
-            ["HGETALL" | "hgetall", key] => Ok(Command::HGetAll {
-                key: key.to_string(),
+            [b"HPERSIST", key, fields @ ..] => Ok(Command::HPersist {
+                key: key.to_vec(),
+                fields: parse_fields(fields)?,
             }),
-            ["SET" | "set"] => Err(ParseError::InvalidArgument(
-                "SET command requires both key and value. Usage: SET <key> <value> [EX <seconds>]"
+            [b"SET", ..] => Err(ParseError::InvalidArgument(
+                "SET command requires both key and value. Usage: SET <key> <value> [NX|XX] [GET] \
+                 [EX <seconds>|PX <milliseconds>|EXAT <timestamp>|PXAT <timestamp>|KEEPTTL]"
                     .to_string(),
             )),
-            ["SET" | "set", _] => Err(ParseError::InvalidArgument(
-                "SET command requires both key and value. Usage: SET <key> <value> [EX <seconds>]"
+            [b"GET", ..] => Err(ParseError::InvalidArgument("Usage: GET <key>".to_string())),
+            [b"DEL", ..] => Err(ParseError::InvalidArgument(
+                "Usage: DEL <key> [key ...]".to_string(),
+            )),
+            [b"MGET", ..] => Err(ParseError::InvalidArgument(
+                "Usage: MGET <key> [key ...]".to_string(),
+            )),
+            [b"SETNX" | b"GETSET" | b"APPEND", ..] => Err(ParseError::InvalidArgument(
+                "Usage: SETNX|GETSET|APPEND <key> <value>".to_string(),
+            )),
+            [b"GETDEL" | b"GETEX" | b"STRLEN", ..] => Err(ParseError::InvalidArgument(
+                "Usage: GETDEL|STRLEN <key>, GETEX <key> [EX|PX|EXAT|PXAT <time>|PERSIST]"
                     .to_string(),
             )),
-            ["SET" | "set", ..] => Err(ParseError::InvalidArgument(
-                "Invalid SET command format. Usage: SET <key> <value> [EX <seconds>]".to_string(),
+            [b"GETRANGE", ..] => Err(ParseError::InvalidArgument(
+                "Usage: GETRANGE <key> <start> <end>".to_string(),
+            )),
+            [b"SETRANGE", ..] => Err(ParseError::InvalidArgument(
+                "Usage: SETRANGE <key> <offset> <value>".to_string(),
             )),
-            ["GET" | "get", ..] | ["DEL" | "del", ..] => Err(ParseError::InvalidArgument(
-                "Usage: GET|DEL <key>".to_string(),
+            [b"INCR" | b"DECR", ..] => Err(ParseError::InvalidArgument(
+                "Usage: INCR|DECR <key>".to_string(),
             )),
-            ["LRANGE" | "lrange", ..] => Err(ParseError::InvalidArgument(
+            [b"INCRBY" | b"DECRBY" | b"INCRBYFLOAT", ..] => Err(ParseError::InvalidArgument(
+                "Usage: INCRBY|DECRBY|INCRBYFLOAT <key> <increment>".to_string(),
+            )),
+            [b"LRANGE", ..] => Err(ParseError::InvalidArgument(
                 "Usage: LRANGE <key> <start> <stop>".to_string(),
             )),
-            ["HSET" | "hset", ..] => Err(ParseError::InvalidArgument(
-                "Usage: HSET <key> <field> <value>".to_string(),
+            [b"LPOP" | b"RPOP", ..] => Err(ParseError::InvalidArgument(
+                "Usage: LPOP|RPOP <key> [count]".to_string(),
             )),
-            ["HGET" | "hget", ..] | ["HGETALL" | "hgetall", ..] => Err(
-                ParseError::InvalidArgument("Usage: HGET|HGETALL <key> [field]".to_string()),
-            ),
-            ["HDEL" | "hdel", ..] => Err(ParseError::InvalidArgument(
+            [b"LLEN", ..] => Err(ParseError::InvalidArgument("Usage: LLEN <key>".to_string())),
+            [b"LINDEX", ..] => Err(ParseError::InvalidArgument(
+                "Usage: LINDEX <key> <index>".to_string(),
+            )),
+            [b"LSET", ..] => Err(ParseError::InvalidArgument(
+                "Usage: LSET <key> <index> <element>".to_string(),
+            )),
+            [b"LINSERT", ..] => Err(ParseError::InvalidArgument(
+                "Usage: LINSERT <key> BEFORE|AFTER <pivot> <element>".to_string(),
+            )),
+            [b"LREM", ..] => Err(ParseError::InvalidArgument(
+                "Usage: LREM <key> <count> <element>".to_string(),
+            )),
+            [b"LTRIM", ..] => Err(ParseError::InvalidArgument(
+                "Usage: LTRIM <key> <start> <stop>".to_string(),
+            )),
+            [b"LPOS", ..] => Err(ParseError::InvalidArgument(
+                "Usage: LPOS <key> <element> [RANK rank] [COUNT num-matches] [MAXLEN len]"
+                    .to_string(),
+            )),
+            [b"LMOVE", ..] => Err(ParseError::InvalidArgument(
+                "Usage: LMOVE <source> <destination> LEFT|RIGHT LEFT|RIGHT".to_string(),
+            )),
+            [b"RPOPLPUSH", ..] => Err(ParseError::InvalidArgument(
+                "Usage: RPOPLPUSH <source> <destination>".to_string(),
+            )),
+            [b"BLPOP" | b"BRPOP", ..] => Err(ParseError::InvalidArgument(
+                "Usage: BLPOP|BRPOP <key> [key ...] <timeout>".to_string(),
+            )),
+            [b"BLMOVE", ..] => Err(ParseError::InvalidArgument(
+                "Usage: BLMOVE <source> <destination> LEFT|RIGHT LEFT|RIGHT <timeout>".to_string(),
+            )),
+            [b"BRPOPLPUSH", ..] => Err(ParseError::InvalidArgument(
+                "Usage: BRPOPLPUSH <source> <destination> <timeout>".to_string(),
+            )),
+            [b"HSET" | b"HMSET", ..] => Err(ParseError::InvalidArgument(
+                "Usage: HSET|HMSET <key> <field> <value> [field value ...]".to_string(),
+            )),
+            [b"HSETNX", ..] => Err(ParseError::InvalidArgument(
+                "Usage: HSETNX <key> <field> <value>".to_string(),
+            )),
+            [b"HMGET", ..] => Err(ParseError::InvalidArgument(
+                "Usage: HMGET <key> <field> [field ...]".to_string(),
+            )),
+            [b"HEXISTS" | b"HSTRLEN", ..] => Err(ParseError::InvalidArgument(
+                "Usage: HEXISTS|HSTRLEN <key> <field>".to_string(),
+            )),
+            [b"HKEYS" | b"HVALS", ..] => Err(ParseError::InvalidArgument(
+                "Usage: HKEYS|HVALS <key>".to_string(),
+            )),
+            [b"HINCRBY" | b"HINCRBYFLOAT", ..] => Err(ParseError::InvalidArgument(
+                "Usage: HINCRBY|HINCRBYFLOAT <key> <field> <increment>".to_string(),
+            )),
+            [b"HRANDFIELD", ..] => Err(ParseError::InvalidArgument(
+                "Usage: HRANDFIELD <key> [count [WITHVALUES]]".to_string(),
+            )),
+            [b"SADD" | b"SREM", ..] => Err(ParseError::InvalidArgument(
+                "Usage: SADD|SREM <key> <member> [member ...]".to_string(),
+            )),
+            [b"SMEMBERS" | b"SCARD", ..] => Err(ParseError::InvalidArgument(
+                "Usage: SMEMBERS|SCARD <key>".to_string(),
+            )),
+            [b"SISMEMBER", ..] => Err(ParseError::InvalidArgument(
+                "Usage: SISMEMBER <key> <member>".to_string(),
+            )),
+            [b"SPOP" | b"SRANDMEMBER", ..] => Err(ParseError::InvalidArgument(
+                "Usage: SPOP|SRANDMEMBER <key> [count]".to_string(),
+            )),
+            [b"SMOVE", ..] => Err(ParseError::InvalidArgument(
+                "Usage: SMOVE <source> <destination> <member>".to_string(),
+            )),
+            [b"SINTER" | b"SUNION" | b"SDIFF", ..] => Err(ParseError::InvalidArgument(
+                "Usage: SINTER|SUNION|SDIFF <key> [key ...]".to_string(),
+            )),
+            [b"SINTERSTORE" | b"SUNIONSTORE" | b"SDIFFSTORE", ..] => {
+                Err(ParseError::InvalidArgument(
+                    "Usage: SINTERSTORE|SUNIONSTORE|SDIFFSTORE <destination> <key> [key ...]"
+                        .to_string(),
+                ))
+            }
+            [b"ZADD", ..] => Err(ParseError::InvalidArgument(
+                "Usage: ZADD <key> [NX|XX] [GT|LT] [CH] [INCR] <score> <member> \
+                 [score member ...]"
+                    .to_string(),
+            )),
+            [b"ZINCRBY", ..] => Err(ParseError::InvalidArgument(
+                "Usage: ZINCRBY <key> <increment> <member>".to_string(),
+            )),
+            [b"ZREM", ..] => Err(ParseError::InvalidArgument(
+                "Usage: ZREM <key> <member> [member ...]".to_string(),
+            )),
+            [b"ZSCORE" | b"ZRANK" | b"ZREVRANK", ..] => Err(ParseError::InvalidArgument(
+                "Usage: ZSCORE|ZRANK|ZREVRANK <key> <member>".to_string(),
+            )),
+            [b"ZCARD", ..] => Err(ParseError::InvalidArgument(
+                "Usage: ZCARD <key>".to_string(),
+            )),
+            [b"ZRANGE", ..] => Err(ParseError::InvalidArgument(
+                "Usage: ZRANGE <key> <start> <stop> [BYSCORE|BYLEX] [REV] \
+                 [LIMIT offset count] [WITHSCORES]"
+                    .to_string(),
+            )),
+            [b"ZREVRANGE", ..] => Err(ParseError::InvalidArgument(
+                "Usage: ZREVRANGE <key> <start> <stop> [WITHSCORES]".to_string(),
+            )),
+            [b"ZRANGEBYSCORE" | b"ZREVRANGEBYSCORE", ..] => Err(ParseError::InvalidArgument(
+                "Usage: ZRANGEBYSCORE|ZREVRANGEBYSCORE <key> <min> <max> [WITHSCORES] \
+                 [LIMIT offset count]"
+                    .to_string(),
+            )),
+            [b"ZRANGEBYLEX" | b"ZREVRANGEBYLEX", ..] => Err(ParseError::InvalidArgument(
+                "Usage: ZRANGEBYLEX|ZREVRANGEBYLEX <key> <min> <max> [LIMIT offset count]"
+                    .to_string(),
+            )),
+            [b"ZCOUNT" | b"ZLEXCOUNT", ..] => Err(ParseError::InvalidArgument(
+                "Usage: ZCOUNT|ZLEXCOUNT <key> <min> <max>".to_string(),
+            )),
+            [b"ZPOPMIN" | b"ZPOPMAX", ..] => Err(ParseError::InvalidArgument(
+                "Usage: ZPOPMIN|ZPOPMAX <key> [count]".to_string(),
+            )),
+            [b"ZUNIONSTORE" | b"ZINTERSTORE" | b"ZDIFFSTORE", ..] => {
+                Err(ParseError::InvalidArgument(
+                    "Usage: ZUNIONSTORE|ZINTERSTORE <destination> <numkeys> <key> [key ...] \
+                 [WEIGHTS weight ...] [AGGREGATE SUM|MIN|MAX], \
+                 ZDIFFSTORE <destination> <numkeys> <key> [key ...]"
+                        .to_string(),
+                ))
+            }
+            [b"XADD", ..] => Err(ParseError::InvalidArgument(
+                "Usage: XADD <key> [NOMKSTREAM] [MAXLEN|MINID [=|~] threshold [LIMIT count]] \
+                 <id|*> <field> <value> [field value ...]"
+                    .to_string(),
+            )),
+            [b"XLEN", ..] => Err(ParseError::InvalidArgument("Usage: XLEN <key>".to_string())),
+            [b"XRANGE" | b"XREVRANGE", ..] => Err(ParseError::InvalidArgument(
+                "Usage: XRANGE <key> <start> <end> [COUNT count], \
+                     XREVRANGE <key> <end> <start> [COUNT count]"
+                    .to_string(),
+            )),
+            [b"XTRIM", ..] => Err(ParseError::InvalidArgument(
+                "Usage: XTRIM <key> MAXLEN|MINID [=|~] <threshold> [LIMIT count]".to_string(),
+            )),
+            [b"XDEL", ..] => Err(ParseError::InvalidArgument(
+                "Usage: XDEL <key> <id> [id ...]".to_string(),
+            )),
+            [b"XSETID", ..] => Err(ParseError::InvalidArgument(
+                "Usage: XSETID <key> <last-id>".to_string(),
+            )),
+            [b"XREADGROUP", ..] => Err(ParseError::InvalidArgument(
+                "Usage: XREADGROUP GROUP <group> <consumer> [COUNT count] [BLOCK milliseconds] \
+                 [NOACK] STREAMS <key> [key ...] <id> [id ...]"
+                    .to_string(),
+            )),
+            [b"XGROUP", ..] => Err(xgroup_usage()),
+            [b"XACK", ..] => Err(ParseError::InvalidArgument(
+                "Usage: XACK <key> <group> <id> [id ...]".to_string(),
+            )),
+            [b"XPENDING", ..] => Err(ParseError::InvalidArgument(
+                "Usage: XPENDING <key> <group> [[IDLE min-idle-time] <start> <end> <count> \
+                 [consumer]]"
+                    .to_string(),
+            )),
+            [b"XCLAIM", ..] => Err(ParseError::InvalidArgument(
+                "Usage: XCLAIM <key> <group> <consumer> <min-idle-time> <id> [id ...] \
+                 [IDLE ms] [TIME unix-time-milliseconds] [RETRYCOUNT count] [FORCE] [JUSTID] \
+                 [LASTID id]"
+                    .to_string(),
+            )),
+            [b"XAUTOCLAIM", ..] => Err(ParseError::InvalidArgument(
+                "Usage: XAUTOCLAIM <key> <group> <consumer> <min-idle-time> <start> \
+                 [COUNT count] [JUSTID]"
+                    .to_string(),
+            )),
+            [b"HEXPIRE" | b"HPEXPIRE" | b"HEXPIREAT" | b"HPEXPIREAT", ..] => {
+                Err(ParseError::InvalidArgument(
+                    "Usage: HEXPIRE|HPEXPIRE|HEXPIREAT|HPEXPIREAT <key> <time> [NX|XX|GT|LT] \
+                 FIELDS <numfields> <field> [field ...]"
+                        .to_string(),
+                ))
+            }
+            [
+                b"HTTL" | b"HPTTL" | b"HEXPIRETIME" | b"HPEXPIRETIME" | b"HPERSIST",
+                ..,
+            ] => Err(ParseError::InvalidArgument(
+                "Usage: HTTL|HPTTL|HEXPIRETIME|HPEXPIRETIME|HPERSIST <key> FIELDS <numfields> \
+                 <field> [field ...]"
+                    .to_string(),
+            )),
+            [b"HGET", ..] | [b"HGETALL", ..] => Err(ParseError::InvalidArgument(
+                "Usage: HGET|HGETALL <key> [field]".to_string(),
+            )),
+            [b"HDEL", ..] => Err(ParseError::InvalidArgument(
                 "Usage: HDEL <key> <field> [field ...]".to_string(),
             )),
-            ["HLEN" | "hlen", _key, ..] => {
+            [b"HLEN", _key, ..] => {
                 Err(ParseError::InvalidArgument("Usage: HLEN <key>".to_string()))
             }
-            ["SAVE" | "save"] => Ok(Command::Save),
+            [b"EXPIRE", key, time, options @ ..] => {
+                parse_expire(key, time, options, Expiry::In, 1000)
+            }
+            [b"PEXPIRE", key, time, options @ ..] => {
+                parse_expire(key, time, options, Expiry::In, 1)
+            }
+            [b"EXPIREAT", key, time, options @ ..] => {
+                parse_expire(key, time, options, Expiry::At, 1000)
+            }
+            [b"PEXPIREAT", key, time, options @ ..] => {
+                parse_expire(key, time, options, Expiry::At, 1)
+            }
+            [b"TTL", key] => Ok(Command::Ttl {
+                key: key.to_vec(),
+                millis: false,
+            }),
+            [b"PTTL", key] => Ok(Command::Ttl {
+                key: key.to_vec(),
+                millis: true,
+            }),
+            [b"EXPIRETIME", key] => Ok(Command::ExpireTime {
+                key: key.to_vec(),
+                millis: false,
+            }),
+            [b"PEXPIRETIME", key] => Ok(Command::ExpireTime {
+                key: key.to_vec(),
+                millis: true,
+            }),
+            [b"PERSIST", key] => Ok(Command::Persist { key: key.to_vec() }),
+            [b"TYPE", key] => Ok(Command::Type { key: key.to_vec() }),
+            [b"EXPIRE" | b"PEXPIRE" | b"EXPIREAT" | b"PEXPIREAT", ..] => {
+                Err(ParseError::InvalidArgument(
+                    "Usage: EXPIRE|PEXPIRE|EXPIREAT|PEXPIREAT <key> <time> [NX|XX|GT|LT]"
+                        .to_string(),
+                ))
+            }
+            [
+                b"TTL" | b"PTTL" | b"EXPIRETIME" | b"PEXPIRETIME" | b"PERSIST",
+                ..,
+            ] => Err(ParseError::InvalidArgument(
+                "Usage: TTL|PTTL|EXPIRETIME|PEXPIRETIME|PERSIST <key>".to_string(),
+            )),
+            [b"TYPE", ..] => Err(ParseError::InvalidArgument("Usage: TYPE <key>".to_string())),
+            [b"SAVE"] => Ok(Command::Save),
+            [b"BGSAVE"] => Ok(Command::BgSave),
+            [b"LASTSAVE"] => Ok(Command::LastSave),
+            [b"BGREWRITEAOF"] => Ok(Command::BgRewriteAof),
+            [b"INFO"] => Ok(Command::Info { section: None }),
+            [b"INFO", section] => Ok(Command::Info {
+                section: Some(section.to_vec()),
+            }),
+            [b"PING"] => Ok(Command::Ping { message: None }),
+            [b"PING", message] => Ok(Command::Ping {
+                message: Some(message.to_vec()),
+            }),
+            [b"ECHO", message] => Ok(Command::Echo {
+                message: message.to_vec(),
+            }),
+            [name @ (b"SUBSCRIBE" | b"PSUBSCRIBE"), channels @ ..] if !channels.is_empty() => {
+                Ok(Command::Subscribe {
+                    channels: channels.iter().map(|channel| channel.to_vec()).collect(),
+                    patterns: name.eq_ignore_ascii_case(b"PSUBSCRIBE"),
+                })
+            }
+            [name @ (b"UNSUBSCRIBE" | b"PUNSUBSCRIBE"), channels @ ..] => {
+                Ok(Command::Unsubscribe {
+                    channels: channels.iter().map(|channel| channel.to_vec()).collect(),
+                    patterns: name.eq_ignore_ascii_case(b"PUNSUBSCRIBE"),
+                })
+            }
+            [b"PUBLISH", channel, message] => Ok(Command::Publish {
+                channel: channel.to_vec(),
+                message: message.to_vec(),
+            }),
+            [b"PUBSUB", subcommand, args @ ..] => parse_pubsub(subcommand, args),
+            [b"MULTI"] => Ok(Command::Multi),
+            [b"EXEC"] => Ok(Command::Exec),
+            [b"DISCARD"] => Ok(Command::Discard),
+            [b"PING", ..] => Err(ParseError::InvalidArgument(
+                "Usage: PING [message]".to_string(),
+            )),
+            [b"ECHO", ..] => Err(ParseError::InvalidArgument(
+                "Usage: ECHO <message>".to_string(),
+            )),
+            [b"SUBSCRIBE" | b"PSUBSCRIBE", ..] => Err(ParseError::InvalidArgument(
+                "Usage: SUBSCRIBE|PSUBSCRIBE <channel> [channel ...]".to_string(),
+            )),
+            [b"PUBLISH", ..] => Err(ParseError::InvalidArgument(
+                "Usage: PUBLISH <channel> <message>".to_string(),
+            )),
+            [b"PUBSUB", ..] => Err(pubsub_usage()),
+            [b"MULTI" | b"EXEC" | b"DISCARD", ..] => Err(ParseError::InvalidArgument(
+                "Usage: MULTI|EXEC|DISCARD".to_string(),
+            )),
             // Any other command is unknown
             _ => Err(ParseError::UnknownCommand),
         }
     }
 }
 
+/// Parses `[NX|XX] [GET] [EX s|PX ms|EXAT s|PXAT ms|KEEPTTL]`, in any order.
+fn parse_set_options(options: &[&[u8]]) -> Result<SetOptions, ParseError> {
+    let syntax_error = || ParseError::InvalidArgument("syntax error".to_string());
+    let mut parsed = SetOptions::default();
+    let mut has_expiry = false;
+    let mut options = options.iter();
+
+    while let Some(option) = options.next() {
+        let option = option.to_ascii_uppercase();
+        match option.as_slice() {
+            b"NX" | b"XX" if parsed.only_if_exists.is_none() => {
+                parsed.only_if_exists = Some(option == b"XX");
+            }
+            b"GET" => parsed.get = true,
+            b"KEEPTTL" if !has_expiry => {
+                parsed.keep_ttl = true;
+                has_expiry = true;
+            }
+            b"EX" | b"PX" | b"EXAT" | b"PXAT" if !has_expiry => {
+                let time = options.next().ok_or_else(syntax_error)?;
+                let scale = if matches!(option.as_slice(), b"EX" | b"EXAT") {
+                    1000
+                } else {
+                    1
+                };
+                // Like in Redis, the time must be positive and fit in a signed
+                // 64-bit count of milliseconds.
+                let millis = parse_number::<i128>(time)
+                    .map_err(|_| not_an_integer())?
+                    .checked_mul(scale)
+                    .filter(|&millis| millis > 0 && millis <= i64::MAX as i128)
+                    .ok_or_else(|| {
+                        ParseError::InvalidArgument(
+                            "invalid expire time in 'set' command".to_string(),
+                        )
+                    })? as i64;
+                parsed.expiry = Some(match option.as_slice() {
+                    b"EX" | b"PX" => Expiry::In(millis),
+                    _ => Expiry::At(millis),
+                });
+                has_expiry = true;
+            }
+            _ => return Err(syntax_error()),
+        }
+    }
+
+    Ok(parsed)
+}
+
+/// Parses the `key value [key value ...]` arguments of MSET and MSETNX.
+fn parse_pairs(args: &[&[u8]], name: &str) -> Result<Pairs, ParseError> {
+    if args.is_empty() || !args.len().is_multiple_of(2) {
+        return Err(ParseError::InvalidArgument(format!(
+            "wrong number of arguments for '{}' command",
+            name.to_ascii_lowercase()
+        )));
+    }
+
+    Ok(args
+        .chunks(2)
+        .map(|pair| (pair[0].to_vec(), pair[1].to_vec()))
+        .collect())
+}
+
+/// Parses `[EX s|PX ms|EXAT s|PXAT ms|PERSIST]`.
+fn parse_getex_options(options: &[&[u8]]) -> Result<Option<ExpiryChange>, ParseError> {
+    let option = match options {
+        [] => return Ok(None),
+        [option] if option.eq_ignore_ascii_case(b"PERSIST") => {
+            return Ok(Some(ExpiryChange::Persist));
+        }
+        [option, _time] => option.to_ascii_uppercase(),
+        _ => return Err(ParseError::InvalidArgument("syntax error".to_string())),
+    };
+
+    let (scale, expiry): (_, fn(i64) -> Expiry) = match option.as_slice() {
+        b"EX" => (1000, Expiry::In),
+        b"PX" => (1, Expiry::In),
+        b"EXAT" => (1000, Expiry::At),
+        b"PXAT" => (1, Expiry::At),
+        _ => return Err(ParseError::InvalidArgument("syntax error".to_string())),
+    };
+    let time = parse_number::<i64>(options[1])
+        .ok()
+        .filter(|&time| time > 0)
+        .and_then(|time| time.checked_mul(scale))
+        .ok_or_else(|| {
+            ParseError::InvalidArgument("invalid expire time in 'getex' command".to_string())
+        })?;
+
+    Ok(Some(ExpiryChange::Expire(expiry(time))))
+}
+
+fn parse_list_end(arg: &[u8]) -> Result<ListEnd, ParseError> {
+    match arg.to_ascii_uppercase().as_slice() {
+        b"LEFT" => Ok(ListEnd::Left),
+        b"RIGHT" => Ok(ListEnd::Right),
+        _ => Err(ParseError::InvalidArgument("syntax error".to_string())),
+    }
+}
+
+/// Parses the timeout of a blocking command, in seconds with an optional
+/// fraction. 0 means no timeout.
+fn parse_timeout(arg: &[u8]) -> Result<Option<Duration>, ParseError> {
+    let seconds = parse_number::<f64>(arg)
+        .ok()
+        .filter(|seconds| seconds.is_finite())
+        .ok_or_else(|| {
+            ParseError::InvalidArgument("timeout is not a float or out of range".to_string())
+        })?;
+    if seconds < 0.0 {
+        return Err(ParseError::InvalidArgument(
+            "timeout is negative".to_string(),
+        ));
+    }
+
+    if seconds == 0.0 {
+        return Ok(None);
+    }
+    Duration::try_from_secs_f64(seconds)
+        .map(Some)
+        .map_err(|_| ParseError::InvalidArgument("timeout is out of range".to_string()))
+}
+
+/// Parses `[RANK rank] [COUNT num-matches] [MAXLEN len]`, in any order.
+fn parse_lpos(key: &[u8], element: &[u8], options: &[&[u8]]) -> Result<Command, ParseError> {
+    let mut rank = 1;
+    let mut count = None;
+    let mut max_len = 0;
+
+    for pair in options.chunks(2) {
+        let [option, value] = pair else {
+            return Err(ParseError::InvalidArgument("syntax error".to_string()));
+        };
+        let value = parse_number::<i64>(value).map_err(|_| not_an_integer())?;
+        match option.to_ascii_uppercase().as_slice() {
+            b"RANK" if value == 0 => {
+                return Err(ParseError::InvalidArgument(
+                    "RANK can't be zero: use 1 to start from the first match, 2 from the \
+                     second ... or use negative to start from the end of the list"
+                        .to_string(),
+                ));
+            }
+            b"RANK" => rank = value,
+            b"COUNT" | b"MAXLEN" if value < 0 => {
+                return Err(ParseError::InvalidArgument(format!(
+                    "{} can't be negative",
+                    String::from_utf8_lossy(option).to_ascii_uppercase()
+                )));
+            }
+            b"COUNT" => count = Some(value as usize),
+            b"MAXLEN" => max_len = value as usize,
+            _ => return Err(ParseError::InvalidArgument("syntax error".to_string())),
+        }
+    }
+
+    Ok(Command::LPos {
+        key: key.to_vec(),
+        element: element.to_vec(),
+        rank,
+        count,
+        max_len,
+    })
+}
+
+/// Parses the arguments of the EXPIRE family. `time` is in units of `scale`
+/// milliseconds, and `expiry` says whether it is relative (`Expiry::In`) or a
+/// Unix time (`Expiry::At`).
+fn parse_expire(
+    key: &[u8],
+    time: &[u8],
+    options: &[&[u8]],
+    expiry: fn(i64) -> Expiry,
+    scale: i64,
+) -> Result<Command, ParseError> {
+    Ok(Command::Expire {
+        key: key.to_vec(),
+        at: expiry(parse_expire_time(time, scale)?),
+        condition: parse_expire_condition(options)?,
+    })
+}
+
+/// Parses the arguments HEXPIRE and friends take after the key: the time,
+/// at most one of NX, XX, GT and LT, then the fields.
+fn parse_hexpire(
+    key: &[u8],
+    time: &[u8],
+    args: &[&[u8]],
+    expiry: fn(i64) -> Expiry,
+    scale: i64,
+) -> Result<Command, ParseError> {
+    let at = expiry(parse_expire_time(time, scale)?);
+    let (options, fields) = match args.first() {
+        Some(option) if !option.eq_ignore_ascii_case(b"FIELDS") => args.split_at(1),
+        _ => args.split_at(0),
+    };
+
+    Ok(Command::HExpire {
+        key: key.to_vec(),
+        fields: parse_fields(fields)?,
+        at,
+        condition: parse_expire_condition(options)?,
+    })
+}
+
+/// Parses `FIELDS numfields field [field ...]`.
+fn parse_fields(args: &[&[u8]]) -> Result<Vec<Vec<u8>>, ParseError> {
+    let [keyword, count, fields @ ..] = args else {
+        return Err(missing_fields());
+    };
+    if !keyword.eq_ignore_ascii_case(b"FIELDS") {
+        return Err(missing_fields());
+    }
+
+    let count = parse_number::<i64>(count).map_err(|_| not_an_integer())?;
+    if count <= 0 {
+        return Err(ParseError::InvalidArgument(
+            "Parameter `numFields` should be greater than 0".to_string(),
+        ));
+    }
+    if count as usize != fields.len() {
+        return Err(ParseError::InvalidArgument(
+            "The `numfields` parameter must match the number of arguments".to_string(),
+        ));
+    }
+    Ok(fields.iter().map(|field| field.to_vec()).collect())
+}
+
+fn missing_fields() -> ParseError {
+    ParseError::InvalidArgument(
+        "Mandatory argument FIELDS is missing or not at the right position".to_string(),
+    )
+}
+
+/// Turns the time EXPIRE and friends take into milliseconds: `time` units of
+/// `scale` milliseconds.
+fn parse_expire_time(time: &[u8], scale: i64) -> Result<i64, ParseError> {
+    parse_number::<i64>(time)
+        .ok()
+        .and_then(|time| time.checked_mul(scale))
+        .ok_or_else(|| ParseError::InvalidArgument("invalid expire time".to_string()))
+}
+
+fn parse_expire_condition(options: &[&[u8]]) -> Result<ExpireCondition, ParseError> {
+    let mut condition = ExpireCondition::default();
+    for option in options {
+        match option.to_ascii_uppercase().as_slice() {
+            b"NX" => condition.nx = true,
+            b"XX" => condition.xx = true,
+            b"GT" => condition.gt = true,
+            b"LT" => condition.lt = true,
+            _ => {
+                return Err(ParseError::InvalidArgument(format!(
+                    "Unsupported option {}",
+                    String::from_utf8_lossy(option)
+                )));
+            }
+        }
+    }
+    if condition.nx && (condition.xx || condition.gt || condition.lt) {
+        return Err(ParseError::InvalidArgument(
+            "NX and XX, GT or LT options at the same time are not compatible".to_string(),
+        ));
+    }
+    if condition.gt && condition.lt {
+        return Err(ParseError::InvalidArgument(
+            "GT and LT options at the same time are not compatible".to_string(),
+        ));
+    }
+    Ok(condition)
+}
+
+// Which operation SINTER, SUNION, SDIFF or their STORE forms compute.
+fn parse_set_op(name: &[u8]) -> SetOp {
+    let name = name.to_ascii_uppercase();
+    if name.starts_with(b"SINTER") {
+        SetOp::Inter
+    } else if name.starts_with(b"SUNION") {
+        SetOp::Union
+    } else {
+        SetOp::Diff
+    }
+}
+
+/// Parses `[NX|XX] [GT|LT] [CH] [INCR] score member [score member ...]`.
+fn parse_zadd(key: &[u8], args: &[&[u8]]) -> Result<Command, ParseError> {
+    let mut options = ZAddOptions::default();
+    let mut incr = false;
+    let mut args = args;
+
+    while let [option, rest @ ..] = args {
+        let option = option.to_ascii_uppercase();
+        match option.as_slice() {
+            b"NX" | b"XX" => {
+                if options.only_if_exists == Some(option == b"NX") {
+                    return Err(ParseError::InvalidArgument(
+                        "XX and NX options at the same time are not compatible".to_string(),
+                    ));
+                }
+                options.only_if_exists = Some(option == b"XX");
+            }
+            b"GT" => options.gt = true,
+            b"LT" => options.lt = true,
+            b"CH" => options.changed = true,
+            b"INCR" => incr = true,
+            _ => break,
+        }
+        args = rest;
+    }
+
+    if (options.gt || options.lt) && options.only_if_exists == Some(false)
+        || options.gt && options.lt
+    {
+        return Err(ParseError::InvalidArgument(
+            "GT, LT, and/or NX options at the same time are not compatible".to_string(),
+        ));
+    }
+    if args.is_empty() || !args.len().is_multiple_of(2) {
+        return Err(ParseError::InvalidArgument("syntax error".to_string()));
+    }
+    if incr && args.len() > 2 {
+        return Err(ParseError::InvalidArgument(
+            "INCR option supports a single increment-element pair".to_string(),
+        ));
+    }
+
+    Ok(Command::ZAdd {
+        key: key.to_vec(),
+        pairs: args
+            .chunks(2)
+            .map(|pair| Ok((parse_score(pair[0])?, pair[1].to_vec())))
+            .collect::<Result<_, ParseError>>()?,
+        options,
+        incr,
+    })
+}
+
+/// What ZRANGE ranges over.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RangeKind {
+    Rank,
+    Score,
+    Lex,
+}
+
+/// Parses ZRANGE's arguments. The older commands it replaced pass the kind
+/// of range and the direction their name implies as `fixed`, and don't take
+/// BYSCORE, BYLEX or REV. Reversed score and lex ranges are given from the
+/// high end, so `start` is then the maximum.
+fn parse_zrange(
+    key: &[u8],
+    start: &[u8],
+    stop: &[u8],
+    options: &[&[u8]],
+    fixed: Option<(RangeKind, bool)>,
+) -> Result<Command, ParseError> {
+    let syntax_error =
+        |detail: &str| ParseError::InvalidArgument(format!("syntax error{}", detail));
+    let (mut kind, mut rev) = fixed.unwrap_or((RangeKind::Rank, false));
+    let mut limit = None;
+    let mut with_scores = false;
+    let mut options = options.iter();
+
+    while let Some(option) = options.next() {
+        match option.to_ascii_uppercase().as_slice() {
+            b"BYSCORE" if fixed.is_none() => kind = RangeKind::Score,
+            b"BYLEX" if fixed.is_none() => kind = RangeKind::Lex,
+            b"REV" if fixed.is_none() => rev = true,
+            b"WITHSCORES" => with_scores = true,
+            b"LIMIT" => {
+                let (Some(offset), Some(count)) = (options.next(), options.next()) else {
+                    return Err(syntax_error(""));
+                };
+                limit = Some((
+                    parse_number(offset).map_err(|_| not_an_integer())?,
+                    parse_number(count).map_err(|_| not_an_integer())?,
+                ));
+            }
+            _ => return Err(syntax_error("")),
+        }
+    }
+
+    if limit.is_some() && kind == RangeKind::Rank {
+        return Err(syntax_error(
+            ", LIMIT is only supported in combination with either BYSCORE or BYLEX",
+        ));
+    }
+    if with_scores && kind == RangeKind::Lex {
+        return Err(syntax_error(
+            ", WITHSCORES not supported in combination with BYLEX",
+        ));
+    }
+
+    let (min, max) = if rev { (stop, start) } else { (start, stop) };
+    let by = match kind {
+        RangeKind::Rank => RangeBy::Rank(
+            parse_number(start).map_err(|_| not_an_integer())?,
+            parse_number(stop).map_err(|_| not_an_integer())?,
+        ),
+        RangeKind::Score => RangeBy::Score(parse_score_bound(min)?, parse_score_bound(max)?),
+        RangeKind::Lex => parse_lex_range(min, max)?,
+    };
+
+    Ok(Command::ZRange {
+        key: key.to_vec(),
+        by,
+        rev,
+        limit,
+        with_scores,
+    })
+}
+
+/// Parses `numkeys key [key ...]`, then WEIGHTS and AGGREGATE for unions and
+/// intersections.
+fn parse_zstore(
+    op: SetOp,
+    destination: &[u8],
+    numkeys: &[u8],
+    args: &[&[u8]],
+) -> Result<Command, ParseError> {
+    let syntax_error = || ParseError::InvalidArgument("syntax error".to_string());
+    let numkeys = parse_number::<usize>(numkeys).map_err(|_| not_an_integer())?;
+    if numkeys == 0 {
+        return Err(ParseError::InvalidArgument(
+            "at least 1 input key is needed for this command".to_string(),
+        ));
+    }
+    if numkeys > args.len() {
+        return Err(syntax_error());
+    }
+
+    let (keys, mut options) = args.split_at(numkeys);
+    let mut weights = Vec::new();
+    let mut aggregate = Aggregate::default();
+
+    while let [option, rest @ ..] = options {
+        match option.to_ascii_uppercase().as_slice() {
+            b"WEIGHTS" if op != SetOp::Diff && rest.len() >= numkeys => {
+                weights = rest[..numkeys]
+                    .iter()
+                    .map(|weight| {
+                        parse_number::<f64>(weight)
+                            .ok()
+                            .filter(|weight| !weight.is_nan())
+                            .ok_or_else(|| {
+                                ParseError::InvalidArgument(
+                                    "weight value is not a float".to_string(),
+                                )
+                            })
+                    })
+                    .collect::<Result<_, _>>()?;
+                options = &rest[numkeys..];
+            }
+            b"AGGREGATE" if op != SetOp::Diff && !rest.is_empty() => {
+                aggregate = match rest[0].to_ascii_uppercase().as_slice() {
+                    b"SUM" => Aggregate::Sum,
+                    b"MIN" => Aggregate::Min,
+                    b"MAX" => Aggregate::Max,
+                    _ => return Err(syntax_error()),
+                };
+                options = &rest[1..];
+            }
+            _ => return Err(syntax_error()),
+        }
+    }
+
+    Ok(Command::ZStore {
+        op,
+        destination: destination.to_vec(),
+        keys: keys.iter().map(|key| key.to_vec()).collect(),
+        weights,
+        aggregate,
+    })
+}
+
+// A score or increment, which may be infinite but not NaN.
+fn parse_score(arg: &[u8]) -> Result<f64, ParseError> {
+    parse_number::<f64>(arg)
+        .ok()
+        .filter(|score| !score.is_nan())
+        .ok_or_else(|| ParseError::InvalidArgument("value is not a valid float".to_string()))
+}
+
+// The minimum or maximum of a score range: a score, `(` in front of one to
+// leave it out, or `-inf` and `+inf`.
+fn parse_score_bound(arg: &[u8]) -> Result<Bound<f64>, ParseError> {
+    let (arg, exclusive) = match arg.strip_prefix(b"(") {
+        Some(arg) => (arg, true),
+        None => (arg, false),
+    };
+    let score = parse_number::<f64>(arg)
+        .ok()
+        .filter(|score| !score.is_nan())
+        .ok_or_else(|| ParseError::InvalidArgument("min or max is not a float".to_string()))?;
+
+    Ok(if exclusive {
+        Bound::Excluded(score)
+    } else {
+        Bound::Included(score)
+    })
+}
+
+// A lex range: each end is a member with `[` in front to include it or `(` to
+// leave it out, or `-` and `+` for the lowest and highest possible member.
+fn parse_lex_range(min: &[u8], max: &[u8]) -> Result<RangeBy, ParseError> {
+    let bound = |arg: &[u8]| match arg.split_first() {
+        Some((b'[', member)) => Ok(Bound::Included(member.to_vec())),
+        Some((b'(', member)) => Ok(Bound::Excluded(member.to_vec())),
+        _ if arg == b"-" || arg == b"+" => Ok(Bound::Unbounded),
+        _ => Err(ParseError::InvalidArgument(
+            "min or max not valid string range item".to_string(),
+        )),
+    };
+    let (min_bound, max_bound) = (bound(min)?, bound(max)?);
+
+    // Nothing lies above `+` or below `-`. Nothing is below the empty member
+    // either, which gives an empty range.
+    if min == b"+" || max == b"-" {
+        return Ok(RangeBy::Lex(Bound::Unbounded, Bound::Excluded(Vec::new())));
+    }
+    Ok(RangeBy::Lex(min_bound, max_bound))
+}
+
+/// Parses `[NOMKSTREAM] [MAXLEN|MINID [=|~] threshold [LIMIT count]] id field
+/// value [field value ...]`.
+fn parse_xadd(key: &[u8], args: &[&[u8]]) -> Result<Command, ParseError> {
+    let mut no_mkstream = false;
+    let mut trim = None;
+    let mut args = args;
+
+    loop {
+        match args {
+            [option, rest @ ..] if option.eq_ignore_ascii_case(b"NOMKSTREAM") => {
+                no_mkstream = true;
+                args = rest;
+            }
+            [strategy, rest @ ..] if is_trim_strategy(strategy) => {
+                let (parsed, rest) = parse_trim(strategy, rest)?;
+                trim = Some(parsed);
+                args = rest;
+            }
+            _ => break,
+        }
+    }
+
+    let [id, fields @ ..] = args else {
+        return Err(ParseError::InvalidArgument(
+            "wrong number of arguments for 'xadd' command".to_string(),
+        ));
+    };
+    if fields.is_empty() || !fields.len().is_multiple_of(2) {
+        return Err(ParseError::InvalidArgument(
+            "wrong number of arguments for 'xadd' command".to_string(),
+        ));
+    }
+    let id = match id.strip_suffix(b"-*") {
+        _ if *id == b"*" => NewId::Auto,
+        Some(ms) if !ms.contains(&b'-') => NewId::AutoSeq(parse_stream_id(ms, 0)?.ms),
+        _ => NewId::Exact(parse_stream_id(id, 0)?),
+    };
+
+    Ok(Command::XAdd {
+        key: key.to_vec(),
+        id,
+        fields: fields
+            .chunks(2)
+            .map(|pair| (pair[0].to_vec(), pair[1].to_vec()))
+            .collect(),
+        no_mkstream,
+        trim,
+    })
+}
+
+fn is_trim_strategy(arg: &[u8]) -> bool {
+    arg.eq_ignore_ascii_case(b"MAXLEN") || arg.eq_ignore_ascii_case(b"MINID")
+}
+
+/// Parses what follows MAXLEN or MINID: `[=|~] threshold [LIMIT count]`.
+/// Returns the arguments left after it. Trimming is always exact, `~` only
+/// allows a LIMIT, where 0 means none.
+fn parse_trim<'a, 'b>(
+    strategy: &[u8],
+    args: &'a [&'b [u8]],
+) -> Result<(Trim, &'a [&'b [u8]]), ParseError> {
+    let (approximate, args) = match args {
+        [b"~", rest @ ..] => (true, rest),
+        [b"=", rest @ ..] => (false, rest),
+        _ => (false, args),
+    };
+    let [threshold, args @ ..] = args else {
+        return Err(ParseError::InvalidArgument("syntax error".to_string()));
+    };
+
+    let by = if strategy.eq_ignore_ascii_case(b"MAXLEN") {
+        let max_len = parse_number::<i64>(threshold).map_err(|_| not_an_integer())?;
+        TrimBy::MaxLen(usize::try_from(max_len).map_err(|_| {
+            ParseError::InvalidArgument("The MAXLEN argument must be >= 0.".to_string())
+        })?)
+    } else {
+        TrimBy::MinId(parse_stream_id(threshold, 0)?)
+    };
+
+    match args {
+        [option, limit, rest @ ..] if option.eq_ignore_ascii_case(b"LIMIT") => {
+            if !approximate {
+                return Err(ParseError::InvalidArgument(
+                    "syntax error, LIMIT cannot be used without the special ~ option".to_string(),
+                ));
+            }
+            let limit = parse_number::<i64>(limit).map_err(|_| not_an_integer())?;
+            let limit = usize::try_from(limit).map_err(|_| {
+                ParseError::InvalidArgument("The LIMIT argument must be >= 0.".to_string())
+            })?;
+            let limit = Some(limit).filter(|&limit| limit > 0);
+            Ok((Trim { by, limit }, rest))
+        }
+        _ => Ok((Trim { by, limit: None }, args)),
+    }
+}
+
+/// Parses XRANGE's `start end [COUNT count]`, which XREVRANGE takes the other
+/// way around. A negative count reads nothing.
+fn parse_xrange(
+    key: &[u8],
+    start: &[u8],
+    end: &[u8],
+    options: &[&[u8]],
+    rev: bool,
+) -> Result<Command, ParseError> {
+    let count = match options {
+        [] => None,
+        [option, count] if option.eq_ignore_ascii_case(b"COUNT") => {
+            let count = parse_number::<i64>(count).map_err(|_| not_an_integer())?;
+            Some(usize::try_from(count).unwrap_or(0))
+        }
+        _ => return Err(ParseError::InvalidArgument("syntax error".to_string())),
+    };
+
+    Ok(Command::XRange {
+        key: key.to_vec(),
+        start: parse_range_bound(start, 0)?,
+        end: parse_range_bound(end, u64::MAX)?,
+        rev,
+        count,
+    })
+}
+
+/// Parses `[COUNT count] [BLOCK milliseconds] STREAMS key [key ...] id [id
+/// ...]`, for XREAD, or for XREADGROUP with its `group` and consumer, which
+/// also takes NOACK.
+fn parse_xread(args: &[&[u8]], group: Option<(&[u8], &[u8])>) -> Result<Command, ParseError> {
+    let name = if group.is_some() {
+        "xreadgroup"
+    } else {
+        "xread"
+    };
+    let mut count = None;
+    let mut block = None;
+    let mut no_ack = false;
+    let mut args = args;
+
+    let streams = loop {
+        match args {
+            [option, value, rest @ ..] if option.eq_ignore_ascii_case(b"COUNT") => {
+                let value = parse_number::<i64>(value).map_err(|_| not_an_integer())?;
+                // Anything below 1 doesn't limit the count.
+                count = usize::try_from(value).ok().filter(|&count| count > 0);
+                args = rest;
+            }
+            [option, value, rest @ ..] if option.eq_ignore_ascii_case(b"BLOCK") => {
+                block = Some(parse_block(value)?);
+                args = rest;
+            }
+            [option, rest @ ..] if group.is_some() && option.eq_ignore_ascii_case(b"NOACK") => {
+                no_ack = true;
+                args = rest;
+            }
+            [option, rest @ ..] if option.eq_ignore_ascii_case(b"STREAMS") => break rest,
+            _ => return Err(ParseError::InvalidArgument("syntax error".to_string())),
+        }
+    };
+
+    if streams.is_empty() || !streams.len().is_multiple_of(2) {
+        return Err(ParseError::InvalidArgument(format!(
+            "Unbalanced '{}' list of streams: for each stream key an ID or '$' must be specified.",
+            name
+        )));
+    }
+    let (keys, ids) = streams.split_at(streams.len() / 2);
+    let ids = ids
+        .iter()
+        .map(|id| match (*id, group) {
+            (b"$", None) => Ok(ReadFrom::Last),
+            (b"$", Some(_)) => Err(ParseError::InvalidArgument(
+                "The $ ID is meaningless in the context of XREADGROUP: you want to read the \
+                 history of this consumer by specifying a proper ID, or use the > ID to get new \
+                 messages. The $ ID would just return an empty result set."
+                    .to_string(),
+            )),
+            (b">", Some(_)) => Ok(ReadFrom::Undelivered),
+            (b">", None) => Err(ParseError::InvalidArgument(
+                "The > ID can be specified only when calling XREADGROUP using the GROUP <group> \
+                 <consumer> option."
+                    .to_string(),
+            )),
+            (id, _) => Ok(ReadFrom::After(parse_stream_id(id, 0)?)),
+        })
+        .collect::<Result<_, _>>()?;
+    let keys = keys.iter().map(|key| key.to_vec()).collect();
+
+    Ok(match group {
+        Some((group, consumer)) => Command::XReadGroup {
+            group: group.to_vec(),
+            consumer: consumer.to_vec(),
+            keys,
+            ids,
+            count,
+            block,
+            no_ack,
+        },
+        None => Command::XRead {
+            keys,
+            ids,
+            count,
+            block,
+        },
+    })
+}
+
+// BLOCK's timeout in milliseconds, where 0 waits forever.
+fn parse_block(arg: &[u8]) -> Result<Option<Duration>, ParseError> {
+    let millis = parse_number::<i64>(arg).map_err(|_| {
+        ParseError::InvalidArgument("timeout is not an integer or out of range".to_string())
+    })?;
+    match u64::try_from(millis) {
+        Ok(0) => Ok(None),
+        Ok(millis) => Ok(Some(Duration::from_millis(millis))),
+        Err(_) => Err(ParseError::InvalidArgument(
+            "timeout is negative".to_string(),
+        )),
+    }
+}
+
+fn parse_xgroup(subcommand: &[u8], args: &[&[u8]]) -> Result<Command, ParseError> {
+    // Where the group starts delivering from: after an ID, or `$` for only
+    // the entries added from now on.
+    let from = |arg: &[u8]| match arg {
+        b"$" => Ok(ReadFrom::Last),
+        id => Ok(ReadFrom::After(parse_stream_id(id, 0)?)),
+    };
+
+    match (subcommand.to_ascii_uppercase().as_slice(), args) {
+        (b"CREATE", [key, group, id, options @ ..]) => {
+            let mkstream = match options {
+                [] => false,
+                [option] if option.eq_ignore_ascii_case(b"MKSTREAM") => true,
+                _ => return Err(ParseError::InvalidArgument("syntax error".to_string())),
+            };
+            Ok(Command::XGroupCreate {
+                key: key.to_vec(),
+                group: group.to_vec(),
+                id: from(id)?,
+                mkstream,
+            })
+        }
+        (b"SETID", [key, group, id]) => Ok(Command::XGroupSetId {
+            key: key.to_vec(),
+            group: group.to_vec(),
+            id: from(id)?,
+        }),
+        (b"DESTROY", [key, group]) => Ok(Command::XGroupDestroy {
+            key: key.to_vec(),
+            group: group.to_vec(),
+        }),
+        (b"CREATECONSUMER", [key, group, consumer]) => Ok(Command::XGroupCreateConsumer {
+            key: key.to_vec(),
+            group: group.to_vec(),
+            consumer: consumer.to_vec(),
+        }),
+        (b"DELCONSUMER", [key, group, consumer]) => Ok(Command::XGroupDelConsumer {
+            key: key.to_vec(),
+            group: group.to_vec(),
+            consumer: consumer.to_vec(),
+        }),
+        _ => Err(xgroup_usage()),
+    }
+}
+
+fn parse_pubsub(subcommand: &[u8], args: &[&[u8]]) -> Result<Command, ParseError> {
+    match (subcommand.to_ascii_uppercase().as_slice(), args) {
+        (b"CHANNELS", []) => Ok(Command::PubSubChannels { pattern: None }),
+        (b"CHANNELS", [pattern]) => Ok(Command::PubSubChannels {
+            pattern: Some(pattern.to_vec()),
+        }),
+        (b"NUMSUB", channels) => Ok(Command::PubSubNumSub {
+            channels: channels.iter().map(|channel| channel.to_vec()).collect(),
+        }),
+        (b"NUMPAT", []) => Ok(Command::PubSubNumPat),
+        _ => Err(pubsub_usage()),
+    }
+}
+
+fn pubsub_usage() -> ParseError {
+    ParseError::InvalidArgument(
+        "Usage: PUBSUB CHANNELS [pattern], PUBSUB NUMSUB [channel ...], PUBSUB NUMPAT".to_string(),
+    )
+}
+
+fn xgroup_usage() -> ParseError {
+    ParseError::InvalidArgument(
+        "Usage: XGROUP CREATE <key> <group> <id|$> [MKSTREAM], \
+         XGROUP SETID <key> <group> <id|$>, XGROUP DESTROY <key> <group>, \
+         XGROUP CREATECONSUMER|DELCONSUMER <key> <group> <consumer>"
+            .to_string(),
+    )
+}
+
+/// Parses XPENDING's optional `[IDLE min-idle-time] start end count
+/// [consumer]`.
+fn parse_xpending(key: &[u8], group: &[u8], args: &[&[u8]]) -> Result<Command, ParseError> {
+    let (min_idle, args) = match args {
+        [option, min_idle, rest @ ..] if option.eq_ignore_ascii_case(b"IDLE") => {
+            (Some(parse_min_idle(min_idle)?), rest)
+        }
+        _ => (None, args),
+    };
+
+    let range = match args {
+        [] if min_idle.is_none() => None,
+        [start, end, count, consumer @ ..] if consumer.len() <= 1 => {
+            let count = parse_number::<i64>(count).map_err(|_| not_an_integer())?;
+            Some(PendingRange {
+                min_idle: min_idle.unwrap_or(0),
+                start: parse_range_bound(start, 0)?,
+                end: parse_range_bound(end, u64::MAX)?,
+                count: usize::try_from(count).unwrap_or(0),
+                consumer: consumer.first().map(|consumer| consumer.to_vec()),
+            })
+        }
+        _ => return Err(ParseError::InvalidArgument("syntax error".to_string())),
+    };
+
+    Ok(Command::XPending {
+        key: key.to_vec(),
+        group: group.to_vec(),
+        range,
+    })
+}
+
+/// Parses XCLAIM's `id [id ...]` and the options after them.
+fn parse_xclaim(
+    key: &[u8],
+    group: &[u8],
+    consumer: &[u8],
+    min_idle: &[u8],
+    args: &[&[u8]],
+) -> Result<Command, ParseError> {
+    let min_idle = parse_min_idle(min_idle)?;
+    // The IDs run up to the first option.
+    let id_count = args
+        .iter()
+        .position(|arg| StreamId::parse(arg, 0).is_none())
+        .unwrap_or(args.len());
+    let (ids, mut args) = args.split_at(id_count);
+    let ids = parse_stream_ids(ids)?;
+    if ids.is_empty() {
+        return Err(invalid_stream_id());
+    }
+
+    let mut options = ClaimOptions::default();
+    loop {
+        let number = |arg: &[u8]| parse_number::<u64>(arg).map_err(|_| not_an_integer());
+        match args {
+            [] => break,
+            [option, value, rest @ ..] if option.eq_ignore_ascii_case(b"IDLE") => {
+                options.delivered_at = Some(DeliveredAt::Idle(number(value)?));
+                args = rest;
+            }
+            [option, value, rest @ ..] if option.eq_ignore_ascii_case(b"TIME") => {
+                options.delivered_at = Some(DeliveredAt::At(number(value)?));
+                args = rest;
+            }
+            [option, value, rest @ ..] if option.eq_ignore_ascii_case(b"RETRYCOUNT") => {
+                options.deliveries = Some(number(value)?);
+                args = rest;
+            }
+            [option, value, rest @ ..] if option.eq_ignore_ascii_case(b"LASTID") => {
+                options.last_id = Some(parse_stream_id(value, 0)?);
+                args = rest;
+            }
+            [option, rest @ ..] if option.eq_ignore_ascii_case(b"FORCE") => {
+                options.force = true;
+                args = rest;
+            }
+            [option, rest @ ..] if option.eq_ignore_ascii_case(b"JUSTID") => {
+                options.just_id = true;
+                args = rest;
+            }
+            _ => return Err(ParseError::InvalidArgument("syntax error".to_string())),
+        }
+    }
+
+    Ok(Command::XClaim {
+        key: key.to_vec(),
+        group: group.to_vec(),
+        consumer: consumer.to_vec(),
+        min_idle,
+        ids,
+        options,
+    })
+}
+
+/// Parses XAUTOCLAIM's `min-idle-time start [COUNT count] [JUSTID]`.
+fn parse_xautoclaim(
+    key: &[u8],
+    group: &[u8],
+    consumer: &[u8],
+    min_idle: &[u8],
+    start: &[u8],
+    options: &[&[u8]],
+) -> Result<Command, ParseError> {
+    let mut count = 100;
+    let mut just_id = false;
+    let mut options = options;
+
+    loop {
+        match options {
+            [] => break,
+            [option, value, rest @ ..] if option.eq_ignore_ascii_case(b"COUNT") => {
+                count = parse_number::<usize>(value)
+                    .ok()
+                    .filter(|&count| count > 0)
+                    .ok_or_else(|| ParseError::InvalidArgument("COUNT must be > 0".to_string()))?;
+                options = rest;
+            }
+            [option, rest @ ..] if option.eq_ignore_ascii_case(b"JUSTID") => {
+                just_id = true;
+                options = rest;
+            }
+            _ => return Err(ParseError::InvalidArgument("syntax error".to_string())),
+        }
+    }
+
+    Ok(Command::XAutoClaim {
+        key: key.to_vec(),
+        group: group.to_vec(),
+        consumer: consumer.to_vec(),
+        min_idle: parse_min_idle(min_idle)?,
+        start: match start {
+            b"-" => StreamId::MIN,
+            start => parse_stream_id(start, 0)?,
+        },
+        count,
+        just_id,
+    })
+}
+
+// The minimum idle time of XCLAIM, XAUTOCLAIM and XPENDING, in
+// milliseconds. A negative one is 0.
+fn parse_min_idle(arg: &[u8]) -> Result<u64, ParseError> {
+    let min_idle = parse_number::<i64>(arg)
+        .map_err(|_| ParseError::InvalidArgument("Invalid min-idle-time argument".to_string()))?;
+    Ok(min_idle.max(0) as u64)
+}
+
+// An entry ID, where a lone millisecond time gets `default_seq`.
+fn parse_stream_id(arg: &[u8], default_seq: u64) -> Result<StreamId, ParseError> {
+    StreamId::parse(arg, default_seq).ok_or_else(invalid_stream_id)
+}
+
+fn parse_stream_ids(args: &[&[u8]]) -> Result<Vec<StreamId>, ParseError> {
+    args.iter().map(|arg| parse_stream_id(arg, 0)).collect()
+}
+
+// One end of an ID range: an ID, `(` in front of one to leave it out, or `-`
+// and `+` for the smallest and greatest possible IDs. A lone millisecond time
+// gets `default_seq`, so that the range covers all of that millisecond.
+fn parse_range_bound(arg: &[u8], default_seq: u64) -> Result<Bound<StreamId>, ParseError> {
+    match arg {
+        b"-" => Ok(Bound::Included(StreamId::MIN)),
+        b"+" => Ok(Bound::Included(StreamId::MAX)),
+        _ => match arg.strip_prefix(b"(") {
+            Some(id) => Ok(Bound::Excluded(parse_stream_id(id, default_seq)?)),
+            None => Ok(Bound::Included(parse_stream_id(arg, default_seq)?)),
+        },
+    }
+}
+
+fn invalid_stream_id() -> ParseError {
+    ParseError::InvalidArgument(
+        "Invalid stream ID specified as stream command argument".to_string(),
+    )
+}
+
+fn not_an_integer() -> ParseError {
+    ParseError::InvalidArgument("value is not an integer or out of range".to_string())
+}
+
+/// Parses a numeric argument. Arguments are raw bytes, so anything that is not
+/// valid UTF-8 is simply not a number.
+fn parse_number<T: std::str::FromStr>(arg: &[u8]) -> Result<T, ()> {
+    std::str::from_utf8(arg)
+        .map_err(|_| ())?
+        .parse::<T>()
+        .map_err(|_| ())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -198,10 +2500,14 @@ mod tests {
         let result = Command::parse(input).unwrap();
 
         match result {
-            Command::Set { key, value, expiry } => {
-                assert_eq!(key, "mykey");
-                assert_eq!(value, "myvalue");
-                assert!(expiry.is_none());
+            Command::Set {
+                key,
+                value,
+                options,
+            } => {
+                assert_eq!(key, b"mykey");
+                assert_eq!(value, b"myvalue");
+                assert!(options.expiry.is_none());
             }
             _ => panic!("Expected SET command"),
         }
@@ -213,13 +2519,33 @@ mod tests {
         let result = Command::parse(input).unwrap();
 
         match result {
-            Command::Set { key, value, expiry } => {
-                assert_eq!(key, "mykey");
-                assert_eq!(value, "myvalue");
-                assert_eq!(expiry.unwrap(), Duration::from_secs(60));
+            Command::Set {
+                key,
+                value,
+                options,
+            } => {
+                assert_eq!(key, b"mykey");
+                assert_eq!(value, b"myvalue");
+                assert_eq!(options.expiry, Some(Expiry::In(60_000)));
+            }
+            _ => panic!("Expected SET command with expiry"),
+        }
+    }
+
+    #[test]
+    fn test_parse_set_with_absolute_expiry() {
+        // Kept as given, to be compared with the clock when the command runs.
+        match Command::parse(b"SET mykey myvalue PXAT 1700000000000").unwrap() {
+            Command::Set { options, .. } => {
+                assert_eq!(options.expiry, Some(Expiry::At(1_700_000_000_000)));
             }
             _ => panic!("Expected SET command with expiry"),
         }
+
+        match Command::parse(b"SET mykey myvalue EXAT 1").unwrap() {
+            Command::Set { options, .. } => assert_eq!(options.expiry, Some(Expiry::At(1000))),
+            _ => panic!("Expected SET command with expiry"),
+        }
     }
 
     #[test]
@@ -228,10 +2554,14 @@ mod tests {
         let result = Command::parse(input).unwrap();
 
         match result {
-            Command::Set { key, value, expiry } => {
-                assert_eq!(key, "mykey");
-                assert_eq!(value, "myvalue");
-                assert_eq!(expiry.unwrap(), Duration::from_secs(30));
+            Command::Set {
+                key,
+                value,
+                options,
+            } => {
+                assert_eq!(key, b"mykey");
+                assert_eq!(value, b"myvalue");
+                assert_eq!(options.expiry, Some(Expiry::In(30_000)));
             }
             _ => panic!("Expected SET command"),
         }
@@ -245,10 +2575,65 @@ mod tests {
         assert!(result.is_err());
         match result.unwrap_err() {
             ParseError::InvalidArgument(msg) => {
-                assert!(msg.contains("Expiry time must be a positive integer"));
+                assert!(msg.contains("not an integer"));
             }
             _ => panic!("Expected InvalidArgument error"),
         }
+
+        for input in [
+            &b"SET k v EX 0"[..],
+            b"SET k v PX -1",
+            b"SET k v EX 18446744073709551",
+            b"SET k v PX 9999999999999999999",
+            b"SET k v EXAT 9223372036854776",
+        ] {
+            match Command::parse(input) {
+                Err(ParseError::InvalidArgument(msg)) => {
+                    assert_eq!(msg, "invalid expire time in 'set' command");
+                }
+                other => panic!("Expected invalid expire time, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_set_options() {
+        match Command::parse(b"SET k v nx get px 1500").unwrap() {
+            Command::Set { options, .. } => {
+                assert_eq!(options.only_if_exists, Some(false));
+                assert!(options.get);
+                assert_eq!(options.expiry, Some(Expiry::In(1500)));
+                assert!(!options.keep_ttl);
+            }
+            _ => panic!("Expected SET command"),
+        }
+
+        match Command::parse(b"SET k v KEEPTTL XX").unwrap() {
+            Command::Set { options, .. } => {
+                assert_eq!(options.only_if_exists, Some(true));
+                assert!(options.keep_ttl);
+                assert!(options.expiry.is_none());
+            }
+            _ => panic!("Expected SET command"),
+        }
+
+        match Command::parse(b"SET k v EXAT 1700000000").unwrap() {
+            Command::Set { options, .. } => {
+                assert_eq!(options.expiry, Some(Expiry::At(1_700_000_000_000)));
+            }
+            _ => panic!("Expected SET command"),
+        }
+    }
+
+    #[test]
+    fn test_parse_set_conflicting_options() {
+        assert!(Command::parse(b"SET k v NX XX").is_err());
+        assert!(Command::parse(b"SET k v EX 10 PX 100").is_err());
+        assert!(Command::parse(b"SET k v EX 10 KEEPTTL").is_err());
+        assert!(Command::parse(b"SET k v EX").is_err());
+        assert!(Command::parse(b"SET k v EX 0").is_err());
+        assert!(Command::parse(b"SET k v PX -5").is_err());
+        assert!(Command::parse(b"SET k v SOMEDAY").is_err());
     }
 
     #[test]
@@ -269,7 +2654,7 @@ mod tests {
 
         match result {
             Command::Get { key } => {
-                assert_eq!(key, "mykey");
+                assert_eq!(key, b"mykey");
             }
             _ => panic!("Expected GET command"),
         }
@@ -282,7 +2667,7 @@ mod tests {
 
         match result {
             Command::Get { key } => {
-                assert_eq!(key, "mykey");
+                assert_eq!(key, b"mykey");
             }
             _ => panic!("Expected GET command"),
         }
@@ -290,15 +2675,139 @@ mod tests {
 
     #[test]
     fn test_parse_del() {
-        let input = b"DEL mykey";
+        let input = b"DEL mykey other";
         let result = Command::parse(input).unwrap();
 
         match result {
-            Command::Del { key } => {
-                assert_eq!(key, "mykey");
+            Command::Del { keys } => {
+                assert_eq!(keys, vec![b"mykey".to_vec(), b"other".to_vec()]);
             }
             _ => panic!("Expected DEL command"),
         }
+        assert!(Command::parse(b"DEL").is_err());
+    }
+
+    #[test]
+    fn test_parse_mixed_case_names() {
+        assert!(matches!(
+            Command::parse(b"Set k v").unwrap(),
+            Command::Set { .. }
+        ));
+        assert!(matches!(
+            Command::parse(b"dEl k").unwrap(),
+            Command::Del { .. }
+        ));
+        assert!(matches!(
+            Command::parse(b"zPopMax z").unwrap(),
+            Command::ZPop { max: true, .. }
+        ));
+    }
+
+    #[test]
+    fn test_parse_multi_key_strings() {
+        match Command::parse(b"MGET a b c").unwrap() {
+            Command::MGet { keys } => {
+                assert_eq!(keys, vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()])
+            }
+            _ => panic!("Expected MGET command"),
+        }
+
+        match Command::parse(b"msetnx a 1 b 2").unwrap() {
+            Command::MSetNx { pairs } => assert_eq!(
+                pairs,
+                vec![
+                    (b"a".to_vec(), b"1".to_vec()),
+                    (b"b".to_vec(), b"2".to_vec())
+                ]
+            ),
+            _ => panic!("Expected MSETNX command"),
+        }
+
+        assert!(Command::parse(b"MGET").is_err());
+        assert!(Command::parse(b"MSET").is_err());
+        assert!(Command::parse(b"MSET a 1 b").is_err());
+    }
+
+    #[test]
+    fn test_parse_getset_is_set_with_get() {
+        match Command::parse(b"GETSET k v").unwrap() {
+            Command::Set { key, options, .. } => {
+                assert_eq!(key, b"k");
+                assert!(options.get);
+                assert!(options.only_if_exists.is_none());
+            }
+            _ => panic!("Expected SET command"),
+        }
+    }
+
+    #[test]
+    fn test_parse_getex() {
+        match Command::parse(b"GETEX k").unwrap() {
+            Command::GetEx { expiry, .. } => assert!(expiry.is_none()),
+            _ => panic!("Expected GETEX command"),
+        }
+        match Command::parse(b"GETEX k persist").unwrap() {
+            Command::GetEx { expiry, .. } => assert_eq!(expiry, Some(ExpiryChange::Persist)),
+            _ => panic!("Expected GETEX command"),
+        }
+        match Command::parse(b"GETEX k EXAT 100").unwrap() {
+            Command::GetEx { expiry, .. } => {
+                assert_eq!(expiry, Some(ExpiryChange::Expire(Expiry::At(100_000))))
+            }
+            _ => panic!("Expected GETEX command"),
+        }
+        match Command::parse(b"GETEX k PX 500").unwrap() {
+            Command::GetEx { expiry, .. } => {
+                assert_eq!(expiry, Some(ExpiryChange::Expire(Expiry::In(500))))
+            }
+            _ => panic!("Expected GETEX command"),
+        }
+
+        assert!(Command::parse(b"GETEX k EX 0").is_err());
+        assert!(Command::parse(b"GETEX k EX").is_err());
+        assert!(Command::parse(b"GETEX k PERSIST EX 10").is_err());
+        assert!(Command::parse(b"GETEX k KEEPTTL").is_err());
+    }
+
+    #[test]
+    fn test_parse_ranges() {
+        match Command::parse(b"GETRANGE k 0 -1").unwrap() {
+            Command::GetRange { start, end, .. } => assert_eq!((start, end), (0, -1)),
+            _ => panic!("Expected GETRANGE command"),
+        }
+        match Command::parse(b"SETRANGE k 10 abc").unwrap() {
+            Command::SetRange { offset, value, .. } => {
+                assert_eq!(offset, 10);
+                assert_eq!(value, b"abc");
+            }
+            _ => panic!("Expected SETRANGE command"),
+        }
+
+        assert!(Command::parse(b"GETRANGE k a 1").is_err());
+        assert!(Command::parse(b"SETRANGE k -1 abc").is_err());
+    }
+
+    #[test]
+    fn test_parse_counters() {
+        let delta_of = |input: &[u8]| match Command::parse(input).unwrap() {
+            Command::IncrBy { delta, .. } => delta,
+            _ => panic!("Expected INCRBY command"),
+        };
+        assert_eq!(delta_of(b"INCR n"), 1);
+        assert_eq!(delta_of(b"decr n"), -1);
+        assert_eq!(delta_of(b"INCRBY n -5"), -5);
+        assert_eq!(delta_of(b"DECRBY n 5"), -5);
+
+        match Command::parse(b"INCRBYFLOAT n 0.5").unwrap() {
+            Command::IncrByFloat { delta, .. } => assert_eq!(delta, 0.5),
+            _ => panic!("Expected INCRBYFLOAT command"),
+        }
+
+        assert!(Command::parse(b"INCRBY n 1.5").is_err());
+        assert!(Command::parse(b"INCRBY n 99999999999999999999").is_err());
+        assert!(Command::parse(b"DECRBY n -9223372036854775808").is_err());
+        assert!(Command::parse(b"INCRBYFLOAT n nan").is_err());
+        assert!(Command::parse(b"INCR").is_err());
     }
 
     #[test]
@@ -308,8 +2817,11 @@ mod tests {
 
         match result {
             Command::LPush { key, values } => {
-                assert_eq!(key, "mylist");
-                assert_eq!(values, vec!["value1", "value2", "value3"]);
+                assert_eq!(key, b"mylist");
+                assert_eq!(
+                    values,
+                    vec![b"value1".to_vec(), b"value2".to_vec(), b"value3".to_vec()]
+                );
             }
             _ => panic!("Expected LPUSH command"),
         }
@@ -322,8 +2834,8 @@ mod tests {
 
         match result {
             Command::LPush { key, values } => {
-                assert_eq!(key, "mylist");
-                assert_eq!(values, vec!["single_value"]);
+                assert_eq!(key, b"mylist");
+                assert_eq!(values, vec![b"single_value".to_vec()]);
             }
             _ => panic!("Expected LPUSH command"),
         }
@@ -350,8 +2862,8 @@ mod tests {
 
         match result {
             Command::RPush { key, values } => {
-                assert_eq!(key, "mylist");
-                assert_eq!(values, vec!["value1", "value2"]);
+                assert_eq!(key, b"mylist");
+                assert_eq!(values, vec![b"value1".to_vec(), b"value2".to_vec()]);
             }
             _ => panic!("Expected RPUSH command"),
         }
@@ -364,7 +2876,7 @@ mod tests {
 
         match result {
             Command::LRange { key, start, stop } => {
-                assert_eq!(key, "mylist");
+                assert_eq!(key, b"mylist");
                 assert_eq!(start, 0);
                 assert_eq!(stop, -1);
             }
@@ -372,6 +2884,114 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_pop() {
+        match Command::parse(b"LPOP l").unwrap() {
+            Command::Pop { end, count, .. } => {
+                assert_eq!(end, ListEnd::Left);
+                assert!(count.is_none());
+            }
+            _ => panic!("Expected LPOP command"),
+        }
+        match Command::parse(b"rpop l 3").unwrap() {
+            Command::Pop { end, count, .. } => {
+                assert_eq!(end, ListEnd::Right);
+                assert_eq!(count, Some(3));
+            }
+            _ => panic!("Expected RPOP command"),
+        }
+
+        assert!(Command::parse(b"LPOP l -1").is_err());
+        assert!(Command::parse(b"LPOP l 1 2").is_err());
+    }
+
+    #[test]
+    fn test_parse_list_editing() {
+        match Command::parse(b"LINSERT l after pivot x").unwrap() {
+            Command::LInsert { before, pivot, .. } => {
+                assert!(!before);
+                assert_eq!(pivot, b"pivot");
+            }
+            _ => panic!("Expected LINSERT command"),
+        }
+        match Command::parse(b"LREM l -2 x").unwrap() {
+            Command::LRem { count, .. } => assert_eq!(count, -2),
+            _ => panic!("Expected LREM command"),
+        }
+
+        assert!(Command::parse(b"LINSERT l AROUND pivot x").is_err());
+        assert!(Command::parse(b"LSET l one x").is_err());
+        assert!(Command::parse(b"LTRIM l 0").is_err());
+    }
+
+    #[test]
+    fn test_parse_lpos() {
+        match Command::parse(b"LPOS l x MAXLEN 10 rank -2 COUNT 0").unwrap() {
+            Command::LPos {
+                rank,
+                count,
+                max_len,
+                ..
+            } => {
+                assert_eq!(rank, -2);
+                assert_eq!(count, Some(0));
+                assert_eq!(max_len, 10);
+            }
+            _ => panic!("Expected LPOS command"),
+        }
+
+        assert!(Command::parse(b"LPOS l x RANK 0").is_err());
+        assert!(Command::parse(b"LPOS l x COUNT -1").is_err());
+        assert!(Command::parse(b"LPOS l x COUNT").is_err());
+        assert!(Command::parse(b"LPOS l x SOMETIMES 1").is_err());
+    }
+
+    #[test]
+    fn test_parse_lmove() {
+        match Command::parse(b"LMOVE a b left RIGHT").unwrap() {
+            Command::LMove { from, to, .. } => {
+                assert_eq!(from, ListEnd::Left);
+                assert_eq!(to, ListEnd::Right);
+            }
+            _ => panic!("Expected LMOVE command"),
+        }
+        match Command::parse(b"RPOPLPUSH a b").unwrap() {
+            Command::LMove { from, to, .. } => {
+                assert_eq!(from, ListEnd::Right);
+                assert_eq!(to, ListEnd::Left);
+            }
+            _ => panic!("Expected LMOVE command"),
+        }
+
+        assert!(Command::parse(b"LMOVE a b UP DOWN").is_err());
+    }
+
+    #[test]
+    fn test_parse_blocking_pops() {
+        match Command::parse(b"BLPOP a b 1.5").unwrap() {
+            Command::BPop { keys, end, timeout } => {
+                assert_eq!(keys, vec![b"a".to_vec(), b"b".to_vec()]);
+                assert_eq!(end, ListEnd::Left);
+                assert_eq!(timeout, Some(Duration::from_millis(1500)));
+            }
+            _ => panic!("Expected BLPOP command"),
+        }
+        match Command::parse(b"brpoplpush a b 0").unwrap() {
+            Command::BLMove {
+                from, to, timeout, ..
+            } => {
+                assert_eq!((from, to), (ListEnd::Right, ListEnd::Left));
+                assert!(timeout.is_none());
+            }
+            _ => panic!("Expected BLMOVE command"),
+        }
+
+        assert!(Command::parse(b"BLPOP 0").is_err());
+        assert!(Command::parse(b"BRPOP a -1").is_err());
+        assert!(Command::parse(b"BLPOP a soon").is_err());
+        assert!(Command::parse(b"BLMOVE a b LEFT UP 0").is_err());
+    }
+
     #[test]
     fn test_parse_lrange_positive_indices() {
         let input = b"LRANGE mylist 1 3";
@@ -379,7 +2999,7 @@ mod tests {
 
         match result {
             Command::LRange { key, start, stop } => {
-                assert_eq!(key, "mylist");
+                assert_eq!(key, b"mylist");
                 assert_eq!(start, 1);
                 assert_eq!(stop, 3);
             }
@@ -404,15 +3024,68 @@ mod tests {
         let result = Command::parse(input).unwrap();
 
         match result {
-            Command::HSet { key, field, value } => {
-                assert_eq!(key, "myhash");
-                assert_eq!(field, "field1");
-                assert_eq!(value, "value1");
+            Command::HSet { key, pairs } => {
+                assert_eq!(key, b"myhash");
+                assert_eq!(pairs, vec![(b"field1".to_vec(), b"value1".to_vec())]);
             }
             _ => panic!("Expected HSET command"),
         }
     }
 
+    #[test]
+    fn test_parse_hset_multiple_fields() {
+        match Command::parse(b"HSET h a 1 b 2").unwrap() {
+            Command::HSet { pairs, .. } => assert_eq!(pairs.len(), 2),
+            _ => panic!("Expected HSET command"),
+        }
+        match Command::parse(b"hmset h a 1").unwrap() {
+            Command::HMSet { pairs, .. } => assert_eq!(pairs.len(), 1),
+            _ => panic!("Expected HMSET command"),
+        }
+
+        assert!(Command::parse(b"HSET h").is_err());
+        assert!(Command::parse(b"HSET h a 1 b").is_err());
+    }
+
+    #[test]
+    fn test_parse_hrandfield() {
+        match Command::parse(b"HRANDFIELD h").unwrap() {
+            Command::HRandField {
+                count, with_values, ..
+            } => {
+                assert!(count.is_none());
+                assert!(!with_values);
+            }
+            _ => panic!("Expected HRANDFIELD command"),
+        }
+        match Command::parse(b"HRANDFIELD h -5 withvalues").unwrap() {
+            Command::HRandField {
+                count, with_values, ..
+            } => {
+                assert_eq!(count, Some(-5));
+                assert!(with_values);
+            }
+            _ => panic!("Expected HRANDFIELD command"),
+        }
+
+        assert!(Command::parse(b"HRANDFIELD h 1 WITHSCORES").is_err());
+        assert!(Command::parse(b"HRANDFIELD h many").is_err());
+    }
+
+    #[test]
+    fn test_parse_hincrby() {
+        match Command::parse(b"HINCRBY h f -3").unwrap() {
+            Command::HIncrBy { field, delta, .. } => {
+                assert_eq!(field, b"f");
+                assert_eq!(delta, -3);
+            }
+            _ => panic!("Expected HINCRBY command"),
+        }
+
+        assert!(Command::parse(b"HINCRBY h f 1.5").is_err());
+        assert!(Command::parse(b"HINCRBYFLOAT h f inf").is_err());
+    }
+
     #[test]
     fn test_parse_hget() {
         let input = b"HGET myhash field1";
@@ -420,8 +3093,8 @@ mod tests {
 
         match result {
             Command::HGet { key, field } => {
-                assert_eq!(key, "myhash");
-                assert_eq!(field, "field1");
+                assert_eq!(key, b"myhash");
+                assert_eq!(field, b"field1");
             }
             _ => panic!("Expected HGET command"),
         }
@@ -434,8 +3107,11 @@ mod tests {
 
         match result {
             Command::HDel { key, fields } => {
-                assert_eq!(key, "myhash");
-                assert_eq!(fields, vec!["field1", "field2", "field3"]);
+                assert_eq!(key, b"myhash");
+                assert_eq!(
+                    fields,
+                    vec![b"field1".to_vec(), b"field2".to_vec(), b"field3".to_vec()]
+                );
             }
             _ => panic!("Expected HDEL command"),
         }
@@ -448,8 +3124,8 @@ mod tests {
 
         match result {
             Command::HDel { key, fields } => {
-                assert_eq!(key, "myhash");
-                assert_eq!(fields, vec!["field1"]);
+                assert_eq!(key, b"myhash");
+                assert_eq!(fields, vec![b"field1".to_vec()]);
             }
             _ => panic!("Expected HDEL command"),
         }
@@ -462,7 +3138,7 @@ mod tests {
 
         match result {
             Command::HLen { key } => {
-                assert_eq!(key, "myhash");
+                assert_eq!(key, b"myhash");
             }
             _ => panic!("Expected HLEN command"),
         }
@@ -475,19 +3151,518 @@ mod tests {
 
         match result {
             Command::HGetAll { key } => {
-                assert_eq!(key, "myhash");
+                assert_eq!(key, b"myhash");
             }
             _ => panic!("Expected HGETALL command"),
         }
     }
 
+    #[test]
+    fn test_parse_expire_family() {
+        match Command::parse(b"PEXPIREAT k 1700000000000 XX gt").unwrap() {
+            Command::Expire { key, at, condition } => {
+                assert_eq!(key, b"k");
+                assert_eq!(at, Expiry::At(1_700_000_000_000));
+                assert!(condition.xx && condition.gt && !condition.nx);
+            }
+            _ => panic!("Expected EXPIRE command"),
+        }
+
+        match Command::parse(b"EXPIRE k 10").unwrap() {
+            Command::Expire { at, .. } => assert_eq!(at, Expiry::In(10_000)),
+            _ => panic!("Expected EXPIRE command"),
+        }
+
+        assert!(matches!(
+            Command::parse(b"PTTL k").unwrap(),
+            Command::Ttl { millis: true, .. }
+        ));
+        assert!(matches!(
+            Command::parse(b"expiretime k").unwrap(),
+            Command::ExpireTime { millis: false, .. }
+        ));
+        assert!(matches!(
+            Command::parse(b"PERSIST k").unwrap(),
+            Command::Persist { .. }
+        ));
+    }
+
+    #[test]
+    fn test_parse_expire_errors() {
+        assert!(Command::parse(b"EXPIRE k").is_err());
+        assert!(Command::parse(b"EXPIRE k soon").is_err());
+        assert!(Command::parse(b"EXPIRE k 9223372036854775807").is_err());
+        assert!(Command::parse(b"EXPIRE k 10 NX GT").is_err());
+        assert!(Command::parse(b"EXPIRE k 10 GT LT").is_err());
+        assert!(Command::parse(b"EXPIRE k 10 SOMETIMES").is_err());
+        assert!(Command::parse(b"TTL").is_err());
+    }
+
+    #[test]
+    fn test_parse_set_commands() {
+        match Command::parse(b"SADD s a b").unwrap() {
+            Command::SAdd { key, members } => {
+                assert_eq!(key, b"s");
+                assert_eq!(members, vec![b"a".to_vec(), b"b".to_vec()]);
+            }
+            _ => panic!("Expected SADD command"),
+        }
+        assert!(matches!(
+            Command::parse(b"spop s").unwrap(),
+            Command::SPop { count: None, .. }
+        ));
+        assert!(matches!(
+            Command::parse(b"SRANDMEMBER s -5").unwrap(),
+            Command::SRandMember {
+                count: Some(-5),
+                ..
+            }
+        ));
+        match Command::parse(b"sunionstore dst a b").unwrap() {
+            Command::SetOpStore {
+                op,
+                destination,
+                keys,
+            } => {
+                assert_eq!(op, SetOp::Union);
+                assert_eq!(destination, b"dst");
+                assert_eq!(keys, vec![b"a".to_vec(), b"b".to_vec()]);
+            }
+            _ => panic!("Expected SUNIONSTORE command"),
+        }
+        assert!(matches!(
+            Command::parse(b"SDIFF a").unwrap(),
+            Command::SetOp {
+                op: SetOp::Diff,
+                ..
+            }
+        ));
+        assert!(matches!(
+            Command::parse(b"TYPE k").unwrap(),
+            Command::Type { .. }
+        ));
+
+        assert!(Command::parse(b"SADD s").is_err());
+        assert!(Command::parse(b"SPOP s -1").is_err());
+        assert!(Command::parse(b"SINTERSTORE dst").is_err());
+        assert!(Command::parse(b"SMOVE a b").is_err());
+    }
+
+    #[test]
+    fn test_parse_zadd() {
+        match Command::parse(b"ZADD z xx gt ch 1.5 a -inf b").unwrap() {
+            Command::ZAdd {
+                key,
+                pairs,
+                options,
+                incr,
+            } => {
+                assert_eq!(key, b"z");
+                assert_eq!(
+                    pairs,
+                    vec![(1.5, b"a".to_vec()), (f64::NEG_INFINITY, b"b".to_vec())]
+                );
+                assert_eq!(
+                    options,
+                    ZAddOptions {
+                        only_if_exists: Some(true),
+                        gt: true,
+                        lt: false,
+                        changed: true,
+                    }
+                );
+                assert!(!incr);
+            }
+            _ => panic!("Expected ZADD command"),
+        }
+        assert!(matches!(
+            Command::parse(b"zadd z INCR 2 a").unwrap(),
+            Command::ZAdd { incr: true, .. }
+        ));
+
+        assert!(Command::parse(b"ZADD z NX XX 1 a").is_err());
+        assert!(Command::parse(b"ZADD z NX GT 1 a").is_err());
+        assert!(Command::parse(b"ZADD z GT LT 1 a").is_err());
+        assert!(Command::parse(b"ZADD z INCR 1 a 2 b").is_err());
+        assert!(Command::parse(b"ZADD z 1 a 2").is_err());
+        assert!(Command::parse(b"ZADD z nan a").is_err());
+        assert!(Command::parse(b"ZINCRBY z x a").is_err());
+    }
+
+    #[test]
+    fn test_parse_zrange() {
+        match Command::parse(b"ZRANGE z (1 +inf BYSCORE LIMIT 2 -1 WITHSCORES").unwrap() {
+            Command::ZRange {
+                by,
+                rev,
+                limit,
+                with_scores,
+                ..
+            } => {
+                assert_eq!(
+                    by,
+                    RangeBy::Score(Bound::Excluded(1.0), Bound::Included(f64::INFINITY))
+                );
+                assert!(!rev);
+                assert_eq!(limit, Some((2, -1)));
+                assert!(with_scores);
+            }
+            _ => panic!("Expected ZRANGE command"),
+        }
+        // Reversed ranges are given from the high end.
+        assert!(matches!(
+            Command::parse(b"ZREVRANGEBYSCORE z 5 1").unwrap(),
+            Command::ZRange {
+                by: RangeBy::Score(Bound::Included(1.0), Bound::Included(5.0)),
+                rev: true,
+                ..
+            }
+        ));
+        assert!(matches!(
+            Command::parse(b"zrange z + [b BYLEX REV").unwrap(),
+            Command::ZRange {
+                by: RangeBy::Lex(Bound::Included(_), Bound::Unbounded),
+                rev: true,
+                ..
+            }
+        ));
+        assert!(matches!(
+            Command::parse(b"ZREVRANGE z 0 -1").unwrap(),
+            Command::ZRange {
+                by: RangeBy::Rank(0, -1),
+                rev: true,
+                ..
+            }
+        ));
+        assert!(matches!(
+            Command::parse(b"ZLEXCOUNT z - +").unwrap(),
+            Command::ZCount {
+                by: RangeBy::Lex(Bound::Unbounded, Bound::Unbounded),
+                ..
+            }
+        ));
+
+        assert!(Command::parse(b"ZRANGE z 0 -1 LIMIT 0 1").is_err());
+        assert!(Command::parse(b"ZRANGE z - + BYLEX WITHSCORES").is_err());
+        assert!(Command::parse(b"ZRANGEBYSCORE z 1 2 REV").is_err());
+        assert!(Command::parse(b"ZRANGEBYLEX z a b").is_err());
+        assert!(Command::parse(b"ZCOUNT z x 1").is_err());
+    }
+
+    #[test]
+    fn test_parse_zstore_and_zpop() {
+        match Command::parse(b"ZUNIONSTORE out 2 a b WEIGHTS 2 0.5 AGGREGATE max").unwrap() {
+            Command::ZStore {
+                op,
+                destination,
+                keys,
+                weights,
+                aggregate,
+            } => {
+                assert_eq!(op, SetOp::Union);
+                assert_eq!(destination, b"out");
+                assert_eq!(keys, vec![b"a".to_vec(), b"b".to_vec()]);
+                assert_eq!(weights, vec![2.0, 0.5]);
+                assert_eq!(aggregate, Aggregate::Max);
+            }
+            _ => panic!("Expected ZUNIONSTORE command"),
+        }
+        assert!(matches!(
+            Command::parse(b"zpopmax z").unwrap(),
+            Command::ZPop {
+                max: true,
+                count: 1,
+                ..
+            }
+        ));
+
+        assert!(Command::parse(b"ZINTERSTORE out 0 a").is_err());
+        assert!(Command::parse(b"ZINTERSTORE out 3 a b").is_err());
+        assert!(Command::parse(b"ZINTERSTORE out 2 a b WEIGHTS 1").is_err());
+        assert!(Command::parse(b"ZDIFFSTORE out 1 a WEIGHTS 1").is_err());
+        assert!(Command::parse(b"ZPOPMIN z -1").is_err());
+    }
+
+    #[test]
+    fn test_parse_xadd() {
+        match Command::parse(b"XADD s NOMKSTREAM MAXLEN ~ 10 LIMIT 0 5-* a 1 b 2").unwrap() {
+            Command::XAdd {
+                key,
+                id,
+                fields,
+                no_mkstream,
+                trim,
+            } => {
+                assert_eq!(key, b"s");
+                assert_eq!(id, NewId::AutoSeq(5));
+                assert_eq!(fields.len(), 2);
+                assert!(no_mkstream);
+                assert_eq!(
+                    trim,
+                    Some(Trim {
+                        by: TrimBy::MaxLen(10),
+                        limit: None,
+                    })
+                );
+            }
+            _ => panic!("Expected XADD command"),
+        }
+        assert!(matches!(
+            Command::parse(b"xadd s MINID 3 * a 1").unwrap(),
+            Command::XAdd {
+                id: NewId::Auto,
+                trim: Some(Trim {
+                    by: TrimBy::MinId(StreamId { ms: 3, seq: 0 }),
+                    ..
+                }),
+                ..
+            }
+        ));
+        assert!(matches!(
+            Command::parse(b"XTRIM s MAXLEN = 0").unwrap(),
+            Command::XTrim { .. }
+        ));
+
+        assert!(Command::parse(b"XADD s * a").is_err());
+        assert!(Command::parse(b"XADD s 1-2-* a 1").is_err());
+        assert!(Command::parse(b"XADD s MAXLEN 10 LIMIT 5 * a 1").is_err());
+        assert!(Command::parse(b"XADD s MAXLEN -1 * a 1").is_err());
+        assert!(Command::parse(b"XTRIM s MAXLEN 1 extra").is_err());
+    }
+
+    #[test]
+    fn test_parse_xrange_and_xread() {
+        match Command::parse(b"XREVRANGE s + (5 COUNT 2").unwrap() {
+            Command::XRange {
+                start,
+                end,
+                rev,
+                count,
+                ..
+            } => {
+                assert_eq!(start, Bound::Excluded(StreamId::new(5, 0)));
+                assert_eq!(end, Bound::Included(StreamId::MAX));
+                assert!(rev);
+                assert_eq!(count, Some(2));
+            }
+            _ => panic!("Expected XREVRANGE command"),
+        }
+        assert!(matches!(
+            Command::parse(b"XRANGE s - 7").unwrap(),
+            Command::XRange {
+                end: Bound::Included(StreamId {
+                    ms: 7,
+                    seq: u64::MAX
+                }),
+                ..
+            }
+        ));
+
+        match Command::parse(b"XREAD COUNT 0 BLOCK 0 STREAMS a b $ 1-1").unwrap() {
+            Command::XRead {
+                keys,
+                ids,
+                count,
+                block,
+            } => {
+                assert_eq!(keys, [b"a".to_vec(), b"b".to_vec()]);
+                assert_eq!(ids, [ReadFrom::Last, ReadFrom::After(StreamId::new(1, 1))]);
+                assert_eq!(count, None);
+                assert_eq!(block, Some(None));
+            }
+            _ => panic!("Expected XREAD command"),
+        }
+        match Command::parse(b"XREADGROUP GROUP g c BLOCK 100 NOACK STREAMS a >").unwrap() {
+            Command::XReadGroup {
+                group,
+                ids,
+                block,
+                no_ack,
+                ..
+            } => {
+                assert_eq!(group, b"g");
+                assert_eq!(ids, [ReadFrom::Undelivered]);
+                assert_eq!(block, Some(Some(Duration::from_millis(100))));
+                assert!(no_ack);
+            }
+            _ => panic!("Expected XREADGROUP command"),
+        }
+
+        assert!(Command::parse(b"XREAD STREAMS a b 0").is_err());
+        assert!(Command::parse(b"XREAD STREAMS a >").is_err());
+        assert!(Command::parse(b"XREAD NOACK STREAMS a 0").is_err());
+        assert!(Command::parse(b"XREADGROUP GROUP g c STREAMS a $").is_err());
+        assert!(Command::parse(b"XREAD BLOCK -1 STREAMS a 0").is_err());
+    }
+
+    #[test]
+    fn test_parse_consumer_groups() {
+        assert!(matches!(
+            Command::parse(b"XGROUP CREATE s g $ MKSTREAM").unwrap(),
+            Command::XGroupCreate {
+                id: ReadFrom::Last,
+                mkstream: true,
+                ..
+            }
+        ));
+        assert!(matches!(
+            Command::parse(b"xgroup delconsumer s g c").unwrap(),
+            Command::XGroupDelConsumer { .. }
+        ));
+        assert!(Command::parse(b"XGROUP CREATE s g").is_err());
+        assert!(Command::parse(b"XGROUP NOPE s g").is_err());
+
+        match Command::parse(b"XPENDING s g IDLE 10 - + 5 alice").unwrap() {
+            Command::XPending {
+                range: Some(range), ..
+            } => {
+                assert_eq!(range.min_idle, 10);
+                assert_eq!(range.count, 5);
+                assert_eq!(range.consumer, Some(b"alice".to_vec()));
+            }
+            _ => panic!("Expected XPENDING command"),
+        }
+        assert!(matches!(
+            Command::parse(b"XPENDING s g").unwrap(),
+            Command::XPending { range: None, .. }
+        ));
+        assert!(Command::parse(b"XPENDING s g IDLE 10").is_err());
+
+        match Command::parse(b"XCLAIM s g c 100 1-0 2 TIME 5 RETRYCOUNT 3 FORCE LASTID 9").unwrap()
+        {
+            Command::XClaim {
+                min_idle,
+                ids,
+                options,
+                ..
+            } => {
+                assert_eq!(min_idle, 100);
+                assert_eq!(ids, [StreamId::new(1, 0), StreamId::new(2, 0)]);
+                assert_eq!(
+                    options,
+                    ClaimOptions {
+                        delivered_at: Some(DeliveredAt::At(5)),
+                        deliveries: Some(3),
+                        force: true,
+                        just_id: false,
+                        last_id: Some(StreamId::new(9, 0)),
+                    }
+                );
+            }
+            _ => panic!("Expected XCLAIM command"),
+        }
+        assert!(Command::parse(b"XCLAIM s g c 100 FORCE").is_err());
+
+        assert!(matches!(
+            Command::parse(b"XAUTOCLAIM s g c -5 - JUSTID").unwrap(),
+            Command::XAutoClaim {
+                min_idle: 0,
+                start: StreamId::MIN,
+                count: 100,
+                just_id: true,
+                ..
+            }
+        ));
+        assert!(Command::parse(b"XAUTOCLAIM s g c 0 0 COUNT 0").is_err());
+    }
+
+    #[test]
+    fn test_parse_pubsub() {
+        match Command::parse(b"psubscribe news.* sport").unwrap() {
+            Command::Subscribe { channels, patterns } => {
+                assert_eq!(channels, [b"news.*".to_vec(), b"sport".to_vec()]);
+                assert!(patterns);
+            }
+            _ => panic!("Expected PSUBSCRIBE command"),
+        }
+        assert!(Command::parse(b"SUBSCRIBE").is_err());
+        assert!(matches!(
+            Command::parse(b"UNSUBSCRIBE").unwrap(),
+            Command::Unsubscribe { channels, patterns: false } if channels.is_empty()
+        ));
+        assert!(matches!(
+            Command::parse(b"PUBLISH news hi").unwrap(),
+            Command::Publish { .. }
+        ));
+        assert!(Command::parse(b"PUBLISH news").is_err());
+
+        assert!(matches!(
+            Command::parse(b"pubsub channels n*").unwrap(),
+            Command::PubSubChannels { pattern: Some(_) }
+        ));
+        assert!(matches!(
+            Command::parse(b"PUBSUB NUMSUB").unwrap(),
+            Command::PubSubNumSub { channels } if channels.is_empty()
+        ));
+        assert!(matches!(
+            Command::parse(b"PUBSUB NUMPAT").unwrap(),
+            Command::PubSubNumPat
+        ));
+        assert!(Command::parse(b"PUBSUB NUMPAT x").is_err());
+    }
+
+    #[test]
+    fn test_parse_hash_field_expiry() {
+        match Command::parse(b"HPEXPIREAT h 1700000000000 GT FIELDS 2 a b").unwrap() {
+            Command::HExpire {
+                key,
+                fields,
+                at,
+                condition,
+            } => {
+                assert_eq!(key, b"h");
+                assert_eq!(fields, vec![b"a".to_vec(), b"b".to_vec()]);
+                assert_eq!(at, Expiry::At(1_700_000_000_000));
+                assert!(condition.gt && !condition.nx);
+            }
+            _ => panic!("Expected HEXPIRE command"),
+        }
+
+        match Command::parse(b"hexpire h 10 fields 1 a").unwrap() {
+            Command::HExpire { at, condition, .. } => {
+                assert_eq!(at, Expiry::In(10_000));
+                assert_eq!(condition, ExpireCondition::default());
+            }
+            _ => panic!("Expected HEXPIRE command"),
+        }
+
+        assert!(matches!(
+            Command::parse(b"HPTTL h FIELDS 1 a").unwrap(),
+            Command::HTtl { millis: true, .. }
+        ));
+        assert!(matches!(
+            Command::parse(b"HEXPIRETIME h FIELDS 1 a").unwrap(),
+            Command::HExpireTime { millis: false, .. }
+        ));
+        assert!(matches!(
+            Command::parse(b"HPERSIST h FIELDS 1 a").unwrap(),
+            Command::HPersist { .. }
+        ));
+
+        let error = |input: &[u8]| Command::parse(input).unwrap_err().to_string();
+        assert_eq!(
+            error(b"HEXPIRE h 10 FIELDS 0"),
+            "ERR Parameter `numFields` should be greater than 0"
+        );
+        assert_eq!(
+            error(b"HTTL h FIELDS 2 a"),
+            "ERR The `numfields` parameter must match the number of arguments"
+        );
+        assert_eq!(
+            error(b"HEXPIRE h 10 NX XX FIELDS 1 a"),
+            "ERR Mandatory argument FIELDS is missing or not at the right position"
+        );
+        assert!(Command::parse(b"HEXPIRE h 10 SOMETIMES FIELDS 1 a").is_err());
+        assert!(Command::parse(b"HPERSIST h").is_err());
+    }
+
     #[test]
     fn test_parse_save() {
         let input = b"SAVE";
         let result = Command::parse(input).unwrap();
 
         match result {
-            Command::Save => {},
+            Command::Save => {}
             _ => panic!("Expected SAVE command"),
         }
     }
@@ -498,11 +3673,95 @@ mod tests {
         let result = Command::parse(input).unwrap();
 
         match result {
-            Command::Save => {},
+            Command::Save => {}
             _ => panic!("Expected SAVE command"),
         }
     }
 
+    #[test]
+    fn test_parse_bgsave_and_lastsave() {
+        assert!(matches!(
+            Command::parse(b"BGSAVE").unwrap(),
+            Command::BgSave
+        ));
+        assert!(matches!(
+            Command::parse(b"lastsave").unwrap(),
+            Command::LastSave
+        ));
+        assert!(matches!(
+            Command::parse(b"INFO persistence").unwrap(),
+            Command::Info { section: Some(s) } if s == b"persistence"
+        ));
+    }
+
+    #[test]
+    fn test_parse_bgrewriteaof() {
+        match Command::parse(b"BGREWRITEAOF").unwrap() {
+            Command::BgRewriteAof => {}
+            _ => panic!("Expected BGREWRITEAOF command"),
+        }
+    }
+
+    #[test]
+    fn test_parse_ping_and_echo() {
+        match Command::parse(b"PING").unwrap() {
+            Command::Ping { message } => assert!(message.is_none()),
+            _ => panic!("Expected PING command"),
+        }
+
+        match Command::parse(b"ECHO hello").unwrap() {
+            Command::Echo { message } => assert_eq!(message, b"hello"),
+            _ => panic!("Expected ECHO command"),
+        }
+    }
+
+    #[test]
+    fn test_from_args() {
+        let args = vec![b"SET".to_vec(), b"my key".to_vec(), b"my value".to_vec()];
+        let result = Command::from_args(&args).unwrap();
+
+        match result {
+            Command::Set {
+                key,
+                value,
+                options,
+            } => {
+                assert_eq!(key, b"my key");
+                assert_eq!(value, b"my value");
+                assert!(options.expiry.is_none());
+            }
+            _ => panic!("Expected SET command"),
+        }
+    }
+
+    #[test]
+    fn test_from_args_binary_safe() {
+        let value = vec![0u8, 159, 146, 150, b' ', b'\r', b'\n'];
+        let args = vec![b"SET".to_vec(), vec![0xff, 0xfe], value.clone()];
+
+        match Command::from_args(&args).unwrap() {
+            Command::Set {
+                key, value: parsed, ..
+            } => {
+                assert_eq!(key, vec![0xff, 0xfe]);
+                assert_eq!(parsed, value);
+            }
+            _ => panic!("Expected SET command"),
+        }
+    }
+
+    #[test]
+    fn test_parse_error_display() {
+        assert_eq!(
+            ParseError::UnknownCommand.to_string(),
+            "ERR unknown command"
+        );
+        assert_eq!(
+            ParseError::InvalidArgument("bad".to_string()).to_string(),
+            "ERR bad"
+        );
+    }
+
     #[test]
     fn test_parse_unknown_command() {
         let input = b"UNKNOWN command";
@@ -510,7 +3769,7 @@ mod tests {
 
         assert!(result.is_err());
         match result.unwrap_err() {
-            ParseError::UnknownCommand => {},
+            ParseError::UnknownCommand => {}
             _ => panic!("Expected UnknownCommand error"),
         }
     }
@@ -522,7 +3781,7 @@ mod tests {
 
         assert!(result.is_err());
         match result.unwrap_err() {
-            ParseError::UnknownCommand => {},
+            ParseError::UnknownCommand => {}
             _ => panic!("Expected UnknownCommand error"),
         }
     }
@@ -534,7 +3793,7 @@ mod tests {
 
         assert!(result.is_err());
         match result.unwrap_err() {
-            ParseError::UnknownCommand => {},
+            ParseError::UnknownCommand => {}
             _ => panic!("Expected UnknownCommand error"),
         }
     }
@@ -545,10 +3804,14 @@ mod tests {
         let result = Command::parse(input).unwrap();
 
         match result {
-            Command::Set { key, value, expiry } => {
-                assert_eq!(key, "mykey");
-                assert_eq!(value, "myvalue");
-                assert!(expiry.is_none());
+            Command::Set {
+                key,
+                value,
+                options,
+            } => {
+                assert_eq!(key, b"mykey");
+                assert_eq!(value, b"myvalue");
+                assert!(options.expiry.is_none());
             }
             _ => panic!("Expected SET command"),
         }
@@ -571,15 +3834,19 @@ mod tests {
     #[test]
     fn test_command_debug_trait() {
         let cmd = Command::Set {
-            key: "testkey".to_string(),
-            value: "testvalue".to_string(),
-            expiry: Some(Duration::from_secs(30)),
+            key: b"testkey".to_vec(),
+            value: b"testvalue".to_vec(),
+            options: SetOptions {
+                expiry: Some(Expiry::In(30_000)),
+                ..Default::default()
+            },
         };
 
         let debug_str = format!("{:?}", cmd);
         assert!(debug_str.contains("Set"));
-        assert!(debug_str.contains("testkey"));
-        assert!(debug_str.contains("testvalue"));
+        // Keys and values are raw bytes, so they show up as byte lists.
+        assert!(debug_str.contains(&format!("{:?}", b"testkey")));
+        assert!(debug_str.contains(&format!("{:?}", b"testvalue")));
     }
 
     #[test]
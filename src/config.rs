@@ -0,0 +1,278 @@
+use crate::aof::FsyncPolicy;
+use crate::notify::NotifyConfig;
+use crate::pubsub::BufferLimit;
+
+/// Server settings, given on the command line the way `redis-server` takes
+/// them, e.g. `iron_cache --appendonly yes --appendfsync always`.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub port: u16,
+    pub dbfilename: String,
+    /// Snapshot once at least `changes` writes have been made and `seconds`
+    /// have passed since the last save, for any `(seconds, changes)` rule.
+    /// Empty disables periodic snapshots.
+    pub save: Vec<(u64, u64)>,
+    /// Start from the contents of this Redis RDB file instead of the
+    /// snapshot and append-only file, which are overwritten with it.
+    pub import_rdb: Option<String>,
+    pub appendonly: bool,
+    pub appendfilename: String,
+    pub appendfsync: FsyncPolicy,
+    /// Start the append-only file with a copy of the snapshot every time one
+    /// is written, followed by the writes made since, instead of rewriting it
+    /// from commands.
+    pub aof_use_snapshot: bool,
+    /// Rewrite the append-only file once it has grown by this many percent
+    /// since the last rewrite. 0 disables automatic rewrites.
+    pub auto_aof_rewrite_percentage: u64,
+    /// Never rewrite automatically while the file is smaller than this.
+    pub auto_aof_rewrite_min_size: u64,
+    /// How far behind on its messages a pub/sub subscriber may fall before
+    /// it is disconnected.
+    pub pubsub_buffer_limit: BufferLimit,
+    /// Which keyspace notifications to publish, none by default.
+    pub notify_keyspace_events: NotifyConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            port: 6969,
+            dbfilename: "dump.db".to_string(),
+            save: vec![(900, 1), (300, 10), (60, 10000)],
+            import_rdb: None,
+            appendonly: false,
+            appendfilename: "appendonly.aof".to_string(),
+            appendfsync: FsyncPolicy::EverySec,
+            aof_use_snapshot: false,
+            auto_aof_rewrite_percentage: 100,
+            auto_aof_rewrite_min_size: 64 * 1024 * 1024,
+            pubsub_buffer_limit: BufferLimit::default(),
+            notify_keyspace_events: NotifyConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Builds a config from `--name value` pairs, starting from the defaults.
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let name = arg
+                .strip_prefix("--")
+                .ok_or_else(|| format!("Unexpected argument '{}'", arg))?;
+            let value = args
+                .next()
+                .ok_or_else(|| format!("Missing value for '--{}'", name))?;
+
+            config.set(name, &value)?;
+        }
+
+        Ok(config)
+    }
+
+    fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "port" => {
+                self.port = value
+                    .parse()
+                    .map_err(|_| format!("Invalid port '{}'", value))?
+            }
+            "dbfilename" => self.dbfilename = value.to_string(),
+            "save" => self.save = parse_save_rules(value)?,
+            "import-rdb" => self.import_rdb = Some(value.to_string()),
+            "appendonly" => self.appendonly = parse_bool(name, value)?,
+            "appendfilename" => self.appendfilename = value.to_string(),
+            "appendfsync" => self.appendfsync = value.parse()?,
+            "aof-use-snapshot" => self.aof_use_snapshot = parse_bool(name, value)?,
+            "auto-aof-rewrite-percentage" => {
+                self.auto_aof_rewrite_percentage = value
+                    .parse()
+                    .map_err(|_| format!("Invalid percentage '{}'", value))?
+            }
+            "auto-aof-rewrite-min-size" => self.auto_aof_rewrite_min_size = parse_memory(value)?,
+            "client-output-buffer-limit" => self.pubsub_buffer_limit = parse_buffer_limit(value)?,
+            "notify-keyspace-events" => self.notify_keyspace_events = value.parse()?,
+            _ => return Err(format!("Unknown option '--{}'", name)),
+        }
+        Ok(())
+    }
+}
+
+fn parse_bool(name: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(format!("'--{}' must be 'yes' or 'no'", name)),
+    }
+}
+
+/// Parses `seconds changes` pairs, e.g. `"900 1 300 10"`. An empty string
+/// means no rules.
+fn parse_save_rules(value: &str) -> Result<Vec<(u64, u64)>, String> {
+    let numbers = value
+        .split_whitespace()
+        .map(|n| n.parse::<u64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| format!("Invalid save rules '{}'", value))?;
+
+    if numbers.len() % 2 != 0 {
+        return Err(format!(
+            "Invalid save rules '{}', expected '<seconds> <changes>' pairs",
+            value
+        ));
+    }
+    Ok(numbers.chunks(2).map(|pair| (pair[0], pair[1])).collect())
+}
+
+/// Parses a byte count with an optional `kb`, `mb` or `gb` suffix.
+fn parse_memory(value: &str) -> Result<u64, String> {
+    let lower = value.to_ascii_lowercase();
+    let (digits, unit) = [
+        ("kb", 1024),
+        ("mb", 1024 * 1024),
+        ("gb", 1024 * 1024 * 1024),
+    ]
+    .into_iter()
+    .find_map(|(suffix, unit)| lower.strip_suffix(suffix).map(|digits| (digits, unit)))
+    .unwrap_or((lower.as_str(), 1));
+
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .ok_or_else(|| format!("Invalid memory size '{}'", value))
+}
+
+/// Parses `pubsub <hard limit> <soft limit> <soft seconds>`. Only pub/sub
+/// clients have their output limited, so the other classes are rejected.
+fn parse_buffer_limit(value: &str) -> Result<BufferLimit, String> {
+    match value.split_whitespace().collect::<Vec<_>>().as_slice() {
+        [class, hard, soft, soft_seconds] if class.eq_ignore_ascii_case("pubsub") => {
+            Ok(BufferLimit {
+                hard: parse_memory(hard)?,
+                soft: parse_memory(soft)?,
+                soft_seconds: soft_seconds
+                    .parse()
+                    .map_err(|_| format!("Invalid number of seconds '{}'", soft_seconds))?,
+            })
+        }
+        [_, _, _, _] => Err(format!(
+            "Invalid client class in '{}', only 'pubsub' is supported",
+            value
+        )),
+        _ => Err(format!(
+            "Invalid client output buffer limit '{}', expected \
+             'pubsub <hard limit> <soft limit> <soft seconds>'",
+            value
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = Config::from_args(Vec::new()).unwrap();
+
+        assert_eq!(config.port, 6969);
+        assert_eq!(config.dbfilename, "dump.db");
+        assert!(!config.appendonly);
+        assert_eq!(config.appendfsync, FsyncPolicy::EverySec);
+    }
+
+    #[test]
+    fn test_append_only_options() {
+        let config = Config::from_args(args(&[
+            "--appendonly",
+            "yes",
+            "--appendfsync",
+            "always",
+            "--appendfilename",
+            "log.aof",
+        ]))
+        .unwrap();
+
+        assert!(config.appendonly);
+        assert_eq!(config.appendfsync, FsyncPolicy::Always);
+        assert_eq!(config.appendfilename, "log.aof");
+    }
+
+    #[test]
+    fn test_auto_rewrite_options() {
+        let config = Config::from_args(args(&[
+            "--auto-aof-rewrite-percentage",
+            "50",
+            "--auto-aof-rewrite-min-size",
+            "16mb",
+        ]))
+        .unwrap();
+
+        assert_eq!(config.auto_aof_rewrite_percentage, 50);
+        assert_eq!(config.auto_aof_rewrite_min_size, 16 * 1024 * 1024);
+    }
+
+    #[test]
+    fn test_save_rules() {
+        let config = Config::from_args(Vec::new()).unwrap();
+        assert_eq!(config.save, vec![(900, 1), (300, 10), (60, 10000)]);
+
+        let config = Config::from_args(args(&["--save", "3600 1 60 500"])).unwrap();
+        assert_eq!(config.save, vec![(3600, 1), (60, 500)]);
+
+        let config = Config::from_args(args(&["--save", ""])).unwrap();
+        assert!(config.save.is_empty());
+
+        assert!(Config::from_args(args(&["--save", "60"])).is_err());
+        assert!(Config::from_args(args(&["--save", "60 x"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_memory() {
+        assert_eq!(parse_memory("100").unwrap(), 100);
+        assert_eq!(parse_memory("2kb").unwrap(), 2048);
+        assert_eq!(parse_memory("1GB").unwrap(), 1024 * 1024 * 1024);
+        assert!(parse_memory("mb").is_err());
+        assert!(parse_memory("12tb").is_err());
+    }
+
+    #[test]
+    fn test_buffer_limit() {
+        let config = Config::from_args(Vec::new()).unwrap();
+        assert_eq!(config.pubsub_buffer_limit, BufferLimit::default());
+
+        let config =
+            Config::from_args(args(&["--client-output-buffer-limit", "pubsub 1mb 0 0"])).unwrap();
+        assert_eq!(
+            config.pubsub_buffer_limit,
+            BufferLimit {
+                hard: 1024 * 1024,
+                soft: 0,
+                soft_seconds: 0
+            }
+        );
+
+        assert!(
+            Config::from_args(args(&["--client-output-buffer-limit", "normal 0 0 0"])).is_err()
+        );
+        assert!(Config::from_args(args(&["--client-output-buffer-limit", "pubsub 1mb"])).is_err());
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        assert!(Config::from_args(args(&["--appendonly", "maybe"])).is_err());
+        assert!(Config::from_args(args(&["--appendfsync", "sometimes"])).is_err());
+        assert!(Config::from_args(args(&["--notify-keyspace-events", "KX"])).is_err());
+        assert!(Config::from_args(args(&["--port"])).is_err());
+        assert!(Config::from_args(args(&["--bogus", "1"])).is_err());
+        assert!(Config::from_args(args(&["port", "1"])).is_err());
+    }
+}
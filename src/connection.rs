@@ -0,0 +1,365 @@
+use crate::resp::{self, Frame, ProtocolError};
+use std::convert::Infallible;
+use std::fmt;
+use std::future;
+use std::io;
+use std::pin::pin;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The arguments of a single client request, command name first.
+pub type Request = Vec<Vec<u8>>;
+
+// How much spare capacity to make room for before each socket read.
+const READ_CHUNK: usize = 16 * 1024;
+// How many bytes of requests may wait to be handled, like Redis' default
+// `client-query-buffer-limit`.
+const MAX_QUERY_BUFFER: usize = 1024 * 1024 * 1024;
+
+/// What `read_request_or` returned.
+#[derive(Debug, PartialEq)]
+pub enum Incoming<T> {
+    Request(Request),
+    /// The client closed the connection.
+    Closed,
+    /// What the other future returned.
+    Other(T),
+}
+
+#[derive(Debug)]
+pub enum ConnectionError {
+    Io(io::Error),
+    Protocol(ProtocolError),
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionError::Io(e) => write!(f, "{}", e),
+            ConnectionError::Protocol(e) => write!(f, "Protocol error: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for ConnectionError {
+    fn from(e: io::Error) -> Self {
+        ConnectionError::Io(e)
+    }
+}
+
+/// A buffered, framed client connection.
+///
+/// Incoming bytes are accumulated until they form complete requests, so a
+/// single read may yield many pipelined commands and a command may span many
+/// reads. Replies are queued and only written out once every request already
+/// buffered has been handled, which batches the responses of a pipeline into
+/// as few writes as possible.
+pub struct Connection<S> {
+    stream: S,
+    input: Vec<u8>,
+    // Offset of the first unparsed byte in `input`.
+    pos: usize,
+    // While the request at `pos` is incomplete, how far into `input` it has
+    // been looked at. It can only be completed by a `\n` past that.
+    scanned: usize,
+    // How far the request at `pos` has been parsed, while it is incomplete.
+    parser: resp::RequestParser,
+    max_query: usize,
+    output: Vec<u8>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    pub fn new(stream: S) -> Self {
+        Connection {
+            stream,
+            input: Vec::with_capacity(READ_CHUNK),
+            pos: 0,
+            scanned: 0,
+            parser: resp::RequestParser::default(),
+            max_query: MAX_QUERY_BUFFER,
+            output: Vec::new(),
+        }
+    }
+
+    /// Returns the next complete request, or `None` once the client has closed
+    /// the connection. Queued replies are flushed before blocking on the socket.
+    pub async fn read_request(&mut self) -> Result<Option<Request>, ConnectionError> {
+        match self
+            .read_request_or(future::pending::<Infallible>())
+            .await?
+        {
+            Incoming::Request(args) => Ok(Some(args)),
+            Incoming::Closed => Ok(None),
+            Incoming::Other(never) => match never {},
+        }
+    }
+
+    /// Like `read_request`, but while waiting on the socket also waits for
+    /// `other`, and returns its output if it is ready first. Requests the
+    /// client has already sent are always handed out before that.
+    pub async fn read_request_or<T>(
+        &mut self,
+        other: impl Future<Output = T>,
+    ) -> Result<Incoming<T>, ConnectionError> {
+        let mut other = pin!(other);
+
+        loop {
+            if self.may_be_complete() {
+                match self.parser.parse(&self.input[self.pos..]) {
+                    Ok(Some((args, consumed))) => {
+                        self.pos += consumed;
+                        self.scanned = self.pos;
+                        if args.is_empty() {
+                            continue; // Blank inline line, nothing to run.
+                        }
+                        return Ok(Incoming::Request(args));
+                    }
+                    Ok(None) => {}
+                    Err(e) => return Err(ConnectionError::Protocol(e)),
+                }
+            }
+            self.scanned = self.input.len();
+            self.check_query_limit()?;
+
+            self.flush().await?;
+            self.compact();
+
+            self.input.reserve(READ_CHUNK);
+            // Both are cancel safe: a read that loses the race has read
+            // nothing, and `other` is only given up on once it is done.
+            tokio::select! {
+                biased;
+                read = self.stream.read_buf(&mut self.input) => {
+                    if read? == 0 {
+                        return Ok(Incoming::Closed);
+                    }
+                }
+                output = &mut other => return Ok(Incoming::Other(output)),
+            }
+        }
+    }
+
+    /// Waits for the client to close the connection, while it is blocked and
+    /// no request of its is being read. Anything it sends in the meantime is
+    /// kept for `read_request`.
+    pub async fn closed(&mut self) -> io::Result<()> {
+        loop {
+            self.check_query_limit()?;
+            self.input.reserve(READ_CHUNK);
+            if self.stream.read_buf(&mut self.input).await? == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Queues a reply to be sent with the next flush.
+    pub fn write_frame(&mut self, frame: &Frame) {
+        frame.encode(&mut self.output);
+    }
+
+    /// Queues a reply that has already been encoded.
+    pub fn write_encoded(&mut self, bytes: &[u8]) {
+        self.output.extend_from_slice(bytes);
+    }
+
+    /// Writes out every queued reply.
+    pub async fn flush(&mut self) -> io::Result<()> {
+        if !self.output.is_empty() {
+            self.stream.write_all(&self.output).await?;
+            self.stream.flush().await?;
+            self.output.clear();
+        }
+        Ok(())
+    }
+
+    // Whether the buffered input may hold a complete request, and so is
+    // worth parsing. Every request ends with a `\n`, so input found to be
+    // incomplete only is once one arrives, or is too long for an inline
+    // request, which the parser has to see to reject.
+    fn may_be_complete(&self) -> bool {
+        self.scanned == self.pos
+            || self.input[self.scanned..].contains(&b'\n')
+            || self.input.len() - self.pos > resp::MAX_INLINE_LEN
+    }
+
+    // Fails once more than `max_query` bytes of requests are waiting to be
+    // handled, rather than buffering whatever the client sends.
+    fn check_query_limit(&self) -> io::Result<()> {
+        if self.input.len() - self.pos > self.max_query {
+            return Err(io::Error::other(
+                "Closed for going over the query buffer limit",
+            ));
+        }
+        Ok(())
+    }
+
+    // Drops the bytes of requests that have already been handed out.
+    fn compact(&mut self) {
+        if self.pos > 0 {
+            self.input.drain(..self.pos);
+            self.scanned -= self.pos;
+            self.pos = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    fn args(parts: &[&str]) -> Request {
+        parts.iter().map(|p| p.as_bytes().to_vec()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_requests_in_one_write() {
+        let (mut client, server) = duplex(4096);
+        let mut conn = Connection::new(server);
+
+        client
+            .write_all(b"*1\r\n$4\r\nPING\r\n*2\r\n$3\r\nGET\r\n$1\r\na\r\nECHO hi\r\n")
+            .await
+            .unwrap();
+        drop(client);
+
+        assert_eq!(conn.read_request().await.unwrap(), Some(args(&["PING"])));
+        assert_eq!(
+            conn.read_request().await.unwrap(),
+            Some(args(&["GET", "a"]))
+        );
+        assert_eq!(
+            conn.read_request().await.unwrap(),
+            Some(args(&["ECHO", "hi"]))
+        );
+        assert!(conn.read_request().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_request_split_across_reads() {
+        let (mut client, server) = duplex(4096);
+        let mut conn = Connection::new(server);
+
+        let reader = tokio::spawn(async move { conn.read_request().await.unwrap() });
+
+        client.write_all(b"*2\r\n$3\r\nGET\r").await.unwrap();
+        tokio::task::yield_now().await;
+        client.write_all(b"\n$5\r\nmy").await.unwrap();
+        tokio::task::yield_now().await;
+        client.write_all(b"key\r\n").await.unwrap();
+
+        assert_eq!(reader.await.unwrap(), Some(args(&["GET", "mykey"])));
+    }
+
+    #[tokio::test]
+    async fn test_large_value() {
+        let (mut client, server) = duplex(1024);
+        let mut conn = Connection::new(server);
+        let value = vec![b'x'; 100_000];
+
+        let mut request = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$100000\r\n".to_vec();
+        request.extend_from_slice(&value);
+        request.extend_from_slice(b"\r\n");
+
+        let writer = tokio::spawn(async move { client.write_all(&request).await });
+
+        let parsed = conn.read_request().await.unwrap().unwrap();
+        assert_eq!(parsed[2], value);
+        writer.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replies_are_batched_until_flush() {
+        let (mut client, server) = duplex(4096);
+        let mut conn = Connection::new(server);
+
+        conn.write_frame(&Frame::ok());
+        conn.write_frame(&Frame::Integer(2));
+        conn.write_encoded(b"$1\r\nx\r\n");
+        conn.flush().await.unwrap();
+        drop(conn);
+
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"+OK\r\n:2\r\n$1\r\nx\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_requests_sent_while_waiting_for_close_are_kept() {
+        let (mut client, server) = duplex(4096);
+        let mut conn = Connection::new(server);
+
+        client.write_all(b"PING\r\n").await.unwrap();
+        drop(client);
+
+        conn.closed().await.unwrap();
+        assert_eq!(conn.read_request().await.unwrap(), Some(args(&["PING"])));
+        assert!(conn.read_request().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_other_future_while_waiting_for_requests() {
+        let (mut client, server) = duplex(4096);
+        let mut conn = Connection::new(server);
+
+        client.write_all(b"PING\r\n").await.unwrap();
+        assert_eq!(
+            conn.read_request_or(async { 1 }).await.unwrap(),
+            Incoming::Request(args(&["PING"]))
+        );
+        assert_eq!(
+            conn.read_request_or(async { 1 }).await.unwrap(),
+            Incoming::Other(1)
+        );
+
+        drop(client);
+        assert_eq!(
+            conn.read_request_or(future::pending::<()>()).await.unwrap(),
+            Incoming::Closed
+        );
+    }
+
+    #[tokio::test]
+    async fn test_protocol_error() {
+        let (mut client, server) = duplex(4096);
+        let mut conn = Connection::new(server);
+
+        client.write_all(b"*1\r\n+PING\r\n").await.unwrap();
+
+        assert!(matches!(
+            conn.read_request().await,
+            Err(ConnectionError::Protocol(ProtocolError::ExpectedBulk(b'+')))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_input_limits() {
+        let (mut client, server) = duplex(4096);
+        let mut conn = Connection::new(server);
+
+        // A line that never ends is cut off.
+        let line = vec![b'a'; resp::MAX_INLINE_LEN + 1];
+        let writer = tokio::spawn(async move { client.write_all(&line).await });
+        assert!(matches!(
+            conn.read_request().await,
+            Err(ConnectionError::Protocol(ProtocolError::TooBigInline))
+        ));
+        writer.abort();
+
+        let (mut client, server) = duplex(4096);
+        let mut conn = Connection::new(server);
+        conn.max_query = 16;
+        client.write_all(b"*1\r\n$100\r\n").await.unwrap();
+        client.write_all(&[b'x'; 20]).await.unwrap();
+        assert!(matches!(
+            conn.read_request().await,
+            Err(ConnectionError::Io(_))
+        ));
+
+        // The same goes for what a blocked client sends.
+        let (mut client, server) = duplex(4096);
+        let mut conn = Connection::new(server);
+        conn.max_query = 16;
+        client.write_all(&[b'x'; 20]).await.unwrap();
+        assert!(conn.closed().await.is_err());
+    }
+}
@@ -0,0 +1,103 @@
+//! CRC-64/Jones, the checksum Redis uses for RDB files and that we use for
+//! snapshots.
+
+use std::io::{self, Write};
+
+// Reflected form of the Jones polynomial 0xad93d23594c935a9.
+const POLY: u64 = 0x95ac_9329_ac4b_c9b5;
+
+const TABLE: [u64; 256] = build_table();
+
+const fn build_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Continues a checksum over `data`. Start from 0.
+pub fn update(mut crc: u64, data: &[u8]) -> u64 {
+    for &byte in data {
+        crc = TABLE[((crc ^ byte as u64) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+pub fn checksum(data: &[u8]) -> u64 {
+    update(0, data)
+}
+
+/// Computes the checksum of everything written through it.
+pub struct Writer<W> {
+    inner: W,
+    crc: u64,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(inner: W) -> Self {
+        Writer { inner, crc: 0 }
+    }
+
+    pub fn crc(&self) -> u64 {
+        self.crc
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.crc = update(self.crc, &buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_value() {
+        // The check value Redis' own crc64 test uses.
+        assert_eq!(checksum(b"123456789"), 0xe9c6_d914_c4b8_d9ca);
+        assert_eq!(checksum(b""), 0);
+    }
+
+    #[test]
+    fn test_incremental_update() {
+        let data = b"This is a test of the emergency broadcast system.";
+        let (head, tail) = data.split_at(20);
+
+        assert_eq!(update(update(0, head), tail), checksum(data));
+    }
+
+    #[test]
+    fn test_writer() {
+        let mut writer = Writer::new(Vec::new());
+        writer.write_all(b"1234").unwrap();
+        writer.write_all(b"56789").unwrap();
+
+        assert_eq!(writer.crc(), 0xe9c6_d914_c4b8_d9ca);
+        assert_eq!(writer.into_inner(), b"123456789");
+    }
+}
@@ -1,2 +1,15 @@
+pub mod aof;
+pub mod blocking;
 pub mod commands;
+pub mod config;
+pub mod connection;
+pub mod crc64;
+pub mod notify;
+pub mod pubsub;
+pub mod rdb;
+pub mod resp;
+pub mod snapshot;
+pub mod sorted_set;
 pub mod storage;
+pub mod stream;
+pub mod transaction;
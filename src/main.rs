@@ -1,194 +1,2562 @@
-use iron_cache::commands::Command;
-use iron_cache::storage::{Storage, Value};
-use std::fs::File;
-use std::io::BufReader;
+use iron_cache::aof::{self, AppendOnlyFile};
+use iron_cache::blocking::{BlockedClients, BlockingOp};
+use iron_cache::commands::{Command, ExpiryChange, PendingRange};
+use iron_cache::config::Config;
+use iron_cache::connection::{Connection, ConnectionError, Incoming, Request};
+use iron_cache::pubsub::{Message, PubSub, Subscriber};
+use iron_cache::rdb;
+use iron_cache::resp::Frame;
+use iron_cache::snapshot::{self, SnapshotError};
+use iron_cache::storage::{
+    ExpireCondition, ListEnd, SetOptions, Snapshot as StorageSnapshot, Storage, StreamsRead, Value,
+    ZAddOptions, now_millis,
+};
+use iron_cache::stream::{ConsumerGroup, Fields, ReadFrom, StreamId};
+use iron_cache::transaction::Transaction;
+use std::path::Path;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, oneshot};
+use tokio::task::JoinHandle;
 
 // Type alias for our shared database type for cleaner code
 type Db = Arc<Mutex<Storage>>;
-const DB_PATH: &str = "dump.db";
-const SAVE_INTERVAL_SECS: u64 = 10;
+// The append-only file is only ever locked briefly and never across an await.
+type Aof = Arc<std::sync::Mutex<AppendOnlyFile>>;
+// Active expiry runs this often, and may hold the lock for at most this long
+// each time. The same as Redis' defaults.
+const ACTIVE_EXPIRE_INTERVAL: Duration = Duration::from_millis(100);
+const ACTIVE_EXPIRE_BUDGET: Duration = Duration::from_millis(25);
+// How long to wait before retrying a periodic save that failed.
+const SAVE_RETRY_DELAY_SECS: u64 = 5;
+const WRONGTYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+
+/// State shared by every connection and background task.
+#[derive(Clone)]
+struct Server {
+    db: Db,
+    aof: Option<Aof>,
+    config: Arc<Config>,
+    saves: Arc<std::sync::Mutex<SaveStatus>>,
+    // Only changed with `db` locked, see `iron_cache::blocking`.
+    blocked: Arc<std::sync::Mutex<BlockedClients>>,
+    pubsub: Arc<std::sync::Mutex<PubSub>>,
+}
+
+impl Server {
+    fn new(storage: Storage, aof: Option<Aof>, config: Config) -> Self {
+        let pubsub = PubSub::new(config.pubsub_buffer_limit);
+        Server {
+            db: Arc::new(Mutex::new(storage)),
+            aof,
+            config: Arc::new(config),
+            saves: Arc::new(std::sync::Mutex::new(SaveStatus {
+                in_progress: false,
+                last_save: now_millis() / 1000,
+                last_attempt: 0,
+                last_ok: true,
+            })),
+            blocked: Arc::new(std::sync::Mutex::new(BlockedClients::new())),
+            pubsub: Arc::new(std::sync::Mutex::new(pubsub)),
+        }
+    }
+}
+
+/// How snapshot saves are going, as reported by LASTSAVE and INFO.
+struct SaveStatus {
+    in_progress: bool,
+    // Unix time of the last successful save, in seconds. Starts out as the
+    // time the server started, like in Redis.
+    last_save: u64,
+    // Unix time the last save was started, whether or not it succeeded.
+    last_attempt: u64,
+    last_ok: bool,
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let listener = TcpListener::bind("127.0.0.1:6969").await?;
-    println!("Server is running on port 6969");
-
-    let storage = match File::open(DB_PATH) {
-        Ok(file) => {
-            // Load existing data from the file
-            let reader = BufReader::new(file);
-            match bincode::deserialize_from(reader) {
-                Ok(decoded) => {
-                    println!("Loaded database from {}", DB_PATH);
-                    decoded
-                }
-                Err(e) => {
-                    eprintln!("Failed to deserialize storage: {}", e);
-                    Storage::new() // Fallback to a new storage instance
-                }
-            }
-        }
-        Err(_) => {
-            // If the file doesn't exist, create a new storage instance
-            Storage::new()
-        }
-    };
+    let config = Config::from_args(std::env::args().skip(1))?;
 
-    let db = Arc::new(Mutex::new(storage));
+    let (storage, aof) = load_data(&config)?;
+    let server = Server::new(storage, aof, config);
 
-    let db_for_saving = db.clone();
-    tokio::spawn(async move {
-        loop {
-            // Wait for the 10 seconds before saving the snapshot
-            tokio::time::sleep(Duration::from_secs(SAVE_INTERVAL_SECS)).await;
+    let listener = TcpListener::bind(("127.0.0.1", server.config.port)).await?;
+    println!("Server is running on port {}", server.config.port);
 
-            // Call the save function
-            save_snapshot(&db_for_saving).await;
-        }
-    });
+    tokio::spawn(save_cron(server.clone()));
+    tokio::spawn(expire_cron(server.clone()));
+
+    if let Some(aof) = server.aof.clone() {
+        tokio::spawn(aof_cron(server.clone(), aof));
+    }
 
     loop {
         let (socket, addr) = listener.accept().await?;
         println!("New connection from {}", addr);
 
-        let db_clone = db.clone();
+        let server = server.clone();
 
         tokio::spawn(async move {
-            if let Err(e) = process_connection(socket, db_clone).await {
+            if let Err(e) = process_connection(socket, server).await {
                 eprintln!("Error processing connection from {}: {}", addr, e);
             }
         });
     }
 }
 
+/// Loads the data to start with, and opens the append-only file if it is
+/// enabled.
+fn load_data(config: &Config) -> Result<(Storage, Option<Aof>), Box<dyn std::error::Error>> {
+    // With the append-only file enabled it is the source of truth.
+    let mut storage = match &config.import_rdb {
+        Some(path) => import_rdb(path)?,
+        None if config.appendonly => replay_aof(config)?,
+        None => load_snapshot(&config.dbfilename)?,
+    };
+
+    let mut aof = None;
+    if config.appendonly {
+        aof = Some(AppendOnlyFile::open(
+            &config.appendfilename,
+            config.appendfsync,
+        )?);
+    }
+    if config.import_rdb.is_some() {
+        persist_import(&mut storage, config, aof.as_mut())?;
+    }
+    // Only from here on: loading isn't a change anyone needs to hear about.
+    storage.set_notify(config.notify_keyspace_events);
+
+    let aof = aof.map(|file| Arc::new(std::sync::Mutex::new(file)));
+    Ok((storage, aof))
+}
+
+/// Loads the snapshot at `path`, or starts empty if there is none. A snapshot
+/// that can't be read is an error: starting empty would silently drop the data
+/// and overwrite the file with the next save.
+fn load_snapshot(path: &str) -> Result<Storage, Box<dyn std::error::Error>> {
+    match snapshot::load(Path::new(path)) {
+        Ok(Some(storage)) => {
+            println!("Loaded database from {}", path);
+            Ok(storage)
+        }
+        Ok(None) => Ok(Storage::new()),
+        Err(e) => Err(format!(
+            "Failed to load snapshot {}: {}. Refusing to start, move the file away to start empty",
+            path, e
+        )
+        .into()),
+    }
+}
+
+/// Loads a Redis RDB file to start from.
+fn import_rdb(path: &str) -> Result<Storage, Box<dyn std::error::Error>> {
+    let storage = rdb::load(Path::new(path))
+        .map_err(|e| format!("Failed to import RDB file {}: {}", path, e))?;
+    println!("Imported {} keys from {}", storage.entries().count(), path);
+    Ok(storage)
+}
+
+/// Replaces the snapshot and append-only file with freshly imported data, so
+/// it is kept across restarts without importing it again.
+fn persist_import(
+    storage: &mut Storage,
+    config: &Config,
+    aof: Option<&mut AppendOnlyFile>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let snapshot = storage.snapshot();
+    snapshot::save(&snapshot, Path::new(&config.dbfilename))?;
+    storage.mark_saved(storage.dirty());
+
+    if let Some(aof) = aof {
+        if config.aof_use_snapshot {
+            aof::write_snapshot_rewrite(Path::new(&config.dbfilename), &aof.rewrite_path())?;
+            aof.finish_rewrite()?;
+        } else {
+            aof::write_rewrite(&snapshot, &aof.rewrite_path())?;
+            aof.finish_rewrite()?;
+        }
+    }
+    Ok(())
+}
+
+/// Loads the data in the append-only file: the snapshot it starts with, if
+/// any, and every command in it on top.
+fn replay_aof(config: &Config) -> Result<Storage, Box<dyn std::error::Error>> {
+    let path = &config.appendfilename;
+    let log = aof::load(Path::new(path))?;
+    if log.truncated > 0 {
+        eprintln!(
+            "Append only file {} ended with an incomplete command, truncated {} bytes",
+            path, log.truncated
+        );
+    }
+
+    let mut storage = match log.base {
+        Some(base) => base,
+        // Until a snapshot is first written into it, the log holds the writes
+        // made on top of `dbfilename`.
+        None if config.aof_use_snapshot => load_snapshot(&config.dbfilename)?,
+        None => Storage::new(),
+    };
+
+    // Expiry is held off until the end, since the log has a DEL for every key
+    // that expired while it was being written.
+    storage.set_loading(true);
+    let count = log.commands.len();
+    for args in log.commands {
+        let command = Command::from_args(&args)
+            .map_err(|e| format!("Invalid command in append only file {}: {}", path, e))?;
+        if let Frame::Error(e) = apply_command(command, &mut storage) {
+            return Err(format!("Failed to replay append only file {}: {}", path, e).into());
+        }
+    }
+    storage.set_loading(false);
+
+    if count > 0 {
+        println!("Replayed {} commands from {}", count, path);
+    }
+    Ok(storage)
+}
+
+/// Removes expired keys in the background, so keys that are never read again
+/// don't stay in memory and in snapshots forever.
+async fn expire_cron(server: Server) {
+    loop {
+        tokio::time::sleep(ACTIVE_EXPIRE_INTERVAL).await;
+        let mut db_lock = server.db.lock().await;
+        db_lock.remove_expired(ACTIVE_EXPIRE_BUDGET);
+        if let Err(e) = append_to_aof(&server, &mut db_lock, &[]) {
+            eprintln!("Error writing to append only file: {}", e);
+        }
+        publish_key_events(&server, &mut db_lock);
+    }
+}
+
+/// Runs once a second, and starts a snapshot whenever one of the configured
+/// save rules is met.
+async fn save_cron(server: Server) {
+    loop {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let changes = server.db.lock().await.dirty();
+        let now = now_millis() / 1000;
+        let due = {
+            let status = server.saves.lock().unwrap();
+            // After a failure, give the disk a moment before trying again.
+            let may_retry =
+                status.last_ok || now.saturating_sub(status.last_attempt) >= SAVE_RETRY_DELAY_SECS;
+            !status.in_progress
+                && may_retry
+                && server.config.save.iter().any(|&(seconds, min_changes)| {
+                    changes >= min_changes && now.saturating_sub(status.last_save) >= seconds
+                })
+        };
+
+        if due {
+            println!("Changes since the last save reached a save rule, saving...");
+            // Errors are logged when the save finishes.
+            let _ = start_bgsave(&server).await;
+        }
+    }
+}
+
+/// Runs once a second while the append-only file is enabled: fsyncs it for
+/// `appendfsync everysec`, and starts a rewrite once it has grown enough.
+async fn aof_cron(server: Server, aof: Aof) {
+    let config = &server.config;
+
+    loop {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let (pending, needs_rewrite) = {
+            let mut aof_lock = aof.lock().unwrap();
+            let needs_rewrite = aof_lock.needs_rewrite(
+                config.auto_aof_rewrite_percentage,
+                config.auto_aof_rewrite_min_size,
+            );
+            (aof_lock.take_pending_sync(), needs_rewrite)
+        };
+
+        let result = match pending {
+            Ok(Some(file)) => tokio::task::spawn_blocking(move || file.sync_data())
+                .await
+                .unwrap_or_else(|e| Err(std::io::Error::other(e))),
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            eprintln!("Error syncing append only file: {}", e);
+        }
+
+        if needs_rewrite {
+            println!(
+                "Starting automatic rewriting of AOF on {}% growth",
+                config.auto_aof_rewrite_percentage
+            );
+            start_aof_rewrite(&server).await;
+        }
+    }
+}
+
+/// Compacts the append-only file in the background.
+///
+/// The dataset is snapshotted and writes start being buffered at the same
+/// instant. The snapshot is then written out as the minimal set of commands,
+/// the buffered writes are appended, and the result replaces the old file.
+async fn start_aof_rewrite(server: &Server) -> Frame {
+    let db_lock = server.db.lock().await;
+    start_aof_rewrite_locked(server, &db_lock)
+}
+
+/// `start_aof_rewrite` with the storage already locked.
+fn start_aof_rewrite_locked(server: &Server, db_lock: &Storage) -> Frame {
+    let Some(aof) = &server.aof else {
+        return Frame::error("ERR Append only file is disabled");
+    };
+
+    if server.config.aof_use_snapshot {
+        // The log only holds what came after the last snapshot, so taking a
+        // new snapshot is what compacts it.
+        return match start_bgsave_locked(server, db_lock) {
+            Ok(_) => Frame::Simple("Background append only file rewriting started".to_string()),
+            Err(response) => response,
+        };
+    }
+
+    if !aof.lock().unwrap().start_rewrite() {
+        return Frame::error("ERR Background append only file rewriting already in progress");
+    }
+    let snapshot = db_lock.snapshot();
+
+    let aof = aof.clone();
+    tokio::task::spawn_blocking(move || {
+        let tmp_path = aof.lock().unwrap().rewrite_path();
+        let result = aof::write_rewrite(&snapshot, &tmp_path)
+            .and_then(|()| aof.lock().unwrap().finish_rewrite());
+
+        match result {
+            Ok(()) => println!("Background AOF rewrite finished successfully"),
+            Err(e) => {
+                eprintln!("Background AOF rewrite failed: {}", e);
+                aof.lock().unwrap().abort_rewrite();
+            }
+        }
+    });
+
+    Frame::Simple("Background append only file rewriting started".to_string())
+}
+
 /// Handles the entire lifecycle of a single client connection.
-async fn process_connection(mut socket: TcpStream, db: Db) -> std::io::Result<()> {
-    let mut buffer = [0; 1024];
+async fn process_connection(socket: TcpStream, server: Server) -> std::io::Result<()> {
+    let mut connection = Connection::new(socket);
+    // Set once the client first subscribes to something.
+    let mut subscriber: Option<Subscriber> = None;
+    // Set between MULTI and EXEC or DISCARD.
+    let mut transaction: Option<Transaction> = None;
 
     loop {
-        match socket.read(&mut buffer).await {
-            Ok(0) => return Ok(()), // Connection closed gracefully
-            Ok(n) => {
-                let response = match Command::parse(&buffer[..n]) {
-                    Ok(command) => execute_command(command, &db).await,
-                    Err(e) => format!("(error) {:?}\n", e),
+        let args = match connection
+            .read_request_or(next_message(&mut subscriber))
+            .await
+        {
+            Ok(Incoming::Request(args)) => args,
+            Ok(Incoming::Other(Some(message))) => {
+                connection.write_encoded(&message);
+                continue;
+            }
+            Ok(Incoming::Other(None)) => {
+                return Err(std::io::Error::other(
+                    "Closed for going over the pub/sub output buffer limit",
+                ));
+            }
+            Ok(Incoming::Closed) => return Ok(()), // Connection closed gracefully
+            Err(ConnectionError::Io(e)) => return Err(e),
+            Err(e @ ConnectionError::Protocol(_)) => {
+                // Like Redis, reply with the error and drop the client.
+                connection.write_frame(&Frame::Error(format!("ERR {}", e)));
+                return connection.flush().await;
+            }
+        };
+
+        let command = Command::from_args(&args);
+        // A subscribed client can only change its subscriptions, until it is
+        // no longer subscribed to anything.
+        let subscribed = subscriber.as_ref().is_some_and(Subscriber::is_subscribed);
+        if subscribed
+            && let Ok(command) = &command
+            && !matches!(
+                command,
+                Command::Subscribe { .. } | Command::Unsubscribe { .. } | Command::Ping { .. }
+            )
+        {
+            connection.write_frame(&Frame::Error(format!(
+                "ERR Can't execute '{}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING are allowed in this context",
+                String::from_utf8_lossy(&args[0]).to_lowercase()
+            )));
+            continue;
+        }
+
+        // Inside a transaction, commands are queued until EXEC or DISCARD.
+        if let Some(queued) = &mut transaction
+            && !matches!(command, Ok(Command::Exec | Command::Discard))
+        {
+            connection.write_frame(&queued.queue(command, args));
+            continue;
+        }
+
+        let response = match command {
+            Ok(Command::Multi) => {
+                transaction = Some(Transaction::new());
+                Some(Frame::ok())
+            }
+            Ok(Command::Exec) => Some(match transaction.take() {
+                Some(transaction) => exec(transaction, &server).await,
+                None => Frame::error("ERR EXEC without MULTI"),
+            }),
+            Ok(Command::Discard) => Some(match transaction.take() {
+                Some(_) => Frame::ok(),
+                None => Frame::error("ERR DISCARD without MULTI"),
+            }),
+            Ok(Command::Subscribe { channels, patterns }) => {
+                let subscriber =
+                    subscriber.get_or_insert_with(|| Subscriber::new(server.pubsub.clone()));
+                for reply in subscriber.subscribe(channels, patterns) {
+                    connection.write_frame(&reply);
+                }
+                continue;
+            }
+            Ok(Command::Unsubscribe { channels, patterns }) => {
+                let subscriber =
+                    subscriber.get_or_insert_with(|| Subscriber::new(server.pubsub.clone()));
+                for reply in subscriber.unsubscribe(channels, patterns) {
+                    connection.write_frame(&reply);
+                }
+                continue;
+            }
+            Ok(Command::Ping { message }) if subscribed => Some(Frame::Array(vec![
+                Frame::bulk("pong"),
+                Frame::bulk(message.unwrap_or_default()),
+            ])),
+            Ok(Command::BPop { keys, end, timeout }) => {
+                let op = BlockingOp::Pop(end);
+                block_on_lists(keys, op, timeout, &mut connection, &server).await?
+            }
+            Ok(Command::BLMove {
+                source,
+                destination,
+                from,
+                to,
+                timeout,
+            }) => {
+                let op = BlockingOp::Move {
+                    destination,
+                    from,
+                    to,
                 };
+                block_on_lists(vec![source], op, timeout, &mut connection, &server).await?
+            }
+            Ok(Command::XRead {
+                keys,
+                ids,
+                count,
+                block: Some(timeout),
+            }) => {
+                let op = BlockingOp::ReadStreams {
+                    from: ids,
+                    count,
+                    group: None,
+                    no_ack: false,
+                };
+                block_on_streams(keys, op, timeout, &mut connection, &server).await?
+            }
+            Ok(Command::XReadGroup {
+                group,
+                consumer,
+                keys,
+                ids,
+                count,
+                block: Some(timeout),
+                no_ack,
+            }) => {
+                let op = BlockingOp::ReadStreams {
+                    from: ids,
+                    count,
+                    group: Some((group, consumer)),
+                    no_ack,
+                };
+                block_on_streams(keys, op, timeout, &mut connection, &server).await?
+            }
+            Ok(command) => Some(execute_command(command, args, &server).await),
+            Err(e) => Some(Frame::Error(e.to_string())),
+        };
+        let Some(response) = response else {
+            return Ok(()); // Closed while blocked
+        };
 
-                // Write the response back to the client
-                socket.write_all(response.as_bytes()).await?;
+        // Replies are sent once every pipelined request has been handled.
+        connection.write_frame(&response);
+    }
+}
+
+// The next message for a subscribed client to write out. Never ready if it
+// has never subscribed to anything.
+async fn next_message(subscriber: &mut Option<Subscriber>) -> Option<Message> {
+    match subscriber {
+        Some(subscriber) => subscriber.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Executes a parsed command against the database, logging it to the
+/// append-only file if it changed anything.
+async fn execute_command(command: Command, args: Request, server: &Server) -> Frame {
+    if let Some(response) = execute_without_storage(&command, server) {
+        return response;
+    }
+
+    // These do their own locking.
+    match command {
+        Command::Save => return save_snapshot(server).await,
+        Command::BgSave => {
+            return match start_bgsave(server).await {
+                Ok(_) => Frame::Simple("Background saving started".to_string()),
+                Err(response) => response,
+            };
+        }
+        Command::BgRewriteAof => return start_aof_rewrite(server).await,
+        Command::Info { section } => return info(server, section.as_deref()).await,
+        _ => {}
+    }
+
+    // Lock the mutex to get access to the storage
+    let mut db_lock = server.db.lock().await;
+    execute_locked(command, args, server, &mut db_lock)
+}
+
+/// Runs the commands that don't touch the storage, so don't need to lock it:
+/// LASTSAVE and the pub/sub ones. `None` for any other command.
+fn execute_without_storage(command: &Command, server: &Server) -> Option<Frame> {
+    let response = match command {
+        Command::LastSave => Frame::Integer(server.saves.lock().unwrap().last_save as i64),
+        Command::Publish { channel, message } => {
+            let receivers = server.pubsub.lock().unwrap().publish(channel, message);
+            Frame::Integer(receivers as i64)
+        }
+        Command::PubSubChannels { pattern } => {
+            let channels = server.pubsub.lock().unwrap().channels(pattern.as_deref());
+            Frame::Array(channels.into_iter().map(Frame::Bulk).collect())
+        }
+        Command::PubSubNumSub { channels } => {
+            let pubsub = server.pubsub.lock().unwrap();
+            Frame::Array(
+                channels
+                    .iter()
+                    .flat_map(|channel| {
+                        let count = pubsub.numsub(channel) as i64;
+                        [Frame::Bulk(channel.clone()), Frame::Integer(count)]
+                    })
+                    .collect(),
+            )
+        }
+        Command::PubSubNumPat => Frame::Integer(server.pubsub.lock().unwrap().numpat() as i64),
+        _ => return None,
+    };
+    Some(response)
+}
+
+/// Runs the commands of a transaction, with the storage locked once for all
+/// of them so no other client gets in between. Replies with their replies.
+async fn exec(transaction: Transaction, server: &Server) -> Frame {
+    let commands = match transaction.into_commands() {
+        Ok(commands) => commands,
+        Err(response) => return response,
+    };
+
+    // The writes are logged between MULTI and EXEC, so that a replay never
+    // applies just some of them.
+    let logs = commands.iter().any(|(command, _)| {
+        command.is_write() || matches!(command, Command::BPop { .. } | Command::BLMove { .. })
+    });
+    let mut db_lock = server.db.lock().await;
+    if logs && let Err(e) = append_to_aof(server, &mut db_lock, &[vec![b"MULTI".to_vec()]]) {
+        eprintln!("Error writing to append only file: {}", e);
+        return Frame::Error(format!("ERR Error writing to the AOF file: {}", e));
+    }
+    // Like in Redis, blocked clients only get what was pushed once the whole
+    // transaction has run, and their pops are logged after it.
+    let mut woken = Woken::default();
+    let responses = commands
+        .into_iter()
+        .map(|(command, args)| execute_queued(command, args, server, &mut db_lock, &mut woken))
+        .collect();
+    if logs && let Err(e) = append_to_aof(server, &mut db_lock, &[vec![b"EXEC".to_vec()]]) {
+        eprintln!("Error writing to append only file: {}", e);
+    }
+    serve_woken(server, &mut db_lock, woken);
+    publish_key_events(server, &mut db_lock);
+    Frame::Array(responses)
+}
+
+/// Runs a command queued by MULTI, with the storage locked for the whole
+/// transaction. A blocking command can't wait in there, so it is served
+/// straight away or times out at once.
+fn execute_queued(
+    command: Command,
+    args: Request,
+    server: &Server,
+    db_lock: &mut Storage,
+    woken: &mut Woken,
+) -> Frame {
+    if let Some(response) = execute_without_storage(&command, server) {
+        return response;
+    }
+
+    let (keys, op) = match command {
+        Command::Save => return save_snapshot_locked(server, db_lock),
+        Command::BgSave => {
+            return match start_bgsave_locked(server, db_lock) {
+                Ok(_) => Frame::Simple("Background saving started".to_string()),
+                Err(response) => response,
+            };
+        }
+        Command::BgRewriteAof => return start_aof_rewrite_locked(server, db_lock),
+        Command::Info { section } => return info_locked(server, db_lock, section.as_deref()),
+        Command::BPop { keys, end, .. } => (keys, BlockingOp::Pop(end)),
+        Command::BLMove {
+            source,
+            destination,
+            from,
+            to,
+            ..
+        } => (
+            vec![source],
+            BlockingOp::Move {
+                destination,
+                from,
+                to,
+            },
+        ),
+        // XREAD and XREADGROUP don't block without the client waiting for
+        // them, so their BLOCK makes no difference here.
+        command => return apply_locked(command, args, server, db_lock, woken),
+    };
+    let response = pop_now(server, db_lock, &keys, &op, woken);
+    publish_key_events(server, db_lock);
+    response.unwrap_or(Frame::Null)
+}
+
+/// The part of `execute_command` done with the storage locked.
+fn execute_locked(
+    command: Command,
+    args: Request,
+    server: &Server,
+    db_lock: &mut Storage,
+) -> Frame {
+    let mut woken = Woken::default();
+    let response = apply_locked(command, args, server, db_lock, &mut woken);
+    serve_woken(server, db_lock, woken);
+    publish_key_events(server, db_lock);
+    response
+}
+
+/// The keys pushed to or added stream entries to, whose waiting clients are
+/// served once the command, or the whole transaction, is done.
+#[derive(Default)]
+struct Woken {
+    lists: Vec<Vec<u8>>,
+    streams: Vec<Vec<u8>>,
+}
+
+/// Serves the clients waiting on the keys in `woken`.
+fn serve_woken(server: &Server, db_lock: &mut Storage, woken: Woken) {
+    for key in woken.lists {
+        serve_blocked(server, db_lock, key);
+    }
+    for key in woken.streams {
+        serve_readers(server, db_lock, &key);
+    }
+}
+
+/// Applies and logs a command, leaving the clients it may serve in `woken`.
+fn apply_locked(
+    command: Command,
+    args: Request,
+    server: &Server,
+    db_lock: &mut Storage,
+    woken: &mut Woken,
+) -> Frame {
+    // A push may be what a blocked client is waiting for.
+    let pushed_to = match &command {
+        Command::LPush { key, .. } | Command::RPush { key, .. } => Some(key.clone()),
+        Command::LMove { destination, .. } => Some(destination.clone()),
+        _ => None,
+    };
+    let added_to = match &command {
+        Command::XAdd { key, .. } => Some(key.clone()),
+        _ => None,
+    };
+
+    let is_write = command.is_write();
+    let (response, logged) = apply_logged(command, args, db_lock);
+    let failed = matches!(response, Frame::Error(_));
+
+    // Like in Redis, a write the log fails to take has still been applied,
+    // so the client gets its real reply. The failure shows in the server log
+    // and in INFO's aof_last_write_status. What expired is logged even when
+    // the command isn't.
+    let logged = if is_write && !failed {
+        &logged[..]
+    } else {
+        &[]
+    };
+    if let Err(e) = append_to_aof(server, db_lock, logged) {
+        eprintln!("Error writing to append only file: {}", e);
+    }
+
+    if !failed {
+        woken.lists.extend(pushed_to);
+        woken.streams.extend(added_to);
+    }
+    publish_key_events(server, db_lock);
+
+    response
+}
+
+// Publishes the keyspace notifications for the changes just made. Called
+// with the storage still locked, so they go out in the order the changes
+// were made.
+fn publish_key_events(server: &Server, db_lock: &mut Storage) {
+    let events = db_lock.take_events();
+    if events.is_empty() {
+        return;
+    }
+
+    let mut pubsub = server.pubsub.lock().unwrap();
+    for event in &events {
+        for (channel, message) in server.config.notify_keyspace_events.messages(event) {
+            pubsub.publish(&channel, &message);
+        }
+    }
+}
+
+// Logs `requests` to the append-only file, if there is one, after the DELs
+// and HDELs of whatever expired before they were applied. Called with the
+// storage still locked, so the file records writes in exactly the order they
+// were applied.
+fn append_to_aof(
+    server: &Server,
+    db_lock: &mut Storage,
+    requests: &[Request],
+) -> std::io::Result<()> {
+    let expired = db_lock.take_expired();
+    let Some(aof) = &server.aof else {
+        return Ok(());
+    };
+    let mut aof = aof.lock().unwrap();
+    for request in expired.iter().chain(requests) {
+        aof.append(request)?;
+    }
+    Ok(())
+}
+
+/// Runs BLPOP, BRPOP or BLMOVE. If one of `keys` holds an element the client
+/// is served straight away. Otherwise it is parked, without holding the
+/// storage lock, until a push serves it or `timeout` passes. Returns `None`
+/// if the client disconnects while it waits.
+async fn block_on_lists(
+    keys: Vec<Vec<u8>>,
+    op: BlockingOp,
+    timeout: Option<Duration>,
+    connection: &mut Connection<TcpStream>,
+    server: &Server,
+) -> std::io::Result<Option<Frame>> {
+    let (id, reply) = {
+        let mut db_lock = server.db.lock().await;
+        let mut woken = Woken::default();
+        let response = pop_now(server, &mut db_lock, &keys, &op, &mut woken);
+        serve_woken(server, &mut db_lock, woken);
+        // Looking the keys up may also have found some expired.
+        publish_key_events(server, &mut db_lock);
+        if response.is_some() {
+            return Ok(response);
+        }
+        server.blocked.lock().unwrap().block(keys, op)
+    };
+
+    wait_until_served(id, reply, timeout, connection, server).await
+}
+
+/// Serves a BLPOP, BRPOP or BLMOVE from the first of `keys` with an element,
+/// if there is one. `None` if they are all empty.
+fn pop_now(
+    server: &Server,
+    db_lock: &mut Storage,
+    keys: &[Vec<u8>],
+    op: &BlockingOp,
+    woken: &mut Woken,
+) -> Option<Frame> {
+    for key in keys {
+        match db_lock.llen(key) {
+            Ok(0) => {}
+            Ok(_) => {
+                let (response, pushed_to) = pop_for_client(server, db_lock, key, op);
+                woken.lists.extend(pushed_to);
+                return Some(response);
             }
-            Err(e) => return Err(e), // Connection error
+            Err(msg) => return Some(Frame::error(msg)),
         }
     }
+    None
 }
 
-/// Executes a parsed command against the database.
-async fn execute_command(command: Command, db: &Db) -> String {
-    // Lock the mutex to get access to the storage
-    let mut db_lock = db.lock().await;
+/// Runs XREAD or XREADGROUP with BLOCK. If there is anything to read the
+/// client gets it straight away. Otherwise it is parked like in
+/// `block_on_lists`, until an XADD gives it something to read.
+async fn block_on_streams(
+    keys: Vec<Vec<u8>>,
+    mut op: BlockingOp,
+    timeout: Option<Duration>,
+    connection: &mut Connection<TcpStream>,
+    server: &Server,
+) -> std::io::Result<Option<Frame>> {
+    let (id, reply) = {
+        let mut db_lock = server.db.lock().await;
+        if let Some(response) = read_for_client(server, &mut db_lock, &keys, &op) {
+            publish_key_events(server, &mut db_lock);
+            return Ok(Some(response));
+        }
+
+        let resolved = resolve_last_ids(&mut db_lock, &keys, &mut op);
+        // Looking the keys up may have found some expired, whether or not
+        // the client gets to block.
+        publish_key_events(server, &mut db_lock);
+        if let Err(msg) = resolved {
+            return Ok(Some(Frame::error(msg)));
+        }
+        server.blocked.lock().unwrap().block(keys, op)
+    };
 
+    wait_until_served(id, reply, timeout, connection, server).await
+}
+
+// Replaces XREAD's `$` with the last ID of the stream, since it is for the
+// entries added after the client started waiting.
+fn resolve_last_ids(
+    db_lock: &mut Storage,
+    keys: &[Vec<u8>],
+    op: &mut BlockingOp,
+) -> Result<(), &'static str> {
+    if let BlockingOp::ReadStreams { from, .. } = op {
+        for (key, from) in keys.iter().zip(from.iter_mut()) {
+            if *from == ReadFrom::Last {
+                let last = db_lock.xlast_id(key)?;
+                *from = ReadFrom::After(last.unwrap_or(StreamId::MIN));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Waits until the client blocked as `id` is served or `timeout` passes.
+/// Returns `None` if it disconnects in the meantime.
+async fn wait_until_served(
+    id: u64,
+    mut reply: oneshot::Receiver<Frame>,
+    timeout: Option<Duration>,
+    connection: &mut Connection<TcpStream>,
+    server: &Server,
+) -> std::io::Result<Option<Frame>> {
+    // Replies to requests pipelined before this one shouldn't wait with it.
+    connection.flush().await?;
+
+    let timed_out = async {
+        match timeout {
+            Some(timeout) => tokio::time::sleep(timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        response = &mut reply => return Ok(Some(response.unwrap_or(Frame::Null))),
+        _ = timed_out => {}
+        closed = connection.closed() => {
+            server.blocked.lock().unwrap().unblock(id);
+            return closed.map(|()| None);
+        }
+    }
+
+    if server.blocked.lock().unwrap().unblock(id) {
+        return Ok(Some(Frame::Null));
+    }
+    // It was served just as the timeout passed, and the reply is on its way.
+    Ok(Some(reply.await.unwrap_or(Frame::Null)))
+}
+
+/// Hands the elements just pushed to `key` to the clients blocked on it,
+/// longest waiting first. Called with the storage still locked after the push.
+fn serve_blocked(server: &Server, db_lock: &mut Storage, key: Vec<u8>) {
+    let mut ready = vec![key];
+
+    while let Some(key) = ready.pop() {
+        server.blocked.lock().unwrap().serve(&key, |client| {
+            if matches!(client.op, BlockingOp::ReadStreams { .. })
+                || !db_lock.llen(&key).is_ok_and(|len| len > 0)
+            {
+                return None;
+            }
+            let (response, pushed_to) = pop_for_client(server, db_lock, &key, &client.op);
+            // BLMOVE's push may in turn serve someone else.
+            ready.extend(pushed_to);
+            Some(response)
+        });
+    }
+}
+
+/// Hands the entries just added to the stream at `key` to the clients
+/// reading it, longest waiting first. Called with the storage still locked
+/// after the XADD.
+fn serve_readers(server: &Server, db_lock: &mut Storage, key: &[u8]) {
+    server.blocked.lock().unwrap().serve(key, |client| {
+        read_for_client(server, db_lock, &client.keys, &client.op)
+    });
+}
+
+/// Reads the streams at `keys` on behalf of a blocking XREAD or XREADGROUP,
+/// logging what a group read changed. `None` if there was nothing to read
+/// yet.
+fn read_for_client(
+    server: &Server,
+    db_lock: &mut Storage,
+    keys: &[Vec<u8>],
+    op: &BlockingOp,
+) -> Option<Frame> {
+    let BlockingOp::ReadStreams {
+        from,
+        count,
+        group,
+        no_ack,
+    } = op
+    else {
+        return None;
+    };
+
+    let (response, logged) = match group {
+        Some((group, consumer)) => apply_group_command(
+            Command::XReadGroup {
+                group: group.clone(),
+                consumer: consumer.clone(),
+                keys: keys.to_vec(),
+                ids: from.clone(),
+                count: *count,
+                block: None,
+                no_ack: *no_ack,
+            },
+            db_lock,
+        ),
+        None => {
+            let command = Command::XRead {
+                keys: keys.to_vec(),
+                ids: from.clone(),
+                count: *count,
+                block: None,
+            };
+            (apply_command(command, db_lock), Vec::new())
+        }
+    };
+
+    // Even a read that found nothing may have added the consumer.
+    if let Err(e) = append_to_aof(server, db_lock, &logged) {
+        eprintln!("Error writing to append only file: {}", e);
+    }
+    (response != Frame::Null).then_some(response)
+}
+
+/// Takes an element from the list at `key`, which must have one, on behalf
+/// of a blocking command. It is logged as the non-blocking command it amounts
+/// to, since replaying the blocking one could block. Also returns where the
+/// element was moved to, if anywhere.
+fn pop_for_client(
+    server: &Server,
+    db_lock: &mut Storage,
+    key: &[u8],
+    op: &BlockingOp,
+) -> (Frame, Option<Vec<u8>>) {
+    let (response, logged, pushed_to) = match op {
+        BlockingOp::Pop(end) => {
+            let Ok(Some(popped)) = db_lock.pop(key, *end, 1) else {
+                return (Frame::Null, None);
+            };
+            let name = match end {
+                ListEnd::Left => b"LPOP".to_vec(),
+                ListEnd::Right => b"RPOP".to_vec(),
+            };
+            let response = [key.to_vec()]
+                .into_iter()
+                .chain(popped)
+                .map(Frame::bulk)
+                .collect();
+            (Frame::Array(response), vec![name, key.to_vec()], None)
+        }
+        BlockingOp::Move {
+            destination,
+            from,
+            to,
+        } => match db_lock.lmove(key, destination, *from, *to) {
+            Ok(Some(element)) => {
+                let logged = vec![
+                    b"LMOVE".to_vec(),
+                    key.to_vec(),
+                    destination.clone(),
+                    list_end_arg(*from),
+                    list_end_arg(*to),
+                ];
+                (Frame::bulk(element), logged, Some(destination.clone()))
+            }
+            Ok(None) => return (Frame::Null, None),
+            Err(msg) => return (Frame::error(msg), None),
+        },
+        BlockingOp::ReadStreams { .. } => unreachable!("stream readers don't pop lists"),
+    };
+
+    if let Err(e) = append_to_aof(server, db_lock, &[logged]) {
+        eprintln!("Error writing to append only file: {}", e);
+    }
+    (response, pushed_to)
+}
+
+/// SPOP removes members at random, so it is logged as an SREM of the members
+/// it removed for a replay to remove the same ones. `None` if it removed none.
+fn spop_as_srem(key: Vec<u8>, response: &Frame) -> Option<Request> {
+    let popped: Vec<Vec<u8>> = match response {
+        Frame::Bulk(member) => vec![member.clone()],
+        Frame::Array(members) => members
+            .iter()
+            .filter_map(|member| match member {
+                Frame::Bulk(member) => Some(member.clone()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    if popped.is_empty() {
+        return None;
+    }
+    Some([b"SREM".to_vec(), key].into_iter().chain(popped).collect())
+}
+
+fn list_end_arg(end: ListEnd) -> Vec<u8> {
+    match end {
+        ListEnd::Left => b"LEFT".to_vec(),
+        ListEnd::Right => b"RIGHT".to_vec(),
+    }
+}
+
+fn score_frame(score: f64) -> Frame {
+    Frame::bulk(score.to_string())
+}
+
+// Sorted set members as a flat array, each followed by its score if asked.
+fn scored_members(members: Vec<(Vec<u8>, f64)>, with_scores: bool) -> Frame {
+    Frame::Array(
+        members
+            .into_iter()
+            .flat_map(|(member, score)| {
+                [
+                    Some(Frame::bulk(member)),
+                    with_scores.then(|| score_frame(score)),
+                ]
+                .into_iter()
+                .flatten()
+            })
+            .collect(),
+    )
+}
+
+fn id_frame(id: StreamId) -> Frame {
+    Frame::bulk(id.to_string())
+}
+
+// A stream entry as its ID and a flat array of its fields and values, which
+// is null for an entry that has been deleted.
+fn entry_frame(id: StreamId, fields: Option<Fields>) -> Frame {
+    let fields = fields.map_or(Frame::Null, |fields| {
+        Frame::Array(
+            fields
+                .into_iter()
+                .flat_map(|(field, value)| [Frame::bulk(field), Frame::bulk(value)])
+                .collect(),
+        )
+    });
+    Frame::Array(vec![id_frame(id), fields])
+}
+
+// What XREAD and XREADGROUP read: each stream's key and entries, or null if
+// there were none.
+fn streams_frame(read: StreamsRead) -> Frame {
+    if read.is_empty() {
+        return Frame::Null;
+    }
+    Frame::Array(
+        read.into_iter()
+            .map(|(key, entries)| {
+                let entries = entries
+                    .into_iter()
+                    .map(|(id, fields)| entry_frame(id, fields))
+                    .collect();
+                Frame::Array(vec![Frame::bulk(key), Frame::Array(entries)])
+            })
+            .collect(),
+    )
+}
+
+// XPENDING's reply: without a range, how many entries are pending, the
+// lowest and highest of their IDs and how many each consumer has; with one,
+// the ID, consumer, idle time and delivery count of each entry in it.
+fn pending_frame(group: &ConsumerGroup, range: Option<PendingRange>) -> Frame {
+    let pending = group.pending();
+    let Some(range) = range else {
+        let (Some((first, _)), Some((last, _))) =
+            (pending.first_key_value(), pending.last_key_value())
+        else {
+            return Frame::Array(vec![
+                Frame::Integer(0),
+                Frame::Null,
+                Frame::Null,
+                Frame::Null,
+            ]);
+        };
+        let consumers = group
+            .pending_per_consumer()
+            .into_iter()
+            .map(|(consumer, count)| {
+                Frame::Array(vec![Frame::bulk(consumer), Frame::bulk(count.to_string())])
+            })
+            .collect();
+        return Frame::Array(vec![
+            Frame::Integer(pending.len() as i64),
+            id_frame(*first),
+            id_frame(*last),
+            Frame::Array(consumers),
+        ]);
+    };
+
+    let now = now_millis();
+    Frame::Array(
+        group
+            .pending_range(range.start, range.end)
+            .map(|(id, entry)| (id, entry, now.saturating_sub(entry.delivered_at)))
+            .filter(|(_, entry, idle)| {
+                *idle >= range.min_idle
+                    && range
+                        .consumer
+                        .as_ref()
+                        .is_none_or(|consumer| *consumer == entry.consumer)
+            })
+            .take(range.count)
+            .map(|(id, entry, idle)| {
+                Frame::Array(vec![
+                    id_frame(*id),
+                    Frame::bulk(entry.consumer.clone()),
+                    Frame::Integer(idle as i64),
+                    Frame::Integer(entry.deliveries as i64),
+                ])
+            })
+            .collect(),
+    )
+}
+
+/// Applies a command like `apply_command`, also returning what to log to the
+/// append-only file for a replay to end up with the same data. That is the
+/// request itself, unless what the command does depends on chance or on the
+/// time.
+fn apply_logged(command: Command, args: Request, db_lock: &mut Storage) -> (Frame, Vec<Request>) {
     match command {
-        Command::Set { key, value, expiry } => {
-            db_lock.set(key, value, expiry);
+        Command::SPop { ref key, .. } => {
+            let key = key.clone();
+            let response = apply_command(command, db_lock);
+            let logged = spop_as_srem(key, &response);
+            (response, logged.into_iter().collect())
+        }
+        // The ID the entry got is logged in place of `*`.
+        Command::XAdd { ref fields, .. } => {
+            let id_index = args.len() - 2 * fields.len() - 1;
+            let response = apply_command(command, db_lock);
+            let mut args = args;
+            if let Frame::Bulk(id) = &response {
+                args[id_index] = id.clone();
+            }
+            (response, vec![args])
+        }
+        Command::XReadGroup { .. } | Command::XClaim { .. } | Command::XAutoClaim { .. } => {
+            apply_group_command(command, db_lock)
+        }
+        _ => (apply_command(command, db_lock), vec![args]),
+    }
+}
+
+/// Applies XREADGROUP, XCLAIM or XAUTOCLAIM. These stamp the entries they
+/// hand out with the time, so rather than the command, what it did to the
+/// group is logged: see `group_changes`.
+fn apply_group_command(command: Command, db_lock: &mut Storage) -> (Frame, Vec<Request>) {
+    match command {
+        Command::XReadGroup {
+            group,
+            consumer,
+            keys,
+            ids,
+            count,
+            no_ack,
+            ..
+        } => {
+            let before: Vec<_> = keys
+                .iter()
+                .map(|key| group_state(db_lock, key, &group, &consumer))
+                .collect();
+            let read = match db_lock.xreadgroup(&group, &consumer, &keys, &ids, count, no_ack) {
+                Ok(read) => read,
+                Err(msg) => return (Frame::error(msg), Vec::new()),
+            };
 
-            "OK\n".to_string()
+            let mut logged = Vec::new();
+            for (key, before) in keys.iter().zip(before) {
+                let delivered: Vec<_> = read
+                    .iter()
+                    .filter(|(read_key, _)| read_key == key)
+                    .flat_map(|(_, entries)| entries.iter().map(|(id, _)| *id))
+                    .collect();
+                logged.extend(group_changes(
+                    db_lock,
+                    key,
+                    &group,
+                    &consumer,
+                    before,
+                    &delivered,
+                    &[],
+                ));
+            }
+            (streams_frame(read), logged)
+        }
+        Command::XClaim {
+            key,
+            group,
+            consumer,
+            min_idle,
+            ids,
+            options,
+        } => {
+            let before = group_state(db_lock, &key, &group, &consumer);
+            let claimed = match db_lock.xclaim(&key, &group, &consumer, min_idle, &ids, &options) {
+                Ok(claimed) => claimed,
+                Err(msg) => return (Frame::error(msg), Vec::new()),
+            };
+
+            let claimed_ids: Vec<_> = claimed.iter().map(|(id, _)| *id).collect();
+            let logged = group_changes(db_lock, &key, &group, &consumer, before, &claimed_ids, &[]);
+            let response = claimed
+                .into_iter()
+                .map(|(id, fields)| match options.just_id {
+                    true => id_frame(id),
+                    false => entry_frame(id, fields),
+                })
+                .collect();
+            (Frame::Array(response), logged)
+        }
+        Command::XAutoClaim {
+            key,
+            group,
+            consumer,
+            min_idle,
+            start,
+            count,
+            just_id,
+        } => {
+            let before = group_state(db_lock, &key, &group, &consumer);
+            let (next, claimed, deleted) = match db_lock
+                .xautoclaim(&key, &group, &consumer, min_idle, start, count, just_id)
+            {
+                Ok(result) => result,
+                Err(msg) => return (Frame::error(msg), Vec::new()),
+            };
+
+            let claimed_ids: Vec<_> = claimed.iter().map(|(id, _)| *id).collect();
+            let logged = group_changes(
+                db_lock,
+                &key,
+                &group,
+                &consumer,
+                before,
+                &claimed_ids,
+                &deleted,
+            );
+            let claimed = claimed
+                .into_iter()
+                .map(|(id, fields)| match just_id {
+                    true => id_frame(id),
+                    false => entry_frame(id, Some(fields)),
+                })
+                .collect();
+            let response = vec![
+                id_frame(next),
+                Frame::Array(claimed),
+                Frame::Array(deleted.into_iter().map(id_frame).collect()),
+            ];
+            (Frame::Array(response), logged)
         }
+        command => (apply_command(command, db_lock), Vec::new()),
+    }
+}
+
+// Whether `consumer` is in the consumer group, and the last ID the group
+// delivered, if the stream at `key` has the group.
+fn group_state(
+    db_lock: &mut Storage,
+    key: &[u8],
+    group: &[u8],
+    consumer: &[u8],
+) -> Option<(bool, StreamId)> {
+    let group = db_lock.xgroup(key, group).ok()?;
+    let has_consumer = group.consumers().any(|name| name == consumer);
+    Some((has_consumer, group.last_delivered))
+}
+
+// The commands that bring a consumer group from its state `before` to what
+// it is now, where the only entries changed in its pending entries list are
+// those `claimed`, now pending exactly as they are, and those `dropped` from
+// it.
+fn group_changes(
+    db_lock: &mut Storage,
+    key: &[u8],
+    group: &[u8],
+    consumer: &[u8],
+    before: Option<(bool, StreamId)>,
+    claimed: &[StreamId],
+    dropped: &[StreamId],
+) -> Vec<Request> {
+    let (Some((had_consumer, last_delivered)), Ok(state)) = (before, db_lock.xgroup(key, group))
+    else {
+        return Vec::new();
+    };
+    let xgroup = |subcommand: &[u8], arg: Vec<u8>| {
+        vec![
+            b"XGROUP".to_vec(),
+            subcommand.to_vec(),
+            key.to_vec(),
+            group.to_vec(),
+            arg,
+        ]
+    };
+
+    let mut logged = Vec::new();
+    if !had_consumer {
+        logged.push(xgroup(b"CREATECONSUMER", consumer.to_vec()));
+    }
+    if state.last_delivered != last_delivered {
+        let id = state.last_delivered.to_string().into_bytes();
+        logged.push(xgroup(b"SETID", id));
+    }
+    for id in claimed {
+        if let Some(entry) = state.pending().get(id) {
+            logged.push(aof::claim_request(key, group, id, entry));
+        }
+    }
+    if !dropped.is_empty() {
+        let ids = dropped.iter().map(|id| id.to_string().into_bytes());
+        logged.push(
+            [b"XACK".to_vec(), key.to_vec(), group.to_vec()]
+                .into_iter()
+                .chain(ids)
+                .collect(),
+        );
+    }
+    logged
+}
+
+/// Applies a command to the storage and builds the reply.
+fn apply_command(command: Command, db_lock: &mut Storage) -> Frame {
+    match command {
+        Command::Set {
+            key,
+            value,
+            options,
+        } => match db_lock.set_with_options(key, value, &options) {
+            Ok((_, old)) if options.get => old.map_or(Frame::Null, Frame::bulk),
+            Ok((true, _)) => Frame::ok(),
+            // A NX or XX condition wasn't met.
+            Ok((false, _)) => Frame::Null,
+            Err(msg) => Frame::error(msg),
+        },
         Command::Get { key } => match db_lock.get(&key) {
             Some(store_value) => match &store_value.data {
-                Value::String(s) => format!("{}\n", s),
-                _ => "(error) WRONGTYPE Operation against a key holding the wrong kind of value\n"
-                    .to_string(),
+                Value::String(s) => Frame::bulk(s.clone()),
+                _ => Frame::error(WRONGTYPE),
             },
-            None => "NIL\n".to_string(),
+            None => Frame::Null,
         },
-        Command::Del { key } => {
-            db_lock.remove(&key);
+        Command::Del { keys } => {
+            let removed = keys
+                .iter()
+                .filter(|key| db_lock.remove(key).is_some())
+                .count();
 
-            "OK\n".to_string()
+            Frame::Integer(removed as i64)
         }
+        Command::MGet { keys } => Frame::Array(
+            keys.iter()
+                // Keys holding anything but a string read as missing.
+                .map(|key| match db_lock.get_string(key) {
+                    Ok(Some(s)) => Frame::bulk(s.clone()),
+                    _ => Frame::Null,
+                })
+                .collect(),
+        ),
+        Command::MSet { pairs } => {
+            db_lock.mset(pairs);
+            Frame::ok()
+        }
+        Command::MSetNx { pairs } => Frame::Integer(db_lock.msetnx(pairs) as i64),
+        Command::SetNx { key, value } => {
+            let options = SetOptions {
+                only_if_exists: Some(false),
+                ..SetOptions::default()
+            };
+            match db_lock.set_with_options(key, value, &options) {
+                Ok((stored, _)) => Frame::Integer(stored as i64),
+                Err(msg) => Frame::error(msg),
+            }
+        }
+        Command::GetDel { key } => match db_lock.getdel(&key) {
+            Ok(value) => value.map_or(Frame::Null, Frame::bulk),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::GetEx { key, expiry } => {
+            // Relative times count from now, not from when the command was
+            // parsed.
+            let at = match &expiry {
+                Some(ExpiryChange::Expire(expiry)) => match expiry.deadline() {
+                    Some(at) => Some(at),
+                    None => return Frame::error("ERR invalid expire time in 'getex' command"),
+                },
+                _ => None,
+            };
+            let value = match db_lock.get_string(&key) {
+                Ok(Some(s)) => s.clone(),
+                Ok(None) => return Frame::Null,
+                Err(msg) => return Frame::error(msg),
+            };
+            if let Some(at) = at {
+                db_lock.expire_at(&key, at, ExpireCondition::default());
+            } else if expiry == Some(ExpiryChange::Persist) {
+                db_lock.persist(&key);
+            }
+            Frame::bulk(value)
+        }
+        Command::Append { key, value } => match db_lock.append(&key, &value) {
+            Ok(len) => Frame::Integer(len as i64),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::StrLen { key } => match db_lock.strlen(&key) {
+            Ok(len) => Frame::Integer(len as i64),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::GetRange { key, start, end } => match db_lock.getrange(&key, start, end) {
+            Ok(range) => Frame::bulk(range),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::SetRange { key, offset, value } => match db_lock.setrange(&key, offset, &value) {
+            Ok(len) => Frame::Integer(len as i64),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::IncrBy { key, delta } => match db_lock.incr_by(&key, delta) {
+            Ok(value) => Frame::Integer(value),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::IncrByFloat { key, delta } => match db_lock.incr_by_float(&key, delta) {
+            Ok(value) => Frame::bulk(value),
+            Err(msg) => Frame::error(msg),
+        },
         Command::LPush { key, values } => match db_lock.lpush(&key, values) {
-            Ok(len) => format!("(integer) {}\n", len),
-            Err(msg) => format!("(error) {}\n", msg),
+            Ok(len) => Frame::Integer(len as i64),
+            Err(msg) => Frame::error(msg),
         },
         Command::RPush { key, values } => match db_lock.rpush(&key, values) {
-            Ok(len) => format!("(integer) {}\n", len),
-            Err(msg) => format!("(error) {}\n", msg),
+            Ok(len) => Frame::Integer(len as i64),
+            Err(msg) => Frame::error(msg),
         },
         Command::LRange { key, start, stop } => match db_lock.lrange(&key, start, stop) {
-            Ok(Some(items)) => items
-                .iter()
-                .map(|item| format!("{}\n", item))
-                .collect::<String>(),
-            Ok(None) => "*(empty list)\n".to_string(),
-            Err(msg) => format!("(error) {}\n", msg),
+            Ok(Some(items)) => Frame::Array(items.into_iter().map(Frame::bulk).collect()),
+            Ok(None) => Frame::Array(vec![]),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::Pop { key, end, count } => match db_lock.pop(&key, end, count.unwrap_or(1)) {
+            Ok(None) => Frame::Null,
+            Ok(Some(popped)) if count.is_none() => {
+                popped.into_iter().next().map_or(Frame::Null, Frame::bulk)
+            }
+            Ok(Some(popped)) => Frame::Array(popped.into_iter().map(Frame::bulk).collect()),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::LLen { key } => match db_lock.llen(&key) {
+            Ok(len) => Frame::Integer(len as i64),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::LIndex { key, index } => match db_lock.lindex(&key, index) {
+            Ok(element) => element.map_or(Frame::Null, |element| Frame::bulk(element.clone())),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::LSet { key, index, value } => match db_lock.lset(&key, index, value) {
+            Ok(()) => Frame::ok(),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::LInsert {
+            key,
+            before,
+            pivot,
+            value,
+        } => match db_lock.linsert(&key, before, &pivot, value) {
+            Ok(len) => Frame::Integer(len),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::LRem { key, count, value } => match db_lock.lrem(&key, count, &value) {
+            Ok(removed) => Frame::Integer(removed as i64),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::LTrim { key, start, stop } => match db_lock.ltrim(&key, start, stop) {
+            Ok(()) => Frame::ok(),
+            Err(msg) => Frame::error(msg),
         },
-        Command::HSet { key, field, value } => match db_lock.hset(key, field, value) {
-            Ok(num) => format!("(integer) {}\n", num),
-            Err(msg) => format!("(error) {}\n", msg),
+        Command::LPos {
+            key,
+            element,
+            rank,
+            count,
+            max_len,
+        } => match db_lock.lpos(&key, &element, rank, count.unwrap_or(1), max_len) {
+            Ok(indices) if count.is_none() => indices
+                .first()
+                .map_or(Frame::Null, |&i| Frame::Integer(i as i64)),
+            Ok(indices) => Frame::Array(
+                indices
+                    .into_iter()
+                    .map(|i| Frame::Integer(i as i64))
+                    .collect(),
+            ),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::LMove {
+            source,
+            destination,
+            from,
+            to,
+        } => match db_lock.lmove(&source, &destination, from, to) {
+            Ok(element) => element.map_or(Frame::Null, Frame::bulk),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::HSet { key, pairs } => match db_lock.hset(key, pairs) {
+            Ok(num) => Frame::Integer(num as i64),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::HMSet { key, pairs } => match db_lock.hset(key, pairs) {
+            Ok(_) => Frame::ok(),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::HSetNx { key, field, value } => match db_lock.hsetnx(&key, field, value) {
+            Ok(set) => Frame::Integer(set as i64),
+            Err(msg) => Frame::error(msg),
         },
         Command::HGet { key, field } => match db_lock.hget(&key, &field) {
-            Ok(Some(value)) => format!("{}\n", value),
-            Ok(None) => "NIL\n".to_string(),
-            Err(msg) => format!("(error) {}\n", msg),
+            Ok(Some(value)) => Frame::bulk(value.clone()),
+            Ok(None) => Frame::Null,
+            Err(msg) => Frame::error(msg),
         },
         Command::HDel { key, fields } => match db_lock.hdel(&key, fields) {
-            Ok(num) => format!("(integer) {}\n", num),
-            Err(msg) => format!("(error) {}\n", msg),
+            Ok(num) => Frame::Integer(num as i64),
+            Err(msg) => Frame::error(msg),
         },
         Command::HLen { key } => match db_lock.hlen(&key) {
-            Ok(num) => format!("(integer) {}\n", num),
-            Err(msg) => format!("(error) {}\n", msg),
+            Ok(num) => Frame::Integer(num as i64),
+            Err(msg) => Frame::error(msg),
         },
         Command::HGetAll { key } => match db_lock.hgetall(&key) {
-            Ok(Some(hash)) => hash
-                .iter()
-                .map(|(k, v)| format!("{}: {}\n", k, v))
-                .collect::<String>(),
-            Ok(None) => "*(empty list)\n".to_string(),
-            Err(msg) => format!("(error) {}\n", msg),
+            Ok(Some(hash)) => Frame::Array(
+                hash.iter()
+                    .flat_map(|(k, v)| [Frame::bulk(k.clone()), Frame::bulk(v.clone())])
+                    .collect(),
+            ),
+            Ok(None) => Frame::Array(vec![]),
+            Err(msg) => Frame::error(msg),
         },
-        Command::Save => {
-            // Save the snapshot of the database to disk
-            save_snapshot(&db).await;
-
-            "OK\n".to_string()
+        Command::HMGet { key, fields } => match db_lock.hmget(&key, &fields) {
+            Ok(values) => Frame::Array(
+                values
+                    .into_iter()
+                    .map(|value| value.map_or(Frame::Null, Frame::bulk))
+                    .collect(),
+            ),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::HExists { key, field } => match db_lock.hget(&key, &field) {
+            Ok(value) => Frame::Integer(value.is_some() as i64),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::HKeys { key } => match db_lock.hgetall(&key) {
+            Ok(hash) => Frame::Array(
+                hash.into_iter()
+                    .flat_map(|hash| hash.keys())
+                    .map(|field| Frame::bulk(field.clone()))
+                    .collect(),
+            ),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::HVals { key } => match db_lock.hgetall(&key) {
+            Ok(hash) => Frame::Array(
+                hash.into_iter()
+                    .flat_map(|hash| hash.values())
+                    .map(|value| Frame::bulk(value.clone()))
+                    .collect(),
+            ),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::HIncrBy { key, field, delta } => match db_lock.hincrby(&key, &field, delta) {
+            Ok(value) => Frame::Integer(value),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::HIncrByFloat { key, field, delta } => {
+            match db_lock.hincrbyfloat(&key, &field, delta) {
+                Ok(value) => Frame::bulk(value),
+                Err(msg) => Frame::error(msg),
+            }
+        }
+        Command::HStrLen { key, field } => match db_lock.hget(&key, &field) {
+            Ok(value) => Frame::Integer(value.map_or(0, Vec::len) as i64),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::HRandField {
+            key,
+            count,
+            with_values,
+        } => match db_lock.hrandfield(&key, count.unwrap_or(1)) {
+            Ok(picked) if count.is_none() => picked
+                .into_iter()
+                .next()
+                .map_or(Frame::Null, |(field, _)| Frame::bulk(field)),
+            Ok(picked) => Frame::Array(
+                picked
+                    .into_iter()
+                    .flat_map(|(field, value)| {
+                        [Some(field), with_values.then_some(value)]
+                            .into_iter()
+                            .flatten()
+                            .map(Frame::bulk)
+                    })
+                    .collect(),
+            ),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::SAdd { key, members } => match db_lock.sadd(&key, members) {
+            Ok(added) => Frame::Integer(added),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::SRem { key, members } => match db_lock.srem(&key, &members) {
+            Ok(removed) => Frame::Integer(removed),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::SMembers { key } => match db_lock.smembers(&key) {
+            Ok(members) => Frame::Array(members.into_iter().map(Frame::bulk).collect()),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::SIsMember { key, member } => match db_lock.sismember(&key, &member) {
+            Ok(found) => Frame::Integer(found as i64),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::SCard { key } => match db_lock.scard(&key) {
+            Ok(len) => Frame::Integer(len as i64),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::SPop { key, count } => match db_lock.spop(&key, count.unwrap_or(1)) {
+            Ok(popped) if count.is_none() => {
+                popped.into_iter().next().map_or(Frame::Null, Frame::bulk)
+            }
+            Ok(popped) => Frame::Array(popped.into_iter().map(Frame::bulk).collect()),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::SRandMember { key, count } => {
+            match db_lock.srandmember(&key, count.unwrap_or(1)) {
+                Ok(picked) if count.is_none() => {
+                    picked.into_iter().next().map_or(Frame::Null, Frame::bulk)
+                }
+                Ok(picked) => Frame::Array(picked.into_iter().map(Frame::bulk).collect()),
+                Err(msg) => Frame::error(msg),
+            }
         }
+        Command::SMove {
+            source,
+            destination,
+            member,
+        } => match db_lock.smove(&source, &destination, member) {
+            Ok(moved) => Frame::Integer(moved as i64),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::SetOp { op, keys } => match db_lock.set_op(op, &keys) {
+            Ok(members) => Frame::Array(members.into_iter().map(Frame::bulk).collect()),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::SetOpStore {
+            op,
+            destination,
+            keys,
+        } => match db_lock.set_op_store(op, destination, &keys) {
+            Ok(len) => Frame::Integer(len as i64),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::ZAdd {
+            key,
+            pairs,
+            options,
+            incr: true,
+        } => {
+            let (delta, member) = pairs.into_iter().next().expect("ZADD INCR takes one pair");
+            match db_lock.zincrby(&key, member, delta, &options) {
+                Ok(score) => score.map_or(Frame::Null, score_frame),
+                Err(msg) => Frame::error(msg),
+            }
+        }
+        Command::ZAdd {
+            key,
+            pairs,
+            options,
+            incr: false,
+        } => match db_lock.zadd(&key, pairs, &options) {
+            Ok(added) => Frame::Integer(added),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::ZIncrBy { key, delta, member } => {
+            match db_lock.zincrby(&key, member, delta, &ZAddOptions::default()) {
+                Ok(score) => score.map_or(Frame::Null, score_frame),
+                Err(msg) => Frame::error(msg),
+            }
+        }
+        Command::ZRem { key, members } => match db_lock.zrem(&key, &members) {
+            Ok(removed) => Frame::Integer(removed),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::ZScore { key, member } => match db_lock.zscore(&key, &member) {
+            Ok(score) => score.map_or(Frame::Null, score_frame),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::ZCard { key } => match db_lock.zcard(&key) {
+            Ok(len) => Frame::Integer(len as i64),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::ZRank { key, member, rev } => match db_lock.zrank(&key, &member, rev) {
+            Ok(rank) => rank.map_or(Frame::Null, |rank| Frame::Integer(rank as i64)),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::ZRange {
+            key,
+            by,
+            rev,
+            limit,
+            with_scores,
+        } => match db_lock.zrange(&key, &by, rev, limit) {
+            Ok(members) => scored_members(members, with_scores),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::ZCount { key, by } => match db_lock.zcount(&key, &by) {
+            Ok(count) => Frame::Integer(count as i64),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::ZPop { key, max, count } => match db_lock.zpop(&key, max, count) {
+            Ok(popped) => scored_members(popped, true),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::ZStore {
+            op,
+            destination,
+            keys,
+            weights,
+            aggregate,
+        } => match db_lock.zstore(op, destination, &keys, &weights, aggregate) {
+            Ok(len) => Frame::Integer(len as i64),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::XAdd {
+            key,
+            id,
+            fields,
+            no_mkstream,
+            trim,
+        } => match db_lock.xadd(&key, id, fields, no_mkstream, trim.as_ref()) {
+            Ok(id) => id.map_or(Frame::Null, id_frame),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::XLen { key } => match db_lock.xlen(&key) {
+            Ok(len) => Frame::Integer(len as i64),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::XRange {
+            key,
+            start,
+            end,
+            rev,
+            count,
+        } => match db_lock.xrange(&key, start, end, rev, count) {
+            Ok(entries) => Frame::Array(
+                entries
+                    .into_iter()
+                    .map(|(id, fields)| entry_frame(id, Some(fields)))
+                    .collect(),
+            ),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::XTrim { key, trim } => match db_lock.xtrim(&key, &trim) {
+            Ok(evicted) => Frame::Integer(evicted as i64),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::XDel { key, ids } => match db_lock.xdel(&key, &ids) {
+            Ok(deleted) => Frame::Integer(deleted as i64),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::XSetId { key, last_id } => match db_lock.xsetid(&key, last_id) {
+            Ok(()) => Frame::ok(),
+            Err(msg) => Frame::error(msg),
+        },
+        // Blocking is up to the caller, see `block_on_streams`.
+        Command::XRead {
+            keys, ids, count, ..
+        } => match db_lock.xread(&keys, &ids, count) {
+            Ok(read) => streams_frame(read),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::XReadGroup { .. } | Command::XClaim { .. } | Command::XAutoClaim { .. } => {
+            apply_group_command(command, db_lock).0
+        }
+        Command::XGroupCreate {
+            key,
+            group,
+            id,
+            mkstream,
+        } => match db_lock.xgroup_create(&key, &group, id, mkstream) {
+            Ok(()) => Frame::ok(),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::XGroupSetId { key, group, id } => match db_lock.xgroup_setid(&key, &group, id) {
+            Ok(()) => Frame::ok(),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::XGroupDestroy { key, group } => match db_lock.xgroup_destroy(&key, &group) {
+            Ok(destroyed) => Frame::Integer(destroyed as i64),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::XGroupCreateConsumer {
+            key,
+            group,
+            consumer,
+        } => match db_lock.xgroup_createconsumer(&key, &group, &consumer) {
+            Ok(created) => Frame::Integer(created as i64),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::XGroupDelConsumer {
+            key,
+            group,
+            consumer,
+        } => match db_lock.xgroup_delconsumer(&key, &group, &consumer) {
+            Ok(pending) => Frame::Integer(pending as i64),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::XAck { key, group, ids } => match db_lock.xack(&key, &group, &ids) {
+            Ok(acked) => Frame::Integer(acked as i64),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::XPending { key, group, range } => match db_lock.xgroup(&key, &group) {
+            Ok(group) => pending_frame(group, range),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::HExpire {
+            key,
+            fields,
+            at,
+            condition,
+        } => {
+            let Some(at) = at.deadline() else {
+                return Frame::error("ERR invalid expire time");
+            };
+            match db_lock.hexpire(&key, &fields, at, condition) {
+                Ok(replies) => Frame::Array(replies.into_iter().map(Frame::Integer).collect()),
+                Err(msg) => Frame::error(msg),
+            }
+        }
+        Command::HTtl {
+            key,
+            fields,
+            millis,
+        } => match db_lock.hexpiry(&key, &fields) {
+            Ok(expiries) => {
+                let now = now_millis();
+                Frame::Array(
+                    expiries
+                        .into_iter()
+                        .map(|expiry| match expiry {
+                            None => Frame::Integer(-2),
+                            Some(None) => Frame::Integer(-1),
+                            Some(Some(at)) => {
+                                let left = at.saturating_sub(now);
+                                Frame::Integer(
+                                    if millis { left } else { (left + 500) / 1000 } as i64
+                                )
+                            }
+                        })
+                        .collect(),
+                )
+            }
+            Err(msg) => Frame::error(msg),
+        },
+        Command::HExpireTime {
+            key,
+            fields,
+            millis,
+        } => match db_lock.hexpiry(&key, &fields) {
+            Ok(expiries) => Frame::Array(
+                expiries
+                    .into_iter()
+                    .map(|expiry| match expiry {
+                        None => Frame::Integer(-2),
+                        Some(None) => Frame::Integer(-1),
+                        Some(Some(at)) => {
+                            Frame::Integer(if millis { at } else { at / 1000 } as i64)
+                        }
+                    })
+                    .collect(),
+            ),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::HPersist { key, fields } => match db_lock.hpersist(&key, &fields) {
+            Ok(replies) => Frame::Array(replies.into_iter().map(Frame::Integer).collect()),
+            Err(msg) => Frame::error(msg),
+        },
+        Command::Expire { key, at, condition } => match at.deadline() {
+            Some(at) => Frame::Integer(db_lock.expire_at(&key, at, condition) as i64),
+            None => Frame::error("ERR invalid expire time"),
+        },
+        Command::Ttl { key, millis } => match db_lock.expiry(&key) {
+            None => Frame::Integer(-2),
+            Some(None) => Frame::Integer(-1),
+            Some(Some(at)) => {
+                let left = at.saturating_sub(now_millis());
+                // Rounded to the nearest second, like Redis.
+                Frame::Integer(if millis { left } else { (left + 500) / 1000 } as i64)
+            }
+        },
+        Command::ExpireTime { key, millis } => match db_lock.expiry(&key) {
+            None => Frame::Integer(-2),
+            Some(None) => Frame::Integer(-1),
+            Some(Some(at)) => Frame::Integer(if millis { at } else { at / 1000 } as i64),
+        },
+        Command::Persist { key } => Frame::Integer(db_lock.persist(&key) as i64),
+        Command::Type { key } => Frame::Simple(
+            db_lock
+                .get(&key)
+                .map_or("none", |entry| entry.data.type_name())
+                .to_string(),
+        ),
+        // Handled by the connection or run asynchronously, see
+        // `process_connection` and `execute_command`.
+        Command::Save
+        | Command::BgSave
+        | Command::LastSave
+        | Command::BgRewriteAof
+        | Command::Info { .. }
+        | Command::BPop { .. }
+        | Command::BLMove { .. }
+        | Command::Subscribe { .. }
+        | Command::Unsubscribe { .. }
+        | Command::Publish { .. }
+        | Command::PubSubChannels { .. }
+        | Command::PubSubNumSub { .. }
+        | Command::PubSubNumPat
+        | Command::Multi
+        | Command::Exec
+        | Command::Discard => Frame::error("ERR This command can't be applied here"),
+        Command::Ping { message } => match message {
+            Some(message) => Frame::bulk(message),
+            None => Frame::Simple("PONG".to_string()),
+        },
+        Command::Echo { message } => Frame::bulk(message),
+    }
+}
+
+/// Saves a snapshot of the database to disk and waits for it to be written.
+async fn save_snapshot(server: &Server) -> Frame {
+    let task = match start_bgsave(server).await {
+        Ok(task) => task,
+        Err(response) => return response,
+    };
+
+    match task.await {
+        Ok(result) => save_reply(result),
+        Err(e) => Frame::Error(format!("ERR Failed to save snapshot: {}", e)),
+    }
+}
+
+/// SAVE with the storage already locked, inside a transaction. The snapshot
+/// is written while the lock is held, so unlike `save_snapshot` this holds
+/// every other client up until it is on disk, like SAVE in Redis.
+fn save_snapshot_locked(server: &Server, db_lock: &mut Storage) -> Frame {
+    match begin_save(server, db_lock) {
+        Ok(save) => save_reply(finish_save(server, save, |changes| {
+            db_lock.mark_saved(changes)
+        })),
+        Err(response) => response,
     }
 }
 
-/// Saves a snapshot of the database to disk.
-async fn save_snapshot(db: &Db) {
-    // We lock the DB here to ensure a consistent state while saving.
+fn save_reply(result: Result<(), SnapshotError>) -> Frame {
+    match result {
+        Ok(()) => Frame::ok(),
+        Err(e) => Frame::Error(format!("ERR Failed to save snapshot: {}", e)),
+    }
+}
+
+/// Starts writing a snapshot of the database in the background. The returned
+/// task finishes once the file is on disk.
+async fn start_bgsave(server: &Server) -> Result<JoinHandle<Result<(), SnapshotError>>, Frame> {
+    let db_lock = server.db.lock().await;
+    start_bgsave_locked(server, &db_lock)
+}
+
+/// `start_bgsave` with the storage already locked.
+fn start_bgsave_locked(
+    server: &Server,
+    db_lock: &Storage,
+) -> Result<JoinHandle<Result<(), SnapshotError>>, Frame> {
+    let save = begin_save(server, db_lock)?;
+    let server = server.clone();
+    Ok(tokio::task::spawn_blocking(move || {
+        finish_save(&server, save, |changes| {
+            server.db.blocking_lock().mark_saved(changes)
+        })
+    }))
+}
+
+/// A save that has been started, waiting to be written out.
+struct PendingSave {
+    snapshot: StorageSnapshot,
+    // How many changes the snapshot holds.
+    changes: u64,
+    // With a snapshot as the base, the log only needs to hold later writes.
+    aof_to_reset: Option<Aof>,
+}
+
+/// Starts a save with the storage locked: marks it as in progress and takes
+/// the snapshot to write.
+fn begin_save(server: &Server, db_lock: &Storage) -> Result<PendingSave, Frame> {
+    let aof_to_reset = server
+        .aof
+        .clone()
+        .filter(|_| server.config.aof_use_snapshot);
+
+    let mut status = server.saves.lock().unwrap();
+    if status.in_progress {
+        return Err(Frame::error("ERR Background save already in progress"));
+    }
+    // Writes made from here on are what the log is reset to once the
+    // snapshot is down.
+    if let Some(aof) = &aof_to_reset
+        && !aof.lock().unwrap().start_rewrite()
+    {
+        return Err(Frame::error(
+            "ERR Background append only file rewriting already in progress",
+        ));
+    }
+
     println!("Saving database snapshot...");
+    status.in_progress = true;
+    status.last_attempt = now_millis() / 1000;
+    Ok(PendingSave {
+        snapshot: db_lock.snapshot(),
+        changes: db_lock.dirty(),
+        aof_to_reset,
+    })
+}
+
+/// Writes out a save started by `begin_save`. Once the file is safely on
+/// disk, `mark_saved` is given the number of changes it holds, which stop
+/// counting as unsaved.
+fn finish_save(
+    server: &Server,
+    save: PendingSave,
+    mark_saved: impl FnOnce(u64),
+) -> Result<(), SnapshotError> {
+    let result = snapshot::save(&save.snapshot, Path::new(&server.config.dbfilename));
+    drop(save.snapshot);
 
-    let mut db_lock = db.lock().await;
-    if !db_lock.is_dirty() {
-        println!("No changes detected, skipping save.");
-        return; // No changes to save
+    if result.is_ok() {
+        mark_saved(save.changes);
     }
 
-    let db_clone_for_saving = Arc::clone(db);
-    let path = DB_PATH.to_string();
+    // The log is replaced by a copy of the snapshot and the writes made
+    // since, in one rename. Like a rewrite from commands, the copy is made
+    // without the log locked.
+    if let Some(aof) = save.aof_to_reset {
+        let reset = result.is_ok() && {
+            let tmp_path = aof.lock().unwrap().rewrite_path();
+            aof::write_snapshot_rewrite(Path::new(&server.config.dbfilename), &tmp_path)
+                .and_then(|()| aof.lock().unwrap().finish_rewrite())
+                .inspect_err(|e| eprintln!("Error resetting append only file: {}", e))
+                .is_ok()
+        };
+        if !reset {
+            aof.lock().unwrap().abort_rewrite();
+        }
+    }
 
-    db_lock.clear_dirty_flag();
+    let mut status = server.saves.lock().unwrap();
+    status.in_progress = false;
+    status.last_ok = result.is_ok();
+    match &result {
+        Ok(()) => {
+            status.last_save = now_millis() / 1000;
+            println!("Database snapshot saved successfully.");
+        }
+        Err(e) => eprintln!("Error saving snapshot: {}", e),
+    }
+    result
+}
 
-    drop(db_lock);
-    let handle = tokio::task::spawn_blocking(move || {
-        // We must lock the mutex here inside the synchronous context.
-        let db_lock = db_clone_for_saving.blocking_lock();
-        let file = File::create(path).expect("Failed to create db file");
-        bincode::serialize_into(file, &*db_lock).expect("Failed to serialize db");
-    });
+/// Builds the reply to INFO. Only the persistence and keyspace sections are
+/// implemented.
+async fn info(server: &Server, section: Option<&[u8]>) -> Frame {
+    let db_lock = server.db.lock().await;
+    info_locked(server, &db_lock, section)
+}
 
-    // Wait for the saving to complete.
-    if let Err(e) = handle.await {
-        eprintln!("Error saving snapshot: {}", e);
-    } else {
-        println!("Database snapshot saved successfully.");
+/// `info` with the storage already locked.
+fn info_locked(server: &Server, db_lock: &Storage, section: Option<&[u8]>) -> Frame {
+    let wants = |name: &str| {
+        section.is_none_or(|section| {
+            [name, "default", "all", "everything"]
+                .iter()
+                .any(|name| section.eq_ignore_ascii_case(name.as_bytes()))
+        })
+    };
+
+    let changes = db_lock.dirty();
+    let keys = db_lock.len();
+    let expires = db_lock.expiring_keys();
+    let subexpiry = db_lock.hashes_with_expiring_fields();
+    let mut report = String::new();
+
+    if wants("clients") {
+        report += &format!(
+            "# Clients\r\nblocked_clients:{}\r\npubsub_clients:{}\r\n",
+            server.blocked.lock().unwrap().len(),
+            server.pubsub.lock().unwrap().subscribed_clients()
+        );
+    }
+
+    if wants("persistence") {
+        let status = server.saves.lock().unwrap();
+        let (aof_rewriting, aof_write_ok) = match &server.aof {
+            Some(aof) => {
+                let aof = aof.lock().unwrap();
+                (aof.is_rewriting(), aof.last_write_ok())
+            }
+            None => (false, true),
+        };
+        report += &format!(
+            "# Persistence\r\n\
+             rdb_changes_since_last_save:{}\r\n\
+             rdb_bgsave_in_progress:{}\r\n\
+             rdb_last_save_time:{}\r\n\
+             rdb_last_bgsave_status:{}\r\n\
+             aof_enabled:{}\r\n\
+             aof_rewrite_in_progress:{}\r\n\
+             aof_last_write_status:{}\r\n",
+            changes,
+            status.in_progress as u8,
+            status.last_save,
+            if status.last_ok { "ok" } else { "err" },
+            server.aof.is_some() as u8,
+            aof_rewriting as u8,
+            if aof_write_ok { "ok" } else { "err" },
+        );
+    }
+
+    if wants("keyspace") {
+        report += "# Keyspace\r\n";
+        if keys > 0 {
+            report += &format!(
+                "db0:keys={},expires={},subexpiry={}\r\n",
+                keys, expires, subexpiry
+            );
+        }
+    }
+
+    Frame::bulk(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Starts a server without persistence, returning its address.
+    async fn start_server() -> std::net::SocketAddr {
+        serve(Server::new(Storage::new(), None, Config::default())).await
+    }
+
+    /// Starts a server that loads its data from the files `config` names,
+    /// the way the real one does when it starts up.
+    async fn start_server_with(config: Config) -> std::net::SocketAddr {
+        let (storage, aof) = load_data(&config).unwrap();
+        serve(Server::new(storage, aof, config)).await
+    }
+
+    /// A config with the append-only file enabled, and it and the snapshot
+    /// kept in a directory of their own.
+    fn persistent_config(name: &str) -> Config {
+        let dir =
+            std::env::temp_dir().join(format!("iron_cache_server_{}_{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |file: &str| dir.join(file).to_string_lossy().into_owned();
+        Config {
+            dbfilename: path("dump.db"),
+            save: Vec::new(),
+            appendonly: true,
+            appendfilename: path("appendonly.aof"),
+            appendfsync: aof::FsyncPolicy::Always,
+            ..Config::default()
+        }
+    }
+
+    async fn serve(server: Server) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(process_connection(socket, server.clone()));
+            }
+        });
+        addr
+    }
+
+    /// Sends each inline command and reads back its whole reply.
+    async fn send(socket: &mut TcpStream, commands: &[&str], reply: &str) {
+        let mut request = String::new();
+        for command in commands {
+            request.push_str(command);
+            request.push_str("\r\n");
+        }
+        socket.write_all(request.as_bytes()).await.unwrap();
+
+        let mut buf = vec![0; reply.len()];
+        socket.read_exact(&mut buf).await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&buf), reply);
+    }
+
+    async fn read_line(socket: &mut TcpStream) -> String {
+        let mut line = Vec::new();
+        while !line.ends_with(b"\r\n") {
+            line.push(socket.read_u8().await.unwrap());
+        }
+        String::from_utf8(line).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_exec_returns_replies() {
+        let mut socket = TcpStream::connect(start_server().await).await.unwrap();
+
+        send(
+            &mut socket,
+            &["MULTI", "SET k 1", "INCR k", "GET k"],
+            "+OK\r\n+QUEUED\r\n+QUEUED\r\n+QUEUED\r\n",
+        )
+        .await;
+        send(&mut socket, &["EXEC"], "*3\r\n+OK\r\n:2\r\n$1\r\n2\r\n").await;
+        // The connection is out of the transaction again.
+        send(&mut socket, &["GET k"], "$1\r\n2\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn test_exec_serves_blocked_clients_after_the_last_command() {
+        let addr = start_server().await;
+        let mut blocked = TcpStream::connect(addr).await.unwrap();
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+
+        blocked.write_all(b"BLPOP q 0\r\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // The push is still there for the rest of the transaction to see.
+        send(
+            &mut socket,
+            &["MULTI", "RPUSH q a", "LLEN q", "EXEC"],
+            "+OK\r\n+QUEUED\r\n+QUEUED\r\n*2\r\n:1\r\n:1\r\n",
+        )
+        .await;
+        send(&mut blocked, &[], "*2\r\n$1\r\nq\r\n$1\r\na\r\n").await;
+        send(&mut socket, &["LLEN q"], ":0\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn test_exec_runtime_error_keeps_the_rest() {
+        let mut socket = TcpStream::connect(start_server().await).await.unwrap();
+
+        send(
+            &mut socket,
+            &["MULTI", "SET k v", "INCR k", "SET other 1"],
+            "+OK\r\n+QUEUED\r\n+QUEUED\r\n+QUEUED\r\n",
+        )
+        .await;
+        send(
+            &mut socket,
+            &["EXEC"],
+            "*3\r\n+OK\r\n-ERR value is not an integer or out of range\r\n+OK\r\n",
+        )
+        .await;
+        send(
+            &mut socket,
+            &["GET k", "GET other"],
+            "$1\r\nv\r\n$1\r\n1\r\n",
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_exec_aborts_after_queueing_error() {
+        let mut socket = TcpStream::connect(start_server().await).await.unwrap();
+
+        send(&mut socket, &["MULTI", "SET k v"], "+OK\r\n+QUEUED\r\n").await;
+        socket.write_all(b"SET k\r\n").await.unwrap();
+        assert!(read_line(&mut socket).await.starts_with("-ERR"));
+        send(
+            &mut socket,
+            &["EXEC"],
+            "-EXECABORT Transaction discarded because of previous errors.\r\n",
+        )
+        .await;
+        send(&mut socket, &["GET k"], "$-1\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn test_exec_times_relative_expiry_from_exec() {
+        let mut socket = TcpStream::connect(start_server().await).await.unwrap();
+
+        send(
+            &mut socket,
+            &["SET k v", "MULTI", "PEXPIRE k 500"],
+            "+OK\r\n+OK\r\n+QUEUED\r\n",
+        )
+        .await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        send(&mut socket, &["EXEC"], "*1\r\n:1\r\n").await;
+
+        socket.write_all(b"PTTL k\r\n").await.unwrap();
+        let left: i64 = read_line(&mut socket).await[1..].trim().parse().unwrap();
+        assert!(left > 400, "{} ms left", left);
+    }
+
+    #[tokio::test]
+    async fn test_exec_times_xclaim_idle_from_exec() {
+        let mut socket = TcpStream::connect(start_server().await).await.unwrap();
+
+        send(
+            &mut socket,
+            &["XADD s 1-0 f v", "XGROUP CREATE s g 0"],
+            "$3\r\n1-0\r\n+OK\r\n",
+        )
+        .await;
+        send(
+            &mut socket,
+            &["XREADGROUP GROUP g alice STREAMS s >"],
+            "*1\r\n*2\r\n$1\r\ns\r\n*1\r\n*2\r\n$3\r\n1-0\r\n*2\r\n$1\r\nf\r\n$1\r\nv\r\n",
+        )
+        .await;
+        send(
+            &mut socket,
+            &["MULTI", "XCLAIM s g bob 0 1-0 IDLE 500 JUSTID"],
+            "+OK\r\n+QUEUED\r\n",
+        )
+        .await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        send(&mut socket, &["EXEC"], "*1\r\n*1\r\n$3\r\n1-0\r\n").await;
+
+        // XPENDING's extended form replies with the ID, the consumer, the
+        // idle time and the delivery count.
+        send(
+            &mut socket,
+            &["XPENDING s g - + 10"],
+            "*1\r\n*4\r\n$3\r\n1-0\r\n$3\r\nbob\r\n",
+        )
+        .await;
+        let idle: u64 = read_line(&mut socket).await[1..].trim().parse().unwrap();
+        assert!((500..650).contains(&idle), "idle for {} ms", idle);
+        send(&mut socket, &[], ":1\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn test_restart_keeps_keys_expired() {
+        let config = persistent_config("expired");
+        let mut socket = TcpStream::connect(start_server_with(config.clone()).await)
+            .await
+            .unwrap();
+
+        send(
+            &mut socket,
+            &["SET k 5 PX 300", "INCR k", "SET j 5 PX 300", "INCR j"],
+            "+OK\r\n:6\r\n+OK\r\n:6\r\n",
+        )
+        .await;
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        // `j` is found expired and recreated before the restart, `k` is
+        // only found expired after it.
+        send(&mut socket, &["GET j", "INCR j"], "$-1\r\n:1\r\n").await;
+
+        let mut socket = TcpStream::connect(start_server_with(config).await)
+            .await
+            .unwrap();
+        send(
+            &mut socket,
+            &["GET k", "PTTL k", "GET j", "PTTL j"],
+            "$-1\r\n:-2\r\n$1\r\n1\r\n:-1\r\n",
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_restart_replays_writes_to_keys_expired_since() {
+        let config = persistent_config("lset");
+        let mut socket = TcpStream::connect(start_server_with(config.clone()).await)
+            .await
+            .unwrap();
+
+        send(
+            &mut socket,
+            &["RPUSH l a", "PEXPIRE l 300", "LSET l 0 b"],
+            ":1\r\n:1\r\n+OK\r\n",
+        )
+        .await;
+        tokio::time::sleep(Duration::from_millis(400)).await;
+
+        let mut socket = TcpStream::connect(start_server_with(config).await)
+            .await
+            .unwrap();
+        send(&mut socket, &["LLEN l"], ":0\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn test_restart_after_rewrite_keeps_expiry() {
+        let config = persistent_config("rewrite");
+        let mut socket = TcpStream::connect(start_server_with(config.clone()).await)
+            .await
+            .unwrap();
+
+        send(
+            &mut socket,
+            &[
+                "SET k v PX 100000",
+                "SET gone v PX 300",
+                "HSET h f v g v",
+                "HPEXPIRE h 100000 FIELDS 1 f",
+                "BGREWRITEAOF",
+            ],
+            "+OK\r\n+OK\r\n:2\r\n*1\r\n:1\r\n\
+             +Background append only file rewriting started\r\n",
+        )
+        .await;
+        loop {
+            socket.write_all(b"INFO persistence\r\n").await.unwrap();
+            let len: usize = read_line(&mut socket).await[1..].trim().parse().unwrap();
+            let mut info = vec![0; len + 2];
+            socket.read_exact(&mut info).await.unwrap();
+            if String::from_utf8(info)
+                .unwrap()
+                .contains("aof_rewrite_in_progress:0")
+            {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        // Written after the rewrite, on top of it.
+        send(&mut socket, &["SET after v PX 100000"], "+OK\r\n").await;
+        tokio::time::sleep(Duration::from_millis(400)).await;
+
+        let mut socket = TcpStream::connect(start_server_with(config).await)
+            .await
+            .unwrap();
+        send(
+            &mut socket,
+            &["GET gone", "GET k", "GET after", "HGETALL h"],
+            "$-1\r\n$1\r\nv\r\n$1\r\nv\r\n*4\r\n$1\r\nf\r\n$1\r\nv\r\n$1\r\ng\r\n$1\r\nv\r\n",
+        )
+        .await;
+        for ttl in ["PTTL k", "PTTL after", "HPTTL h FIELDS 1 f"] {
+            socket
+                .write_all(format!("{}\r\n", ttl).as_bytes())
+                .await
+                .unwrap();
+            let mut reply = read_line(&mut socket).await;
+            if reply.starts_with('*') {
+                reply = read_line(&mut socket).await;
+            }
+            let left: i64 = reply[1..].trim().parse().unwrap();
+            assert!(left > 90000 && left <= 100000, "{}: {} ms left", ttl, left);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_restart_with_log_started_from_snapshot() {
+        let config = Config {
+            aof_use_snapshot: true,
+            ..persistent_config("use_snapshot")
+        };
+        let mut socket = TcpStream::connect(start_server_with(config.clone()).await)
+            .await
+            .unwrap();
+
+        send(
+            &mut socket,
+            &["RPUSH l a", "SAVE", "RPUSH l b"],
+            ":1\r\n+OK\r\n:2\r\n",
+        )
+        .await;
+
+        // The log holds the snapshot, so it alone is enough to restart from.
+        std::fs::remove_file(&config.dbfilename).unwrap();
+        let mut socket = TcpStream::connect(start_server_with(config).await)
+            .await
+            .unwrap();
+        send(
+            &mut socket,
+            &["LRANGE l 0 -1"],
+            "*2\r\n$1\r\na\r\n$1\r\nb\r\n",
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_discard() {
+        let mut socket = TcpStream::connect(start_server().await).await.unwrap();
+
+        send(
+            &mut socket,
+            &["MULTI", "SET k v", "DISCARD"],
+            "+OK\r\n+QUEUED\r\n+OK\r\n",
+        )
+        .await;
+        send(&mut socket, &["GET k"], "$-1\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn test_exec_and_discard_without_multi() {
+        let mut socket = TcpStream::connect(start_server().await).await.unwrap();
+
+        send(
+            &mut socket,
+            &["EXEC", "DISCARD"],
+            "-ERR EXEC without MULTI\r\n-ERR DISCARD without MULTI\r\n",
+        )
+        .await;
     }
 }
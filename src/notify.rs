@@ -0,0 +1,180 @@
+//! Keyspace notifications: pub/sub messages published when a key changes,
+//! on `__keyspace@0__:<key>` with the event as the message, and on
+//! `__keyevent@0__:<event>` with the key as the message.
+//!
+//! `Storage` records an event for every change as it makes it, and the
+//! server publishes them once the command is done, with the storage still
+//! locked so they go out in the order the changes were made. Which events
+//! are recorded is configured like Redis' `notify-keyspace-events`.
+
+use std::str::FromStr;
+
+/// The kinds of events notifications can be enabled for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventClass {
+    /// Changes any type of key can go through: `del`, `expire`, `persist`.
+    Generic,
+    String,
+    List,
+    Set,
+    Hash,
+    SortedSet,
+    Stream,
+    /// A key removed because it expired, whether found on access or by the
+    /// background expiry.
+    Expired,
+    /// A key removed to free memory. There is no memory limit to enforce, so
+    /// none are, but the flag is accepted like in Redis.
+    Evicted,
+}
+
+impl EventClass {
+    // The `notify-keyspace-events` flags of each class, `A` enables them all.
+    const FLAGS: [(char, EventClass); 9] = [
+        ('g', EventClass::Generic),
+        ('$', EventClass::String),
+        ('l', EventClass::List),
+        ('s', EventClass::Set),
+        ('h', EventClass::Hash),
+        ('z', EventClass::SortedSet),
+        ('t', EventClass::Stream),
+        ('x', EventClass::Expired),
+        ('e', EventClass::Evicted),
+    ];
+
+    fn bit(self) -> u16 {
+        1 << self as u16
+    }
+}
+
+/// A change to a key, waiting to be published.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyEvent {
+    pub class: EventClass,
+    /// What happened, usually the name of the command that did it, e.g.
+    /// `set`, `lpush` or `expired`.
+    pub event: &'static str,
+    pub key: Vec<u8>,
+}
+
+/// Which notifications to publish. Nothing is, by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct NotifyConfig {
+    keyspace: bool,
+    keyevent: bool,
+    classes: u16,
+}
+
+impl NotifyConfig {
+    /// Whether events of `class` are published on any channel.
+    pub fn is_enabled(&self, class: EventClass) -> bool {
+        (self.keyspace || self.keyevent) && self.classes & class.bit() != 0
+    }
+
+    /// The `(channel, message)` pairs to publish `event` as.
+    pub fn messages(&self, event: &KeyEvent) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut messages = Vec::new();
+        if !self.is_enabled(event.class) {
+            return messages;
+        }
+
+        if self.keyspace {
+            let mut channel = b"__keyspace@0__:".to_vec();
+            channel.extend_from_slice(&event.key);
+            messages.push((channel, event.event.as_bytes().to_vec()));
+        }
+        if self.keyevent {
+            let channel = format!("__keyevent@0__:{}", event.event).into_bytes();
+            messages.push((channel, event.key.clone()));
+        }
+        messages
+    }
+}
+
+impl FromStr for NotifyConfig {
+    type Err = String;
+
+    /// Parses Redis' flags: `K` and `E` for the keyspace and keyevent
+    /// channels, a letter for each class, and `A` for all of them.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = NotifyConfig::default();
+        for flag in s.chars() {
+            match flag {
+                'K' => config.keyspace = true,
+                'E' => config.keyevent = true,
+                'A' => {
+                    for (_, class) in EventClass::FLAGS {
+                        config.classes |= class.bit();
+                    }
+                }
+                flag => {
+                    let (_, class) = EventClass::FLAGS
+                        .into_iter()
+                        .find(|&(class_flag, _)| class_flag == flag)
+                        .ok_or_else(|| {
+                            format!(
+                                "Invalid keyspace notification flag '{}', expected some of \
+                                 K, E, g, $, l, s, h, z, t, x, e and A",
+                                flag
+                            )
+                        })?;
+                    config.classes |= class.bit();
+                }
+            }
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(class: EventClass, event: &'static str, key: &str) -> KeyEvent {
+        KeyEvent {
+            class,
+            event,
+            key: key.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_parse_flags() {
+        let config: NotifyConfig = "Elx".parse().unwrap();
+        assert!(config.is_enabled(EventClass::List));
+        assert!(config.is_enabled(EventClass::Expired));
+        assert!(!config.is_enabled(EventClass::String));
+
+        // Classes without a channel to publish on are off.
+        let config: NotifyConfig = "A".parse().unwrap();
+        assert!(!config.is_enabled(EventClass::Generic));
+        let config: NotifyConfig = "KA".parse().unwrap();
+        assert!(config.is_enabled(EventClass::Evicted));
+
+        assert_eq!("".parse::<NotifyConfig>().unwrap(), NotifyConfig::default());
+        assert!("Kq".parse::<NotifyConfig>().is_err());
+    }
+
+    #[test]
+    fn test_messages() {
+        let config: NotifyConfig = "KEg$".parse().unwrap();
+        assert_eq!(
+            config.messages(&event(EventClass::String, "set", "k")),
+            [
+                (b"__keyspace@0__:k".to_vec(), b"set".to_vec()),
+                (b"__keyevent@0__:set".to_vec(), b"k".to_vec()),
+            ]
+        );
+        assert!(
+            config
+                .messages(&event(EventClass::Hash, "hset", "k"))
+                .is_empty()
+        );
+
+        let config: NotifyConfig = "Eg".parse().unwrap();
+        assert_eq!(
+            config.messages(&event(EventClass::Generic, "del", "k")),
+            [(b"__keyevent@0__:del".to_vec(), b"k".to_vec())]
+        );
+    }
+}
@@ -0,0 +1,629 @@
+//! Publish/subscribe: clients subscribed to channels, or to glob-style
+//! patterns of channel names, and the messages published to them.
+//!
+//! A published message is encoded once and queued for every receiver, whose
+//! own connection task writes it out, so a publisher never waits on a slow
+//! subscriber. So that a subscriber that doesn't read its messages can't
+//! grow its queue without bound, it is disconnected once the queue goes over
+//! the hard limit, or stays over the soft limit for too long, like Redis'
+//! `client-output-buffer-limit pubsub`.
+
+use crate::resp::Frame;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// An encoded message, shared by every client it is queued for.
+pub type Message = Arc<[u8]>;
+
+/// How many bytes of messages may be queued for a subscriber. A limit of 0
+/// is disabled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BufferLimit {
+    /// Disconnect as soon as more than this is queued.
+    pub hard: u64,
+    /// Disconnect once more than this has been queued for `soft_seconds`.
+    pub soft: u64,
+    pub soft_seconds: u64,
+}
+
+impl Default for BufferLimit {
+    // The same as Redis' defaults for pub/sub clients.
+    fn default() -> Self {
+        BufferLimit {
+            hard: 32 * 1024 * 1024,
+            soft: 8 * 1024 * 1024,
+            soft_seconds: 60,
+        }
+    }
+}
+
+// What a client's connection and the registry share about its queue.
+#[derive(Default)]
+struct Queue {
+    // Bytes queued but not taken out by the connection yet.
+    bytes: AtomicU64,
+    // Set when the client went over its limit. Whatever is still queued is
+    // dropped rather than written out.
+    dropped: AtomicBool,
+}
+
+// A client as the registry sees it.
+struct Client {
+    channels: HashSet<Vec<u8>>,
+    patterns: HashSet<Vec<u8>>,
+    sender: mpsc::UnboundedSender<Message>,
+    queue: Arc<Queue>,
+    // When the queue went over the soft limit, if it still is. Only checked
+    // as messages are queued, which is the only time the queue grows.
+    over_soft_since: Option<Instant>,
+}
+
+impl Client {
+    fn subscriptions(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    // Queues `message`, unless that takes the client over `limit`. Returns
+    // false if the client has to be disconnected.
+    fn send(&mut self, message: &Message, limit: &BufferLimit) -> bool {
+        let size = message.len() as u64;
+        let queued = self.queue.bytes.load(Ordering::Relaxed) + size;
+
+        if limit.hard > 0 && queued > limit.hard {
+            return false;
+        }
+        if limit.soft > 0 && queued > limit.soft {
+            let since = *self.over_soft_since.get_or_insert_with(Instant::now);
+            if since.elapsed() >= Duration::from_secs(limit.soft_seconds) {
+                return false;
+            }
+        } else {
+            self.over_soft_since = None;
+        }
+
+        self.queue.bytes.fetch_add(size, Ordering::Relaxed);
+        // The connection may be on its way out, and takes the client with it.
+        let _ = self.sender.send(message.clone());
+        true
+    }
+}
+
+/// Every client's subscriptions.
+pub struct PubSub {
+    limit: BufferLimit,
+    next_id: u64,
+    clients: HashMap<u64, Client>,
+    // The clients subscribed to each channel, and to each pattern.
+    channels: HashMap<Vec<u8>, HashSet<u64>>,
+    patterns: HashMap<Vec<u8>, HashSet<u64>>,
+}
+
+impl PubSub {
+    pub fn new(limit: BufferLimit) -> Self {
+        PubSub {
+            limit,
+            next_id: 0,
+            clients: HashMap::new(),
+            channels: HashMap::new(),
+            patterns: HashMap::new(),
+        }
+    }
+
+    /// Sends `message` to the clients subscribed to `channel`, and to those
+    /// subscribed to a pattern matching it, once for each such pattern.
+    /// Returns how many times it was sent. Clients it would take over their
+    /// buffer limit are disconnected instead, but still counted.
+    pub fn publish(&mut self, channel: &[u8], message: &[u8]) -> usize {
+        let mut receivers = 0;
+        let mut too_slow = Vec::new();
+
+        if let Some(ids) = self.channels.get(channel) {
+            let encoded = encode(vec![
+                Frame::bulk("message"),
+                Frame::bulk(channel),
+                Frame::bulk(message),
+            ]);
+            for id in ids {
+                receivers += 1;
+                let client = self.clients.get_mut(id).expect("subscribers are clients");
+                if !client.send(&encoded, &self.limit) {
+                    too_slow.push(*id);
+                }
+            }
+        }
+
+        for (pattern, ids) in &self.patterns {
+            if !glob_match(pattern, channel) {
+                continue;
+            }
+            let encoded = encode(vec![
+                Frame::bulk("pmessage"),
+                Frame::bulk(pattern.as_slice()),
+                Frame::bulk(channel),
+                Frame::bulk(message),
+            ]);
+            for id in ids {
+                receivers += 1;
+                let client = self.clients.get_mut(id).expect("subscribers are clients");
+                if !client.send(&encoded, &self.limit) {
+                    too_slow.push(*id);
+                }
+            }
+        }
+
+        for id in too_slow {
+            if let Some(client) = self.remove_client(id) {
+                client.queue.dropped.store(true, Ordering::Relaxed);
+            }
+        }
+        receivers
+    }
+
+    /// The channels with at least one subscriber, only those matching
+    /// `pattern` if one is given. Pattern subscriptions aren't included.
+    pub fn channels(&self, pattern: Option<&[u8]>) -> Vec<Vec<u8>> {
+        self.channels
+            .keys()
+            .filter(|channel| pattern.is_none_or(|pattern| glob_match(pattern, channel)))
+            .cloned()
+            .collect()
+    }
+
+    /// The number of clients subscribed to `channel`, not counting those
+    /// subscribed to a pattern matching it.
+    pub fn numsub(&self, channel: &[u8]) -> usize {
+        self.channels.get(channel).map_or(0, HashSet::len)
+    }
+
+    /// The number of distinct patterns subscribed to.
+    pub fn numpat(&self) -> usize {
+        self.patterns.len()
+    }
+
+    /// The number of clients subscribed to at least one channel or pattern.
+    pub fn subscribed_clients(&self) -> usize {
+        self.clients
+            .values()
+            .filter(|client| client.subscriptions() > 0)
+            .count()
+    }
+
+    fn add_client(&mut self) -> (u64, mpsc::UnboundedReceiver<Message>, Arc<Queue>) {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let queue = Arc::new(Queue::default());
+        self.clients.insert(
+            id,
+            Client {
+                channels: HashSet::new(),
+                patterns: HashSet::new(),
+                sender,
+                queue: queue.clone(),
+                over_soft_since: None,
+            },
+        );
+        (id, receiver, queue)
+    }
+
+    // Subscribes client `id` to a channel, or a pattern. Returns how many
+    // subscriptions the client has now.
+    fn subscribe(&mut self, id: u64, name: &[u8], pattern: bool) -> usize {
+        // Already disconnected for going over its limit.
+        let Some(client) = self.clients.get_mut(&id) else {
+            return 0;
+        };
+        let (names, subscribers) = if pattern {
+            (&mut client.patterns, &mut self.patterns)
+        } else {
+            (&mut client.channels, &mut self.channels)
+        };
+
+        if names.insert(name.to_vec()) {
+            subscribers.entry(name.to_vec()).or_default().insert(id);
+        }
+        client.subscriptions()
+    }
+
+    // The opposite of `subscribe`.
+    fn unsubscribe(&mut self, id: u64, name: &[u8], pattern: bool) -> usize {
+        let Some(client) = self.clients.get_mut(&id) else {
+            return 0;
+        };
+        let (names, subscribers) = if pattern {
+            (&mut client.patterns, &mut self.patterns)
+        } else {
+            (&mut client.channels, &mut self.channels)
+        };
+
+        if names.remove(name) {
+            forget(subscribers, name, id);
+        }
+        client.subscriptions()
+    }
+
+    // The channels, or patterns, client `id` is subscribed to, sorted.
+    fn subscriptions_of(&self, id: u64, pattern: bool) -> Vec<Vec<u8>> {
+        let Some(client) = self.clients.get(&id) else {
+            return Vec::new();
+        };
+        let names = if pattern {
+            &client.patterns
+        } else {
+            &client.channels
+        };
+
+        let mut names: Vec<_> = names.iter().cloned().collect();
+        names.sort();
+        names
+    }
+
+    fn remove_client(&mut self, id: u64) -> Option<Client> {
+        let client = self.clients.remove(&id)?;
+        for channel in &client.channels {
+            forget(&mut self.channels, channel, id);
+        }
+        for pattern in &client.patterns {
+            forget(&mut self.patterns, pattern, id);
+        }
+        Some(client)
+    }
+}
+
+// Removes client `id` from the subscribers of `name`, and `name` with it if
+// that was the last one.
+fn forget(subscribers: &mut HashMap<Vec<u8>, HashSet<u64>>, name: &[u8], id: u64) {
+    if let Some(ids) = subscribers.get_mut(name) {
+        ids.remove(&id);
+        if ids.is_empty() {
+            subscribers.remove(name);
+        }
+    }
+}
+
+fn encode(message: Vec<Frame>) -> Message {
+    Frame::Array(message).to_bytes().into()
+}
+
+/// A connection's side of its subscriptions. Messages published to them
+/// arrive through `recv`, and dropping it unsubscribes from everything.
+pub struct Subscriber {
+    id: u64,
+    registry: Arc<Mutex<PubSub>>,
+    receiver: mpsc::UnboundedReceiver<Message>,
+    queue: Arc<Queue>,
+}
+
+impl Subscriber {
+    pub fn new(registry: Arc<Mutex<PubSub>>) -> Self {
+        let (id, receiver, queue) = registry.lock().unwrap().add_client();
+        Subscriber {
+            id,
+            registry,
+            receiver,
+            queue,
+        }
+    }
+
+    /// Waits for the next message to write out to the client. Returns `None`
+    /// once it has been disconnected for going over its buffer limit.
+    pub async fn recv(&mut self) -> Option<Message> {
+        let message = self.receiver.recv().await?;
+        if self.queue.dropped.load(Ordering::Relaxed) {
+            return None;
+        }
+        self.queue
+            .bytes
+            .fetch_sub(message.len() as u64, Ordering::Relaxed);
+        Some(message)
+    }
+
+    /// Whether the client is subscribed to any channel or pattern.
+    pub fn is_subscribed(&self) -> bool {
+        let registry = self.registry.lock().unwrap();
+        registry
+            .clients
+            .get(&self.id)
+            .is_some_and(|client| client.subscriptions() > 0)
+    }
+
+    /// SUBSCRIBE, or PSUBSCRIBE when `patterns` is set. Returns the reply
+    /// for each channel.
+    pub fn subscribe(&self, channels: Vec<Vec<u8>>, patterns: bool) -> Vec<Frame> {
+        let kind = if patterns { "psubscribe" } else { "subscribe" };
+        let mut registry = self.registry.lock().unwrap();
+
+        channels
+            .into_iter()
+            .map(|channel| {
+                let count = registry.subscribe(self.id, &channel, patterns);
+                reply(kind, Frame::Bulk(channel), count)
+            })
+            .collect()
+    }
+
+    /// UNSUBSCRIBE, or PUNSUBSCRIBE when `patterns` is set, from every
+    /// channel subscribed to if none are given. Returns the reply for each
+    /// channel.
+    pub fn unsubscribe(&self, channels: Vec<Vec<u8>>, patterns: bool) -> Vec<Frame> {
+        let kind = if patterns {
+            "punsubscribe"
+        } else {
+            "unsubscribe"
+        };
+        let mut registry = self.registry.lock().unwrap();
+
+        let channels = if channels.is_empty() {
+            registry.subscriptions_of(self.id, patterns)
+        } else {
+            channels
+        };
+        if channels.is_empty() {
+            let count = self.count(&registry);
+            return vec![reply(kind, Frame::Null, count)];
+        }
+
+        channels
+            .into_iter()
+            .map(|channel| {
+                let count = registry.unsubscribe(self.id, &channel, patterns);
+                reply(kind, Frame::Bulk(channel), count)
+            })
+            .collect()
+    }
+
+    fn count(&self, registry: &PubSub) -> usize {
+        registry
+            .clients
+            .get(&self.id)
+            .map_or(0, Client::subscriptions)
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        self.registry.lock().unwrap().remove_client(self.id);
+    }
+}
+
+fn reply(kind: &str, channel: Frame, count: usize) -> Frame {
+    Frame::Array(vec![
+        Frame::bulk(kind),
+        channel,
+        Frame::Integer(count as i64),
+    ])
+}
+
+/// Whether `string` matches the glob-style `pattern`, with Redis' rules: `*`
+/// matches any run of bytes, `?` any one byte, `[abc]` and `[a-z]` one byte
+/// in the set, `[^abc]` one byte not in it, and `\` escapes the next byte.
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // Where the pattern continues after the last `*`, and the position in
+    // the string it would have to match from if the `*` took one more byte.
+    let mut backtrack = None;
+
+    while s < string.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            backtrack = Some((p, s + 1));
+            continue;
+        }
+        if p < pattern.len()
+            && let Some(next) = match_one(pattern, p, string[s])
+        {
+            p = next;
+            s += 1;
+            continue;
+        }
+        match backtrack {
+            Some((after_star, from)) => {
+                p = after_star;
+                s = from;
+                backtrack = Some((after_star, from + 1));
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+// Matches `byte` against the pattern element starting at `p`, which isn't a
+// `*`. Returns where the next element starts if it matches.
+fn match_one(pattern: &[u8], p: usize, byte: u8) -> Option<usize> {
+    match pattern[p] {
+        b'?' => Some(p + 1),
+        b'\\' if p + 1 < pattern.len() => (pattern[p + 1] == byte).then_some(p + 2),
+        b'[' => {
+            let mut i = p + 1;
+            let negated = pattern.get(i) == Some(&b'^');
+            if negated {
+                i += 1;
+            }
+
+            let mut matched = false;
+            while i < pattern.len() && pattern[i] != b']' {
+                if pattern[i] == b'\\' && i + 1 < pattern.len() {
+                    matched |= pattern[i + 1] == byte;
+                    i += 2;
+                } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' {
+                    let (low, high) = if pattern[i] <= pattern[i + 2] {
+                        (pattern[i], pattern[i + 2])
+                    } else {
+                        (pattern[i + 2], pattern[i])
+                    };
+                    matched |= (low..=high).contains(&byte);
+                    i += 3;
+                } else {
+                    matched |= pattern[i] == byte;
+                    i += 1;
+                }
+            }
+
+            // An unterminated set runs to the end of the pattern.
+            (matched != negated).then_some((i + 1).min(pattern.len()))
+        }
+        literal => (literal == byte).then_some(p + 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry(limit: BufferLimit) -> Arc<Mutex<PubSub>> {
+        Arc::new(Mutex::new(PubSub::new(limit)))
+    }
+
+    fn names(names: &[&str]) -> Vec<Vec<u8>> {
+        names.iter().map(|name| name.as_bytes().to_vec()).collect()
+    }
+
+    fn message(parts: &[&str]) -> Message {
+        encode(parts.iter().map(|part| Frame::bulk(*part)).collect())
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"news.*", b"news.tech"));
+        assert!(!glob_match(b"news.*", b"sport.news"));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(!glob_match(b"h?llo", b"hllo"));
+        assert!(glob_match(b"h*llo*", b"heeeello world"));
+        assert!(glob_match(b"*a*b", b"xaxxab"));
+        assert!(!glob_match(b"*a*b", b"xaxxa"));
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[ae]llo", b"hillo"));
+        assert!(glob_match(b"h[^e]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-c]llo", b"hbllo"));
+        assert!(glob_match(b"h[c-a]llo", b"hbllo"));
+        assert!(glob_match(b"a\\*b", b"a*b"));
+        assert!(!glob_match(b"a\\*b", b"axb"));
+        assert!(glob_match(b"[\\]]", b"]"));
+    }
+
+    #[tokio::test]
+    async fn test_publish_to_channels_and_patterns() {
+        let registry = registry(BufferLimit::default());
+        let mut first = Subscriber::new(registry.clone());
+        let second = Subscriber::new(registry.clone());
+
+        first.subscribe(names(&["news"]), false);
+        first.subscribe(names(&["n*", "*s"]), true);
+        second.subscribe(names(&["sport"]), false);
+
+        assert_eq!(registry.lock().unwrap().publish(b"news", b"hi"), 3);
+        assert_eq!(
+            first.recv().await.unwrap(),
+            message(&["message", "news", "hi"])
+        );
+        let mut pmessages = vec![first.recv().await.unwrap(), first.recv().await.unwrap()];
+        pmessages.sort();
+        assert_eq!(
+            pmessages,
+            [
+                message(&["pmessage", "*s", "news", "hi"]),
+                message(&["pmessage", "n*", "news", "hi"]),
+            ]
+        );
+        assert_eq!(registry.lock().unwrap().publish(b"weather", b"hi"), 0);
+
+        let registry_lock = registry.lock().unwrap();
+        assert_eq!(registry_lock.numsub(b"news"), 1);
+        assert_eq!(registry_lock.numpat(), 2);
+        let mut channels = registry_lock.channels(None);
+        channels.sort();
+        assert_eq!(channels, names(&["news", "sport"]));
+        assert_eq!(registry_lock.channels(Some(b"s*")), names(&["sport"]));
+        assert_eq!(registry_lock.subscribed_clients(), 2);
+    }
+
+    #[test]
+    fn test_unsubscribe() {
+        let registry = registry(BufferLimit::default());
+        let subscriber = Subscriber::new(registry.clone());
+
+        assert_eq!(
+            subscriber.subscribe(names(&["a", "b", "a"]), false),
+            [
+                reply("subscribe", Frame::bulk("a"), 1),
+                reply("subscribe", Frame::bulk("b"), 2),
+                reply("subscribe", Frame::bulk("a"), 2),
+            ]
+        );
+        subscriber.subscribe(names(&["p*"]), true);
+        assert_eq!(
+            subscriber.unsubscribe(Vec::new(), false),
+            [
+                reply("unsubscribe", Frame::bulk("a"), 2),
+                reply("unsubscribe", Frame::bulk("b"), 1),
+            ]
+        );
+        assert!(subscriber.is_subscribed());
+        assert_eq!(
+            subscriber.unsubscribe(Vec::new(), false),
+            [reply("unsubscribe", Frame::Null, 1)]
+        );
+
+        drop(subscriber);
+        let registry = registry.lock().unwrap();
+        assert_eq!(registry.numpat(), 0);
+        assert!(registry.clients.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_slow_subscribers_are_disconnected() {
+        let limit = BufferLimit {
+            hard: 100,
+            soft: 0,
+            soft_seconds: 0,
+        };
+        let registry = registry(limit);
+        let mut slow = Subscriber::new(registry.clone());
+        let mut fast = Subscriber::new(registry.clone());
+        slow.subscribe(names(&["c"]), false);
+        fast.subscribe(names(&["c"]), false);
+
+        let payload = "x".repeat(10);
+        for _ in 0..3 {
+            assert_eq!(
+                registry.lock().unwrap().publish(b"c", payload.as_bytes()),
+                2
+            );
+            assert!(fast.recv().await.is_some());
+        }
+
+        // The third message would have taken it over the limit.
+        assert!(slow.recv().await.is_none());
+        assert_eq!(registry.lock().unwrap().numsub(b"c"), 1);
+        assert!(!slow.is_subscribed());
+    }
+
+    #[tokio::test]
+    async fn test_soft_limit() {
+        let limit = BufferLimit {
+            hard: 0,
+            soft: 10,
+            soft_seconds: 0,
+        };
+        let registry = registry(limit);
+        let mut subscriber = Subscriber::new(registry.clone());
+        subscriber.subscribe(names(&["c"]), false);
+
+        assert_eq!(
+            registry
+                .lock()
+                .unwrap()
+                .publish(b"c", b"x".repeat(20).as_slice()),
+            1
+        );
+        assert!(subscriber.recv().await.is_none());
+    }
+}
@@ -0,0 +1,996 @@
+//! Reading and writing Redis RDB files, so data can be moved between Redis and
+//! IronCache.
+//!
+//! Strings, lists, hashes, sets and sorted sets are read in every encoding
+//! Redis has used for them (ziplists, listpacks, quicklists, zipmaps, intsets
+//! and LZF compressed strings).
+//! Exports use plain encodings that Redis 5.0 and later can load. Those have
+//! no place for streams or the expiry of hash fields, which are left out.
+
+use crate::crc64;
+use crate::snapshot;
+use crate::sorted_set::SortedSet;
+use crate::storage::{FieldMap, HashValue, Members, Storage, StoreValue, Value, now_millis};
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 5] = b"REDIS";
+/// The newest RDB version that can be imported (Redis 7.4).
+pub const MAX_VERSION: u32 = 12;
+// Old enough for any Redis still in use to read.
+const EXPORT_VERSION: u32 = 9;
+
+const OPCODE_SLOT_INFO: u8 = 0xf4;
+const OPCODE_FUNCTION2: u8 = 0xf5;
+const OPCODE_FUNCTION_PRE_GA: u8 = 0xf6;
+const OPCODE_MODULE_AUX: u8 = 0xf7;
+const OPCODE_IDLE: u8 = 0xf8;
+const OPCODE_FREQ: u8 = 0xf9;
+const OPCODE_AUX: u8 = 0xfa;
+const OPCODE_RESIZEDB: u8 = 0xfb;
+const OPCODE_EXPIRETIME_MS: u8 = 0xfc;
+const OPCODE_EXPIRETIME: u8 = 0xfd;
+const OPCODE_SELECTDB: u8 = 0xfe;
+const OPCODE_EOF: u8 = 0xff;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_HASH_ZIPMAP: u8 = 9;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_SET_LISTPACK: u8 = 20;
+
+// Special string encodings, flagged by the top two bits of a length.
+const ENC_INT8: u8 = 0;
+const ENC_INT16: u8 = 1;
+const ENC_INT32: u8 = 2;
+const ENC_LZF: u8 = 3;
+
+// Quicklist node containers.
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+const QUICKLIST_NODE_PACKED: u64 = 2;
+
+#[derive(Debug)]
+pub enum RdbError {
+    Io(io::Error),
+    NotRdb,
+    UnsupportedVersion(u32),
+    /// Data this server has no equivalent for, such as a stream.
+    Unsupported(String),
+    ChecksumMismatch,
+    Truncated,
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for RdbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RdbError::Io(e) => write!(f, "{}", e),
+            RdbError::NotRdb => write!(f, "not an RDB file"),
+            RdbError::UnsupportedVersion(v) => write!(
+                f,
+                "RDB version {} is not supported (this build reads up to {})",
+                v, MAX_VERSION
+            ),
+            RdbError::Unsupported(what) => write!(f, "{} can't be imported", what),
+            RdbError::ChecksumMismatch => write!(f, "checksum mismatch, the file is corrupt"),
+            RdbError::Truncated => write!(f, "the file is truncated"),
+            RdbError::Corrupt(what) => write!(f, "the file is corrupt: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for RdbError {}
+
+/// What an export had to leave out, for the caller to warn about.
+#[derive(Debug, Default, PartialEq)]
+pub struct ExportLosses {
+    /// The keys holding streams, which aren't exported at all.
+    pub streams: Vec<Vec<u8>>,
+    /// The hashes exported without the expiry of their fields.
+    pub field_expiries: Vec<Vec<u8>>,
+}
+
+impl From<io::Error> for RdbError {
+    fn from(e: io::Error) -> Self {
+        RdbError::Io(e)
+    }
+}
+
+/// Reads the RDB file at `path`. Keys that have already expired are dropped.
+pub fn load(path: &Path) -> Result<Storage, RdbError> {
+    parse(&fs::read(path)?)
+}
+
+/// Parses a complete RDB file.
+pub fn parse(data: &[u8]) -> Result<Storage, RdbError> {
+    if data.len() < MAGIC.len() + 4 || !data.starts_with(MAGIC) {
+        return Err(RdbError::NotRdb);
+    }
+    let version = std::str::from_utf8(&data[MAGIC.len()..MAGIC.len() + 4])
+        .ok()
+        .and_then(|v| v.parse::<u32>().ok())
+        .ok_or(RdbError::NotRdb)?;
+    if version == 0 || version > MAX_VERSION {
+        return Err(RdbError::UnsupportedVersion(version));
+    }
+
+    let mut input = Reader::new(&data[MAGIC.len() + 4..]);
+    let mut storage = Storage::new();
+    let mut expiry = None;
+    let now = now_millis();
+
+    loop {
+        match input.u8()? {
+            OPCODE_EOF => break,
+            OPCODE_SELECTDB => {
+                let db = input.length()?;
+                if db != 0 {
+                    return Err(RdbError::Unsupported(format!("Database {}", db)));
+                }
+            }
+            OPCODE_RESIZEDB => {
+                input.length()?;
+                input.length()?;
+            }
+            OPCODE_AUX => {
+                input.string()?;
+                input.string()?;
+            }
+            OPCODE_EXPIRETIME_MS => expiry = Some(u64::from_le_bytes(input.array()?)),
+            OPCODE_EXPIRETIME => expiry = Some(u32::from_le_bytes(input.array()?) as u64 * 1000),
+            OPCODE_FREQ => {
+                input.u8()?;
+            }
+            OPCODE_IDLE => {
+                input.length()?;
+            }
+            OPCODE_SLOT_INFO => {
+                for _ in 0..3 {
+                    input.length()?;
+                }
+            }
+            // Function libraries are code, not data.
+            OPCODE_FUNCTION2 => {
+                input.string()?;
+            }
+            OPCODE_MODULE_AUX | OPCODE_FUNCTION_PRE_GA => {
+                return Err(RdbError::Unsupported("Module data".to_string()));
+            }
+            value_type => {
+                let key = input.string()?;
+                let data = read_value(&mut input, value_type)?;
+                let expiry = expiry.take();
+                if expiry.is_some_and(|at| at <= now) || data.is_empty_collection() {
+                    continue;
+                }
+                storage.insert(key, StoreValue { data, expiry });
+            }
+        }
+    }
+
+    // Since version 5 the file ends with a checksum of everything before it,
+    // or zero if Redis was told not to compute one.
+    if version >= 5 {
+        let end = data.len() - input.remaining();
+        let checksum = u64::from_le_bytes(input.array()?);
+        if checksum != 0 && crc64::checksum(&data[..end]) != checksum {
+            return Err(RdbError::ChecksumMismatch);
+        }
+    }
+
+    Ok(storage)
+}
+
+fn read_value(input: &mut Reader, value_type: u8) -> Result<Value, RdbError> {
+    let value = match value_type {
+        TYPE_STRING => Value::String(input.string()?),
+        TYPE_LIST => {
+            let len = input.length()?;
+            let mut list = VecDeque::new();
+            for _ in 0..len {
+                list.push_back(input.string()?);
+            }
+            Value::List(list)
+        }
+        TYPE_LIST_ZIPLIST => Value::List(ziplist_entries(&input.string()?)?.into()),
+        TYPE_LIST_QUICKLIST => {
+            let mut list = VecDeque::new();
+            for _ in 0..input.length()? {
+                list.extend(ziplist_entries(&input.string()?)?);
+            }
+            Value::List(list)
+        }
+        TYPE_LIST_QUICKLIST_2 => {
+            let mut list = VecDeque::new();
+            for _ in 0..input.length()? {
+                match input.length()? {
+                    QUICKLIST_NODE_PLAIN => list.push_back(input.string()?),
+                    QUICKLIST_NODE_PACKED => list.extend(listpack_entries(&input.string()?)?),
+                    _ => return Err(RdbError::Corrupt("unknown quicklist node container")),
+                }
+            }
+            Value::List(list)
+        }
+        TYPE_SET => {
+            let len = input.length()?;
+            let mut set = Members::new();
+            for _ in 0..len {
+                set.insert(input.string()?);
+            }
+            Value::Set(set)
+        }
+        TYPE_SET_INTSET => Value::Set(intset_members(&input.string()?)?),
+        TYPE_SET_LISTPACK => Value::Set(listpack_entries(&input.string()?)?.into_iter().collect()),
+        TYPE_ZSET | TYPE_ZSET_2 => {
+            let len = input.length()?;
+            let mut set = SortedSet::new();
+            for _ in 0..len {
+                let member = input.string()?;
+                let score = if value_type == TYPE_ZSET {
+                    input.string_double()?
+                } else {
+                    f64::from_le_bytes(input.array()?)
+                };
+                if score.is_nan() {
+                    return Err(RdbError::Corrupt("invalid sorted set score"));
+                }
+                set.insert(member, score);
+            }
+            Value::SortedSet(set)
+        }
+        TYPE_ZSET_ZIPLIST => Value::SortedSet(into_scores(ziplist_entries(&input.string()?)?)?),
+        TYPE_ZSET_LISTPACK => Value::SortedSet(into_scores(listpack_entries(&input.string()?)?)?),
+        TYPE_HASH => {
+            let len = input.length()?;
+            let mut hash = FieldMap::new();
+            for _ in 0..len {
+                let field = input.string()?;
+                hash.insert(field, input.string()?);
+            }
+            Value::Hash(hash.into())
+        }
+        TYPE_HASH_ZIPMAP => Value::Hash(zipmap_pairs(&input.string()?)?.into()),
+        TYPE_HASH_ZIPLIST => Value::Hash(into_pairs(ziplist_entries(&input.string()?)?)?.into()),
+        TYPE_HASH_LISTPACK => Value::Hash(into_pairs(listpack_entries(&input.string()?)?)?.into()),
+        other => return Err(RdbError::Unsupported(format!("Value type {}", other))),
+    };
+    Ok(value)
+}
+
+fn into_pairs(entries: Vec<Vec<u8>>) -> Result<FieldMap, RdbError> {
+    if !entries.len().is_multiple_of(2) {
+        return Err(RdbError::Corrupt("hash with a field but no value"));
+    }
+    let mut entries = entries.into_iter();
+    let mut hash = FieldMap::new();
+    while let (Some(field), Some(value)) = (entries.next(), entries.next()) {
+        hash.insert(field, value);
+    }
+    Ok(hash)
+}
+
+// Sorted sets are packed as each member followed by its score.
+fn into_scores(entries: Vec<Vec<u8>>) -> Result<SortedSet, RdbError> {
+    if !entries.len().is_multiple_of(2) {
+        return Err(RdbError::Corrupt("sorted set member without a score"));
+    }
+    let mut entries = entries.into_iter();
+    let mut set = SortedSet::new();
+    while let (Some(member), Some(score)) = (entries.next(), entries.next()) {
+        set.insert(member, parse_score(&score)?);
+    }
+    Ok(set)
+}
+
+fn parse_score(score: &[u8]) -> Result<f64, RdbError> {
+    std::str::from_utf8(score)
+        .ok()
+        .and_then(|score| score.parse::<f64>().ok())
+        .filter(|score| !score.is_nan())
+        .ok_or(RdbError::Corrupt("invalid sorted set score"))
+}
+
+/// A cursor over the bytes of an RDB file, or of an encoding embedded in one.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data, pos: 0 }
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], RdbError> {
+        if len > self.remaining() {
+            return Err(RdbError::Truncated);
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], RdbError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, RdbError> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// Reads a length, or the encoding of a specially encoded string.
+    fn length_or_encoding(&mut self) -> Result<Length, RdbError> {
+        let first = self.u8()?;
+        let length = match first >> 6 {
+            0 => (first & 0x3f) as u64,
+            1 => ((first & 0x3f) as u64) << 8 | self.u8()? as u64,
+            2 => match first {
+                0x80 => u32::from_be_bytes(self.array()?) as u64,
+                0x81 => u64::from_be_bytes(self.array()?),
+                _ => return Err(RdbError::Corrupt("unknown length encoding")),
+            },
+            _ => return Ok(Length::Encoded(first & 0x3f)),
+        };
+        Ok(Length::Plain(length))
+    }
+
+    fn length(&mut self) -> Result<u64, RdbError> {
+        match self.length_or_encoding()? {
+            Length::Plain(len) => Ok(len),
+            Length::Encoded(_) => Err(RdbError::Corrupt("expected a length")),
+        }
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>, RdbError> {
+        let len = match self.length_or_encoding()? {
+            Length::Plain(len) => len,
+            Length::Encoded(ENC_INT8) => return Ok((self.u8()? as i8).to_string().into_bytes()),
+            Length::Encoded(ENC_INT16) => {
+                return Ok(i16::from_le_bytes(self.array()?).to_string().into_bytes());
+            }
+            Length::Encoded(ENC_INT32) => {
+                return Ok(i32::from_le_bytes(self.array()?).to_string().into_bytes());
+            }
+            Length::Encoded(ENC_LZF) => {
+                let compressed_len = self.length()?;
+                let len = self.length()?;
+                let compressed = self.take(usize_from(compressed_len)?)?;
+                return lzf_decompress(compressed, usize_from(len)?);
+            }
+            Length::Encoded(_) => return Err(RdbError::Corrupt("unknown string encoding")),
+        };
+        Ok(self.take(usize_from(len)?)?.to_vec())
+    }
+
+    /// A score the way the oldest sorted set encoding stores it: as text
+    /// behind a one byte length, with three lengths reserved for NaN and the
+    /// infinities. Scores can't be NaN, so that one is rejected.
+    fn string_double(&mut self) -> Result<f64, RdbError> {
+        match self.u8()? {
+            253 => Err(RdbError::Corrupt("invalid sorted set score")),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            len => {
+                let text = self.take(len as usize)?;
+                parse_score(text)
+            }
+        }
+    }
+}
+
+enum Length {
+    Plain(u64),
+    Encoded(u8),
+}
+
+fn usize_from(len: u64) -> Result<usize, RdbError> {
+    usize::try_from(len).map_err(|_| RdbError::Truncated)
+}
+
+fn lzf_decompress(input: &[u8], len: usize) -> Result<Vec<u8>, RdbError> {
+    const CORRUPT: RdbError = RdbError::Corrupt("invalid LZF data");
+
+    // A back reference copies at most 264 bytes for every 2 bytes of input,
+    // which bounds what a bogus length can make us allocate.
+    let mut out = Vec::with_capacity(len.min(input.len() * 132));
+    let mut input = Reader::new(input);
+
+    while input.remaining() > 0 {
+        let ctrl = input.u8()? as usize;
+        if ctrl < 32 {
+            // A run of ctrl + 1 literal bytes.
+            out.extend_from_slice(input.take(ctrl + 1).map_err(|_| CORRUPT)?);
+        } else {
+            // A back reference into what has been decompressed so far.
+            let mut run = ctrl >> 5;
+            if run == 7 {
+                run += input.u8().map_err(|_| CORRUPT)? as usize;
+            }
+            let offset = ((ctrl & 0x1f) << 8) + input.u8().map_err(|_| CORRUPT)? as usize + 1;
+            let start = out.len().checked_sub(offset).ok_or(CORRUPT)?;
+            // The source and destination may overlap, so copy byte by byte.
+            for i in start..start + run + 2 {
+                out.push(out[i]);
+            }
+        }
+        if out.len() > len {
+            return Err(CORRUPT);
+        }
+    }
+
+    if out.len() != len {
+        return Err(CORRUPT);
+    }
+    Ok(out)
+}
+
+/// Decodes the entries of a ziplist, the compact list encoding used up to
+/// Redis 6.2.
+fn ziplist_entries(ziplist: &[u8]) -> Result<Vec<Vec<u8>>, RdbError> {
+    let mut input = Reader::new(ziplist);
+    // Total size, offset of the last entry and entry count.
+    input.take(10)?;
+
+    let mut entries = Vec::new();
+    loop {
+        // Each entry starts with the length of the previous one.
+        match input.u8()? {
+            0xff => break,
+            0xfe => {
+                input.take(4)?;
+            }
+            _ => {}
+        }
+
+        let encoding = input.u8()?;
+        let entry = match encoding >> 6 {
+            0 => input.take((encoding & 0x3f) as usize)?.to_vec(),
+            1 => {
+                let len = ((encoding & 0x3f) as usize) << 8 | input.u8()? as usize;
+                input.take(len)?.to_vec()
+            }
+            2 => {
+                let len = u32::from_be_bytes(input.array()?) as usize;
+                input.take(len)?.to_vec()
+            }
+            _ => {
+                let n: i64 = match encoding {
+                    0xc0 => i16::from_le_bytes(input.array()?) as i64,
+                    0xd0 => i32::from_le_bytes(input.array()?) as i64,
+                    0xe0 => i64::from_le_bytes(input.array()?),
+                    0xf0 => read_i24(&mut input)?,
+                    0xfe => input.u8()? as i8 as i64,
+                    0xf1..=0xfd => (encoding & 0x0f) as i64 - 1,
+                    _ => return Err(RdbError::Corrupt("unknown ziplist entry encoding")),
+                };
+                n.to_string().into_bytes()
+            }
+        };
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Decodes the entries of a listpack, which replaced the ziplist in Redis 7.
+fn listpack_entries(listpack: &[u8]) -> Result<Vec<Vec<u8>>, RdbError> {
+    let mut input = Reader::new(listpack);
+    // Total size and entry count.
+    input.take(6)?;
+
+    let mut entries = Vec::new();
+    loop {
+        let start = input.pos;
+        let encoding = input.u8()?;
+        if encoding == 0xff {
+            break;
+        }
+
+        let entry = match encoding {
+            0x00..=0x7f => encoding.to_string().into_bytes(),
+            0x80..=0xbf => input.take((encoding & 0x3f) as usize)?.to_vec(),
+            0xc0..=0xdf => {
+                // 13 bit signed integer.
+                let n = ((encoding & 0x1f) as u16) << 8 | input.u8()? as u16;
+                (((n << 3) as i16) >> 3).to_string().into_bytes()
+            }
+            0xe0..=0xef => {
+                let len = ((encoding & 0x0f) as usize) << 8 | input.u8()? as usize;
+                input.take(len)?.to_vec()
+            }
+            0xf0 => {
+                let len = u32::from_le_bytes(input.array()?) as usize;
+                input.take(len)?.to_vec()
+            }
+            0xf1 => i16::from_le_bytes(input.array()?).to_string().into_bytes(),
+            0xf2 => read_i24(&mut input)?.to_string().into_bytes(),
+            0xf3 => i32::from_le_bytes(input.array()?).to_string().into_bytes(),
+            0xf4 => i64::from_le_bytes(input.array()?).to_string().into_bytes(),
+            _ => return Err(RdbError::Corrupt("unknown listpack entry encoding")),
+        };
+        entries.push(entry);
+        skip_backlen(&mut input, start)?;
+    }
+    Ok(entries)
+}
+
+// Every listpack entry ends with its own length, for walking backwards, which
+// takes as many bytes as it needs at 7 bits each.
+fn skip_backlen(input: &mut Reader, entry_start: usize) -> Result<(), RdbError> {
+    let len = input.pos - entry_start;
+    let size = match len {
+        0..=127 => 1,
+        128..16383 => 2,
+        16383..2097151 => 3,
+        2097151..268435455 => 4,
+        _ => 5,
+    };
+    input.take(size)?;
+    Ok(())
+}
+
+fn read_i24(input: &mut Reader) -> Result<i64, RdbError> {
+    let [a, b, c] = input.array()?;
+    Ok((i32::from_le_bytes([0, a, b, c]) >> 8) as i64)
+}
+
+/// Decodes an intset, the encoding of sets that only hold integers.
+fn intset_members(intset: &[u8]) -> Result<Members, RdbError> {
+    let mut input = Reader::new(intset);
+    let width = u32::from_le_bytes(input.array()?) as usize;
+    let len = u32::from_le_bytes(input.array()?) as usize;
+
+    let mut set = Members::new();
+    for _ in 0..len {
+        let n = match width {
+            2 => i16::from_le_bytes(input.array()?) as i64,
+            4 => i32::from_le_bytes(input.array()?) as i64,
+            8 => i64::from_le_bytes(input.array()?),
+            _ => return Err(RdbError::Corrupt("unknown intset encoding")),
+        };
+        set.insert(n.to_string().into_bytes());
+    }
+    Ok(set)
+}
+
+/// Decodes a zipmap, the hash encoding used before Redis 2.6.
+fn zipmap_pairs(zipmap: &[u8]) -> Result<FieldMap, RdbError> {
+    fn length(input: &mut Reader) -> Result<Option<usize>, RdbError> {
+        match input.u8()? {
+            0xff => Ok(None),
+            0xfe => Ok(Some(u32::from_le_bytes(input.array()?) as usize)),
+            len => Ok(Some(len as usize)),
+        }
+    }
+
+    let mut input = Reader::new(zipmap);
+    // Entry count, only accurate when small.
+    input.u8()?;
+
+    let mut hash = FieldMap::new();
+    while let Some(field_len) = length(&mut input)? {
+        let field = input.take(field_len)?.to_vec();
+        let value_len =
+            length(&mut input)?.ok_or(RdbError::Corrupt("zipmap field without value"))?;
+        let free = input.u8()? as usize;
+        let value = input.take(value_len)?.to_vec();
+        input.take(free)?;
+        hash.insert(field, value);
+    }
+    Ok(hash)
+}
+
+/// Writes `storage` to `path` as an RDB file, replacing it atomically.
+/// Expired keys and hash fields are left out.
+pub fn save(storage: &Storage, path: &Path) -> Result<ExportLosses, RdbError> {
+    snapshot::replace_file(path, |tmp_path| {
+        let mut out = BufWriter::new(File::create(tmp_path)?);
+        let losses = write(storage, &mut out)?;
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        Ok(losses)
+    })
+}
+
+/// Encodes `storage` as an RDB file. Returns what couldn't be encoded.
+pub fn write(storage: &Storage, out: &mut impl Write) -> io::Result<ExportLosses> {
+    let mut out = crc64::Writer::new(out);
+    let now = now_millis();
+
+    write!(out, "REDIS{:04}", EXPORT_VERSION)?;
+    write_aux(&mut out, b"redis-bits", b"64")?;
+    write_aux(&mut out, b"ctime", (now / 1000).to_string().as_bytes())?;
+
+    let mut losses = ExportLosses::default();
+    let live: Vec<_> = storage
+        .entries()
+        .filter(|(_, value)| value.expiry.is_none_or(|at| at > now))
+        .filter(|(key, value)| match &value.data {
+            Value::Stream(_) => {
+                losses.streams.push(key.to_vec());
+                false
+            }
+            Value::Hash(hash) => live_fields(hash, now).next().is_some(),
+            _ => true,
+        })
+        .collect();
+    let expiring = live.iter().filter(|(_, v)| v.expiry.is_some()).count();
+
+    out.write_all(&[OPCODE_SELECTDB])?;
+    write_length(&mut out, 0)?;
+    out.write_all(&[OPCODE_RESIZEDB])?;
+    write_length(&mut out, live.len() as u64)?;
+    write_length(&mut out, expiring as u64)?;
+
+    for (key, value) in live {
+        if let Some(at) = value.expiry {
+            out.write_all(&[OPCODE_EXPIRETIME_MS])?;
+            out.write_all(&at.to_le_bytes())?;
+        }
+
+        match &value.data {
+            Value::String(s) => {
+                out.write_all(&[TYPE_STRING])?;
+                write_string(&mut out, key)?;
+                write_string(&mut out, s)?;
+            }
+            Value::List(list) => {
+                out.write_all(&[TYPE_LIST])?;
+                write_string(&mut out, key)?;
+                write_length(&mut out, list.len() as u64)?;
+                for item in list {
+                    write_string(&mut out, item)?;
+                }
+            }
+            // Version 9 has no way to store the expiry of a field, so the
+            // fields are written without one.
+            Value::Hash(hash) => {
+                let fields: Vec<_> = live_fields(hash, now).collect();
+                if fields
+                    .iter()
+                    .any(|(field, _)| hash.field_expiry(field).is_some())
+                {
+                    losses.field_expiries.push(key.to_vec());
+                }
+                out.write_all(&[TYPE_HASH])?;
+                write_string(&mut out, key)?;
+                write_length(&mut out, fields.len() as u64)?;
+                for (field, value) in fields {
+                    write_string(&mut out, field)?;
+                    write_string(&mut out, value)?;
+                }
+            }
+            Value::Stream(_) => unreachable!("streams are left out"),
+            Value::Set(set) => {
+                out.write_all(&[TYPE_SET])?;
+                write_string(&mut out, key)?;
+                write_length(&mut out, set.len() as u64)?;
+                for member in set {
+                    write_string(&mut out, member)?;
+                }
+            }
+            Value::SortedSet(set) => {
+                out.write_all(&[TYPE_ZSET_2])?;
+                write_string(&mut out, key)?;
+                write_length(&mut out, set.len() as u64)?;
+                for (member, score) in set.iter() {
+                    write_string(&mut out, member)?;
+                    out.write_all(&score.to_le_bytes())?;
+                }
+            }
+        }
+    }
+
+    out.write_all(&[OPCODE_EOF])?;
+    let crc = out.crc();
+    out.into_inner().write_all(&crc.to_le_bytes())?;
+    Ok(losses)
+}
+
+// The fields of `hash` that haven't expired by `now`.
+fn live_fields(hash: &HashValue, now: u64) -> impl Iterator<Item = (&Vec<u8>, &Vec<u8>)> {
+    hash.iter()
+        .filter(move |(field, _)| hash.field_expiry(field).is_none_or(|at| at > now))
+}
+
+fn write_aux(out: &mut impl Write, name: &[u8], value: &[u8]) -> io::Result<()> {
+    out.write_all(&[OPCODE_AUX])?;
+    write_string(out, name)?;
+    write_string(out, value)
+}
+
+fn write_length(out: &mut impl Write, len: u64) -> io::Result<()> {
+    if len < 1 << 6 {
+        out.write_all(&[len as u8])
+    } else if len < 1 << 14 {
+        out.write_all(&[0x40 | (len >> 8) as u8, len as u8])
+    } else if len <= u32::MAX as u64 {
+        out.write_all(&[0x80])?;
+        out.write_all(&(len as u32).to_be_bytes())
+    } else {
+        out.write_all(&[0x81])?;
+        out.write_all(&len.to_be_bytes())
+    }
+}
+
+fn write_string(out: &mut impl Write, s: &[u8]) -> io::Result<()> {
+    write_length(out, s.len() as u64)?;
+    out.write_all(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rdb(version: u32, body: &[u8]) -> Vec<u8> {
+        let mut data = format!("REDIS{:04}", version).into_bytes();
+        data.extend_from_slice(body);
+        data.push(OPCODE_EOF);
+        if version >= 5 {
+            data.extend_from_slice(&crc64::checksum(&data).to_le_bytes());
+        }
+        data
+    }
+
+    fn string_value(storage: &mut Storage, key: &[u8]) -> Vec<u8> {
+        match &storage.get(key).unwrap().data {
+            Value::String(s) => s.clone(),
+            _ => panic!("Expected string value"),
+        }
+    }
+
+    #[test]
+    fn test_strings_and_expiry() {
+        let future = now_millis() + 60_000;
+        let mut body = vec![OPCODE_SELECTDB, 0, TYPE_STRING, 1, b'a', 3];
+        body.extend_from_slice(b"foo");
+        // Integer encoded value.
+        body.extend_from_slice(&[TYPE_STRING, 1, b'n', 0xc1]);
+        body.extend_from_slice(&(-300i16).to_le_bytes());
+        // Expiring, and expired.
+        body.push(OPCODE_EXPIRETIME_MS);
+        body.extend_from_slice(&future.to_le_bytes());
+        body.extend_from_slice(&[TYPE_STRING, 1, b't', 1, b'x']);
+        body.push(OPCODE_EXPIRETIME);
+        body.extend_from_slice(&1u32.to_le_bytes());
+        body.extend_from_slice(&[TYPE_STRING, 1, b'e', 1, b'x']);
+
+        let mut storage = parse(&rdb(9, &body)).unwrap();
+        assert_eq!(string_value(&mut storage, b"a"), b"foo");
+        assert_eq!(string_value(&mut storage, b"n"), b"-300");
+        assert_eq!(storage.get(b"t").unwrap().expiry, Some(future));
+        assert!(storage.get(b"e").is_none());
+    }
+
+    #[test]
+    fn test_lzf_string() {
+        // "aaaaaaaaaa": one literal 'a', then a back reference copying 9 more.
+        let compressed = [0x00, b'a', 0xe0, 0x00, 0x00];
+        let mut body = vec![TYPE_STRING, 1, b'k', 0xc3, compressed.len() as u8, 10];
+        body.extend_from_slice(&compressed);
+
+        let mut storage = parse(&rdb(9, &body)).unwrap();
+        assert_eq!(string_value(&mut storage, b"k"), b"aaaaaaaaaa");
+    }
+
+    #[test]
+    fn test_ziplist_and_quicklist() {
+        // Entries "ab", 7 (immediate), 1000 (int16).
+        let mut ziplist = vec![0; 10];
+        ziplist.extend_from_slice(&[0, 0x02, b'a', b'b']);
+        ziplist.extend_from_slice(&[4, 0xf8]);
+        ziplist.extend_from_slice(&[2, 0xc0]);
+        ziplist.extend_from_slice(&1000i16.to_le_bytes());
+        ziplist.push(0xff);
+
+        let mut body = vec![TYPE_LIST_QUICKLIST, 1, b'l', 1, ziplist.len() as u8];
+        body.extend_from_slice(&ziplist);
+
+        let mut storage = parse(&rdb(9, &body)).unwrap();
+        assert_eq!(
+            storage.lrange(b"l", 0, -1).unwrap().unwrap(),
+            vec![b"ab".to_vec(), b"7".to_vec(), b"1000".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_listpack_hash() {
+        // Entries "f", 5 (7 bit), "g", -2 (13 bit).
+        let mut listpack = vec![0; 6];
+        listpack.extend_from_slice(&[0x81, b'f', 2]);
+        listpack.extend_from_slice(&[0x05, 1]);
+        listpack.extend_from_slice(&[0x81, b'g', 2]);
+        listpack.extend_from_slice(&[0xdf, 0xfe, 2]);
+        listpack.push(0xff);
+
+        let mut body = vec![TYPE_HASH_LISTPACK, 1, b'h', listpack.len() as u8];
+        body.extend_from_slice(&listpack);
+
+        let mut storage = parse(&rdb(11, &body)).unwrap();
+        assert_eq!(storage.hget(b"h", b"f").unwrap().unwrap(), b"5");
+        assert_eq!(storage.hget(b"h", b"g").unwrap().unwrap(), b"-2");
+    }
+
+    #[test]
+    fn test_zipmap_hash() {
+        let zipmap = [1, 1, b'f', 2, 0, b'v', b'1', 0xff];
+        let mut body = vec![TYPE_HASH_ZIPMAP, 1, b'h', zipmap.len() as u8];
+        body.extend_from_slice(&zipmap);
+
+        let mut storage = parse(&rdb(3, &body)).unwrap();
+        assert_eq!(storage.hget(b"h", b"f").unwrap().unwrap(), b"v1");
+    }
+
+    #[test]
+    fn test_intset_and_listpack_sets() {
+        // 16 bit members 1 and -3.
+        let intset = [2, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0xfd, 0xff];
+        let mut body = vec![TYPE_SET_INTSET, 1, b'i', intset.len() as u8];
+        body.extend_from_slice(&intset);
+        // Entries "a" and 7.
+        let mut listpack = vec![0; 6];
+        listpack.extend_from_slice(&[0x81, b'a', 2]);
+        listpack.extend_from_slice(&[0x07, 1]);
+        listpack.push(0xff);
+        body.extend_from_slice(&[TYPE_SET_LISTPACK, 1, b'p', listpack.len() as u8]);
+        body.extend_from_slice(&listpack);
+
+        let mut storage = parse(&rdb(11, &body)).unwrap();
+        let mut members = storage.smembers(b"i").unwrap();
+        members.sort();
+        assert_eq!(members, vec![b"-3".to_vec(), b"1".to_vec()]);
+        assert!(storage.sismember(b"p", b"a").unwrap());
+        assert!(storage.sismember(b"p", b"7").unwrap());
+    }
+
+    #[test]
+    fn test_sorted_sets() {
+        // Scores as text, the last one +inf.
+        let mut body = vec![TYPE_ZSET, 1, b'z', 2, 1, b'a', 3];
+        body.extend_from_slice(b"1.5");
+        body.extend_from_slice(&[1, b'b', 254]);
+        body.extend_from_slice(&[TYPE_ZSET_2, 1, b'd', 1, 1, b'a']);
+        body.extend_from_slice(&(-2.25f64).to_le_bytes());
+        // Entries "a" and 2.
+        let mut listpack = vec![0; 6];
+        listpack.extend_from_slice(&[0x81, b'a', 2]);
+        listpack.extend_from_slice(&[0x02, 1]);
+        listpack.push(0xff);
+        body.extend_from_slice(&[TYPE_ZSET_LISTPACK, 1, b'p', listpack.len() as u8]);
+        body.extend_from_slice(&listpack);
+
+        let mut storage = parse(&rdb(11, &body)).unwrap();
+        assert_eq!(storage.zscore(b"z", b"a"), Ok(Some(1.5)));
+        assert_eq!(storage.zscore(b"z", b"b"), Ok(Some(f64::INFINITY)));
+        assert_eq!(storage.zscore(b"d", b"a"), Ok(Some(-2.25)));
+        assert_eq!(storage.zscore(b"p", b"a"), Ok(Some(2.0)));
+
+        // NaN is not a score.
+        let body = [TYPE_ZSET, 1, b'z', 1, 1, b'a', 253];
+        assert!(matches!(parse(&rdb(11, &body)), Err(RdbError::Corrupt(_))));
+    }
+
+    #[test]
+    fn test_rejects_bad_files() {
+        assert!(matches!(parse(b"nope"), Err(RdbError::NotRdb)));
+        assert!(matches!(
+            parse(&rdb(99, &[])),
+            Err(RdbError::UnsupportedVersion(99))
+        ));
+        assert!(matches!(
+            parse(&rdb(9, &[15, 1, b's'])),
+            Err(RdbError::Unsupported(_))
+        ));
+
+        let mut data = rdb(9, &[TYPE_STRING, 1, b'a', 1, b'b']);
+        data[13] ^= 0xff;
+        assert!(matches!(parse(&data), Err(RdbError::ChecksumMismatch)));
+        assert!(matches!(
+            parse(&data[..data.len() - 4]),
+            Err(RdbError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn test_write_and_read_back() {
+        let mut storage = Storage::new();
+        let long_value = vec![b'x'; 20_000];
+        storage
+            .set(b"s".to_vec(), long_value.clone(), None)
+            .unwrap();
+        storage
+            .set(
+                b"t".to_vec(),
+                b"v".to_vec(),
+                Some(std::time::Duration::from_secs(60)),
+            )
+            .unwrap();
+        storage
+            .rpush(b"l", vec![b"a".to_vec(), b"b".to_vec()])
+            .unwrap();
+        storage
+            .hset(b"h".to_vec(), vec![(b"f".to_vec(), b"v".to_vec())])
+            .unwrap();
+        storage.sadd(b"set", vec![b"m".to_vec()]).unwrap();
+        storage
+            .zadd(
+                b"z",
+                vec![(0.5, b"m".to_vec())],
+                &crate::storage::ZAddOptions::default(),
+            )
+            .unwrap();
+
+        let mut data = Vec::new();
+        assert_eq!(write(&storage, &mut data).unwrap(), ExportLosses::default());
+        assert!(data.starts_with(b"REDIS0009"));
+
+        let mut loaded = parse(&data).unwrap();
+        assert_eq!(string_value(&mut loaded, b"s"), long_value);
+        assert!(loaded.get(b"t").unwrap().expiry.is_some());
+        assert_eq!(
+            loaded.lrange(b"l", 0, -1).unwrap().unwrap(),
+            vec![b"a".to_vec(), b"b".to_vec()]
+        );
+        assert_eq!(loaded.hget(b"h", b"f").unwrap().unwrap(), b"v");
+        assert_eq!(loaded.smembers(b"set").unwrap(), vec![b"m".to_vec()]);
+        assert_eq!(loaded.zscore(b"z", b"m"), Ok(Some(0.5)));
+    }
+
+    #[test]
+    fn test_write_leaves_out_what_rdb_9_cannot_hold() {
+        let mut storage = Storage::new();
+        let fields = |names: &[&str]| -> Vec<(Vec<u8>, Vec<u8>)> {
+            names
+                .iter()
+                .map(|name| (name.as_bytes().to_vec(), b"v".to_vec()))
+                .collect()
+        };
+        storage
+            .hset(b"h".to_vec(), fields(&["kept", "ttl", "gone"]))
+            .unwrap();
+        let now = now_millis() as i64;
+        let condition = Default::default();
+        storage
+            .hexpire(b"h", &[b"ttl".to_vec()], now + 60_000, condition)
+            .unwrap();
+        storage
+            .hexpire(b"h", &[b"gone".to_vec()], now + 20, condition)
+            .unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(30));
+        storage
+            .xadd(
+                b"s",
+                crate::stream::NewId::Auto,
+                vec![(b"f".to_vec(), b"v".to_vec())],
+                false,
+                None,
+            )
+            .unwrap();
+
+        let mut data = Vec::new();
+        let losses = write(&storage, &mut data).unwrap();
+        assert_eq!(losses.streams, [b"s".to_vec()]);
+        assert_eq!(losses.field_expiries, [b"h".to_vec()]);
+
+        let mut loaded = parse(&data).unwrap();
+        assert_eq!(loaded.hget(b"h", b"ttl").unwrap().unwrap(), b"v");
+        assert!(loaded.hget(b"h", b"gone").unwrap().is_none());
+        assert!(loaded.get(b"s").is_none());
+    }
+}
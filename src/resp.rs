@@ -0,0 +1,455 @@
+use std::fmt;
+
+/// A single RESP2 value, used for the replies we send back to clients.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Null,
+    Array(Vec<Frame>),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ProtocolError {
+    InvalidMultibulkLength,
+    InvalidBulkLength,
+    ExpectedBulk(u8),
+    UnbalancedQuotes,
+    TooBigInline,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::InvalidMultibulkLength => write!(f, "invalid multibulk length"),
+            ProtocolError::InvalidBulkLength => write!(f, "invalid bulk length"),
+            ProtocolError::ExpectedBulk(byte) => {
+                write!(f, "expected '$', got '{}'", *byte as char)
+            }
+            ProtocolError::UnbalancedQuotes => write!(f, "unbalanced quotes in request"),
+            ProtocolError::TooBigInline => write!(f, "too big inline request"),
+        }
+    }
+}
+
+/// A parsed request's arguments plus the number of bytes it occupied, or
+/// `None` when more input is needed.
+pub type ParseResult = Result<Option<(Vec<Vec<u8>>, usize)>, ProtocolError>;
+
+// Same limits Redis applies to a single request.
+const MAX_MULTIBULK_LEN: i64 = 1024 * 1024;
+const MAX_BULK_LEN: i64 = 512 * 1024 * 1024;
+pub const MAX_INLINE_LEN: usize = 64 * 1024;
+// Room for at most this many arguments is made up front, the rest as they
+// arrive, so a large count alone doesn't allocate much.
+const MAX_ARGS_PREALLOC: i64 = 1024;
+
+impl Frame {
+    pub fn ok() -> Frame {
+        Frame::Simple("OK".to_string())
+    }
+
+    pub fn error(msg: impl Into<String>) -> Frame {
+        Frame::Error(msg.into())
+    }
+
+    pub fn bulk(data: impl Into<Vec<u8>>) -> Frame {
+        Frame::Bulk(data.into())
+    }
+
+    /// Appends the wire representation of this frame to `out`.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Frame::Simple(s) => {
+                out.push(b'+');
+                out.extend_from_slice(s.as_bytes());
+                out.extend_from_slice(b"\r\n");
+            }
+            Frame::Error(msg) => {
+                out.push(b'-');
+                out.extend_from_slice(msg.as_bytes());
+                out.extend_from_slice(b"\r\n");
+            }
+            Frame::Integer(n) => {
+                out.extend_from_slice(format!(":{}\r\n", n).as_bytes());
+            }
+            Frame::Bulk(data) => {
+                out.extend_from_slice(format!("${}\r\n", data.len()).as_bytes());
+                out.extend_from_slice(data);
+                out.extend_from_slice(b"\r\n");
+            }
+            Frame::Null => out.extend_from_slice(b"$-1\r\n"),
+            Frame::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(out);
+                }
+            }
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode(&mut out);
+        out
+    }
+}
+
+/// Appends `args` to `out` as a RESP array of bulk strings, the form in which
+/// clients send commands.
+pub fn encode_request(args: &[Vec<u8>], out: &mut Vec<u8>) {
+    out.extend_from_slice(format!("*{}\r\n", args.len()).as_bytes());
+    for arg in args {
+        out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        out.extend_from_slice(arg);
+        out.extend_from_slice(b"\r\n");
+    }
+}
+
+/// Parses a single request from the start of `buf`.
+///
+/// Requests are either RESP arrays of bulk strings (what real clients send) or,
+/// like Redis, a plain inline line such as `SET key value\r\n` for telnet/netcat
+/// users. Returns the argument list and the number of bytes consumed, or `None`
+/// if `buf` does not yet hold a complete request. An empty inline line yields an
+/// empty argument list, which callers should simply skip.
+pub fn parse_request(buf: &[u8]) -> ParseResult {
+    RequestParser::default().parse(buf)
+}
+
+/// Parses requests out of input that arrives a read at a time.
+///
+/// A multibulk request that is only partly there is parsed as far as it
+/// goes, and the next call carries on from there, so one that takes many
+/// reads to arrive is still only parsed, and its arguments copied, once.
+#[derive(Debug, Default)]
+pub struct RequestParser {
+    partial: Option<Multibulk>,
+}
+
+// A multibulk request parsed up to `pos`, counted from its start.
+#[derive(Debug)]
+struct Multibulk {
+    // How many arguments are still to come.
+    remaining: usize,
+    args: Vec<Vec<u8>>,
+    pos: usize,
+    // The length of the next argument once its `$` line has been read, at
+    // which point `pos` is where its data starts.
+    bulk_len: Option<usize>,
+}
+
+impl RequestParser {
+    /// Like `parse_request`. After a call that returns `None`, the next one
+    /// must be given the same input again, with whatever has arrived since
+    /// added at the end.
+    pub fn parse(&mut self, buf: &[u8]) -> ParseResult {
+        let partial = match &mut self.partial {
+            Some(partial) => partial,
+            None if buf.first() == Some(&b'*') => match start_multibulk(buf)? {
+                Some(partial) => self.partial.insert(partial),
+                None => return Ok(None),
+            },
+            None if buf.is_empty() => return Ok(None),
+            None => return parse_inline(buf),
+        };
+
+        let result = partial.parse(buf);
+        if !matches!(result, Ok(None)) {
+            self.partial = None;
+        }
+        result
+    }
+}
+
+fn start_multibulk(buf: &[u8]) -> Result<Option<Multibulk>, ProtocolError> {
+    let Some((line, pos)) = read_line(buf, 0) else {
+        return Ok(None);
+    };
+
+    let count = parse_int(&line[1..]).ok_or(ProtocolError::InvalidMultibulkLength)?;
+    if count > MAX_MULTIBULK_LEN {
+        return Err(ProtocolError::InvalidMultibulkLength);
+    }
+
+    Ok(Some(Multibulk {
+        remaining: count.max(0) as usize,
+        args: Vec::with_capacity(count.clamp(0, MAX_ARGS_PREALLOC) as usize),
+        pos,
+        bulk_len: None,
+    }))
+}
+
+impl Multibulk {
+    fn parse(&mut self, buf: &[u8]) -> ParseResult {
+        while self.remaining > 0 {
+            let len = match self.bulk_len {
+                Some(len) => len,
+                None => {
+                    let Some((line, next)) = read_line(buf, self.pos) else {
+                        return Ok(None);
+                    };
+                    if line.first() != Some(&b'$') {
+                        return Err(ProtocolError::ExpectedBulk(
+                            line.first().copied().unwrap_or(b' '),
+                        ));
+                    }
+
+                    let len = parse_int(&line[1..]).ok_or(ProtocolError::InvalidBulkLength)?;
+                    if !(0..=MAX_BULK_LEN).contains(&len) {
+                        return Err(ProtocolError::InvalidBulkLength);
+                    }
+                    self.pos = next;
+                    *self.bulk_len.insert(len as usize)
+                }
+            };
+
+            let start = self.pos;
+            if buf.len() < start + len + 2 {
+                return Ok(None);
+            }
+            if &buf[start + len..start + len + 2] != b"\r\n" {
+                return Err(ProtocolError::InvalidBulkLength);
+            }
+
+            self.args.push(buf[start..start + len].to_vec());
+            self.pos = start + len + 2;
+            self.bulk_len = None;
+            self.remaining -= 1;
+        }
+
+        Ok(Some((std::mem::take(&mut self.args), self.pos)))
+    }
+}
+
+fn parse_inline(buf: &[u8]) -> ParseResult {
+    let Some((line, consumed)) = read_line(buf, 0) else {
+        if buf.len() > MAX_INLINE_LEN {
+            return Err(ProtocolError::TooBigInline);
+        }
+        return Ok(None);
+    };
+    if line.len() > MAX_INLINE_LEN {
+        return Err(ProtocolError::TooBigInline);
+    }
+
+    let args = split_inline_args(line)?;
+    Ok(Some((args, consumed)))
+}
+
+/// Splits an inline request into arguments. Double and single quotes group
+/// words the way `redis-cli` and `sdssplitargs` do, so `SET k "hello world"`
+/// works from a plain terminal.
+fn split_inline_args(line: &[u8]) -> Result<Vec<Vec<u8>>, ProtocolError> {
+    let mut args = Vec::new();
+    let mut i = 0;
+
+    while i < line.len() {
+        if line[i].is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        let mut arg = Vec::new();
+        match line[i] {
+            quote @ (b'"' | b'\'') => {
+                i += 1;
+                loop {
+                    match line.get(i) {
+                        None => return Err(ProtocolError::UnbalancedQuotes),
+                        Some(&b'\\') if quote == b'"' && i + 1 < line.len() => {
+                            arg.push(match line[i + 1] {
+                                b'n' => b'\n',
+                                b'r' => b'\r',
+                                b't' => b'\t',
+                                other => other,
+                            });
+                            i += 2;
+                        }
+                        Some(&c) if c == quote => {
+                            i += 1;
+                            break;
+                        }
+                        Some(&c) => {
+                            arg.push(c);
+                            i += 1;
+                        }
+                    }
+                }
+                // A closing quote must be followed by whitespace or the end.
+                if i < line.len() && !line[i].is_ascii_whitespace() {
+                    return Err(ProtocolError::UnbalancedQuotes);
+                }
+            }
+            _ => {
+                while i < line.len() && !line[i].is_ascii_whitespace() {
+                    arg.push(line[i]);
+                    i += 1;
+                }
+            }
+        }
+        args.push(arg);
+    }
+
+    Ok(args)
+}
+
+/// Returns the line starting at `start` without its terminator, and the offset
+/// just past the terminating `\n`. A trailing `\r` is stripped.
+fn read_line(buf: &[u8], start: usize) -> Option<(&[u8], usize)> {
+    let end = buf[start..].iter().position(|&b| b == b'\n')? + start;
+    let line = &buf[start..end];
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    Some((line, end + 1))
+}
+
+fn parse_int(digits: &[u8]) -> Option<i64> {
+    std::str::from_utf8(digits).ok()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_simple_and_error() {
+        assert_eq!(Frame::ok().to_bytes(), b"+OK\r\n");
+        assert_eq!(Frame::error("ERR boom").to_bytes(), b"-ERR boom\r\n");
+    }
+
+    #[test]
+    fn test_encode_integer_bulk_and_null() {
+        assert_eq!(Frame::Integer(-3).to_bytes(), b":-3\r\n");
+        assert_eq!(Frame::bulk("hello").to_bytes(), b"$5\r\nhello\r\n");
+        assert_eq!(Frame::bulk("").to_bytes(), b"$0\r\n\r\n");
+        assert_eq!(Frame::Null.to_bytes(), b"$-1\r\n");
+    }
+
+    #[test]
+    fn test_encode_nested_array() {
+        let frame = Frame::Array(vec![
+            Frame::bulk("a"),
+            Frame::Integer(1),
+            Frame::Array(vec![]),
+        ]);
+        assert_eq!(frame.to_bytes(), b"*3\r\n$1\r\na\r\n:1\r\n*0\r\n");
+    }
+
+    #[test]
+    fn test_encode_request_round_trip() {
+        let args = vec![b"SET".to_vec(), b"k".to_vec(), b"a\r\nb".to_vec()];
+        let mut out = Vec::new();
+        encode_request(&args, &mut out);
+
+        assert_eq!(out, b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$4\r\na\r\nb\r\n");
+        assert_eq!(parse_request(&out).unwrap(), Some((args, out.len())));
+    }
+
+    #[test]
+    fn test_parse_multibulk() {
+        let input = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n";
+        let (args, consumed) = parse_request(input).unwrap().unwrap();
+
+        assert_eq!(
+            args,
+            vec![b"SET".to_vec(), b"key".to_vec(), b"value".to_vec()]
+        );
+        assert_eq!(consumed, input.len());
+    }
+
+    #[test]
+    fn test_parse_multibulk_with_spaces_and_crlf_in_value() {
+        let input = b"*2\r\n$3\r\nGET\r\n$7\r\na b\r\ncd\r\n";
+        let (args, _) = parse_request(input).unwrap().unwrap();
+
+        assert_eq!(args[1], b"a b\r\ncd".to_vec());
+    }
+
+    #[test]
+    fn test_parse_multibulk_incomplete() {
+        let input = b"*2\r\n$3\r\nGET\r\n$3\r\nke";
+        assert!(parse_request(input).unwrap().is_none());
+        assert!(parse_request(b"*2\r").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_parser_carries_on_from_where_it_stopped() {
+        let input = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n";
+        let mut parser = RequestParser::default();
+        for end in 0..input.len() {
+            assert!(parser.parse(&input[..end]).unwrap().is_none(), "{}", end);
+        }
+        // The arguments parsed so far are kept, not parsed again: had they
+        // been, this garbage in place of them would be an error.
+        let mut rest = vec![b'!'; 22];
+        rest.extend_from_slice(&input[22..]);
+        let (args, consumed) = parser.parse(&rest).unwrap().unwrap();
+        assert_eq!(
+            args,
+            vec![b"SET".to_vec(), b"key".to_vec(), b"value".to_vec()]
+        );
+        assert_eq!(consumed, 33);
+
+        // It starts afresh on the next request.
+        let (args, _) = parser.parse(b"*1\r\n$4\r\nPING\r\n").unwrap().unwrap();
+        assert_eq!(args, vec![b"PING".to_vec()]);
+    }
+
+    #[test]
+    fn test_parse_multibulk_invalid_lengths() {
+        assert_eq!(
+            parse_request(b"*x\r\n").unwrap_err(),
+            ProtocolError::InvalidMultibulkLength
+        );
+        assert_eq!(
+            parse_request(b"*1\r\n$-5\r\n").unwrap_err(),
+            ProtocolError::InvalidBulkLength
+        );
+        assert_eq!(
+            parse_request(b"*1\r\n:1\r\n").unwrap_err(),
+            ProtocolError::ExpectedBulk(b':')
+        );
+    }
+
+    #[test]
+    fn test_parse_inline() {
+        let (args, consumed) = parse_request(b"SET mykey  myvalue\r\nGET")
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            args,
+            vec![b"SET".to_vec(), b"mykey".to_vec(), b"myvalue".to_vec()]
+        );
+        assert_eq!(consumed, 20);
+    }
+
+    #[test]
+    fn test_parse_inline_quoted() {
+        let (args, _) = parse_request(b"SET k \"hello world\\n\" 'x y'\n")
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(args[2], b"hello world\n".to_vec());
+        assert_eq!(args[3], b"x y".to_vec());
+        assert_eq!(
+            parse_request(b"SET k \"oops\n").unwrap_err(),
+            ProtocolError::UnbalancedQuotes
+        );
+    }
+
+    #[test]
+    fn test_parse_inline_incomplete_and_empty() {
+        assert!(parse_request(b"GET key").unwrap().is_none());
+        assert_eq!(
+            parse_request(&vec![b'a'; MAX_INLINE_LEN + 1]).unwrap_err(),
+            ProtocolError::TooBigInline
+        );
+
+        let (args, consumed) = parse_request(b"\r\n").unwrap().unwrap();
+        assert!(args.is_empty());
+        assert_eq!(consumed, 2);
+    }
+}
@@ -0,0 +1,413 @@
+use crate::crc64;
+use crate::storage::{Snapshot, Storage, StoredDataV1};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+// A snapshot is laid out as:
+//
+//   MAGIC | version (u32 LE) | bincode-encoded `Storage` | CRC-64 (u64 LE)
+//
+// where the checksum covers everything before it. Bump `VERSION` whenever the
+// serialized layout of `Storage` changes in a way old files can't be read as.
+//
+// Version 2 added expiries to the fields of hashes. Version 1 files, and the
+// legacy ones from before the header, are read with the old layout.
+const MAGIC: &[u8; 9] = b"IRONCACHE";
+pub const VERSION: u32 = 2;
+const HEADER_LEN: usize = MAGIC.len() + 4;
+const CHECKSUM_LEN: usize = 8;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    UnsupportedVersion(u32),
+    ChecksumMismatch,
+    Truncated,
+    Corrupt(bincode::Error),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "{}", e),
+            SnapshotError::UnsupportedVersion(v) => write!(
+                f,
+                "snapshot version {} is not supported (this build reads up to {})",
+                v, VERSION
+            ),
+            SnapshotError::ChecksumMismatch => write!(f, "checksum mismatch, the file is corrupt"),
+            SnapshotError::Truncated => write!(f, "the file is truncated"),
+            SnapshotError::Corrupt(e) => write!(f, "the file is corrupt: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+impl From<bincode::Error> for SnapshotError {
+    fn from(e: bincode::Error) -> Self {
+        match *e {
+            bincode::ErrorKind::Io(e) => SnapshotError::Io(e),
+            _ => SnapshotError::Corrupt(e),
+        }
+    }
+}
+
+/// Writes `snapshot` to `path` without ever leaving a half-written file
+/// there.
+///
+/// The snapshot goes to a temporary file next to `path`, is fsynced, and is
+/// then renamed over `path`, so a crash or a full disk leaves the previous
+/// snapshot intact.
+pub fn save(snapshot: &Snapshot, path: &Path) -> Result<(), SnapshotError> {
+    replace_file(path, |tmp_path| write_file(snapshot, tmp_path))
+}
+
+/// Creates the file at `path` by having `write` create it under a temporary
+/// name first, then renaming it into place once `write` has synced it.
+pub(crate) fn replace_file<T, E: From<io::Error>>(
+    path: &Path,
+    write: impl FnOnce(&Path) -> Result<T, E>,
+) -> Result<T, E> {
+    let tmp_path = temp_path(path);
+
+    let result = write(&tmp_path).and_then(|written| {
+        fs::rename(&tmp_path, path)?;
+        sync_parent_dir(path)?;
+        Ok(written)
+    });
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+fn write_file(snapshot: &Snapshot, path: &Path) -> Result<(), SnapshotError> {
+    let mut out = crc64::Writer::new(BufWriter::new(File::create(path)?));
+
+    out.write_all(MAGIC)?;
+    out.write_all(&VERSION.to_le_bytes())?;
+    bincode::serialize_into(&mut out, snapshot)?;
+
+    let crc = out.crc();
+    let mut inner = out.into_inner();
+    inner.write_all(&crc.to_le_bytes())?;
+
+    inner.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    Ok(())
+}
+
+/// Loads the snapshot at `path`, or `None` if there is no file.
+///
+/// The header and checksum are verified before anything is deserialized.
+/// Files written before snapshots had a header are still accepted.
+pub fn load(path: &Path) -> Result<Option<Storage>, SnapshotError> {
+    match fs::read(path) {
+        Ok(data) => decode(&data).map(Some),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Loads a snapshot from the whole of `data`, as `load` reads it from a file.
+pub fn decode(data: &[u8]) -> Result<Storage, SnapshotError> {
+    if !data.starts_with(MAGIC) {
+        return load_legacy(data);
+    }
+
+    if data.len() < HEADER_LEN + CHECKSUM_LEN {
+        return Err(SnapshotError::Truncated);
+    }
+
+    let version = u32::from_le_bytes(data[MAGIC.len()..HEADER_LEN].try_into().unwrap());
+    if version > VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+
+    let (content, checksum) = data.split_at(data.len() - CHECKSUM_LEN);
+    if crc64::checksum(content) != u64::from_le_bytes(checksum.try_into().unwrap()) {
+        return Err(SnapshotError::ChecksumMismatch);
+    }
+
+    let content = &content[HEADER_LEN..];
+    let storage = if version < 2 {
+        bincode::deserialize::<StoredDataV1>(content)?.into()
+    } else {
+        bincode::deserialize(content)?
+    };
+    Ok(storage)
+}
+
+// Before the header existed a snapshot was just the bincode-encoded
+// `Storage`. There is no checksum to verify, so at least make sure a damaged
+// length prefix can't make us allocate more than the file could hold.
+fn load_legacy(data: &[u8]) -> Result<Storage, SnapshotError> {
+    use bincode::Options;
+
+    bincode::options()
+        .with_fixint_encoding()
+        .with_limit(data.len() as u64)
+        .deserialize::<StoredDataV1>(data)
+        .map(Storage::from)
+        .map_err(|e| match *e {
+            bincode::ErrorKind::SizeLimit | bincode::ErrorKind::Io(_) => SnapshotError::Truncated,
+            _ => SnapshotError::Corrupt(e),
+        })
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".tmp-{}", std::process::id()));
+    path.with_file_name(name)
+}
+
+// Makes the rename itself durable.
+#[cfg(unix)]
+pub(crate) fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn sync_parent_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Value;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn temp_file(name: &str) -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::SeqCst);
+        std::env::temp_dir().join(format!(
+            "iron_cache_snapshot_{}_{}_{}",
+            std::process::id(),
+            n,
+            name
+        ))
+    }
+
+    fn sample_storage() -> Storage {
+        let mut storage = Storage::new();
+        storage.set(b"k".to_vec(), b"v".to_vec(), None).unwrap();
+        storage
+            .rpush(b"l", vec![b"a".to_vec(), b"b".to_vec()])
+            .unwrap();
+        storage
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = temp_file("roundtrip.db");
+        save(&sample_storage().snapshot(), &path).unwrap();
+
+        let mut loaded = load(&path).unwrap().unwrap();
+        match &loaded.get(b"k").unwrap().data {
+            Value::String(s) => assert_eq!(s, b"v"),
+            _ => panic!("Expected string value"),
+        }
+        assert!(!temp_path(&path).exists());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_load_missing_file() {
+        assert!(load(&temp_file("missing.db")).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_detects_corruption() {
+        let path = temp_file("corrupt.db");
+        save(&sample_storage().snapshot(), &path).unwrap();
+
+        let mut data = fs::read(&path).unwrap();
+        let middle = data.len() / 2;
+        data[middle] ^= 0xff;
+        fs::write(&path, &data).unwrap();
+
+        assert!(matches!(load(&path), Err(SnapshotError::ChecksumMismatch)));
+
+        fs::write(&path, &data[..HEADER_LEN + 2]).unwrap();
+        assert!(matches!(load(&path), Err(SnapshotError::Truncated)));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_rejects_newer_version() {
+        let path = temp_file("future.db");
+        save(&sample_storage().snapshot(), &path).unwrap();
+
+        let mut data = fs::read(&path).unwrap();
+        data[MAGIC.len()..HEADER_LEN].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        fs::write(&path, &data).unwrap();
+
+        assert!(matches!(
+            load(&path),
+            Err(SnapshotError::UnsupportedVersion(v)) if v == VERSION + 1
+        ));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_loads_legacy_snapshot() {
+        let path = temp_file("legacy.db");
+        fs::write(&path, bincode::serialize(&sample_storage()).unwrap()).unwrap();
+
+        let mut loaded = load(&path).unwrap().unwrap();
+        assert!(loaded.get(b"k").is_some());
+
+        fs::write(&path, b"definitely not a snapshot").unwrap();
+        assert!(load(&path).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_keeps_hash_field_expiries() {
+        let path = temp_file("field_ttl.db");
+        let mut storage = Storage::new();
+        let at = crate::storage::now_millis() + 60_000;
+        storage
+            .hset(b"h".to_vec(), vec![(b"f".to_vec(), b"v".to_vec())])
+            .unwrap();
+        storage
+            .hexpire(b"h", &[b"f".to_vec()], at as i64, Default::default())
+            .unwrap();
+        save(&storage.snapshot(), &path).unwrap();
+
+        let mut loaded = load(&path).unwrap().unwrap();
+        assert_eq!(
+            loaded.hexpiry(b"h", &[b"f".to_vec()]).unwrap(),
+            vec![Some(Some(at))]
+        );
+        assert_eq!(loaded.hashes_with_expiring_fields(), 1);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_keeps_sorted_sets() {
+        let path = temp_file("zset.db");
+        let mut storage = Storage::new();
+        let pairs = (0..100)
+            .map(|i| (f64::from(i % 7), format!("m{}", i).into_bytes()))
+            .collect();
+        storage
+            .zadd(b"z", pairs, &crate::storage::ZAddOptions::default())
+            .unwrap();
+        save(&storage.snapshot(), &path).unwrap();
+
+        let mut loaded = load(&path).unwrap().unwrap();
+        let all = crate::sorted_set::RangeBy::Rank(0, -1);
+        assert_eq!(
+            loaded.zrange(b"z", &all, false, None),
+            storage.zrange(b"z", &all, false, None)
+        );
+        assert_eq!(loaded.zrank(b"z", b"m14", false), Ok(Some(1)));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_keeps_streams() {
+        use crate::stream::{NewId, ReadFrom, StreamId};
+        use std::ops::Bound;
+
+        let path = temp_file("stream.db");
+        let mut storage = Storage::new();
+        let fields = vec![(b"f".to_vec(), b"v".to_vec())];
+        let id = storage
+            .xadd(b"s", NewId::Auto, fields, false, None)
+            .unwrap()
+            .unwrap();
+        storage
+            .xgroup_create(b"s", b"g", ReadFrom::After(StreamId::MIN), false)
+            .unwrap();
+        storage
+            .xreadgroup(
+                b"g",
+                b"c",
+                &[b"s".to_vec()],
+                &[ReadFrom::Undelivered],
+                None,
+                false,
+            )
+            .unwrap();
+        save(&storage.snapshot(), &path).unwrap();
+
+        let mut loaded = load(&path).unwrap().unwrap();
+        let all = |storage: &mut Storage| {
+            storage.xrange(b"s", Bound::Unbounded, Bound::Unbounded, false, None)
+        };
+        assert_eq!(all(&mut loaded), all(&mut storage));
+        let group = loaded.xgroup(b"s", b"g").unwrap();
+        assert_eq!(group.last_delivered, id);
+        assert_eq!(group.pending()[&id].consumer, b"c");
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_loads_version_1_hashes() {
+        // The layout version 1 wrote hashes in, as a plain field map.
+        #[derive(serde::Serialize)]
+        enum OldValue {
+            #[allow(dead_code)]
+            String(Vec<u8>),
+            #[allow(dead_code)]
+            List(Vec<Vec<u8>>),
+            Hash(crate::storage::FieldMap),
+        }
+        #[derive(serde::Serialize)]
+        struct OldStoreValue {
+            data: OldValue,
+            expiry: Option<u64>,
+        }
+        #[derive(serde::Serialize)]
+        struct OldStorage {
+            data: std::collections::HashMap<Vec<u8>, OldStoreValue>,
+        }
+
+        let hash = [(b"f".to_vec(), b"v".to_vec())].into();
+        let old = OldStorage {
+            data: [(
+                b"h".to_vec(),
+                OldStoreValue {
+                    data: OldValue::Hash(hash),
+                    expiry: None,
+                },
+            )]
+            .into(),
+        };
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&1u32.to_le_bytes());
+        bincode::serialize_into(&mut data, &old).unwrap();
+        let checksum = crc64::checksum(&data);
+        data.extend_from_slice(&checksum.to_le_bytes());
+        let path = temp_file("v1.db");
+        fs::write(&path, &data).unwrap();
+
+        let mut loaded = load(&path).unwrap().unwrap();
+        assert_eq!(loaded.hget(b"h", b"f").unwrap(), Some(&b"v".to_vec()));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,674 @@
+//! The sorted set: members ordered by score, then by member, kept in a skip
+//! list for ordered and rank queries alongside a map for score lookups.
+//!
+//! The skip list is the one Redis uses: every link records how many nodes it
+//! skips, so the rank of a member and the member at a rank are found in
+//! O(log n) like any other lookup. Nodes live in a `Vec` and link to each
+//! other by index, with freed slots reused.
+
+use crate::storage::random_below;
+use serde::ser::SerializeSeq;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::HashMap;
+use std::ops::Bound;
+
+const MAX_LEVEL: usize = 32;
+// The header node, which holds no member and links to the first node of
+// every level.
+const HEAD: usize = 0;
+
+/// Which members a ZRANGE style query selects.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RangeBy {
+    /// By rank, both ends included. Negative ranks count from the end.
+    Rank(i64, i64),
+    Score(Bound<f64>, Bound<f64>),
+    /// By member, for sets whose members all have the same score.
+    Lex(Bound<Vec<u8>>, Bound<Vec<u8>>),
+}
+
+/// How ZUNIONSTORE and ZINTERSTORE combine the scores of a member found in
+/// more than one input.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Aggregate {
+    #[default]
+    Sum,
+    Min,
+    Max,
+}
+
+impl Aggregate {
+    pub fn combine(self, a: f64, b: f64) -> f64 {
+        match self {
+            // inf + -inf, which Redis counts as 0.
+            Aggregate::Sum => match a + b {
+                sum if sum.is_nan() => 0.0,
+                sum => sum,
+            },
+            Aggregate::Min => a.min(b),
+            Aggregate::Max => a.max(b),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(from = "Vec<(Vec<u8>, f64)>")]
+pub struct SortedSet {
+    scores: HashMap<Vec<u8>, f64>,
+    list: SkipList,
+}
+
+// Serialized as its members and scores, lowest score first. The skip list is
+// rebuilt when loading.
+impl Serialize for SortedSet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Formats like bincode need the length up front, which the skip
+        // list walk doesn't give.
+        let mut seq = serializer.serialize_seq(Some(self.len()))?;
+        for pair in self.iter() {
+            seq.serialize_element(&pair)?;
+        }
+        seq.end()
+    }
+}
+
+impl From<Vec<(Vec<u8>, f64)>> for SortedSet {
+    fn from(members: Vec<(Vec<u8>, f64)>) -> Self {
+        let mut set = SortedSet::new();
+        for (member, score) in members {
+            set.insert(member, score);
+        }
+        set
+    }
+}
+
+impl SortedSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Adds `member` or changes its score. Returns whether it is new.
+    pub fn insert(&mut self, member: Vec<u8>, score: f64) -> bool {
+        match self.scores.insert(member.clone(), score) {
+            Some(old) if old == score => false,
+            Some(old) => {
+                self.list.remove(old, &member);
+                self.list.insert(score, member);
+                false
+            }
+            None => {
+                self.list.insert(score, member);
+                true
+            }
+        }
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => {
+                self.list.remove(score, member);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// How many members rank before `member`, counting from the lowest
+    /// score, or from the highest with `rev`.
+    pub fn rank(&self, member: &[u8], rev: bool) -> Option<usize> {
+        let score = self.score(member)?;
+        let rank = self.list.rank(score, member)?;
+        Some(if rev { self.len() - 1 - rank } else { rank })
+    }
+
+    /// Every member with its score, lowest score first.
+    pub fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, f64)> {
+        self.list.walk(self.list.first(), false)
+    }
+
+    /// The members `by` selects, in order or in reverse with `rev`. Of those,
+    /// `offset` are skipped and at most `count` returned.
+    pub fn range(
+        &self,
+        by: &RangeBy,
+        rev: bool,
+        offset: usize,
+        count: Option<usize>,
+    ) -> Vec<(Vec<u8>, f64)> {
+        let (start, len) = match by {
+            RangeBy::Rank(start, stop) => {
+                let Some((start, stop)) = self.ranks(*start, *stop) else {
+                    return Vec::new();
+                };
+                let rank = if rev { self.len() - 1 - start } else { start };
+                (self.list.by_rank(rank), stop - start + 1)
+            }
+            _ if rev => (
+                self.list
+                    .last_where(|score, member| below_max(by, score, member)),
+                usize::MAX,
+            ),
+            _ => (
+                self.list
+                    .first_where(|score, member| above_min(by, score, member)),
+                usize::MAX,
+            ),
+        };
+
+        self.list
+            .walk(start, rev)
+            .take(len)
+            .take_while(|(member, score)| {
+                above_min(by, *score, member) && below_max(by, *score, member)
+            })
+            .skip(offset)
+            .take(count.unwrap_or(usize::MAX))
+            .map(|(member, score)| (member.clone(), score))
+            .collect()
+    }
+
+    /// How many members `by` selects, without walking them.
+    pub fn count(&self, by: &RangeBy) -> usize {
+        if let RangeBy::Rank(start, stop) = by {
+            return self
+                .ranks(*start, *stop)
+                .map_or(0, |(start, stop)| stop - start + 1);
+        }
+
+        let first = self
+            .list
+            .first_where(|score, member| above_min(by, score, member));
+        let last = self
+            .list
+            .last_where(|score, member| below_max(by, score, member));
+        match (first, last) {
+            (Some(first), Some(last)) => {
+                let first = self.list.node_rank(first);
+                let last = self.list.node_rank(last);
+                (last + 1).saturating_sub(first)
+            }
+            _ => 0,
+        }
+    }
+
+    /// Removes and returns up to `count` members with the lowest scores, or
+    /// the highest with `max`.
+    pub fn pop(&mut self, max: bool, count: usize) -> Vec<(Vec<u8>, f64)> {
+        let popped = self.range(&RangeBy::Rank(0, -1), max, 0, Some(count));
+        for (member, _) in &popped {
+            self.remove(member);
+        }
+        popped
+    }
+
+    // Turns ZRANGE style ranks into an inclusive range of valid ranks, if
+    // there are any in it.
+    fn ranks(&self, start: i64, stop: i64) -> Option<(usize, usize)> {
+        let len = self.len() as i64;
+        let start = if start < 0 {
+            (len + start).max(0)
+        } else {
+            start
+        };
+        let stop = if stop < 0 {
+            len + stop
+        } else {
+            stop.min(len - 1)
+        };
+        (start <= stop && start < len).then_some((start as usize, stop as usize))
+    }
+}
+
+// Whether a member isn't before the start of the range `by` selects by score
+// or member. Ranges by rank select everything here.
+fn above_min(by: &RangeBy, score: f64, member: &[u8]) -> bool {
+    match by {
+        RangeBy::Rank(..) => true,
+        RangeBy::Score(min, _) => match min {
+            Bound::Included(min) => score >= *min,
+            Bound::Excluded(min) => score > *min,
+            Bound::Unbounded => true,
+        },
+        RangeBy::Lex(min, _) => match min {
+            Bound::Included(min) => member >= min.as_slice(),
+            Bound::Excluded(min) => member > min.as_slice(),
+            Bound::Unbounded => true,
+        },
+    }
+}
+
+// Whether a member isn't past the end of the range.
+fn below_max(by: &RangeBy, score: f64, member: &[u8]) -> bool {
+    match by {
+        RangeBy::Rank(..) => true,
+        RangeBy::Score(_, max) => match max {
+            Bound::Included(max) => score <= *max,
+            Bound::Excluded(max) => score < *max,
+            Bound::Unbounded => true,
+        },
+        RangeBy::Lex(_, max) => match max {
+            Bound::Included(max) => member <= max.as_slice(),
+            Bound::Excluded(max) => member < max.as_slice(),
+            Bound::Unbounded => true,
+        },
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Link {
+    next: Option<usize>,
+    // How many nodes the link moves forward: 1 to the very next node.
+    span: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    member: Vec<u8>,
+    score: f64,
+    forward: Vec<Link>,
+    backward: Option<usize>,
+}
+
+impl Node {
+    fn new(member: Vec<u8>, score: f64, level: usize) -> Self {
+        Node {
+            member,
+            score,
+            forward: vec![
+                Link {
+                    next: None,
+                    span: 0
+                };
+                level
+            ],
+            backward: None,
+        }
+    }
+
+    // Whether this node comes before `score` and `member`.
+    fn is_before(&self, score: f64, member: &[u8]) -> bool {
+        self.score < score || self.score == score && self.member.as_slice() < member
+    }
+}
+
+#[derive(Debug, Clone)]
+struct SkipList {
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    tail: Option<usize>,
+    len: usize,
+    // How many levels are in use.
+    level: usize,
+}
+
+impl Default for SkipList {
+    fn default() -> Self {
+        SkipList {
+            nodes: vec![Node::new(Vec::new(), 0.0, MAX_LEVEL)],
+            free: Vec::new(),
+            tail: None,
+            len: 0,
+            level: 1,
+        }
+    }
+}
+
+impl SkipList {
+    fn link(&self, node: usize, level: usize) -> &Link {
+        &self.nodes[node].forward[level]
+    }
+
+    fn link_mut(&mut self, node: usize, level: usize) -> &mut Link {
+        &mut self.nodes[node].forward[level]
+    }
+
+    fn first(&self) -> Option<usize> {
+        self.link(HEAD, 0).next
+    }
+
+    // Each level is used by a quarter of the nodes of the level below.
+    fn random_level() -> usize {
+        let mut level = 1;
+        while level < MAX_LEVEL && random_below(4) == 0 {
+            level += 1;
+        }
+        level
+    }
+
+    // For every level, the last node before `score` and `member`, and its
+    // rank counting the header as 0.
+    fn predecessors(&self, score: f64, member: &[u8]) -> ([usize; MAX_LEVEL], [usize; MAX_LEVEL]) {
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut rank = [0; MAX_LEVEL];
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            rank[i] = if i + 1 == self.level { 0 } else { rank[i + 1] };
+            while let Some(next) = self.link(x, i).next
+                && self.nodes[next].is_before(score, member)
+            {
+                rank[i] += self.link(x, i).span;
+                x = next;
+            }
+            update[i] = x;
+        }
+        (update, rank)
+    }
+
+    // Adds a member that isn't in the list.
+    fn insert(&mut self, score: f64, member: Vec<u8>) {
+        let (mut update, mut rank) = self.predecessors(score, &member);
+
+        let level = Self::random_level();
+        if level > self.level {
+            for i in self.level..level {
+                rank[i] = 0;
+                update[i] = HEAD;
+                self.link_mut(HEAD, i).span = self.len;
+            }
+            self.level = level;
+        }
+
+        let node = Node::new(member, score, level);
+        let x = match self.free.pop() {
+            Some(slot) => {
+                self.nodes[slot] = node;
+                slot
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+
+        for i in 0..level {
+            let before = self.link(update[i], i).clone();
+            *self.link_mut(x, i) = Link {
+                next: before.next,
+                span: before.span - (rank[0] - rank[i]),
+            };
+            *self.link_mut(update[i], i) = Link {
+                next: Some(x),
+                span: rank[0] - rank[i] + 1,
+            };
+        }
+        for (i, &before) in update.iter().enumerate().take(self.level).skip(level) {
+            self.link_mut(before, i).span += 1;
+        }
+
+        self.nodes[x].backward = (update[0] != HEAD).then_some(update[0]);
+        match self.link(x, 0).next {
+            Some(next) => self.nodes[next].backward = Some(x),
+            None => self.tail = Some(x),
+        }
+        self.len += 1;
+    }
+
+    fn remove(&mut self, score: f64, member: &[u8]) {
+        let (update, _) = self.predecessors(score, member);
+        let Some(x) = self.link(update[0], 0).next else {
+            return;
+        };
+        if self.nodes[x].score != score || self.nodes[x].member != member {
+            return;
+        }
+
+        for (i, &before) in update.iter().enumerate().take(self.level) {
+            if self.link(before, i).next == Some(x) {
+                let removed = self.link(x, i).clone();
+                let link = self.link_mut(before, i);
+                link.span += removed.span;
+                link.span -= 1;
+                link.next = removed.next;
+            } else {
+                self.link_mut(before, i).span -= 1;
+            }
+        }
+
+        let backward = self.nodes[x].backward;
+        match self.link(x, 0).next {
+            Some(next) => self.nodes[next].backward = backward,
+            None => self.tail = backward,
+        }
+        while self.level > 1 && self.link(HEAD, self.level - 1).next.is_none() {
+            self.level -= 1;
+        }
+
+        self.nodes[x].member = Vec::new();
+        self.nodes[x].forward = Vec::new();
+        self.free.push(x);
+        self.len -= 1;
+    }
+
+    // The rank of the member, from 0.
+    fn rank(&self, score: f64, member: &[u8]) -> Option<usize> {
+        let (update, rank) = self.predecessors(score, member);
+        let x = self.link(update[0], 0).next?;
+        (self.nodes[x].score == score && self.nodes[x].member == member).then_some(rank[0])
+    }
+
+    fn node_rank(&self, node: usize) -> usize {
+        let node = &self.nodes[node];
+        self.rank(node.score, &node.member)
+            .expect("nodes in the list have a rank")
+    }
+
+    // The node at `rank`, from 0.
+    fn by_rank(&self, rank: usize) -> Option<usize> {
+        // Ranks count the header as 0 here.
+        let target = rank + 1;
+        let mut traversed = 0;
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.link(x, i).next
+                && traversed + self.link(x, i).span <= target
+            {
+                traversed += self.link(x, i).span;
+                x = next;
+            }
+            if traversed == target {
+                return Some(x);
+            }
+        }
+        None
+    }
+
+    // The first node for which `pred` holds, when it holds for every node
+    // after one it holds for.
+    fn first_where(&self, pred: impl Fn(f64, &[u8]) -> bool) -> Option<usize> {
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.link(x, i).next
+                && !pred(self.nodes[next].score, &self.nodes[next].member)
+            {
+                x = next;
+            }
+        }
+        self.link(x, 0).next
+    }
+
+    // The last node for which `pred` holds, when it holds for every node
+    // before one it holds for.
+    fn last_where(&self, pred: impl Fn(f64, &[u8]) -> bool) -> Option<usize> {
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.link(x, i).next
+                && pred(self.nodes[next].score, &self.nodes[next].member)
+            {
+                x = next;
+            }
+        }
+        (x != HEAD).then_some(x)
+    }
+
+    // The members from `start` on, walking backwards with `rev`.
+    fn walk(&self, start: Option<usize>, rev: bool) -> impl Iterator<Item = (&Vec<u8>, f64)> {
+        std::iter::successors(start, move |&x| {
+            if rev {
+                self.nodes[x].backward
+            } else {
+                self.link(x, 0).next
+            }
+        })
+        .map(|x| (&self.nodes[x].member, self.nodes[x].score))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members(pairs: &[(Vec<u8>, f64)]) -> Vec<&str> {
+        pairs
+            .iter()
+            .map(|(member, _)| std::str::from_utf8(member).unwrap())
+            .collect()
+    }
+
+    fn sample() -> SortedSet {
+        let mut set = SortedSet::new();
+        for (i, name) in ["a", "b", "c", "d", "e"].iter().enumerate() {
+            set.insert(name.as_bytes().to_vec(), i as f64);
+        }
+        set
+    }
+
+    #[test]
+    fn test_order_and_rank() {
+        let mut set = SortedSet::new();
+        assert!(set.insert(b"b".to_vec(), 2.0));
+        assert!(set.insert(b"a".to_vec(), 2.0));
+        assert!(set.insert(b"c".to_vec(), 1.0));
+        assert!(!set.insert(b"c".to_vec(), 3.0));
+
+        let all: Vec<_> = set.iter().map(|(member, _)| member.clone()).collect();
+        assert_eq!(all, vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(set.rank(b"a", false), Some(0));
+        assert_eq!(set.rank(b"c", false), Some(2));
+        assert_eq!(set.rank(b"c", true), Some(0));
+        assert_eq!(set.rank(b"x", false), None);
+
+        assert!(set.remove(b"a"));
+        assert!(!set.remove(b"a"));
+        assert_eq!(set.rank(b"b", false), Some(0));
+        assert_eq!(set.len(), 2);
+    }
+
+    #[test]
+    fn test_many_members() {
+        let mut set = SortedSet::new();
+        // Inserted out of order, with some removed again.
+        for i in 0..1000u32 {
+            let n = (i * 7919) % 1000;
+            set.insert(n.to_string().into_bytes(), n as f64);
+        }
+        for n in (0..1000u32).filter(|n| n % 3 == 0) {
+            set.remove(n.to_string().as_bytes());
+        }
+
+        let kept: Vec<u32> = (0..1000).filter(|n| n % 3 != 0).collect();
+        assert_eq!(set.len(), kept.len());
+        for (rank, n) in kept.iter().enumerate() {
+            assert_eq!(set.rank(n.to_string().as_bytes(), false), Some(rank));
+            assert_eq!(
+                set.list.by_rank(rank).map(|x| set.list.nodes[x].score),
+                Some(*n as f64)
+            );
+        }
+        let scores: Vec<f64> = set.iter().map(|(_, score)| score).collect();
+        assert_eq!(scores, kept.iter().map(|&n| n as f64).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_range_by_rank() {
+        let set = sample();
+        assert_eq!(
+            members(&set.range(&RangeBy::Rank(1, 2), false, 0, None)),
+            ["b", "c"]
+        );
+        assert_eq!(
+            members(&set.range(&RangeBy::Rank(-2, -1), false, 0, None)),
+            ["d", "e"]
+        );
+        assert_eq!(
+            members(&set.range(&RangeBy::Rank(0, 1), true, 0, None)),
+            ["e", "d"]
+        );
+        assert_eq!(
+            members(&set.range(&RangeBy::Rank(3, 100), false, 0, None)),
+            ["d", "e"]
+        );
+        assert!(set.range(&RangeBy::Rank(3, 1), false, 0, None).is_empty());
+        assert!(set.range(&RangeBy::Rank(5, 10), false, 0, None).is_empty());
+        assert_eq!(set.count(&RangeBy::Rank(-100, 100)), 5);
+    }
+
+    #[test]
+    fn test_range_by_score() {
+        let set = sample();
+        let by = RangeBy::Score(Bound::Excluded(0.0), Bound::Included(3.0));
+        assert_eq!(members(&set.range(&by, false, 0, None)), ["b", "c", "d"]);
+        assert_eq!(members(&set.range(&by, true, 0, None)), ["d", "c", "b"]);
+        assert_eq!(members(&set.range(&by, false, 1, Some(1))), ["c"]);
+        assert_eq!(set.count(&by), 3);
+
+        let everything = RangeBy::Score(Bound::Unbounded, Bound::Unbounded);
+        assert_eq!(set.count(&everything), 5);
+        let nothing = RangeBy::Score(Bound::Included(10.0), Bound::Unbounded);
+        assert!(set.range(&nothing, true, 0, None).is_empty());
+        assert_eq!(set.count(&nothing), 0);
+    }
+
+    #[test]
+    fn test_range_by_lex() {
+        let mut set = SortedSet::new();
+        for name in ["a", "b", "c", "d"] {
+            set.insert(name.as_bytes().to_vec(), 0.0);
+        }
+
+        let by = RangeBy::Lex(
+            Bound::Included(b"b".to_vec()),
+            Bound::Excluded(b"d".to_vec()),
+        );
+        assert_eq!(members(&set.range(&by, false, 0, None)), ["b", "c"]);
+        assert_eq!(members(&set.range(&by, true, 0, None)), ["c", "b"]);
+        assert_eq!(
+            set.count(&RangeBy::Lex(
+                Bound::Unbounded,
+                Bound::Included(b"b".to_vec())
+            )),
+            2
+        );
+    }
+
+    #[test]
+    fn test_pop() {
+        let mut set = sample();
+        assert_eq!(members(&set.pop(false, 2)), ["a", "b"]);
+        assert_eq!(members(&set.pop(true, 1)), ["e"]);
+        assert_eq!(members(&set.pop(true, 10)), ["d", "c"]);
+        assert!(set.is_empty());
+    }
+
+    #[test]
+    fn test_serialization() {
+        let set = sample();
+        let loaded: SortedSet = bincode::deserialize(&bincode::serialize(&set).unwrap()).unwrap();
+        assert_eq!(
+            loaded.iter().collect::<Vec<_>>(),
+            set.iter().collect::<Vec<_>>()
+        );
+        assert_eq!(loaded.rank(b"c", false), Some(2));
+    }
+}
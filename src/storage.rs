@@ -1,226 +1,1590 @@
+use crate::notify::{EventClass, KeyEvent, NotifyConfig};
+use crate::sorted_set::{Aggregate, RangeBy, SortedSet};
+use crate::stream::{
+    AutoClaimed, ClaimOptions, ConsumerGroup, Fields, NewId, ReadFrom, Stream, StreamId, Trim,
+};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::{HashMap, VecDeque},
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    cell::Cell,
+    collections::{BTreeSet, HashMap, HashSet, VecDeque, hash_map::RandomState},
+    hash::{BuildHasher, Hasher},
+    ops::{Bound, Deref},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Milliseconds since the Unix epoch, the unit expiry timestamps are kept in.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis() as u64
+}
+
+/// The longest a string may grow to through APPEND and SETRANGE, the same as
+/// Redis' default `proto-max-bulk-len`.
+pub const MAX_STRING_LEN: usize = 512 * 1024 * 1024;
+const STRING_TOO_LONG: &str = "ERR string exceeds maximum allowed size (proto-max-bulk-len)";
+const NOT_AN_INTEGER: &str = "ERR value is not an integer or out of range";
+const NOT_A_FLOAT: &str = "ERR value is not a valid float";
+const INVALID_SET_EXPIRY: &str = "ERR invalid expire time in 'set' command";
+const OUT_OF_RANGE: &str = "ERR value is out of range";
+
+/// The most items a negative count can ask HRANDFIELD or SRANDMEMBER for. Redis takes
+/// counts down to `-i64::MAX`, but here every item picked is held in memory
+/// until the reply is written, so a huge count could take the server down.
+const MAX_RANDOM_REPEATS: u64 = 1024 * 1024;
+
+/// Key/value or field/value pairs, as MSET and HSET take them.
+pub type Pairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// The field/value pairs stored under a hash key.
+pub type FieldMap = HashMap<Vec<u8>, Vec<u8>>;
+
+/// The entries XREAD or XREADGROUP read, by stream. Entries deleted since
+/// they were delivered have no fields.
+pub type StreamsRead = Vec<(Vec<u8>, Vec<(StreamId, Option<Fields>)>)>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Value {
-    String(String),
-    List(VecDeque<String>),
-    Hash(HashMap<String, String>),
+    String(Vec<u8>),
+    List(VecDeque<Vec<u8>>),
+    Hash(HashValue),
+    Set(Members),
+    SortedSet(SortedSet),
+    Stream(Stream),
+}
+
+impl Value {
+    /// Whether this is a collection with nothing left in it. Like
+    /// Redis we never keep those around: the key is deleted instead.
+    pub fn is_empty_collection(&self) -> bool {
+        match self {
+            Value::String(_) => false,
+            Value::List(list) => list.is_empty(),
+            Value::Hash(hash) => hash.is_empty(),
+            Value::Set(set) => set.is_empty(),
+            Value::SortedSet(set) => set.is_empty(),
+            // Except streams, whose last ID and consumer groups must outlive
+            // their entries.
+            Value::Stream(_) => false,
+        }
+    }
+
+    /// The name TYPE replies with.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::SortedSet(_) => "zset",
+            Value::Stream(_) => "stream",
+        }
+    }
+}
+
+/// The fields of a hash, some of which may expire on their own (HEXPIRE).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(from = "StoredHash")]
+pub struct HashValue {
+    fields: FieldMap,
+    // When the fields that have an expiry expire, in Unix milliseconds.
+    expires: HashMap<Vec<u8>, u64>,
+    // The same, soonest first. Rebuilt when loading.
+    #[serde(skip)]
+    order: BTreeSet<(u64, Vec<u8>)>,
+}
+
+// What a `HashValue` is serialized as.
+#[derive(Deserialize)]
+struct StoredHash {
+    fields: FieldMap,
+    expires: HashMap<Vec<u8>, u64>,
+}
+
+impl From<StoredHash> for HashValue {
+    fn from(stored: StoredHash) -> Self {
+        let order = stored
+            .expires
+            .iter()
+            .map(|(field, &at)| (at, field.clone()))
+            .collect();
+        HashValue {
+            fields: stored.fields,
+            expires: stored.expires,
+            order,
+        }
+    }
+}
+
+impl From<FieldMap> for HashValue {
+    fn from(fields: FieldMap) -> Self {
+        HashValue {
+            fields,
+            ..Default::default()
+        }
+    }
+}
+
+impl Deref for HashValue {
+    type Target = FieldMap;
+
+    fn deref(&self) -> &FieldMap {
+        &self.fields
+    }
+}
+
+impl HashValue {
+    /// When `field` expires, if it has an expiry.
+    pub fn field_expiry(&self, field: &[u8]) -> Option<u64> {
+        self.expires.get(field).copied()
+    }
+
+    /// The fields that have an expiry, with when they expire.
+    pub fn expiring_fields(&self) -> impl Iterator<Item = (&Vec<u8>, u64)> {
+        self.expires.iter().map(|(field, &at)| (field, at))
+    }
+
+    // When the first of the fields expires.
+    fn next_expiry(&self) -> Option<u64> {
+        self.order.first().map(|(at, _)| *at)
+    }
+
+    // Sets `field` to `value`, dropping any expiry it had, like HSET does.
+    fn insert(&mut self, field: Vec<u8>, value: Vec<u8>) -> Option<Vec<u8>> {
+        self.set_field_expiry(&field, None);
+        self.fields.insert(field, value)
+    }
+
+    fn remove(&mut self, field: &[u8]) -> Option<Vec<u8>> {
+        self.set_field_expiry(field, None);
+        self.fields.remove(field)
+    }
+
+    fn set_field_expiry(&mut self, field: &[u8], at: Option<u64>) {
+        if let Some(old) = self.expires.remove(field) {
+            self.order.remove(&(old, field.to_vec()));
+        }
+        if let Some(at) = at {
+            self.expires.insert(field.to_vec(), at);
+            self.order.insert((at, field.to_vec()));
+        }
+    }
+
+    // Removes the fields whose expiry is at or before `now`, and returns
+    // them.
+    fn remove_expired(&mut self, now: u64) -> Vec<Vec<u8>> {
+        let mut removed = Vec::new();
+        while let Some((at, _)) = self.order.first()
+            && *at <= now
+        {
+            let (_, field) = self.order.pop_first().unwrap();
+            self.expires.remove(&field);
+            self.fields.remove(&field);
+            removed.push(field);
+        }
+        removed
+    }
+}
+
+/// The members of a set. They are kept in a list as well as by value, so a
+/// random one can be picked, or popped, without walking the whole set.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(from = "Vec<Vec<u8>>")]
+pub struct Members {
+    items: Vec<Arc<[u8]>>,
+    // Where each member is in `items`.
+    index: HashMap<Arc<[u8]>, usize>,
+}
+
+impl Members {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn contains(&self, member: &[u8]) -> bool {
+        self.index.contains_key(member)
+    }
+
+    /// Adds `member`. Returns false if it was already there.
+    pub fn insert(&mut self, member: Vec<u8>) -> bool {
+        if self.contains(&member) {
+            return false;
+        }
+        let member: Arc<[u8]> = member.into();
+        self.index.insert(member.clone(), self.items.len());
+        self.items.push(member);
+        true
+    }
+
+    /// Removes `member`. Returns false if it wasn't there.
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self.index.remove(member) {
+            Some(i) => {
+                self.swap_remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Iterates over the members, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = &[u8]> {
+        self.items.iter().map(|member| &**member)
+    }
+
+    // Removes a random member and returns it.
+    fn pop_random(&mut self) -> Option<Vec<u8>> {
+        if self.is_empty() {
+            return None;
+        }
+        let member = self.swap_remove(random_below(self.items.len()));
+        self.index.remove(&member);
+        Some(member.to_vec())
+    }
+
+    // Removes the member at `i` from `items`, moving the last one into its
+    // place. Leaves `index` alone for the removed member.
+    fn swap_remove(&mut self, i: usize) -> Arc<[u8]> {
+        let member = self.items.swap_remove(i);
+        if let Some(moved) = self.items.get(i) {
+            *self.index.get_mut(moved).expect("every member is indexed") = i;
+        }
+        member
+    }
+}
+
+impl PartialEq for Members {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().all(|member| other.contains(member))
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl FromIterator<Vec<u8>> for Members {
+    fn from_iter<I: IntoIterator<Item = Vec<u8>>>(members: I) -> Self {
+        let mut set = Members::new();
+        for member in members {
+            set.insert(member);
+        }
+        set
+    }
+}
+
+impl From<Vec<Vec<u8>>> for Members {
+    fn from(members: Vec<Vec<u8>>) -> Self {
+        members.into_iter().collect()
+    }
+}
+
+impl<'a> IntoIterator for &'a Members {
+    type Item = &'a [u8];
+    type IntoIter = std::iter::Map<std::slice::Iter<'a, Arc<[u8]>>, fn(&Arc<[u8]>) -> &[u8]>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.items.iter().map(|member| &**member)
+    }
+}
+
+// Serialized as a plain sequence of members, the way it was when sets were a
+// `HashSet`, so older snapshots still load.
+impl Serialize for Members {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StoreValue {
     pub data: Value,
     pub expiry: Option<u64>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// The NX, XX, GT and LT options of EXPIRE and friends. A key without an
+/// expiry counts as never expiring when comparing with GT and LT.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ExpireCondition {
+    pub nx: bool,
+    pub xx: bool,
+    pub gt: bool,
+    pub lt: bool,
+}
+
+impl ExpireCondition {
+    fn allows(&self, current: Option<u64>, at: i64) -> bool {
+        let current = current.map(|current| current as i64);
+        !(self.nx && current.is_some()
+            || self.xx && current.is_none()
+            || self.gt && current.is_none_or(|current| at <= current)
+            || self.lt && current.is_some_and(|current| at >= current))
+    }
+}
+
+/// Which of SINTER, SUNION and SDIFF to compute.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetOp {
+    Inter,
+    Union,
+    Diff,
+}
+
+/// The options ZADD takes besides the scores and members.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ZAddOptions {
+    /// Only add new members (NX), or only update existing ones (XX).
+    pub only_if_exists: Option<bool>,
+    /// Only update a score to a greater (GT) or a lesser (LT) one. New
+    /// members are added either way.
+    pub gt: bool,
+    pub lt: bool,
+    /// Count the members whose score changed in the reply, not just the
+    /// added ones (CH).
+    pub changed: bool,
+}
+
+impl ZAddOptions {
+    fn allows_update(&self, current: f64, score: f64) -> bool {
+        self.only_if_exists != Some(false)
+            && !(self.gt && score <= current || self.lt && score >= current)
+    }
+}
+
+/// Which end of a list to push to or pop from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListEnd {
+    Left,
+    Right,
+}
+
+/// An expiry time as a command gives it. A relative one counts from when the
+/// command runs rather than when it was parsed, which for one queued in a
+/// transaction can be a good while later.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Expiry {
+    /// This many milliseconds from now.
+    In(i64),
+    /// At this Unix time in milliseconds.
+    At(i64),
+}
+
+impl Expiry {
+    /// The Unix time in milliseconds this comes to, or `None` if that is out
+    /// of range.
+    pub fn deadline(self) -> Option<i64> {
+        match self {
+            Expiry::In(millis) => (now_millis() as i64).checked_add(millis),
+            Expiry::At(at) => Some(at),
+        }
+    }
+}
+
+/// The options SET takes besides the key and value.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SetOptions {
+    pub expiry: Option<Expiry>,
+    /// Keep the key's current expiry rather than clearing it (KEEPTTL).
+    pub keep_ttl: bool,
+    /// Only set the key if it doesn't exist (NX), or only if it does (XX).
+    pub only_if_exists: Option<bool>,
+    /// Return the old value (GET).
+    pub get: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(from = "StoredData")]
 pub struct Storage {
-    data: HashMap<String, StoreValue>,
-    // This field is used to track if the storage has been modified.
+    // Values are shared with the snapshots taken of them, and copied on
+    // write while a snapshot still holds them.
+    data: HashMap<Arc<[u8]>, Arc<StoreValue>>,
+    // Number of changes made since the last successful save.
+    #[serde(skip)]
+    dirty: u64,
+    // The keys that have an expiry, soonest first, so expired keys can be
+    // found without scanning `data`. Rebuilt when loading.
+    #[serde(skip)]
+    expires: BTreeSet<(u64, Vec<u8>)>,
+    // The hashes that have fields with an expiry, by when the first of those
+    // expires. Rebuilt when loading.
+    #[serde(skip)]
+    hash_expires: BTreeSet<(u64, Vec<u8>)>,
+    // Which keyspace notifications to record, and the ones recorded since
+    // they were last taken.
+    #[serde(skip)]
+    notify: NotifyConfig,
     #[serde(skip)]
-    dirty: bool,
+    events: Vec<KeyEvent>,
+    // The DEL and HDEL commands that remove what expired since they were
+    // last taken, for the append-only file to log.
+    #[serde(skip)]
+    expired: Vec<Vec<Vec<u8>>>,
+    // While the append-only file is replayed nothing expires, so every
+    // command in it sees the keys it saw when it first ran.
+    #[serde(skip)]
+    loading: bool,
+}
+
+/// A point-in-time copy of the data, to save while the storage carries on
+/// changing. Taking one copies no values: they stay shared until the
+/// storage writes to them.
+#[derive(Serialize)]
+pub struct Snapshot {
+    // Serialized exactly like a `Storage`.
+    data: HashMap<Arc<[u8]>, Arc<StoreValue>>,
+}
+
+impl Snapshot {
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Iterates over every key, including ones that had expired but not yet
+    /// been removed when the snapshot was taken.
+    pub fn entries(&self) -> impl Iterator<Item = (&[u8], &StoreValue)> {
+        self.data.iter().map(|(key, value)| (&**key, &**value))
+    }
+}
+
+// What a `Storage` is serialized as.
+#[derive(Deserialize)]
+struct StoredData {
+    data: HashMap<Vec<u8>, StoreValue>,
+}
+
+impl From<StoredData> for Storage {
+    // Keys and hash fields that expired while the server was down are
+    // dropped on load.
+    fn from(stored: StoredData) -> Self {
+        let now = now_millis();
+        let mut storage = Storage::new();
+        for (key, mut value) in stored.data {
+            if value.expiry.is_some_and(|at| at <= now) {
+                continue;
+            }
+            if let Value::Hash(hash) = &mut value.data {
+                hash.remove_expired(now);
+            }
+            if !value.data.is_empty_collection() {
+                storage.insert_entry(key, value);
+            }
+        }
+        storage
+    }
+}
+
+// How values were serialized in version 1 snapshots, before hash fields could
+// have an expiry. The variants must stay in the same order as `Value`'s.
+#[derive(Deserialize)]
+enum ValueV1 {
+    String(Vec<u8>),
+    List(VecDeque<Vec<u8>>),
+    Hash(FieldMap),
+}
+
+#[derive(Deserialize)]
+struct StoreValueV1 {
+    data: ValueV1,
+    expiry: Option<u64>,
+}
+
+/// A `Storage` as version 1 snapshots hold it. Deserialize one of these and
+/// convert it to load an old snapshot.
+#[derive(Deserialize)]
+pub struct StoredDataV1 {
+    data: HashMap<Vec<u8>, StoreValueV1>,
+}
+
+impl From<StoredDataV1> for Storage {
+    fn from(stored: StoredDataV1) -> Self {
+        let data = stored
+            .data
+            .into_iter()
+            .map(|(key, value)| {
+                let data = match value.data {
+                    ValueV1::String(s) => Value::String(s),
+                    ValueV1::List(list) => Value::List(list),
+                    ValueV1::Hash(fields) => Value::Hash(fields.into()),
+                };
+                let expiry = value.expiry;
+                (key, StoreValue { data, expiry })
+            })
+            .collect();
+        StoredData { data }.into()
+    }
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Storage {
     pub fn new() -> Self {
         Storage {
             data: HashMap::new(),
-            dirty: false,
+            dirty: 0,
+            expires: BTreeSet::new(),
+            hash_expires: BTreeSet::new(),
+            notify: NotifyConfig::default(),
+            events: Vec::new(),
+            expired: Vec::new(),
+            loading: false,
         }
     }
 
     pub fn is_dirty(&self) -> bool {
+        self.dirty > 0
+    }
+
+    /// How many changes have been made since the last successful save.
+    pub fn dirty(&self) -> u64 {
         self.dirty
     }
 
-    pub fn clear_dirty_flag(&mut self) {
-        self.dirty = false;
+    /// Records that a save covering the first `changes` changes succeeded.
+    /// Changes made while it was being written still count as unsaved.
+    pub fn mark_saved(&mut self, changes: u64) {
+        self.dirty = self.dirty.saturating_sub(changes);
     }
 
-    pub fn set(&mut self, key: String, value: String, expiry: Option<Duration>) {
-        let expiry_timestamp = expiry.map(|duration| {
-            let future_time = SystemTime::now() + duration;
-            future_time
-                .duration_since(UNIX_EPOCH)
-                .expect("Time went backwards")
-                .as_millis() as u64
-        });
+    /// Starts recording the keyspace notifications `notify` enables.
+    pub fn set_notify(&mut self, notify: NotifyConfig) {
+        self.notify = notify;
+    }
+
+    /// The keyspace notifications recorded since the last call, oldest
+    /// first.
+    pub fn take_events(&mut self) -> Vec<KeyEvent> {
+        std::mem::take(&mut self.events)
+    }
 
-        self.data.insert(
+    /// Stops keys and hash fields from expiring while `loading`, for
+    /// replaying the append-only file. It logs what expired as DEL and HDEL
+    /// commands, so the replay removes them at the right point instead.
+    pub fn set_loading(&mut self, loading: bool) {
+        self.loading = loading;
+    }
+
+    /// The commands that remove the keys and hash fields that expired since
+    /// the last call, oldest first. They must be logged before any command
+    /// applied after they expired.
+    pub fn take_expired(&mut self) -> Vec<Vec<Vec<u8>>> {
+        std::mem::take(&mut self.expired)
+    }
+
+    // Removes `key`, which has expired.
+    fn expire_key(&mut self, key: &[u8]) {
+        self.remove_entry(key);
+        self.dirty += 1;
+        self.notify(EventClass::Expired, "expired", key);
+        self.expired.push(vec![b"DEL".to_vec(), key.to_vec()]);
+    }
+
+    // Records that `event` happened to `key`, if notifications of its class
+    // are enabled.
+    fn notify(&mut self, class: EventClass, event: &'static str, key: &[u8]) {
+        if self.notify.is_enabled(class) {
+            self.events.push(KeyEvent {
+                class,
+                event,
+                key: key.to_vec(),
+            });
+        }
+    }
+
+    /// How many keys there are, including ones that have expired but not yet
+    /// been removed.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Iterates over every key, including ones that have expired but not yet
+    /// been removed.
+    pub fn entries(&self) -> impl Iterator<Item = (&[u8], &StoreValue)> {
+        self.data.iter().map(|(key, value)| (&**key, &**value))
+    }
+
+    /// Takes a snapshot of the data. Only the map of keys is copied, so this
+    /// is cheap enough to do with the storage locked.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            data: self.data.clone(),
+        }
+    }
+
+    pub fn set(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expiry: Option<Duration>,
+    ) -> Result<(), &'static str> {
+        let expiry_timestamp = expiry
+            .map(|duration| {
+                i64::try_from(duration.as_millis())
+                    .ok()
+                    .and_then(|millis| Expiry::In(millis).deadline())
+                    .map(|at| at as u64)
+                    .ok_or(INVALID_SET_EXPIRY)
+            })
+            .transpose()?;
+
+        self.notify(EventClass::String, "set", &key);
+        if expiry.is_some() {
+            self.notify(EventClass::Generic, "expire", &key);
+        }
+        self.insert_entry(
             key,
             StoreValue {
                 data: Value::String(value),
                 expiry: expiry_timestamp,
             },
         );
-        self.dirty = true;
+        self.dirty += 1;
+        Ok(())
     }
 
-    pub fn get(&mut self, key: &str) -> Option<&mut StoreValue> {
-        // First check if key exists and if it's expired
-        if let Some(store_value) = self.data.get(key) {
-            if let Some(expiry_timestamp) = store_value.expiry {
-                let current_timestamp = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .expect("Time went backwards")
-                    .as_millis() as u64;
-
-                if current_timestamp >= expiry_timestamp {
-                    self.data.remove(key);
-                    self.dirty = true;
-                    return None;
-                }
+    /// SET with all its options. Returns whether the value was stored, and
+    /// with `options.get` the old value. Asking for the old value of a key
+    /// that isn't a string is an error, and then nothing is stored.
+    pub fn set_with_options(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        options: &SetOptions,
+    ) -> Result<(bool, Option<Vec<u8>>), &'static str> {
+        let expiry = options
+            .expiry
+            .map(|expiry| {
+                expiry
+                    .deadline()
+                    .filter(|&at| at > 0)
+                    .map(|at| at as u64)
+                    .ok_or(INVALID_SET_EXPIRY)
+            })
+            .transpose()?;
+        let existing = self.get(&key);
+        let exists = existing.is_some();
+        let current_expiry = existing.as_ref().and_then(|entry| entry.expiry);
+        let old = match existing.map(|entry| &entry.data) {
+            Some(Value::String(old)) if options.get => Some(old.clone()),
+            Some(_) if options.get => {
+                return Err("WRONGTYPE Operation against a key holding the wrong kind of value");
             }
-            // Key exists and hasn't expired
-            self.data.get_mut(key)
+            _ => None,
+        };
+
+        if options
+            .only_if_exists
+            .is_some_and(|only_if| only_if != exists)
+        {
+            return Ok((false, old));
+        }
+
+        let expiry = if options.keep_ttl {
+            current_expiry
         } else {
-            // Key doesn't exist
-            None
+            expiry
+        };
+        self.notify(EventClass::String, "set", &key);
+        if options.expiry.is_some() {
+            self.notify(EventClass::Generic, "expire", &key);
         }
+        self.insert_entry(
+            key,
+            StoreValue {
+                data: Value::String(value),
+                expiry,
+            },
+        );
+        self.dirty += 1;
+        Ok((true, old))
     }
 
-    pub fn remove(&mut self, key: &str) -> Option<StoreValue> {
-        // Return the inner data string when removing.
-        let result = self.data.remove(key);
-        if result.is_some() {
-            self.dirty = true;
-        }
+    /// Stores `value` under `key` as is, replacing whatever was there.
+    pub fn insert(&mut self, key: Vec<u8>, value: StoreValue) {
+        self.insert_entry(key, value);
+        self.dirty += 1;
+    }
 
-        result
+    /// Looks `key` up. Every command goes through here (or `get_mut` or
+    /// `get_or_create`), so an expired key is removed on the spot and is
+    /// never seen, whatever its type. Use `expire_at` and `persist` to change
+    /// the expiry, which also keep the expiry index up to date.
+    pub fn get(&mut self, key: &[u8]) -> Option<&StoreValue> {
+        self.expire_if_needed(key);
+        self.data.get(key).map(|entry| &**entry)
     }
 
-    pub fn lpush(&mut self, key: &str, values: Vec<String>) -> Result<usize, &'static str> {
-        let entry = self
-            .data
-            .entry(key.to_string())
-            .or_insert_with(|| StoreValue {
-                data: Value::List(VecDeque::new()),
+    // Like `get`, for changing the value. One still shared with a snapshot
+    // is copied first, so only look a key up this way to write to it.
+    fn get_mut(&mut self, key: &[u8]) -> Option<&mut StoreValue> {
+        self.expire_if_needed(key);
+        self.data.get_mut(key).map(Arc::make_mut)
+    }
+
+    // Like `get`, but creates the key holding `empty` if it doesn't exist.
+    fn get_or_create(&mut self, key: &[u8], empty: impl FnOnce() -> Value) -> &mut StoreValue {
+        self.expire_if_needed(key);
+        let entry = self.data.entry(key.into()).or_insert_with(|| {
+            Arc::new(StoreValue {
+                data: empty(),
                 expiry: None,
-            });
+            })
+        });
+        Arc::make_mut(entry)
+    }
 
-        match &mut entry.data {
-            Value::List(list) => {
-                for v in values.into_iter() {
-                    list.push_front(v);
-                }
-                self.dirty = true;
-                Ok(list.len())
-            }
-            _ => Err("WRONGTYPE Operation against a key holding the wrong kind of value"),
+    // Removes `key` if it has expired, or the fields of a hash that have.
+    fn expire_if_needed(&mut self, key: &[u8]) {
+        if self.loading {
+            return;
+        }
+        let Some(entry) = self.data.get(key) else {
+            return;
+        };
+        let fields_expire = fields_expire(entry);
+        if entry.expiry.is_none() && fields_expire.is_none() {
+            return;
+        }
+
+        let now = now_millis();
+        if entry.expiry.is_some_and(|at| at <= now) {
+            self.expire_key(key);
+        } else if fields_expire.is_some_and(|at| at <= now) {
+            self.expire_fields(key, now);
         }
     }
 
-    pub fn rpush(&mut self, key: &str, values: Vec<String>) -> Result<usize, &'static str> {
-        let entry = self
-            .data
-            .entry(key.to_string())
-            .or_insert_with(|| StoreValue {
-                data: Value::List(VecDeque::new()),
-                expiry: None,
-            });
+    // Removes the fields of the hash at `key` that expired by `now`, and the
+    // hash itself if that leaves it empty.
+    fn expire_fields(&mut self, key: &[u8], now: u64) {
+        let Some(StoreValue {
+            data: Value::Hash(hash),
+            ..
+        }) = self.data.get_mut(key).map(Arc::make_mut)
+        else {
+            return;
+        };
+        let before = hash.next_expiry();
+        let fields = hash.remove_expired(now);
+        self.reindex_hash(key, before);
+        self.dirty += 1;
+        self.notify(EventClass::Hash, "hexpired", key);
+        self.remove_if_empty(key);
+        let mut hdel = vec![b"HDEL".to_vec(), key.to_vec()];
+        hdel.extend(fields);
+        self.expired.push(hdel);
+    }
 
-        match &mut entry.data {
-            Value::List(list) => {
-                for v in values {
-                    list.push_back(v);
-                }
-                self.dirty = true;
-                Ok(list.len())
-            }
-            _ => Err("WRONGTYPE Operation against a key holding the wrong kind of value"),
+    pub fn remove(&mut self, key: &[u8]) -> Option<Arc<StoreValue>> {
+        self.expire_if_needed(key);
+        let result = self.remove_entry(key);
+        if result.is_some() {
+            self.dirty += 1;
+            self.notify(EventClass::Generic, "del", key);
         }
+
+        result
     }
 
-    pub fn lrange(
-        &mut self,
-        key: &str,
-        start: i64,
-        stop: i64,
-    ) -> Result<Option<Vec<String>>, &'static str> {
-        match self.data.get(key) {
-            None => Ok(None),
-            Some(store_value) => match &store_value.data {
-                Value::List(list) => {
-                    let len = list.len() as i64;
+    /// Makes `key` expire at `at`, in Unix milliseconds, if `condition`
+    /// allows it. A time that has already passed deletes the key. Returns
+    /// false if the key doesn't exist or the condition isn't met.
+    pub fn expire_at(&mut self, key: &[u8], at: i64, condition: ExpireCondition) -> bool {
+        let Some(entry) = self.get(key) else {
+            return false;
+        };
+        if !condition.allows(entry.expiry, at) {
+            return false;
+        }
 
-                    // Convert Redis-style negative indices to regular indices
-                    let start = if start < 0 { len + start } else { start }.max(0) as usize;
-                    let stop = if stop < 0 { len + stop } else { stop }.max(0) as usize;
+        if at <= now_millis() as i64 && !self.loading {
+            self.remove(key);
+        } else {
+            self.set_expiry(key, Some(at as u64));
+            self.notify(EventClass::Generic, "expire", key);
+        }
+        true
+    }
 
-                    if start >= list.len() || start > stop {
-                        return Ok(Some(Vec::new())); // Return empty list for out of range
-                    }
+    /// Removes the expiry of `key`. Returns false if the key doesn't exist or
+    /// has no expiry.
+    pub fn persist(&mut self, key: &[u8]) -> bool {
+        if self.get(key).is_none_or(|entry| entry.expiry.is_none()) {
+            return false;
+        }
+        self.set_expiry(key, None);
+        self.notify(EventClass::Generic, "persist", key);
+        true
+    }
 
-                    // `..=stop` is inclusive. Ensure `stop` is within bounds.
-                    let stop = stop.min(list.len() - 1);
+    /// When `key` expires: `None` if it doesn't exist, `Some(None)` if it
+    /// never does.
+    pub fn expiry(&mut self, key: &[u8]) -> Option<Option<u64>> {
+        self.get(key).map(|entry| entry.expiry)
+    }
 
-                    let result = list
-                        .iter()
-                        .skip(start)
-                        .take(stop - start + 1)
-                        .cloned()
-                        .collect();
-                    Ok(Some(result))
-                }
-                _ => Err("WRONGTYPE Operation against a key holding the wrong kind of value"),
-            },
+    fn set_expiry(&mut self, key: &[u8], expiry: Option<u64>) {
+        if let Some(mut entry) = self.remove_entry(key) {
+            Arc::make_mut(&mut entry).expiry = expiry;
+            self.insert_entry(key.to_vec(), entry);
+            self.dirty += 1;
         }
     }
 
-    pub fn hset(&mut self, key: String, field: String, value: String) -> Result<i32, &'static str> {
-        let entry = self
-            .data
-            .entry(key.to_string())
-            .or_insert_with(|| StoreValue {
-                data: Value::Hash(HashMap::new()),
-                expiry: None,
-            });
+    /// Removes keys whose expiry has passed, soonest first, then the expired
+    /// fields of hashes, until there are none left or `budget` is used up.
+    /// Returns how many keys and hashes were cleaned up.
+    pub fn remove_expired(&mut self, budget: Duration) -> usize {
+        let started = Instant::now();
+        let now = now_millis();
+        let mut removed = 0;
+        // Checking the clock is not free, so only do it every so often.
+        let out_of_time =
+            |removed: usize| removed.is_multiple_of(16) && started.elapsed() >= budget;
+
+        while let Some((at, key)) = self.expires.first()
+            && *at <= now
+        {
+            let key = key.clone();
+            self.expire_key(&key);
+            removed += 1;
+            if out_of_time(removed) {
+                return removed;
+            }
+        }
 
-        match &mut entry.data {
-            Value::Hash(hash) => {
-                let result = if hash.contains_key(&field) { 0 } else { 1 };
-                hash.insert(field, value);
-                self.dirty = true;
-                Ok(result)
+        while let Some((at, key)) = self.hash_expires.first()
+            && *at <= now
+        {
+            let key = key.clone();
+            self.expire_fields(&key, now);
+            removed += 1;
+            if out_of_time(removed) {
+                break;
             }
-            _ => Err("WRONGTYPE Operation against a key holding the wrong kind of value"),
         }
+        removed
     }
 
-    pub fn hget(&mut self, key: &str, field: &str) -> Result<Option<&String>, &'static str> {
-        match self.data.get(key) {
+    /// How many keys have an expiry set.
+    pub fn expiring_keys(&self) -> usize {
+        self.expires.len()
+    }
+
+    // Every key is added and removed through these two, which keep the
+    // expiry indexes in step with `data`.
+    fn insert_entry(&mut self, key: Vec<u8>, value: impl Into<Arc<StoreValue>>) {
+        let value = value.into();
+        if let Some(old) = self.data.get(key.as_slice()) {
+            if let Some(at) = old.expiry {
+                self.expires.remove(&(at, key.clone()));
+            }
+            if let Some(at) = fields_expire(old) {
+                self.hash_expires.remove(&(at, key.clone()));
+            }
+        }
+        if let Some(at) = value.expiry {
+            self.expires.insert((at, key.clone()));
+        }
+        if let Some(at) = fields_expire(&value) {
+            self.hash_expires.insert((at, key.clone()));
+        }
+        self.data.insert(key.into(), value);
+    }
+
+    fn remove_entry(&mut self, key: &[u8]) -> Option<Arc<StoreValue>> {
+        let value = self.data.remove(key)?;
+        if let Some(at) = value.expiry {
+            self.expires.remove(&(at, key.to_vec()));
+        }
+        if let Some(at) = fields_expire(&value) {
+            self.hash_expires.remove(&(at, key.to_vec()));
+        }
+        Some(value)
+    }
+
+    // Updates the index of hashes with expiring fields after the fields of
+    // the hash at `key` changed. `before` is when its first field expired
+    // beforehand.
+    fn reindex_hash(&mut self, key: &[u8], before: Option<u64>) {
+        let after = self.data.get(key).and_then(|entry| fields_expire(entry));
+        if after == before {
+            return;
+        }
+        if let Some(at) = before {
+            self.hash_expires.remove(&(at, key.to_vec()));
+        }
+        if let Some(at) = after {
+            self.hash_expires.insert((at, key.to_vec()));
+        }
+    }
+
+    // Deletes `key` if it holds a collection that is now empty.
+    fn remove_if_empty(&mut self, key: &[u8]) {
+        if self.discard_if_empty(key) {
+            self.notify(EventClass::Generic, "del", key);
+        }
+    }
+
+    // Like `remove_if_empty`, for a collection that was only just created
+    // and so was never there as far as anyone can tell. Returns whether it
+    // was removed.
+    fn discard_if_empty(&mut self, key: &[u8]) -> bool {
+        let empty = self
+            .data
+            .get(key)
+            .is_some_and(|value| value.data.is_empty_collection());
+        if empty {
+            self.remove_entry(key);
+        }
+        empty
+    }
+
+    /// Sets every key to its value, dropping any previous expiry.
+    pub fn mset(&mut self, pairs: Pairs) {
+        for (key, value) in pairs {
+            // Without an expiry there is nothing to be out of range.
+            let _ = self.set(key, value, None);
+        }
+    }
+
+    /// Like `mset`, but sets nothing at all if any of the keys exists.
+    pub fn msetnx(&mut self, pairs: Pairs) -> bool {
+        if pairs.iter().any(|(key, _)| self.get(key).is_some()) {
+            return false;
+        }
+        self.mset(pairs);
+        true
+    }
+
+    /// The string stored at `key`, if there is one.
+    pub fn get_string(&mut self, key: &[u8]) -> Result<Option<&Vec<u8>>, &'static str> {
+        match self.get(key).map(|entry| &entry.data) {
             None => Ok(None),
-            Some(store_value) => match &store_value.data {
-                Value::Hash(hash) => Ok(hash.get(field)),
-                _ => Err("WRONGTYPE Operation against a key holding the wrong kind of value"),
-            },
+            Some(Value::String(s)) => Ok(Some(s)),
+            Some(_) => Err("WRONGTYPE Operation against a key holding the wrong kind of value"),
         }
     }
 
-    pub fn hdel(&mut self, key: &str, fields: Vec<String>) -> Result<i32, &'static str> {
-        match self.data.get_mut(key) {
-            None => Ok(0),
-            Some(store_value) => match &mut store_value.data {
-                Value::Hash(hash) => {
-                    let mut deleted_count = 0;
-                    for field in fields {
-                        if hash.remove(&field).is_some() {
-                            deleted_count += 1;
-                        }
+    /// Removes the string at `key` and returns it.
+    pub fn getdel(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, &'static str> {
+        if self.get_string(key)?.is_none() {
+            return Ok(None);
+        }
+        match self
+            .remove(key)
+            .map(|entry| Arc::unwrap_or_clone(entry).data)
+        {
+            Some(Value::String(s)) => Ok(Some(s)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Adds `delta` to the integer stored as a string at `key`, starting from
+    /// 0 if there is no key. The key keeps its expiry.
+    pub fn incr_by(&mut self, key: &[u8], delta: i64) -> Result<i64, &'static str> {
+        let entry = self.get_or_create(key, || Value::String(b"0".to_vec()));
+        let Value::String(s) = &mut entry.data else {
+            return Err("WRONGTYPE Operation against a key holding the wrong kind of value");
+        };
+
+        let current = std::str::from_utf8(s)
+            .ok()
+            .and_then(|s| s.parse::<i64>().ok())
+            .ok_or(NOT_AN_INTEGER)?;
+        let value = current
+            .checked_add(delta)
+            .ok_or("ERR increment or decrement would overflow")?;
+
+        *s = value.to_string().into_bytes();
+        self.dirty += 1;
+        self.notify(EventClass::String, "incrby", key);
+        Ok(value)
+    }
+
+    /// Like `incr_by` for floating point numbers. Returns the new value as it
+    /// is stored.
+    pub fn incr_by_float(&mut self, key: &[u8], delta: f64) -> Result<Vec<u8>, &'static str> {
+        let entry = self.get_or_create(key, || Value::String(b"0".to_vec()));
+        let Value::String(s) = &mut entry.data else {
+            return Err("WRONGTYPE Operation against a key holding the wrong kind of value");
+        };
+
+        let current = std::str::from_utf8(s)
+            .ok()
+            .and_then(|s| s.parse::<f64>().ok())
+            .filter(|n| n.is_finite())
+            .ok_or(NOT_A_FLOAT)?;
+        let value = current + delta;
+        if !value.is_finite() {
+            return Err("ERR increment would produce NaN or Infinity");
+        }
+
+        *s = value.to_string().into_bytes();
+        let value = s.clone();
+        self.dirty += 1;
+        self.notify(EventClass::String, "incrbyfloat", key);
+        Ok(value)
+    }
+
+    /// Appends `value` to the string at `key`, creating it if needed.
+    /// Returns the new length.
+    pub fn append(&mut self, key: &[u8], value: &[u8]) -> Result<usize, &'static str> {
+        let entry = self.get_or_create(key, || Value::String(Vec::new()));
+        let Value::String(s) = &mut entry.data else {
+            return Err("WRONGTYPE Operation against a key holding the wrong kind of value");
+        };
+        if s.len() + value.len() > MAX_STRING_LEN {
+            return Err(STRING_TOO_LONG);
+        }
+
+        s.extend_from_slice(value);
+        let len = s.len();
+        self.dirty += 1;
+        self.notify(EventClass::String, "append", key);
+        Ok(len)
+    }
+
+    pub fn strlen(&mut self, key: &[u8]) -> Result<usize, &'static str> {
+        Ok(self.get_string(key)?.map_or(0, Vec::len))
+    }
+
+    /// The bytes of the string at `key` from `start` to `end` inclusive.
+    /// Negative offsets count from the end.
+    pub fn getrange(&mut self, key: &[u8], start: i64, end: i64) -> Result<Vec<u8>, &'static str> {
+        let Some(s) = self.get_string(key)? else {
+            return Ok(Vec::new());
+        };
+        let len = s.len() as i64;
+
+        let start = if start < 0 { len + start } else { start }.max(0);
+        let end = if end < 0 { len + end } else { end }.max(0).min(len - 1);
+        if start > end || len == 0 {
+            return Ok(Vec::new());
+        }
+        Ok(s[start as usize..=end as usize].to_vec())
+    }
+
+    /// Overwrites the string at `key` with `value` from `offset` on, padding
+    /// with zero bytes if the string is shorter. Returns the new length.
+    pub fn setrange(
+        &mut self,
+        key: &[u8],
+        offset: usize,
+        value: &[u8],
+    ) -> Result<usize, &'static str> {
+        // Writing nothing doesn't create the key.
+        if value.is_empty() {
+            return self.strlen(key);
+        }
+        if offset.saturating_add(value.len()) > MAX_STRING_LEN {
+            return Err(STRING_TOO_LONG);
+        }
+
+        let entry = self.get_or_create(key, || Value::String(Vec::new()));
+        let Value::String(s) = &mut entry.data else {
+            return Err("WRONGTYPE Operation against a key holding the wrong kind of value");
+        };
+
+        let end = offset + value.len();
+        if s.len() < end {
+            s.resize(end, 0);
+        }
+        s[offset..end].copy_from_slice(value);
+        let len = s.len();
+        self.dirty += 1;
+        self.notify(EventClass::String, "setrange", key);
+        Ok(len)
+    }
+
+    pub fn lpush(&mut self, key: &[u8], values: Vec<Vec<u8>>) -> Result<usize, &'static str> {
+        let entry = self.get_or_create(key, || Value::List(VecDeque::new()));
+        let Value::List(list) = &mut entry.data else {
+            return Err("WRONGTYPE Operation against a key holding the wrong kind of value");
+        };
+
+        let pushed = values.len() as u64;
+        for v in values.into_iter() {
+            list.push_front(v);
+        }
+        let len = list.len();
+        self.dirty += pushed;
+        self.notify(EventClass::List, "lpush", key);
+        Ok(len)
+    }
+
+    pub fn rpush(&mut self, key: &[u8], values: Vec<Vec<u8>>) -> Result<usize, &'static str> {
+        let entry = self.get_or_create(key, || Value::List(VecDeque::new()));
+        let Value::List(list) = &mut entry.data else {
+            return Err("WRONGTYPE Operation against a key holding the wrong kind of value");
+        };
+
+        let pushed = values.len() as u64;
+        list.extend(values);
+        let len = list.len();
+        self.dirty += pushed;
+        self.notify(EventClass::List, "rpush", key);
+        Ok(len)
+    }
+
+    pub fn lrange(
+        &mut self,
+        key: &[u8],
+        start: i64,
+        stop: i64,
+    ) -> Result<Option<Vec<Vec<u8>>>, &'static str> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(store_value) => match &store_value.data {
+                Value::List(list) => {
+                    let len = list.len() as i64;
+
+                    // Convert Redis-style negative indices to regular indices
+                    let start = if start < 0 { len + start } else { start }.max(0) as usize;
+                    let stop = if stop < 0 { len + stop } else { stop }.max(0) as usize;
+
+                    if start >= list.len() || start > stop {
+                        return Ok(Some(Vec::new())); // Return empty list for out of range
                     }
-                    Ok(deleted_count)
+
+                    // `..=stop` is inclusive. Ensure `stop` is within bounds.
+                    let stop = stop.min(list.len() - 1);
+
+                    let result = list
+                        .iter()
+                        .skip(start)
+                        .take(stop - start + 1)
+                        .cloned()
+                        .collect();
+                    Ok(Some(result))
                 }
                 _ => Err("WRONGTYPE Operation against a key holding the wrong kind of value"),
             },
         }
     }
 
-    pub fn hlen(&mut self, key: &str) -> Result<usize, &'static str> {
-        match self.data.get(key) {
+    fn list(&mut self, key: &[u8]) -> Result<Option<&VecDeque<Vec<u8>>>, &'static str> {
+        match self.get(key).map(|entry| &entry.data) {
+            None => Ok(None),
+            Some(Value::List(list)) => Ok(Some(list)),
+            Some(_) => Err("WRONGTYPE Operation against a key holding the wrong kind of value"),
+        }
+    }
+
+    // Like `list`, for changing it.
+    fn list_mut(&mut self, key: &[u8]) -> Result<Option<&mut VecDeque<Vec<u8>>>, &'static str> {
+        match self.get_mut(key).map(|entry| &mut entry.data) {
+            None => Ok(None),
+            Some(Value::List(list)) => Ok(Some(list)),
+            Some(_) => Err("WRONGTYPE Operation against a key holding the wrong kind of value"),
+        }
+    }
+
+    /// Removes up to `count` elements from one end of the list at `key`, or
+    /// returns `None` if there is no list.
+    pub fn pop(
+        &mut self,
+        key: &[u8],
+        end: ListEnd,
+        count: usize,
+    ) -> Result<Option<Vec<Vec<u8>>>, &'static str> {
+        let Some(list) = self.list_mut(key)? else {
+            return Ok(None);
+        };
+
+        let count = count.min(list.len());
+        let popped: Vec<_> = match end {
+            ListEnd::Left => list.drain(..count).collect(),
+            ListEnd::Right => list.drain(list.len() - count..).rev().collect(),
+        };
+
+        self.dirty += popped.len() as u64;
+        if !popped.is_empty() {
+            self.notify(EventClass::List, pop_event(end), key);
+        }
+        self.remove_if_empty(key);
+        Ok(Some(popped))
+    }
+
+    pub fn llen(&mut self, key: &[u8]) -> Result<usize, &'static str> {
+        Ok(self.list(key)?.map_or(0, |list| list.len()))
+    }
+
+    /// The element at `index`, counting from the end if it is negative.
+    pub fn lindex(&mut self, key: &[u8], index: i64) -> Result<Option<&Vec<u8>>, &'static str> {
+        Ok(self
+            .list(key)?
+            .and_then(|list| list_index(index, list.len()).map(|i| &list[i])))
+    }
+
+    pub fn lset(&mut self, key: &[u8], index: i64, value: Vec<u8>) -> Result<(), &'static str> {
+        let list = self.list_mut(key)?.ok_or("ERR no such key")?;
+        let i = list_index(index, list.len()).ok_or("ERR index out of range")?;
+
+        list[i] = value;
+        self.dirty += 1;
+        self.notify(EventClass::List, "lset", key);
+        Ok(())
+    }
+
+    /// Inserts `value` before or after the first occurrence of `pivot`.
+    /// Returns the new length, 0 if there is no list, or -1 if `pivot` isn't
+    /// in it.
+    pub fn linsert(
+        &mut self,
+        key: &[u8],
+        before: bool,
+        pivot: &[u8],
+        value: Vec<u8>,
+    ) -> Result<i64, &'static str> {
+        let Some(list) = self.list_mut(key)? else {
+            return Ok(0);
+        };
+        let Some(i) = list.iter().position(|element| element == pivot) else {
+            return Ok(-1);
+        };
+
+        list.insert(if before { i } else { i + 1 }, value);
+        let len = list.len();
+        self.dirty += 1;
+        self.notify(EventClass::List, "linsert", key);
+        Ok(len as i64)
+    }
+
+    /// Removes the first `count` occurrences of `value`, the last ones if
+    /// `count` is negative, or all of them if it is 0. Returns how many were
+    /// removed.
+    pub fn lrem(&mut self, key: &[u8], count: i64, value: &[u8]) -> Result<usize, &'static str> {
+        let Some(list) = self.list_mut(key)? else {
+            return Ok(0);
+        };
+
+        let limit = if count == 0 {
+            usize::MAX
+        } else {
+            count.unsigned_abs() as usize
+        };
+        let mut matches: Vec<usize> = list
+            .iter()
+            .enumerate()
+            .filter(|(_, element)| element.as_slice() == value)
+            .map(|(i, _)| i)
+            .collect();
+        if count < 0 {
+            matches.reverse();
+        }
+        matches.truncate(limit);
+        // Remove from the back so the remaining indices stay valid.
+        matches.sort_unstable_by(|a, b| b.cmp(a));
+        for &i in &matches {
+            list.remove(i);
+        }
+
+        if !matches.is_empty() {
+            self.dirty += 1;
+            self.notify(EventClass::List, "lrem", key);
+            self.remove_if_empty(key);
+        }
+        Ok(matches.len())
+    }
+
+    /// Keeps only the elements from `start` to `stop` inclusive, with the same
+    /// index rules as `lrange`.
+    pub fn ltrim(&mut self, key: &[u8], start: i64, stop: i64) -> Result<(), &'static str> {
+        let Some(list) = self.list_mut(key)? else {
+            return Ok(());
+        };
+        let len = list.len() as i64;
+
+        let start = if start < 0 { len + start } else { start }.max(0);
+        let stop = if stop < 0 { len + stop } else { stop }.min(len - 1);
+        if start > stop {
+            list.clear();
+        } else {
+            list.truncate(stop as usize + 1);
+            list.drain(..start as usize);
+        }
+
+        self.dirty += 1;
+        self.notify(EventClass::List, "ltrim", key);
+        self.remove_if_empty(key);
+        Ok(())
+    }
+
+    /// The indices of `element` in the list, for LPOS. A negative `rank`
+    /// searches from the end, and `rank` N skips the first N - 1 matches.
+    /// At most `count` matches are returned (0 for all of them), after
+    /// comparing at most `max_len` elements (0 for no limit).
+    pub fn lpos(
+        &mut self,
+        key: &[u8],
+        element: &[u8],
+        rank: i64,
+        count: usize,
+        max_len: usize,
+    ) -> Result<Vec<usize>, &'static str> {
+        let Some(list) = self.list(key)? else {
+            return Ok(Vec::new());
+        };
+
+        let len = list.len();
+        let max_len = if max_len == 0 { len } else { max_len.min(len) };
+        let count = if count == 0 { usize::MAX } else { count };
+        let indices: Box<dyn Iterator<Item = usize>> = if rank > 0 {
+            Box::new(0..max_len)
+        } else {
+            Box::new((len - max_len..len).rev())
+        };
+
+        Ok(indices
+            .filter(|&i| list[i] == element)
+            .skip(rank.unsigned_abs() as usize - 1)
+            .take(count)
+            .collect())
+    }
+
+    /// Atomically pops an element from one end of `source` and pushes it to
+    /// one end of `destination`, which may be the same list. Returns the
+    /// element, or `None` if there is no source list.
+    pub fn lmove(
+        &mut self,
+        source: &[u8],
+        destination: &[u8],
+        from: ListEnd,
+        to: ListEnd,
+    ) -> Result<Option<Vec<u8>>, &'static str> {
+        if self.list(source)?.is_none() {
+            return Ok(None);
+        }
+        // Checked before popping, so a bad destination leaves the source alone.
+        self.list(destination)?;
+
+        let source_list = self.list_mut(source)?.expect("source list exists");
+        let element = match from {
+            ListEnd::Left => source_list.pop_front(),
+            ListEnd::Right => source_list.pop_back(),
+        }
+        .expect("lists are never empty");
+        self.notify(EventClass::List, pop_event(from), source);
+
+        let pushed = vec![element.clone()];
+        match to {
+            ListEnd::Left => self.lpush(destination, pushed)?,
+            ListEnd::Right => self.rpush(destination, pushed)?,
+        };
+        // Only now, so rotating a one-element list keeps the key and its
+        // expiry.
+        self.remove_if_empty(source);
+        Ok(Some(element))
+    }
+
+    fn hash(&mut self, key: &[u8]) -> Result<Option<&HashValue>, &'static str> {
+        match self.get(key).map(|entry| &entry.data) {
+            None => Ok(None),
+            Some(Value::Hash(hash)) => Ok(Some(hash)),
+            Some(_) => Err("WRONGTYPE Operation against a key holding the wrong kind of value"),
+        }
+    }
+
+    // Like `hash`, for changing it.
+    fn hash_mut(&mut self, key: &[u8]) -> Result<Option<&mut HashValue>, &'static str> {
+        match self.get_mut(key).map(|entry| &mut entry.data) {
+            None => Ok(None),
+            Some(Value::Hash(hash)) => Ok(Some(hash)),
+            Some(_) => Err("WRONGTYPE Operation against a key holding the wrong kind of value"),
+        }
+    }
+
+    /// Sets each field to its value, dropping any expiry the field had.
+    /// Returns how many of the fields are new.
+    pub fn hset(&mut self, key: Vec<u8>, pairs: Pairs) -> Result<i32, &'static str> {
+        let entry = self.get_or_create(&key, || Value::Hash(HashValue::default()));
+        let Value::Hash(hash) = &mut entry.data else {
+            return Err("WRONGTYPE Operation against a key holding the wrong kind of value");
+        };
+
+        let before = hash.next_expiry();
+        let mut added = 0;
+        for (field, value) in pairs {
+            if hash.insert(field, value).is_none() {
+                added += 1;
+            }
+        }
+        self.reindex_hash(&key, before);
+        self.dirty += 1;
+        self.notify(EventClass::Hash, "hset", &key);
+        Ok(added)
+    }
+
+    /// Sets `field` only if the hash doesn't have it yet. Returns whether it
+    /// was set.
+    pub fn hsetnx(
+        &mut self,
+        key: &[u8],
+        field: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Result<bool, &'static str> {
+        if self
+            .hash(key)?
+            .is_some_and(|hash| hash.contains_key(&field))
+        {
+            return Ok(false);
+        }
+        self.hset(key.to_vec(), vec![(field, value)])?;
+        Ok(true)
+    }
+
+    pub fn hget(&mut self, key: &[u8], field: &[u8]) -> Result<Option<&Vec<u8>>, &'static str> {
+        Ok(self.hash(key)?.and_then(|hash| hash.get(field)))
+    }
+
+    /// The values of `fields`, `None` for the ones that aren't set.
+    pub fn hmget(
+        &mut self,
+        key: &[u8],
+        fields: &[Vec<u8>],
+    ) -> Result<Vec<Option<Vec<u8>>>, &'static str> {
+        let hash = self.hash(key)?;
+        Ok(fields
+            .iter()
+            .map(|field| hash.as_ref().and_then(|hash| hash.get(field)).cloned())
+            .collect())
+    }
+
+    /// Removes `fields` from the hash, and the hash itself once it has no
+    /// fields left. Returns how many were removed.
+    pub fn hdel(&mut self, key: &[u8], fields: Vec<Vec<u8>>) -> Result<i32, &'static str> {
+        let Some(hash) = self.hash_mut(key)? else {
+            return Ok(0);
+        };
+
+        let before = hash.next_expiry();
+        let mut deleted_count = 0;
+        for field in fields {
+            if hash.remove(&field).is_some() {
+                deleted_count += 1;
+            }
+        }
+
+        if deleted_count > 0 {
+            self.reindex_hash(key, before);
+            self.dirty += 1;
+            self.notify(EventClass::Hash, "hdel", key);
+            self.remove_if_empty(key);
+        }
+        Ok(deleted_count)
+    }
+
+    /// Adds `delta` to the integer stored in `field`, starting from 0 if it
+    /// isn't set.
+    pub fn hincrby(&mut self, key: &[u8], field: &[u8], delta: i64) -> Result<i64, &'static str> {
+        let entry = self.get_or_create(key, || Value::Hash(HashValue::default()));
+        let Value::Hash(hash) = &mut entry.data else {
+            return Err("WRONGTYPE Operation against a key holding the wrong kind of value");
+        };
+
+        let current = match hash.get(field) {
+            None => 0,
+            Some(value) => std::str::from_utf8(value)
+                .ok()
+                .and_then(|s| s.parse::<i64>().ok())
+                .ok_or("ERR hash value is not an integer")?,
+        };
+        let value = current
+            .checked_add(delta)
+            .ok_or("ERR increment or decrement would overflow")?;
+
+        // Unlike HSET this keeps the field's expiry, as Redis does.
+        hash.fields
+            .insert(field.to_vec(), value.to_string().into_bytes());
+        self.dirty += 1;
+        self.notify(EventClass::Hash, "hincrby", key);
+        Ok(value)
+    }
+
+    /// Like `hincrby` for floating point numbers. Returns the new value as it
+    /// is stored.
+    pub fn hincrbyfloat(
+        &mut self,
+        key: &[u8],
+        field: &[u8],
+        delta: f64,
+    ) -> Result<Vec<u8>, &'static str> {
+        let entry = self.get_or_create(key, || Value::Hash(HashValue::default()));
+        let Value::Hash(hash) = &mut entry.data else {
+            return Err("WRONGTYPE Operation against a key holding the wrong kind of value");
+        };
+
+        let current = match hash.get(field) {
+            None => 0.0,
+            Some(value) => std::str::from_utf8(value)
+                .ok()
+                .and_then(|s| s.parse::<f64>().ok())
+                .filter(|n| n.is_finite())
+                .ok_or("ERR hash value is not a float")?,
+        };
+        let value = current + delta;
+        if !value.is_finite() {
+            return Err("ERR increment would produce NaN or Infinity");
+        }
+
+        let value = value.to_string().into_bytes();
+        hash.fields.insert(field.to_vec(), value.clone());
+        self.dirty += 1;
+        self.notify(EventClass::Hash, "hincrbyfloat", key);
+        Ok(value)
+    }
+
+    /// Random fields of the hash with their values, for HRANDFIELD. A
+    /// positive `count` picks up to that many distinct fields, a negative one
+    /// picks exactly `-count` fields that may repeat.
+    pub fn hrandfield(&mut self, key: &[u8], count: i64) -> Result<Pairs, &'static str> {
+        if count < 0 && count.unsigned_abs() > MAX_RANDOM_REPEATS {
+            return Err(OUT_OF_RANGE);
+        }
+        let Some(hash) = self.hash(key)? else {
+            return Ok(Vec::new());
+        };
+
+        // The fields picked are found in one walk over the hash, in order of
+        // position, then put back in the order they were picked.
+        let positions = random_positions(hash.len(), count);
+        let mut by_position: Vec<usize> = (0..positions.len()).collect();
+        by_position.sort_unstable_by_key(|&k| positions[k]);
+        let mut picked = vec![(Vec::new(), Vec::new()); positions.len()];
+        let mut entries = hash.iter().enumerate();
+        let mut current = entries.next();
+        for k in by_position {
+            while let Some((i, _)) = current
+                && i < positions[k]
+            {
+                current = entries.next();
+            }
+            let (_, (field, value)) = current.expect("positions are below the hash's length");
+            picked[k] = (field.clone(), value.clone());
+        }
+        Ok(picked)
+    }
+
+    pub fn hlen(&mut self, key: &[u8]) -> Result<usize, &'static str> {
+        match self.get(key) {
             None => Ok(0),
             Some(store_value) => match &store_value.data {
                 Value::Hash(map) => Ok(map.len()),
@@ -229,427 +1593,2998 @@ impl Storage {
         }
     }
 
-    pub fn hgetall(&self, key: &str) -> Result<Option<&HashMap<String, String>>, &'static str> {
-        match self.data.get(key) {
+    pub fn hgetall(&mut self, key: &[u8]) -> Result<Option<&FieldMap>, &'static str> {
+        match self.get(key) {
             None => Ok(None),
             Some(store_value) => match &store_value.data {
-                Value::Hash(hash) => Ok(Some(hash)),
+                Value::Hash(hash) => Ok(Some(&hash.fields)),
                 _ => Err("WRONGTYPE Operation against a key holding the wrong kind of value"),
             },
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::time::Duration;
+    /// Makes each of `fields` expire at `at`, in Unix milliseconds, where
+    /// `condition` allows it, for HEXPIRE and friends. A time that has
+    /// already passed deletes the field. Returns for each field -2 if it
+    /// doesn't exist, 0 if the condition isn't met, 1 if the expiry was set
+    /// and 2 if the field was deleted.
+    pub fn hexpire(
+        &mut self,
+        key: &[u8],
+        fields: &[Vec<u8>],
+        at: i64,
+        condition: ExpireCondition,
+    ) -> Result<Vec<i64>, &'static str> {
+        let expired = at <= now_millis() as i64 && !self.loading;
+        let Some(hash) = self.hash_mut(key)? else {
+            return Ok(vec![-2; fields.len()]);
+        };
 
-    #[test]
-    fn test_new_storage_is_empty() {
-        let mut storage = Storage::new();
-        assert!(storage.get("some_key").is_none());
-        assert!(!storage.is_dirty());
+        let before = hash.next_expiry();
+        let replies: Vec<i64> = fields
+            .iter()
+            .map(|field| {
+                if !hash.contains_key(field) {
+                    -2
+                } else if !condition.allows(hash.field_expiry(field), at) {
+                    0
+                } else if expired {
+                    hash.remove(field);
+                    2
+                } else {
+                    hash.set_field_expiry(field, Some(at as u64));
+                    1
+                }
+            })
+            .collect();
+
+        if replies.iter().any(|&reply| reply > 0) {
+            self.reindex_hash(key, before);
+            self.dirty += 1;
+            if replies.contains(&1) {
+                self.notify(EventClass::Hash, "hexpire", key);
+            }
+            if replies.contains(&2) {
+                self.notify(EventClass::Hash, "hexpired", key);
+            }
+            self.remove_if_empty(key);
+        }
+        Ok(replies)
+    }
+
+    /// When each of `fields` expires: `None` if the field doesn't exist,
+    /// `Some(None)` if it never does.
+    pub fn hexpiry(
+        &mut self,
+        key: &[u8],
+        fields: &[Vec<u8>],
+    ) -> Result<Vec<Option<Option<u64>>>, &'static str> {
+        let hash = self.hash(key)?;
+        Ok(fields
+            .iter()
+            .map(|field| {
+                let hash = hash.as_ref()?;
+                hash.contains_key(field).then(|| hash.field_expiry(field))
+            })
+            .collect())
+    }
+
+    /// Removes the expiry of each of `fields`. Returns for each field -2 if
+    /// it doesn't exist, -1 if it has no expiry and 1 if it was removed.
+    pub fn hpersist(&mut self, key: &[u8], fields: &[Vec<u8>]) -> Result<Vec<i64>, &'static str> {
+        let Some(hash) = self.hash_mut(key)? else {
+            return Ok(vec![-2; fields.len()]);
+        };
+
+        let before = hash.next_expiry();
+        let replies: Vec<i64> = fields
+            .iter()
+            .map(|field| {
+                if !hash.contains_key(field) {
+                    -2
+                } else if hash.field_expiry(field).is_none() {
+                    -1
+                } else {
+                    hash.set_field_expiry(field, None);
+                    1
+                }
+            })
+            .collect();
+
+        if replies.contains(&1) {
+            self.reindex_hash(key, before);
+            self.dirty += 1;
+            self.notify(EventClass::Hash, "hpersist", key);
+        }
+        Ok(replies)
+    }
+
+    /// How many hashes have fields with an expiry.
+    pub fn hashes_with_expiring_fields(&self) -> usize {
+        self.hash_expires.len()
+    }
+
+    // The set stored at `key`, if there is one.
+    fn members(&mut self, key: &[u8]) -> Result<Option<&Members>, &'static str> {
+        match self.get(key).map(|entry| &entry.data) {
+            None => Ok(None),
+            Some(Value::Set(set)) => Ok(Some(set)),
+            Some(_) => Err("WRONGTYPE Operation against a key holding the wrong kind of value"),
+        }
+    }
+
+    // Like `members`, for changing it.
+    fn members_mut(&mut self, key: &[u8]) -> Result<Option<&mut Members>, &'static str> {
+        match self.get_mut(key).map(|entry| &mut entry.data) {
+            None => Ok(None),
+            Some(Value::Set(set)) => Ok(Some(set)),
+            Some(_) => Err("WRONGTYPE Operation against a key holding the wrong kind of value"),
+        }
+    }
+
+    /// Adds `members` to the set, creating it if needed. Returns how many
+    /// weren't in it yet.
+    pub fn sadd(&mut self, key: &[u8], members: Vec<Vec<u8>>) -> Result<i64, &'static str> {
+        let entry = self.get_or_create(key, || Value::Set(Members::new()));
+        let Value::Set(set) = &mut entry.data else {
+            return Err("WRONGTYPE Operation against a key holding the wrong kind of value");
+        };
+
+        let mut added = 0;
+        for member in members {
+            if set.insert(member) {
+                added += 1;
+            }
+        }
+        self.dirty += added as u64;
+        if added > 0 {
+            self.notify(EventClass::Set, "sadd", key);
+        }
+        Ok(added)
+    }
+
+    /// Removes `members` from the set, and the set itself once it is empty.
+    /// Returns how many were in it.
+    pub fn srem(&mut self, key: &[u8], members: &[Vec<u8>]) -> Result<i64, &'static str> {
+        let Some(set) = self.members_mut(key)? else {
+            return Ok(0);
+        };
+
+        let removed = members.iter().filter(|member| set.remove(member)).count();
+        if removed > 0 {
+            self.dirty += 1;
+            self.notify(EventClass::Set, "srem", key);
+            self.remove_if_empty(key);
+        }
+        Ok(removed as i64)
+    }
+
+    /// Every member of the set, in no particular order.
+    pub fn smembers(&mut self, key: &[u8]) -> Result<Vec<Vec<u8>>, &'static str> {
+        Ok(self
+            .members(key)?
+            .map(|set| set.iter().map(<[u8]>::to_vec).collect())
+            .unwrap_or_default())
+    }
+
+    pub fn sismember(&mut self, key: &[u8], member: &[u8]) -> Result<bool, &'static str> {
+        Ok(self.members(key)?.is_some_and(|set| set.contains(member)))
+    }
+
+    pub fn scard(&mut self, key: &[u8]) -> Result<usize, &'static str> {
+        Ok(self.members(key)?.map_or(0, |set| set.len()))
+    }
+
+    /// Removes up to `count` random members and returns them.
+    pub fn spop(&mut self, key: &[u8], count: usize) -> Result<Vec<Vec<u8>>, &'static str> {
+        let Some(set) = self.members_mut(key)? else {
+            return Ok(Vec::new());
+        };
+
+        let mut popped = Vec::new();
+        while popped.len() < count
+            && let Some(member) = set.pop_random()
+        {
+            popped.push(member);
+        }
+        if !popped.is_empty() {
+            self.dirty += 1;
+            self.notify(EventClass::Set, "spop", key);
+            self.remove_if_empty(key);
+        }
+        Ok(popped)
+    }
+
+    /// Random members, for SRANDMEMBER. A positive `count` picks up to that
+    /// many distinct members, a negative one picks exactly `-count` members
+    /// that may repeat.
+    pub fn srandmember(&mut self, key: &[u8], count: i64) -> Result<Vec<Vec<u8>>, &'static str> {
+        if count < 0 && count.unsigned_abs() > MAX_RANDOM_REPEATS {
+            return Err(OUT_OF_RANGE);
+        }
+        let Some(set) = self.members(key)? else {
+            return Ok(Vec::new());
+        };
+        Ok(random_positions(set.len(), count)
+            .into_iter()
+            .map(|i| set.items[i].to_vec())
+            .collect())
+    }
+
+    /// Moves `member` from one set to another. Returns false if it isn't in
+    /// `source`. Both keys are type checked before anything changes.
+    pub fn smove(
+        &mut self,
+        source: &[u8],
+        destination: &[u8],
+        member: Vec<u8>,
+    ) -> Result<bool, &'static str> {
+        self.members(destination)?;
+        if !self
+            .members(source)?
+            .is_some_and(|set| set.contains(&member))
+        {
+            return Ok(false);
+        }
+        if source == destination {
+            return Ok(true);
+        }
+
+        if let Some(set) = self.members_mut(source)? {
+            set.remove(&member);
+        }
+        self.dirty += 1;
+        self.notify(EventClass::Set, "srem", source);
+        self.remove_if_empty(source);
+        self.sadd(destination, vec![member])?;
+        Ok(true)
+    }
+
+    /// The intersection, union or difference of the sets at `keys`. A key
+    /// that doesn't exist counts as an empty set. For a difference, the
+    /// members of the first set that are in none of the others.
+    pub fn set_op(&mut self, op: SetOp, keys: &[Vec<u8>]) -> Result<Members, &'static str> {
+        // Check every key first, so one of the wrong type is an error
+        // whatever the others hold, and expired ones are gone.
+        for key in keys {
+            self.members(key)?;
+        }
+        let sets: Vec<Option<&Members>> = keys
+            .iter()
+            .map(
+                |key| match self.data.get(key.as_slice()).map(|entry| &entry.data) {
+                    Some(Value::Set(set)) => Some(set),
+                    _ => None,
+                },
+            )
+            .collect();
+
+        let result = match op {
+            SetOp::Union => sets
+                .iter()
+                .flatten()
+                .flat_map(|set| set.iter())
+                .map(<[u8]>::to_vec)
+                .collect(),
+            SetOp::Inter => {
+                let Some(mut sets) = sets.into_iter().collect::<Option<Vec<_>>>() else {
+                    return Ok(Members::new());
+                };
+                // Only the smallest set needs walking.
+                sets.sort_by_key(|set| set.len());
+                let Some((smallest, others)) = sets.split_first() else {
+                    return Ok(Members::new());
+                };
+                smallest
+                    .iter()
+                    .filter(|member| others.iter().all(|set| set.contains(member)))
+                    .map(<[u8]>::to_vec)
+                    .collect()
+            }
+            SetOp::Diff => {
+                let Some((Some(first), others)) = sets.split_first() else {
+                    return Ok(Members::new());
+                };
+                first
+                    .iter()
+                    .filter(|member| others.iter().flatten().all(|set| !set.contains(member)))
+                    .map(<[u8]>::to_vec)
+                    .collect()
+            }
+        };
+        Ok(result)
+    }
+
+    /// Like `set_op`, but stores the result at `destination`, replacing
+    /// whatever was there. An empty result deletes `destination`. Returns
+    /// the size of the result.
+    pub fn set_op_store(
+        &mut self,
+        op: SetOp,
+        destination: Vec<u8>,
+        keys: &[Vec<u8>],
+    ) -> Result<usize, &'static str> {
+        let result = self.set_op(op, keys)?;
+        let len = result.len();
+        if result.is_empty() {
+            self.remove(&destination);
+        } else {
+            let event = match op {
+                SetOp::Inter => "sinterstore",
+                SetOp::Union => "sunionstore",
+                SetOp::Diff => "sdiffstore",
+            };
+            self.notify(EventClass::Set, event, &destination);
+            self.insert(
+                destination,
+                StoreValue {
+                    data: Value::Set(result),
+                    expiry: None,
+                },
+            );
+        }
+        Ok(len)
+    }
+
+    // The sorted set stored at `key`, if there is one.
+    fn zset(&mut self, key: &[u8]) -> Result<Option<&SortedSet>, &'static str> {
+        match self.get(key).map(|entry| &entry.data) {
+            None => Ok(None),
+            Some(Value::SortedSet(set)) => Ok(Some(set)),
+            Some(_) => Err("WRONGTYPE Operation against a key holding the wrong kind of value"),
+        }
+    }
+
+    // Like `zset`, for changing it.
+    fn zset_mut(&mut self, key: &[u8]) -> Result<Option<&mut SortedSet>, &'static str> {
+        match self.get_mut(key).map(|entry| &mut entry.data) {
+            None => Ok(None),
+            Some(Value::SortedSet(set)) => Ok(Some(set)),
+            Some(_) => Err("WRONGTYPE Operation against a key holding the wrong kind of value"),
+        }
+    }
+
+    /// Adds members with their scores, or updates the scores of existing
+    /// ones, as far as `options` allow. Returns how many were added, plus
+    /// how many changed with `options.changed`.
+    pub fn zadd(
+        &mut self,
+        key: &[u8],
+        pairs: Vec<(f64, Vec<u8>)>,
+        options: &ZAddOptions,
+    ) -> Result<i64, &'static str> {
+        let entry = self.get_or_create(key, || Value::SortedSet(SortedSet::new()));
+        let Value::SortedSet(set) = &mut entry.data else {
+            return Err("WRONGTYPE Operation against a key holding the wrong kind of value");
+        };
+
+        let (mut added, mut changed) = (0, 0);
+        for (score, member) in pairs {
+            match set.score(&member) {
+                Some(current) if current == score || !options.allows_update(current, score) => {}
+                Some(_) => {
+                    set.insert(member, score);
+                    changed += 1;
+                }
+                None if options.only_if_exists == Some(true) => {}
+                None => {
+                    set.insert(member, score);
+                    added += 1;
+                }
+            }
+        }
+
+        if added + changed > 0 {
+            self.dirty += 1;
+            self.notify(EventClass::SortedSet, "zadd", key);
+        }
+        // XX may have left a set created above empty.
+        self.discard_if_empty(key);
+        Ok(if options.changed {
+            added + changed
+        } else {
+            added
+        })
+    }
+
+    /// Adds `delta` to the score of `member`, adding it with a score of
+    /// `delta` if needed. Returns the new score, or `None` if `options` didn't
+    /// allow the change.
+    pub fn zincrby(
+        &mut self,
+        key: &[u8],
+        member: Vec<u8>,
+        delta: f64,
+        options: &ZAddOptions,
+    ) -> Result<Option<f64>, &'static str> {
+        let entry = self.get_or_create(key, || Value::SortedSet(SortedSet::new()));
+        let Value::SortedSet(set) = &mut entry.data else {
+            return Err("WRONGTYPE Operation against a key holding the wrong kind of value");
+        };
+
+        let current = set.score(&member);
+        let score = current.unwrap_or(0.0) + delta;
+        let result = if score.is_nan() {
+            Err("ERR resulting score is not a number (NaN)")
+        } else if match current {
+            Some(current) => !options.allows_update(current, score),
+            None => options.only_if_exists == Some(true),
+        } {
+            Ok(None)
+        } else {
+            set.insert(member, score);
+            self.dirty += 1;
+            self.notify(EventClass::SortedSet, "zincr", key);
+            Ok(Some(score))
+        };
+
+        self.discard_if_empty(key);
+        result
+    }
+
+    /// Removes `members`, and the sorted set once it is empty. Returns how
+    /// many were in it.
+    pub fn zrem(&mut self, key: &[u8], members: &[Vec<u8>]) -> Result<i64, &'static str> {
+        let Some(set) = self.zset_mut(key)? else {
+            return Ok(0);
+        };
+
+        let removed = members.iter().filter(|member| set.remove(member)).count();
+        if removed > 0 {
+            self.dirty += 1;
+            self.notify(EventClass::SortedSet, "zrem", key);
+            self.remove_if_empty(key);
+        }
+        Ok(removed as i64)
+    }
+
+    pub fn zscore(&mut self, key: &[u8], member: &[u8]) -> Result<Option<f64>, &'static str> {
+        Ok(self.zset(key)?.and_then(|set| set.score(member)))
+    }
+
+    pub fn zcard(&mut self, key: &[u8]) -> Result<usize, &'static str> {
+        Ok(self.zset(key)?.map_or(0, |set| set.len()))
+    }
+
+    /// The rank of `member` from the lowest score, or the highest with `rev`.
+    pub fn zrank(
+        &mut self,
+        key: &[u8],
+        member: &[u8],
+        rev: bool,
+    ) -> Result<Option<usize>, &'static str> {
+        Ok(self.zset(key)?.and_then(|set| set.rank(member, rev)))
+    }
+
+    /// The members `by` selects with their scores, in order or in reverse
+    /// with `rev`. `limit` is LIMIT's offset and count: a negative offset
+    /// selects nothing and a negative count everything after the offset.
+    pub fn zrange(
+        &mut self,
+        key: &[u8],
+        by: &RangeBy,
+        rev: bool,
+        limit: Option<(i64, i64)>,
+    ) -> Result<Vec<(Vec<u8>, f64)>, &'static str> {
+        let Some(set) = self.zset(key)? else {
+            return Ok(Vec::new());
+        };
+
+        let (offset, count) = limit.unwrap_or((0, -1));
+        if offset < 0 {
+            return Ok(Vec::new());
+        }
+        let count = usize::try_from(count).ok();
+        Ok(set.range(by, rev, offset as usize, count))
+    }
+
+    /// How many members `by` selects, for ZCOUNT and ZLEXCOUNT.
+    pub fn zcount(&mut self, key: &[u8], by: &RangeBy) -> Result<usize, &'static str> {
+        Ok(self.zset(key)?.map_or(0, |set| set.count(by)))
+    }
+
+    /// Removes and returns up to `count` members with the lowest scores, or
+    /// the highest with `max`.
+    pub fn zpop(
+        &mut self,
+        key: &[u8],
+        max: bool,
+        count: usize,
+    ) -> Result<Vec<(Vec<u8>, f64)>, &'static str> {
+        let Some(set) = self.zset_mut(key)? else {
+            return Ok(Vec::new());
+        };
+
+        let popped = set.pop(max, count);
+        if !popped.is_empty() {
+            self.dirty += 1;
+            let event = if max { "zpopmax" } else { "zpopmin" };
+            self.notify(EventClass::SortedSet, event, key);
+            self.remove_if_empty(key);
+        }
+        Ok(popped)
+    }
+
+    /// Combines the sorted sets at `keys` into `destination`, replacing
+    /// whatever was there, for ZUNIONSTORE, ZINTERSTORE and ZDIFFSTORE.
+    /// Plain sets count as sorted sets whose scores are all 1. Each input's
+    /// scores are multiplied by its weight, 1 if `weights` has none for it,
+    /// and `aggregate` combines the scores of a member found in several.
+    /// Returns the size of the result, an empty one deletes `destination`.
+    pub fn zstore(
+        &mut self,
+        op: SetOp,
+        destination: Vec<u8>,
+        keys: &[Vec<u8>],
+        weights: &[f64],
+        aggregate: Aggregate,
+    ) -> Result<usize, &'static str> {
+        for key in keys {
+            if let Some(entry) = self.get(key)
+                && !matches!(entry.data, Value::Set(_) | Value::SortedSet(_))
+            {
+                return Err("WRONGTYPE Operation against a key holding the wrong kind of value");
+            }
+        }
+
+        let inputs: Vec<HashMap<&[u8], f64>> = keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| {
+                let weight = weights.get(i).copied().unwrap_or(1.0);
+                let weighted = |score: f64| match score * weight {
+                    // inf times 0, which Redis counts as 0.
+                    score if score.is_nan() => 0.0,
+                    score => score,
+                };
+                match self.data.get(key.as_slice()).map(|entry| &entry.data) {
+                    Some(Value::SortedSet(set)) => set
+                        .iter()
+                        .map(|(member, score)| (member.as_slice(), weighted(score)))
+                        .collect(),
+                    Some(Value::Set(set)) => {
+                        set.iter().map(|member| (member, weighted(1.0))).collect()
+                    }
+                    _ => HashMap::new(),
+                }
+            })
+            .collect();
+
+        let mut result: HashMap<&[u8], f64> = HashMap::new();
+        match op {
+            SetOp::Union => {
+                for input in &inputs {
+                    for (&member, &score) in input {
+                        result
+                            .entry(member)
+                            .and_modify(|total| *total = aggregate.combine(*total, score))
+                            .or_insert(score);
+                    }
+                }
+            }
+            SetOp::Inter => {
+                if let Some((first, others)) = inputs.split_first() {
+                    for (&member, &score) in first {
+                        let combined = others.iter().try_fold(score, |total, input| {
+                            input
+                                .get(member)
+                                .map(|&score| aggregate.combine(total, score))
+                        });
+                        if let Some(combined) = combined {
+                            result.insert(member, combined);
+                        }
+                    }
+                }
+            }
+            SetOp::Diff => {
+                if let Some((first, others)) = inputs.split_first() {
+                    for (&member, &score) in first {
+                        if others.iter().all(|input| !input.contains_key(member)) {
+                            result.insert(member, score);
+                        }
+                    }
+                }
+            }
+        }
+
+        let result: SortedSet = result
+            .into_iter()
+            .map(|(member, score)| (member.to_vec(), score))
+            .collect::<Vec<_>>()
+            .into();
+        let len = result.len();
+        if result.is_empty() {
+            self.remove(&destination);
+        } else {
+            let event = match op {
+                SetOp::Inter => "zinterstore",
+                SetOp::Union => "zunionstore",
+                SetOp::Diff => "zdiffstore",
+            };
+            self.notify(EventClass::SortedSet, event, &destination);
+            self.insert(
+                destination,
+                StoreValue {
+                    data: Value::SortedSet(result),
+                    expiry: None,
+                },
+            );
+        }
+        Ok(len)
+    }
+
+    // The stream stored at `key`, if there is one.
+    fn stream(&mut self, key: &[u8]) -> Result<Option<&Stream>, &'static str> {
+        match self.get(key).map(|entry| &entry.data) {
+            None => Ok(None),
+            Some(Value::Stream(stream)) => Ok(Some(stream)),
+            Some(_) => Err("WRONGTYPE Operation against a key holding the wrong kind of value"),
+        }
+    }
+
+    // Like `stream`, for changing it.
+    fn stream_mut(&mut self, key: &[u8]) -> Result<Option<&mut Stream>, &'static str> {
+        match self.get_mut(key).map(|entry| &mut entry.data) {
+            None => Ok(None),
+            Some(Value::Stream(stream)) => Ok(Some(stream)),
+            Some(_) => Err("WRONGTYPE Operation against a key holding the wrong kind of value"),
+        }
+    }
+
+    // Like `stream`, for XGROUP, which needs the key to exist.
+    fn existing_stream(&mut self, key: &[u8]) -> Result<&mut Stream, &'static str> {
+        self.stream_mut(key)?.ok_or(
+            "ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may \
+             want to use the MKSTREAM option to create an empty stream automatically.",
+        )
+    }
+
+    /// Appends an entry to the stream at `key`, creating it unless
+    /// `no_mkstream`, then trims it if asked. Returns the new entry's ID, or
+    /// `None` if there was no stream to add it to.
+    pub fn xadd(
+        &mut self,
+        key: &[u8],
+        id: NewId,
+        fields: Fields,
+        no_mkstream: bool,
+        trim: Option<&Trim>,
+    ) -> Result<Option<StreamId>, &'static str> {
+        let created = self.stream(key)?.is_none();
+        if created && no_mkstream {
+            return Ok(None);
+        }
+
+        let entry = self.get_or_create(key, || Value::Stream(Stream::new()));
+        let Value::Stream(stream) = &mut entry.data else {
+            unreachable!("checked to be a stream or missing");
+        };
+        let id = match stream.add(id, fields, now_millis()) {
+            Ok(id) => id,
+            Err(msg) => {
+                // A bad ID mustn't leave an empty stream behind.
+                if created {
+                    self.remove_entry(key);
+                }
+                return Err(msg);
+            }
+        };
+        let trimmed = trim.map_or(0, |trim| stream.trim(trim));
+
+        self.dirty += 1;
+        self.notify(EventClass::Stream, "xadd", key);
+        if trimmed > 0 {
+            self.notify(EventClass::Stream, "xtrim", key);
+        }
+        Ok(Some(id))
+    }
+
+    pub fn xlen(&mut self, key: &[u8]) -> Result<usize, &'static str> {
+        Ok(self.stream(key)?.map_or(0, |stream| stream.len()))
+    }
+
+    /// The entries between `start` and `end`, newest first with `rev`.
+    pub fn xrange(
+        &mut self,
+        key: &[u8],
+        start: Bound<StreamId>,
+        end: Bound<StreamId>,
+        rev: bool,
+        count: Option<usize>,
+    ) -> Result<Vec<(StreamId, Fields)>, &'static str> {
+        Ok(self
+            .stream(key)?
+            .map(|stream| stream.range(start, end, rev, count))
+            .unwrap_or_default())
+    }
+
+    /// The ID of the last entry ever added to the stream at `key`.
+    pub fn xlast_id(&mut self, key: &[u8]) -> Result<Option<StreamId>, &'static str> {
+        Ok(self.stream(key)?.map(|stream| stream.last_id()))
+    }
+
+    /// Evicts old entries from the stream at `key`. Returns how many.
+    pub fn xtrim(&mut self, key: &[u8], trim: &Trim) -> Result<usize, &'static str> {
+        let evicted = self.stream_mut(key)?.map_or(0, |stream| stream.trim(trim));
+        if evicted > 0 {
+            self.dirty += 1;
+            self.notify(EventClass::Stream, "xtrim", key);
+        }
+        Ok(evicted)
+    }
+
+    pub fn xdel(&mut self, key: &[u8], ids: &[StreamId]) -> Result<usize, &'static str> {
+        let deleted = self.stream_mut(key)?.map_or(0, |stream| stream.delete(ids));
+        if deleted > 0 {
+            self.dirty += 1;
+            self.notify(EventClass::Stream, "xdel", key);
+        }
+        Ok(deleted)
+    }
+
+    /// Sets the ID new entries of the stream at `key` must be above.
+    pub fn xsetid(&mut self, key: &[u8], id: StreamId) -> Result<(), &'static str> {
+        let stream = self.stream_mut(key)?.ok_or("ERR no such key")?;
+        stream.set_last_id(id)?;
+        self.dirty += 1;
+        self.notify(EventClass::Stream, "xsetid", key);
+        Ok(())
+    }
+
+    /// Reads the streams at `keys`, each after the matching ID in `from`, for
+    /// XREAD. Only the streams with entries to read are in the result.
+    pub fn xread(
+        &mut self,
+        keys: &[Vec<u8>],
+        from: &[ReadFrom],
+        count: Option<usize>,
+    ) -> Result<StreamsRead, &'static str> {
+        let mut read = Vec::new();
+        for (key, from) in keys.iter().zip(from) {
+            let Some(stream) = self.stream(key)? else {
+                continue;
+            };
+            let ReadFrom::After(after) = *from else {
+                continue;
+            };
+            let entries = stream.range(Bound::Excluded(after), Bound::Unbounded, false, count);
+            if !entries.is_empty() {
+                let entries = entries
+                    .into_iter()
+                    .map(|(id, fields)| (id, Some(fields)))
+                    .collect();
+                read.push((key.clone(), entries));
+            }
+        }
+        Ok(read)
+    }
+
+    /// Reads the streams at `keys` on behalf of `consumer` of `group`, for
+    /// XREADGROUP, see `Stream::read_group`. Every stream must have the
+    /// group. Streams read for undelivered entries are left out of the result
+    /// if they had none.
+    pub fn xreadgroup(
+        &mut self,
+        group: &[u8],
+        consumer: &[u8],
+        keys: &[Vec<u8>],
+        from: &[ReadFrom],
+        count: Option<usize>,
+        no_ack: bool,
+    ) -> Result<StreamsRead, &'static str> {
+        // Every key and group is checked before any is read, so a read
+        // either fails without changing anything or reads every key.
+        for key in keys {
+            if self
+                .stream(key)?
+                .and_then(|stream| stream.group(group))
+                .is_none()
+            {
+                return Err("NOGROUP No such key or consumer group");
+            }
+        }
+
+        let now = now_millis();
+        let mut read = Vec::new();
+        // Whether anything was delivered or a consumer added. Only the
+        // consumer's last seen time changes otherwise.
+        let mut changed = false;
+        for (key, from) in keys.iter().zip(from) {
+            // Not through `get`, which could find the key expired since.
+            let Some(Value::Stream(stream)) = self
+                .data
+                .get_mut(key.as_slice())
+                .map(|entry| &mut Arc::make_mut(entry).data)
+            else {
+                unreachable!("checked to be a stream above");
+            };
+            changed |= stream
+                .group(group)
+                .is_some_and(|group| !group.has_consumer(consumer));
+            let entries = stream
+                .read_group(group, consumer, *from, count, no_ack, now)
+                .expect("the group was checked above");
+            changed |= !entries.is_empty();
+            if !entries.is_empty() || *from != ReadFrom::Undelivered {
+                read.push((key.clone(), entries));
+            }
+        }
+        if changed {
+            self.dirty += 1;
+        }
+        Ok(read)
+    }
+
+    /// Creates a consumer group, and with `mkstream` the stream too if needed.
+    pub fn xgroup_create(
+        &mut self,
+        key: &[u8],
+        group: &[u8],
+        from: ReadFrom,
+        mkstream: bool,
+    ) -> Result<(), &'static str> {
+        if mkstream && self.stream(key)?.is_none() {
+            self.insert(
+                key.to_vec(),
+                StoreValue {
+                    data: Value::Stream(Stream::new()),
+                    expiry: None,
+                },
+            );
+        }
+        self.existing_stream(key)?.create_group(group, from)?;
+        self.dirty += 1;
+        self.notify(EventClass::Stream, "xgroup-create", key);
+        Ok(())
+    }
+
+    pub fn xgroup_setid(
+        &mut self,
+        key: &[u8],
+        group: &[u8],
+        from: ReadFrom,
+    ) -> Result<(), &'static str> {
+        self.existing_stream(key)?.set_group_id(group, from)?;
+        self.dirty += 1;
+        self.notify(EventClass::Stream, "xgroup-setid", key);
+        Ok(())
+    }
+
+    pub fn xgroup_destroy(&mut self, key: &[u8], group: &[u8]) -> Result<bool, &'static str> {
+        let destroyed = self.existing_stream(key)?.destroy_group(group);
+        if destroyed {
+            self.dirty += 1;
+            self.notify(EventClass::Stream, "xgroup-destroy", key);
+        }
+        Ok(destroyed)
+    }
+
+    pub fn xgroup_createconsumer(
+        &mut self,
+        key: &[u8],
+        group: &[u8],
+        consumer: &[u8],
+    ) -> Result<bool, &'static str> {
+        let created = self
+            .existing_stream(key)?
+            .create_consumer(group, consumer, now_millis())?;
+        if created {
+            self.dirty += 1;
+            self.notify(EventClass::Stream, "xgroup-createconsumer", key);
+        }
+        Ok(created)
+    }
+
+    /// Removes a consumer and the entries it had pending. Returns how many.
+    pub fn xgroup_delconsumer(
+        &mut self,
+        key: &[u8],
+        group: &[u8],
+        consumer: &[u8],
+    ) -> Result<usize, &'static str> {
+        let pending = self
+            .existing_stream(key)?
+            .delete_consumer(group, consumer)?;
+        self.dirty += 1;
+        self.notify(EventClass::Stream, "xgroup-delconsumer", key);
+        Ok(pending)
+    }
+
+    pub fn xack(
+        &mut self,
+        key: &[u8],
+        group: &[u8],
+        ids: &[StreamId],
+    ) -> Result<usize, &'static str> {
+        let acked = self
+            .stream_mut(key)?
+            .map_or(0, |stream| stream.ack(group, ids));
+        if acked > 0 {
+            self.dirty += 1;
+        }
+        Ok(acked)
+    }
+
+    /// The consumer group `group` of the stream at `key`, for XPENDING.
+    pub fn xgroup(&mut self, key: &[u8], group: &[u8]) -> Result<&ConsumerGroup, &'static str> {
+        self.stream(key)?
+            .and_then(|stream| stream.group(group))
+            .ok_or("NOGROUP No such key or consumer group")
+    }
+
+    /// See `Stream::claim`.
+    pub fn xclaim(
+        &mut self,
+        key: &[u8],
+        group: &[u8],
+        consumer: &[u8],
+        min_idle: u64,
+        ids: &[StreamId],
+        options: &ClaimOptions,
+    ) -> Result<Vec<(StreamId, Option<Fields>)>, &'static str> {
+        let stream = self
+            .stream_mut(key)?
+            .ok_or("NOGROUP No such key or consumer group")?;
+        let claimed = stream.claim(group, consumer, min_idle, ids, options, now_millis())?;
+        self.dirty += 1;
+        Ok(claimed)
+    }
+
+    /// See `Stream::auto_claim`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn xautoclaim(
+        &mut self,
+        key: &[u8],
+        group: &[u8],
+        consumer: &[u8],
+        min_idle: u64,
+        start: StreamId,
+        count: usize,
+        just_id: bool,
+    ) -> Result<AutoClaimed, &'static str> {
+        let stream = self
+            .stream_mut(key)?
+            .ok_or("NOGROUP No such key or consumer group")?;
+        let claimed = stream.auto_claim(
+            group,
+            consumer,
+            min_idle,
+            start,
+            count,
+            just_id,
+            now_millis(),
+        )?;
+        self.dirty += 1;
+        Ok(claimed)
+    }
+}
+
+// The keyspace notification for popping from `end` of a list.
+fn pop_event(end: ListEnd) -> &'static str {
+    match end {
+        ListEnd::Left => "lpop",
+        ListEnd::Right => "rpop",
+    }
+}
+
+// When the first field of a hash expires, if `value` is a hash with fields
+// that do.
+fn fields_expire(value: &StoreValue) -> Option<u64> {
+    match &value.data {
+        Value::Hash(hash) => hash.next_expiry(),
+        _ => None,
+    }
+}
+
+// Which items to pick, by position among `len`, the way HRANDFIELD and
+// SRANDMEMBER do: a positive `count` picks up to that many distinct items, a
+// negative one picks exactly `-count` items that may repeat. Takes time in
+// proportion to the number picked rather than to `len`.
+fn random_positions(len: usize, count: i64) -> Vec<usize> {
+    let mut positions = Vec::new();
+    if len == 0 {
+        return positions;
+    }
+    if count < 0 {
+        // Grown as items are picked, rather than allocated for `-count` of
+        // them up front.
+        for _ in 0..count.unsigned_abs() {
+            positions.push(random_below(len));
+        }
+        return positions;
+    }
+
+    let count = (count as u64).min(len as u64) as usize;
+    if count == len {
+        positions.extend(0..len);
+        return positions;
+    }
+    // Floyd's algorithm: each step picks from one more position than the
+    // last, taking the new one if the pick was already taken.
+    let mut taken = HashSet::with_capacity(count);
+    for j in len - count..len {
+        let i = random_below(j + 1);
+        let i = if taken.contains(&i) { j } else { i };
+        taken.insert(i);
+        positions.push(i);
+    }
+    positions
+}
+
+/// A random number below `n`, from a xorshift generator seeded randomly for
+/// each thread. Fine for picking random elements, not for anything secret.
+pub(crate) fn random_below(n: usize) -> usize {
+    thread_local! {
+        static STATE: Cell<u64> = Cell::new(RandomState::new().build_hasher().finish() | 1);
+    }
+
+    STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        state.set(x);
+        (x % n as u64) as usize
+    })
+}
+
+// Turns a Redis list index, which counts from the end when negative, into a
+// position in a list of `len` elements.
+fn list_index(index: i64, len: usize) -> Option<usize> {
+    let index = if index < 0 { index + len as i64 } else { index };
+    (0..len as i64).contains(&index).then_some(index as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::TrimBy;
+    use std::time::Duration;
+
+    #[test]
+    fn test_new_storage_is_empty() {
+        let mut storage = Storage::new();
+        assert!(storage.get(b"some_key").is_none());
+        assert!(!storage.is_dirty());
+    }
+
+    #[test]
+    fn test_set_and_get_string_value() {
+        let mut storage = Storage::new();
+        let key = b"hello".to_vec();
+        let value = b"world".to_vec();
+
+        storage.set(key.clone(), value.clone(), None).unwrap();
+        assert!(storage.is_dirty());
+
+        let stored_value = storage.get(&key).unwrap();
+        match &stored_value.data {
+            Value::String(s) => assert_eq!(s, &value),
+            _ => panic!("Expected string value"),
+        }
+        assert!(stored_value.expiry.is_none());
+    }
+
+    #[test]
+    fn test_set_with_expiry() {
+        let mut storage = Storage::new();
+        let key = b"expiring_key".to_vec();
+        let value = b"expiring_value".to_vec();
+        let expiry = Duration::from_secs(1);
+
+        storage
+            .set(key.clone(), value.clone(), Some(expiry))
+            .unwrap();
+        let stored_value = storage.get(&key).unwrap();
+
+        match &stored_value.data {
+            Value::String(s) => assert_eq!(s, &value),
+            _ => panic!("Expected string value"),
+        }
+        assert!(stored_value.expiry.is_some());
+
+        // A deadline past what a Unix time in milliseconds can hold is
+        // refused rather than wrapping around.
+        let too_far = Duration::from_millis(i64::MAX as u64);
+        assert_eq!(
+            storage.set(key.clone(), value.clone(), Some(too_far)),
+            Err(INVALID_SET_EXPIRY)
+        );
+        let options = SetOptions {
+            expiry: Some(Expiry::In(i64::MAX)),
+            ..SetOptions::default()
+        };
+        assert_eq!(
+            storage.set_with_options(key.clone(), value, &options),
+            Err(INVALID_SET_EXPIRY)
+        );
+        assert!(storage.get(&key).unwrap().expiry.is_some());
+    }
+
+    #[test]
+    fn test_set_overwrite_value() {
+        let mut storage = Storage::new();
+        let key = b"test_key".to_vec();
+        let initial_value = b"initial".to_vec();
+        let new_value = b"overwritten".to_vec();
+
+        storage
+            .set(key.clone(), initial_value.clone(), None)
+            .unwrap();
+        storage.set(key.clone(), new_value.clone(), None).unwrap();
+
+        let stored_value = storage.get(&key).unwrap();
+        match &stored_value.data {
+            Value::String(s) => assert_eq!(s, &new_value),
+            _ => panic!("Expected string value"),
+        }
+    }
+
+    #[test]
+    fn test_get_non_existent_key() {
+        let mut storage = Storage::new();
+        assert!(storage.get(b"non_existent_key").is_none());
+    }
+
+    #[test]
+    fn test_snapshot_unaffected_by_later_writes() {
+        let mut storage = Storage::new();
+        storage
+            .set(b"s".to_vec(), b"before".to_vec(), None)
+            .unwrap();
+        storage.rpush(b"l", vec![b"a".to_vec()]).unwrap();
+
+        let snapshot = storage.snapshot();
+        storage.set(b"s".to_vec(), b"after".to_vec(), None).unwrap();
+        storage.rpush(b"l", vec![b"b".to_vec()]).unwrap();
+        storage.set(b"new".to_vec(), b"v".to_vec(), None).unwrap();
+
+        assert_eq!(snapshot.len(), 2);
+        for (key, value) in snapshot.entries() {
+            match (key, &value.data) {
+                (b"s", Value::String(s)) => assert_eq!(s, b"before"),
+                (b"l", Value::List(list)) => assert_eq!(list.len(), 1),
+                _ => panic!("Unexpected key in snapshot"),
+            }
+        }
+        assert_eq!(storage.llen(b"l").unwrap(), 2);
+    }
+
+    #[test]
+    fn test_reads_leave_snapshot_values_shared() {
+        let mut storage = Storage::new();
+        storage.rpush(b"l", vec![b"a".to_vec()]).unwrap();
+        storage
+            .hset(b"h".to_vec(), vec![(b"f".to_vec(), b"v".to_vec())])
+            .unwrap();
+        storage.sadd(b"s", vec![b"m".to_vec()]).unwrap();
+
+        let snapshot = storage.snapshot();
+        storage.lrange(b"l", 0, -1).unwrap();
+        storage.hgetall(b"h").unwrap();
+        storage.smembers(b"s").unwrap();
+        // Only a write copies the value the snapshot holds.
+        for key in [&b"l"[..], b"h", b"s"] {
+            assert!(Arc::ptr_eq(&snapshot.data[key], &storage.data[key]));
+        }
+        storage.rpush(b"l", vec![b"b".to_vec()]).unwrap();
+        assert!(!Arc::ptr_eq(
+            &snapshot.data[&b"l"[..]],
+            &storage.data[&b"l"[..]]
+        ));
+    }
+
+    #[test]
+    fn test_remove_key() {
+        let mut storage = Storage::new();
+        let key = b"to_remove".to_vec();
+        let value = b"some_value".to_vec();
+
+        storage.set(key.clone(), value.clone(), None).unwrap();
+        assert!(storage.get(&key).is_some());
+
+        let removed_value = storage.remove(&key);
+        assert!(removed_value.is_some());
+
+        if let Some(store_value) = removed_value {
+            match &store_value.data {
+                Value::String(s) => assert_eq!(*s, value),
+                _ => panic!("Expected string value"),
+            }
+        }
+
+        assert!(storage.get(&key).is_none());
+        assert!(storage.is_dirty());
+    }
+
+    #[test]
+    fn test_remove_expired() {
+        let mut storage = Storage::new();
+        storage
+            .set(b"gone".to_vec(), b"v".to_vec(), Some(Duration::ZERO))
+            .unwrap();
+        storage
+            .set(
+                b"later".to_vec(),
+                b"v".to_vec(),
+                Some(Duration::from_secs(60)),
+            )
+            .unwrap();
+        storage.set(b"kept".to_vec(), b"v".to_vec(), None).unwrap();
+        // Overwriting without a TTL takes the key out of the index.
+        storage
+            .set(b"reset".to_vec(), b"v".to_vec(), Some(Duration::ZERO))
+            .unwrap();
+        storage.set(b"reset".to_vec(), b"w".to_vec(), None).unwrap();
+        assert_eq!(storage.expiring_keys(), 2);
+
+        assert_eq!(storage.remove_expired(Duration::from_millis(1)), 1);
+        assert_eq!(storage.entries().count(), 3);
+        assert_eq!(storage.expiring_keys(), 1);
+        assert!(storage.get(b"reset").is_some());
+        assert_eq!(storage.remove_expired(Duration::from_millis(1)), 0);
+    }
+
+    #[test]
+    fn test_keyspace_events() {
+        let mut storage = Storage::new();
+        storage.set(b"quiet".to_vec(), b"v".to_vec(), None).unwrap();
+        storage.set_notify("KA".parse().unwrap());
+        assert!(storage.take_events().is_empty());
+
+        storage
+            .set(b"s".to_vec(), b"v".to_vec(), Some(Duration::ZERO))
+            .unwrap();
+        assert!(storage.get(b"s").is_none());
+        storage.rpush(b"l", list_of(&["a"])).unwrap();
+        storage.pop(b"l", ListEnd::Left, 1).unwrap();
+        // Nothing to update, so there never was a set to delete.
+        let xx = ZAddOptions {
+            only_if_exists: Some(true),
+            ..Default::default()
+        };
+        storage.zadd(b"z", vec![(1.0, b"m".to_vec())], &xx).unwrap();
+
+        let events: Vec<_> = storage
+            .take_events()
+            .into_iter()
+            .map(|event| (event.class, event.event, event.key))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                (EventClass::String, "set", b"s".to_vec()),
+                (EventClass::Generic, "expire", b"s".to_vec()),
+                (EventClass::Expired, "expired", b"s".to_vec()),
+                (EventClass::List, "rpush", b"l".to_vec()),
+                (EventClass::List, "lpop", b"l".to_vec()),
+                (EventClass::Generic, "del", b"l".to_vec()),
+            ]
+        );
+
+        storage.set_notify("Kx".parse().unwrap());
+        storage
+            .set(b"t".to_vec(), b"v".to_vec(), Some(Duration::ZERO))
+            .unwrap();
+        storage.remove_expired(Duration::from_millis(1));
+        let events = storage.take_events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event, "expired");
+    }
+
+    #[test]
+    fn test_expired_keys_are_dropped_on_load() {
+        let mut storage = Storage::new();
+        storage
+            .set(b"gone".to_vec(), b"v".to_vec(), Some(Duration::ZERO))
+            .unwrap();
+        storage
+            .set(
+                b"later".to_vec(),
+                b"v".to_vec(),
+                Some(Duration::from_secs(60)),
+            )
+            .unwrap();
+
+        let bytes = bincode::serialize(&storage).unwrap();
+        let loaded: Storage = bincode::deserialize(&bytes).unwrap();
+
+        assert_eq!(loaded.entries().count(), 1);
+        assert_eq!(loaded.expiring_keys(), 1);
+    }
+
+    fn insert_expired(storage: &mut Storage, key: &[u8], data: Value) {
+        storage.insert(
+            key.to_vec(),
+            StoreValue {
+                data,
+                expiry: Some(now_millis() - 1),
+            },
+        );
+    }
+
+    #[test]
+    fn test_expired_keys_are_invisible_to_every_type() {
+        let mut storage = Storage::new();
+        let hash = FieldMap::from([(b"f".to_vec(), b"v".to_vec())]);
+        insert_expired(
+            &mut storage,
+            b"l",
+            Value::List(VecDeque::from([b"a".to_vec()])),
+        );
+        insert_expired(&mut storage, b"h", Value::Hash(hash.clone().into()));
+        insert_expired(&mut storage, b"h2", Value::Hash(hash.into()));
+
+        assert_eq!(storage.lrange(b"l", 0, -1).unwrap(), None);
+        assert_eq!(storage.hget(b"h", b"f").unwrap(), None);
+        assert_eq!(storage.hlen(b"h2").unwrap(), 0);
+        assert!(storage.is_empty());
+        assert_eq!(storage.expiring_keys(), 0);
+    }
+
+    #[test]
+    fn test_writing_to_an_expired_key_starts_afresh() {
+        let mut storage = Storage::new();
+        insert_expired(&mut storage, b"k", Value::String(b"old".to_vec()));
+        insert_expired(&mut storage, b"h", Value::String(b"old".to_vec()));
+
+        // No WRONGTYPE: the string is gone, and so is its expiry.
+        assert_eq!(storage.rpush(b"k", vec![b"a".to_vec()]).unwrap(), 1);
+        assert_eq!(storage.get(b"k").unwrap().expiry, None);
+        assert_eq!(
+            storage.hset(b"h".to_vec(), vec![(b"f".to_vec(), b"v".to_vec())]),
+            Ok(1)
+        );
+
+        insert_expired(&mut storage, b"d", Value::String(b"old".to_vec()));
+        assert!(storage.remove(b"d").is_none());
+    }
+
+    #[test]
+    fn test_expire_at_and_persist() {
+        let mut storage = Storage::new();
+        let later = now_millis() as i64 + 60_000;
+        storage.rpush(b"l", vec![b"a".to_vec()]).unwrap();
+
+        assert!(!storage.expire_at(b"missing", later, ExpireCondition::default()));
+        assert!(storage.expire_at(b"l", later, ExpireCondition::default()));
+        assert_eq!(storage.expiry(b"l"), Some(Some(later as u64)));
+        assert_eq!(storage.expiring_keys(), 1);
+
+        assert!(storage.persist(b"l"));
+        assert!(!storage.persist(b"l"));
+        assert_eq!(storage.expiry(b"l"), Some(None));
+        assert_eq!(storage.expiring_keys(), 0);
+        assert_eq!(storage.expiry(b"missing"), None);
+
+        // A time in the past deletes the key.
+        assert!(storage.expire_at(b"l", 1, ExpireCondition::default()));
+        assert!(storage.get(b"l").is_none());
+    }
+
+    #[test]
+    fn test_expire_conditions() {
+        let mut storage = Storage::new();
+        let now = now_millis() as i64;
+        storage.set(b"k".to_vec(), b"v".to_vec(), None).unwrap();
+        let nx = ExpireCondition {
+            nx: true,
+            ..Default::default()
+        };
+        let xx = ExpireCondition {
+            xx: true,
+            ..Default::default()
+        };
+        let gt = ExpireCondition {
+            gt: true,
+            ..Default::default()
+        };
+        let lt = ExpireCondition {
+            lt: true,
+            ..Default::default()
+        };
+
+        // No expiry yet: XX and GT fail, LT succeeds as "never" is later.
+        assert!(!storage.expire_at(b"k", now + 50_000, xx));
+        assert!(!storage.expire_at(b"k", now + 50_000, gt));
+        assert!(storage.expire_at(b"k", now + 50_000, lt));
+
+        assert!(!storage.expire_at(b"k", now + 10_000, nx));
+        assert!(!storage.expire_at(b"k", now + 10_000, gt));
+        assert!(storage.expire_at(b"k", now + 90_000, gt));
+        assert!(!storage.expire_at(b"k", now + 95_000, lt));
+        assert!(storage.expire_at(b"k", now + 20_000, xx));
+        assert_eq!(storage.expiry(b"k"), Some(Some((now + 20_000) as u64)));
+    }
+
+    #[test]
+    fn test_set_conditions() {
+        let mut storage = Storage::new();
+        let nx = SetOptions {
+            only_if_exists: Some(false),
+            ..Default::default()
+        };
+        let xx = SetOptions {
+            only_if_exists: Some(true),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            storage.set_with_options(b"k".to_vec(), b"1".to_vec(), &xx),
+            Ok((false, None))
+        );
+        assert!(storage.get(b"k").is_none());
+        assert_eq!(
+            storage.set_with_options(b"k".to_vec(), b"1".to_vec(), &nx),
+            Ok((true, None))
+        );
+        assert_eq!(
+            storage.set_with_options(b"k".to_vec(), b"2".to_vec(), &nx),
+            Ok((false, None))
+        );
+        assert_eq!(
+            storage.set_with_options(b"k".to_vec(), b"3".to_vec(), &xx),
+            Ok((true, None))
+        );
+        match &storage.get(b"k").unwrap().data {
+            Value::String(s) => assert_eq!(s, b"3"),
+            _ => panic!("Expected string value"),
+        }
+    }
+
+    #[test]
+    fn test_set_get_and_keep_ttl() {
+        let mut storage = Storage::new();
+        storage
+            .set(
+                b"k".to_vec(),
+                b"old".to_vec(),
+                Some(Duration::from_secs(60)),
+            )
+            .unwrap();
+        let expiry = storage.expiry(b"k").unwrap();
+
+        let keep = SetOptions {
+            keep_ttl: true,
+            get: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            storage.set_with_options(b"k".to_vec(), b"new".to_vec(), &keep),
+            Ok((true, Some(b"old".to_vec())))
+        );
+        assert_eq!(storage.expiry(b"k").unwrap(), expiry);
+
+        // Without KEEPTTL the expiry is cleared, like a plain SET.
+        let get = SetOptions {
+            get: true,
+            ..Default::default()
+        };
+        storage
+            .set_with_options(b"k".to_vec(), b"newer".to_vec(), &get)
+            .unwrap();
+        assert_eq!(storage.expiry(b"k"), Some(None));
+
+        storage.rpush(b"l", vec![b"a".to_vec()]).unwrap();
+        assert!(
+            storage
+                .set_with_options(b"l".to_vec(), b"v".to_vec(), &get)
+                .is_err()
+        );
+        assert!(storage.lrange(b"l", 0, -1).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_string_operations() {
+        let mut storage = Storage::new();
+
+        assert_eq!(storage.append(b"s", b"Hello"), Ok(5));
+        assert_eq!(storage.append(b"s", b" World"), Ok(11));
+        assert_eq!(storage.strlen(b"s"), Ok(11));
+        assert_eq!(storage.strlen(b"missing"), Ok(0));
+
+        assert_eq!(storage.getrange(b"s", 0, 4).unwrap(), b"Hello");
+        assert_eq!(storage.getrange(b"s", -5, -1).unwrap(), b"World");
+        assert_eq!(storage.getrange(b"s", 6, 100).unwrap(), b"World");
+        assert_eq!(storage.getrange(b"s", 5, 2).unwrap(), b"");
+        assert_eq!(storage.getrange(b"missing", 0, -1).unwrap(), b"");
+
+        assert_eq!(storage.getdel(b"s").unwrap().unwrap(), b"Hello World");
+        assert!(storage.get(b"s").is_none());
+        assert_eq!(storage.getdel(b"s"), Ok(None));
+
+        storage.rpush(b"l", vec![b"a".to_vec()]).unwrap();
+        assert!(storage.append(b"l", b"x").is_err());
+        assert!(storage.strlen(b"l").is_err());
+        assert!(storage.getdel(b"l").is_err());
+    }
+
+    #[test]
+    fn test_msetnx_is_all_or_nothing() {
+        let mut storage = Storage::new();
+        storage.set(b"b".to_vec(), b"old".to_vec(), None).unwrap();
+
+        let pairs = vec![
+            (b"a".to_vec(), b"1".to_vec()),
+            (b"b".to_vec(), b"2".to_vec()),
+        ];
+        assert!(!storage.msetnx(pairs.clone()));
+        assert!(storage.get(b"a").is_none());
+        assert_eq!(storage.get_string(b"b").unwrap().unwrap(), b"old");
+
+        storage.remove(b"b");
+        assert!(storage.msetnx(pairs));
+        assert_eq!(storage.get_string(b"a").unwrap().unwrap(), b"1");
+        assert_eq!(storage.get_string(b"b").unwrap().unwrap(), b"2");
+    }
+
+    #[test]
+    fn test_counters() {
+        let mut storage = Storage::new();
+
+        assert_eq!(storage.incr_by(b"n", 1), Ok(1));
+        assert_eq!(storage.incr_by(b"n", -11), Ok(-10));
+        assert_eq!(storage.get_string(b"n").unwrap().unwrap(), b"-10");
+
+        storage
+            .set(b"max".to_vec(), i64::MAX.to_string().into_bytes(), None)
+            .unwrap();
+        assert!(storage.incr_by(b"max", 1).is_err());
+        assert_eq!(
+            storage.get_string(b"max").unwrap().unwrap(),
+            b"9223372036854775807"
+        );
+
+        storage.set(b"s".to_vec(), b"abc".to_vec(), None).unwrap();
+        assert_eq!(storage.incr_by(b"s", 1), Err(NOT_AN_INTEGER));
+        assert_eq!(storage.incr_by_float(b"s", 1.0), Err(NOT_A_FLOAT));
+
+        assert_eq!(storage.incr_by_float(b"f", 10.5).unwrap(), b"10.5");
+        assert_eq!(storage.incr_by_float(b"f", 0.1).unwrap(), b"10.6");
+        assert_eq!(storage.incr_by_float(b"n", 2.5).unwrap(), b"-7.5");
+        assert!(storage.incr_by_float(b"f", f64::MAX).is_ok());
+        assert!(storage.incr_by_float(b"f", f64::MAX).is_err());
+
+        storage.rpush(b"l", vec![b"1".to_vec()]).unwrap();
+        assert!(storage.incr_by(b"l", 1).is_err());
+    }
+
+    #[test]
+    fn test_counter_keeps_expiry() {
+        let mut storage = Storage::new();
+        storage
+            .set(b"n".to_vec(), b"5".to_vec(), Some(Duration::from_secs(60)))
+            .unwrap();
+
+        assert_eq!(storage.incr_by(b"n", 1), Ok(6));
+        assert!(matches!(storage.expiry(b"n"), Some(Some(_))));
+    }
+
+    #[test]
+    fn test_setrange_pads_with_zeros() {
+        let mut storage = Storage::new();
+
+        assert_eq!(storage.setrange(b"k", 5, b"ab"), Ok(7));
+        assert_eq!(storage.get_string(b"k").unwrap().unwrap(), b"\0\0\0\0\0ab");
+        assert_eq!(storage.setrange(b"k", 1, b"xy"), Ok(7));
+        assert_eq!(storage.get_string(b"k").unwrap().unwrap(), b"\0xy\0\0ab");
+
+        assert_eq!(storage.setrange(b"empty", 3, b""), Ok(0));
+        assert!(storage.get(b"empty").is_none());
+        assert!(storage.setrange(b"k", MAX_STRING_LEN, b"x").is_err());
+    }
+
+    #[test]
+    fn test_remove_non_existent_key() {
+        let mut storage = Storage::new();
+        assert!(storage.remove(b"non_existent_key").is_none());
+    }
+
+    #[test]
+    fn test_dirty_flag() {
+        let mut storage = Storage::new();
+        assert!(!storage.is_dirty());
+
+        storage
+            .set(b"key".to_vec(), b"value".to_vec(), None)
+            .unwrap();
+        assert!(storage.is_dirty());
+
+        storage.mark_saved(1);
+        assert!(!storage.is_dirty());
+    }
+
+    #[test]
+    fn test_changes_made_during_a_save_stay_dirty() {
+        let mut storage = Storage::new();
+        storage.set(b"a".to_vec(), b"1".to_vec(), None).unwrap();
+        storage
+            .rpush(b"l", vec![b"x".to_vec(), b"y".to_vec()])
+            .unwrap();
+        assert_eq!(storage.dirty(), 3);
+
+        // A save starts here, covering the three changes so far.
+        let changes = storage.dirty();
+        storage.set(b"b".to_vec(), b"2".to_vec(), None).unwrap();
+        storage.mark_saved(changes);
+
+        assert_eq!(storage.dirty(), 1);
+    }
+
+    // List operations tests
+    #[test]
+    fn test_lpush_new_list() {
+        let mut storage = Storage::new();
+        let key: &[u8] = b"mylist";
+        let values = vec![b"value1".to_vec(), b"value2".to_vec()];
+
+        let result = storage.lpush(key, values.clone());
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap(), 2);
+        assert!(storage.is_dirty());
+
+        // Check that values were inserted in reverse order (left push)
+        let range_result = storage.lrange(key, 0, -1).unwrap().unwrap();
+        assert_eq!(range_result, vec![b"value2".to_vec(), b"value1".to_vec()]);
+    }
+
+    #[test]
+    fn test_rpush_new_list() {
+        let mut storage = Storage::new();
+        let key: &[u8] = b"mylist";
+        let values = vec![b"value1".to_vec(), b"value2".to_vec()];
+
+        let result = storage.rpush(key, values.clone());
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap(), 2);
+
+        // Check that values were inserted in order (right push)
+        let range_result = storage.lrange(key, 0, -1).unwrap().unwrap();
+        assert_eq!(range_result, vec![b"value1".to_vec(), b"value2".to_vec()]);
+    }
+
+    #[test]
+    fn test_lpush_existing_list() {
+        let mut storage = Storage::new();
+        let key: &[u8] = b"mylist";
+
+        storage.rpush(key, vec![b"existing".to_vec()]).unwrap();
+        let result = storage.lpush(key, vec![b"new".to_vec()]);
+
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap(), 2);
+
+        let range_result = storage.lrange(key, 0, -1).unwrap().unwrap();
+        assert_eq!(range_result, vec![b"new".to_vec(), b"existing".to_vec()]);
+    }
+
+    #[test]
+    fn test_lpush_wrong_type() {
+        let mut storage = Storage::new();
+        let key: &[u8] = b"stringkey";
+
+        storage
+            .set(key.to_vec(), b"stringvalue".to_vec(), None)
+            .unwrap();
+        let result = storage.lpush(key, vec![b"value".to_vec()]);
+
+        assert!(result.is_err());
+        assert_eq!(
+            result.unwrap_err(),
+            "WRONGTYPE Operation against a key holding the wrong kind of value"
+        );
+    }
+
+    #[test]
+    fn test_lrange_empty_list() {
+        let mut storage = Storage::new();
+        let result = storage.lrange(b"nonexistent", 0, -1);
+        assert!(result.is_ok());
+        assert!(result.unwrap().is_none());
+    }
+
+    #[test]
+    fn test_lrange_with_indices() {
+        let mut storage = Storage::new();
+        let key: &[u8] = b"mylist";
+        let values = vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec(), b"d".to_vec()];
+
+        storage.rpush(key, values).unwrap();
+
+        // Test positive indices
+        let result = storage.lrange(key, 1, 2).unwrap().unwrap();
+        assert_eq!(result, vec![b"b".to_vec(), b"c".to_vec()]);
+
+        // Test negative indices
+        let result = storage.lrange(key, -2, -1).unwrap().unwrap();
+        assert_eq!(result, vec![b"c".to_vec(), b"d".to_vec()]);
+
+        // Test full range
+        let result = storage.lrange(key, 0, -1).unwrap().unwrap();
+        assert_eq!(
+            result,
+            vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec(), b"d".to_vec()]
+        );
+
+        // Test out of range
+        let result = storage.lrange(key, 10, 20).unwrap().unwrap();
+        assert!(result.is_empty());
+    }
+
+    #[test]
+    fn test_lrange_wrong_type() {
+        let mut storage = Storage::new();
+        let key: &[u8] = b"stringkey";
+
+        storage
+            .set(key.to_vec(), b"stringvalue".to_vec(), None)
+            .unwrap();
+        let result = storage.lrange(key, 0, -1);
+
+        assert!(result.is_err());
+        assert_eq!(
+            result.unwrap_err(),
+            "WRONGTYPE Operation against a key holding the wrong kind of value"
+        );
+    }
+
+    fn list_of(items: &[&str]) -> Vec<Vec<u8>> {
+        items.iter().map(|item| item.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_pop_deletes_emptied_list() {
+        let mut storage = Storage::new();
+        storage.rpush(b"l", list_of(&["a", "b", "c"])).unwrap();
+
+        assert_eq!(
+            storage.pop(b"l", ListEnd::Left, 1),
+            Ok(Some(list_of(&["a"])))
+        );
+        assert_eq!(
+            storage.pop(b"l", ListEnd::Right, 5),
+            Ok(Some(list_of(&["c", "b"])))
+        );
+        assert!(storage.get(b"l").is_none());
+        assert_eq!(storage.pop(b"l", ListEnd::Left, 1), Ok(None));
+        assert_eq!(storage.llen(b"l"), Ok(0));
+    }
+
+    #[test]
+    fn test_list_indexing() {
+        let mut storage = Storage::new();
+        storage.rpush(b"l", list_of(&["a", "b", "c"])).unwrap();
+
+        assert_eq!(storage.lindex(b"l", 0).unwrap().unwrap(), b"a");
+        assert_eq!(storage.lindex(b"l", -1).unwrap().unwrap(), b"c");
+        assert_eq!(storage.lindex(b"l", 3), Ok(None));
+        assert_eq!(storage.lindex(b"l", -4), Ok(None));
+
+        assert_eq!(storage.lset(b"l", -2, b"B".to_vec()), Ok(()));
+        assert_eq!(
+            storage.lset(b"l", 3, b"x".to_vec()),
+            Err("ERR index out of range")
+        );
+        assert_eq!(
+            storage.lset(b"missing", 0, b"x".to_vec()),
+            Err("ERR no such key")
+        );
+
+        assert_eq!(storage.linsert(b"l", true, b"B", b"x".to_vec()), Ok(4));
+        assert_eq!(storage.linsert(b"l", false, b"c", b"y".to_vec()), Ok(5));
+        assert_eq!(storage.linsert(b"l", true, b"zzz", b"x".to_vec()), Ok(-1));
+        assert_eq!(
+            storage.linsert(b"missing", true, b"a", b"x".to_vec()),
+            Ok(0)
+        );
+        assert_eq!(
+            storage.lrange(b"l", 0, -1).unwrap().unwrap(),
+            list_of(&["a", "x", "B", "c", "y"])
+        );
+    }
+
+    #[test]
+    fn test_lrem() {
+        let mut storage = Storage::new();
+        storage
+            .rpush(b"l", list_of(&["a", "b", "a", "c", "a"]))
+            .unwrap();
+
+        assert_eq!(storage.lrem(b"l", -2, b"a"), Ok(2));
+        assert_eq!(
+            storage.lrange(b"l", 0, -1).unwrap().unwrap(),
+            list_of(&["a", "b", "c"])
+        );
+        assert_eq!(storage.lrem(b"l", 1, b"b"), Ok(1));
+        assert_eq!(storage.lrem(b"l", 0, b"zzz"), Ok(0));
+
+        storage.rpush(b"l", list_of(&["a"])).unwrap();
+        assert_eq!(storage.lrem(b"l", 0, b"a"), Ok(2));
+        assert_eq!(storage.lrem(b"l", 0, b"c"), Ok(1));
+        assert!(storage.get(b"l").is_none());
+    }
+
+    #[test]
+    fn test_ltrim() {
+        let mut storage = Storage::new();
+        storage
+            .rpush(b"l", list_of(&["a", "b", "c", "d", "e"]))
+            .unwrap();
+
+        storage.ltrim(b"l", 1, -2).unwrap();
+        assert_eq!(
+            storage.lrange(b"l", 0, -1).unwrap().unwrap(),
+            list_of(&["b", "c", "d"])
+        );
+        storage.ltrim(b"l", -100, 100).unwrap();
+        assert_eq!(storage.llen(b"l"), Ok(3));
+
+        storage.ltrim(b"l", 2, 1).unwrap();
+        assert!(storage.get(b"l").is_none());
+    }
+
+    #[test]
+    fn test_lpos() {
+        let mut storage = Storage::new();
+        storage
+            .rpush(b"l", list_of(&["a", "b", "c", "1", "2", "3", "c", "c"]))
+            .unwrap();
+
+        assert_eq!(storage.lpos(b"l", b"c", 1, 1, 0), Ok(vec![2]));
+        assert_eq!(storage.lpos(b"l", b"c", 2, 1, 0), Ok(vec![6]));
+        assert_eq!(storage.lpos(b"l", b"c", -1, 1, 0), Ok(vec![7]));
+        assert_eq!(storage.lpos(b"l", b"c", 1, 0, 0), Ok(vec![2, 6, 7]));
+        assert_eq!(storage.lpos(b"l", b"c", -1, 2, 0), Ok(vec![7, 6]));
+        assert_eq!(storage.lpos(b"l", b"c", 1, 0, 3), Ok(vec![2]));
+        assert_eq!(storage.lpos(b"l", b"zzz", 1, 1, 0), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_lmove() {
+        let mut storage = Storage::new();
+        storage.rpush(b"src", list_of(&["a", "b"])).unwrap();
+
+        assert_eq!(
+            storage.lmove(b"src", b"dst", ListEnd::Right, ListEnd::Left),
+            Ok(Some(b"b".to_vec()))
+        );
+        assert_eq!(
+            storage.lmove(b"src", b"dst", ListEnd::Left, ListEnd::Left),
+            Ok(Some(b"a".to_vec()))
+        );
+        assert!(storage.get(b"src").is_none());
+        assert_eq!(
+            storage.lrange(b"dst", 0, -1).unwrap().unwrap(),
+            list_of(&["a", "b"])
+        );
+        assert_eq!(
+            storage.lmove(b"src", b"dst", ListEnd::Left, ListEnd::Left),
+            Ok(None)
+        );
+
+        // Rotating a list onto itself keeps the key and its expiry.
+        storage.rpush(b"one", list_of(&["x"])).unwrap();
+        storage.expire_at(
+            b"one",
+            now_millis() as i64 + 60_000,
+            ExpireCondition::default(),
+        );
+        assert!(
+            storage
+                .lmove(b"one", b"one", ListEnd::Left, ListEnd::Right)
+                .is_ok()
+        );
+        assert!(matches!(storage.expiry(b"one"), Some(Some(_))));
+
+        storage.set(b"s".to_vec(), b"v".to_vec(), None).unwrap();
+        assert!(
+            storage
+                .lmove(b"dst", b"s", ListEnd::Left, ListEnd::Left)
+                .is_err()
+        );
+        assert_eq!(storage.llen(b"dst"), Ok(2));
+    }
+
+    // Hash operations tests
+    #[test]
+    fn test_hset_new_hash() {
+        let mut storage = Storage::new();
+        let key = b"myhash".to_vec();
+        let field = b"field1".to_vec();
+        let value = b"value1".to_vec();
+
+        let result = storage.hset(key.clone(), vec![(field.clone(), value.clone())]);
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap(), 1); // New field
+        assert!(storage.is_dirty());
+
+        let get_result = storage.hget(&key, &field).unwrap();
+        assert_eq!(get_result.unwrap(), &value);
+    }
+
+    #[test]
+    fn test_hset_existing_field() {
+        let mut storage = Storage::new();
+        let key = b"myhash".to_vec();
+        let field = b"field1".to_vec();
+
+        storage
+            .hset(key.clone(), vec![(field.clone(), b"old_value".to_vec())])
+            .unwrap();
+        let result = storage.hset(key.clone(), vec![(field.clone(), b"new_value".to_vec())]);
+
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap(), 0); // Existing field
+
+        let get_result = storage.hget(&key, &field).unwrap();
+        assert_eq!(get_result.unwrap(), b"new_value");
+    }
+
+    #[test]
+    fn test_hset_wrong_type() {
+        let mut storage = Storage::new();
+        let key = b"stringkey".to_vec();
+
+        storage
+            .set(key.clone(), b"stringvalue".to_vec(), None)
+            .unwrap();
+        let result = storage.hset(key, vec![(b"field".to_vec(), b"value".to_vec())]);
+
+        assert!(result.is_err());
+        assert_eq!(
+            result.unwrap_err(),
+            "WRONGTYPE Operation against a key holding the wrong kind of value"
+        );
+    }
+
+    #[test]
+    fn test_hget_nonexistent_key() {
+        let mut storage = Storage::new();
+        let result = storage.hget(b"nonexistent", b"field");
+        assert!(result.is_ok());
+        assert!(result.unwrap().is_none());
+    }
+
+    #[test]
+    fn test_hget_nonexistent_field() {
+        let mut storage = Storage::new();
+        let key = b"myhash".to_vec();
+
+        storage
+            .hset(key.clone(), vec![(b"field1".to_vec(), b"value1".to_vec())])
+            .unwrap();
+        let result = storage.hget(&key, b"nonexistent_field");
+
+        assert!(result.is_ok());
+        assert!(result.unwrap().is_none());
+    }
+
+    #[test]
+    fn test_hdel() {
+        let mut storage = Storage::new();
+        let key = b"myhash".to_vec();
+
+        storage
+            .hset(key.clone(), vec![(b"field1".to_vec(), b"value1".to_vec())])
+            .unwrap();
+        storage
+            .hset(key.clone(), vec![(b"field2".to_vec(), b"value2".to_vec())])
+            .unwrap();
+        storage
+            .hset(key.clone(), vec![(b"field3".to_vec(), b"value3".to_vec())])
+            .unwrap();
+
+        // Delete existing fields
+        let result = storage.hdel(&key, vec![b"field1".to_vec(), b"field3".to_vec()]);
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap(), 2);
+
+        // Check that field2 still exists
+        assert!(storage.hget(&key, b"field2").unwrap().is_some());
+        assert!(storage.hget(&key, b"field1").unwrap().is_none());
+        assert!(storage.hget(&key, b"field3").unwrap().is_none());
+
+        // Delete non-existent field
+        let result = storage.hdel(&key, vec![b"nonexistent".to_vec()]);
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap(), 0);
+    }
+
+    #[test]
+    fn test_hset_multiple_fields() {
+        let mut storage = Storage::new();
+        let pairs = |items: &[(&str, &str)]| {
+            items
+                .iter()
+                .map(|(f, v)| (f.as_bytes().to_vec(), v.as_bytes().to_vec()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            storage.hset(b"h".to_vec(), pairs(&[("a", "1"), ("b", "2")])),
+            Ok(2)
+        );
+        assert_eq!(
+            storage.hset(b"h".to_vec(), pairs(&[("b", "3"), ("c", "4")])),
+            Ok(1)
+        );
+        assert_eq!(
+            storage.hmget(b"h", &[b"a".to_vec(), b"x".to_vec(), b"b".to_vec()]),
+            Ok(vec![Some(b"1".to_vec()), None, Some(b"3".to_vec())])
+        );
+        assert_eq!(storage.hmget(b"missing", &[b"a".to_vec()]), Ok(vec![None]));
+    }
+
+    #[test]
+    fn test_hdel_deletes_emptied_hash() {
+        let mut storage = Storage::new();
+        storage
+            .hset(b"h".to_vec(), vec![(b"f".to_vec(), b"v".to_vec())])
+            .unwrap();
+        let dirty = storage.dirty();
+
+        assert_eq!(storage.hdel(b"h", vec![b"f".to_vec()]), Ok(1));
+        assert!(storage.get(b"h").is_none());
+        assert!(storage.dirty() > dirty);
+    }
+
+    #[test]
+    fn test_hsetnx() {
+        let mut storage = Storage::new();
+
+        assert_eq!(storage.hsetnx(b"h", b"f".to_vec(), b"1".to_vec()), Ok(true));
+        assert_eq!(
+            storage.hsetnx(b"h", b"f".to_vec(), b"2".to_vec()),
+            Ok(false)
+        );
+        assert_eq!(storage.hget(b"h", b"f").unwrap().unwrap(), b"1");
+    }
+
+    #[test]
+    fn test_hash_counters() {
+        let mut storage = Storage::new();
+
+        assert_eq!(storage.hincrby(b"h", b"n", 5), Ok(5));
+        assert_eq!(storage.hincrby(b"h", b"n", -7), Ok(-2));
+        assert_eq!(storage.hincrbyfloat(b"h", b"n", 0.5).unwrap(), b"-1.5");
+        assert_eq!(
+            storage.hincrby(b"h", b"n", 1),
+            Err("ERR hash value is not an integer")
+        );
+
+        storage
+            .hset(b"h".to_vec(), vec![(b"s".to_vec(), b"abc".to_vec())])
+            .unwrap();
+        assert_eq!(
+            storage.hincrbyfloat(b"h", b"s", 1.0),
+            Err("ERR hash value is not a float")
+        );
+
+        storage
+            .hset(
+                b"h".to_vec(),
+                vec![(b"max".to_vec(), i64::MAX.to_string().into_bytes())],
+            )
+            .unwrap();
+        assert!(storage.hincrby(b"h", b"max", 1).is_err());
     }
 
     #[test]
-    fn test_set_and_get_string_value() {
+    fn test_hrandfield() {
         let mut storage = Storage::new();
-        let key = "hello".to_string();
-        let value = "world".to_string();
+        let pairs = (0..10)
+            .map(|i| (vec![b'f', b'0' + i], vec![b'v', b'0' + i]))
+            .collect::<Vec<_>>();
+        storage.hset(b"h".to_vec(), pairs).unwrap();
 
-        storage.set(key.clone(), value.clone(), None);
-        assert!(storage.is_dirty());
+        let mut picked = storage.hrandfield(b"h", 5).unwrap();
+        assert_eq!(picked.len(), 5);
+        picked.sort();
+        picked.dedup();
+        assert_eq!(picked.len(), 5);
+        for (field, value) in &picked {
+            assert_eq!(field[1], value[1]);
+        }
 
-        let stored_value = storage.get(&key).unwrap();
-        match &stored_value.data {
-            Value::String(s) => assert_eq!(s, &value),
-            _ => panic!("Expected string value"),
+        assert_eq!(storage.hrandfield(b"h", 100).unwrap().len(), 10);
+        let repeated = storage.hrandfield(b"h", -30).unwrap();
+        assert_eq!(repeated.len(), 30);
+        for (field, value) in &repeated {
+            assert_eq!(field[1], value[1]);
         }
-        assert!(stored_value.expiry.is_none());
+        assert!(storage.hrandfield(b"missing", 3).unwrap().is_empty());
+
+        // However many fields there are, a huge negative count is refused
+        // rather than trying to pick that many.
+        for count in [i64::MIN, -3_000_000_000] {
+            assert_eq!(storage.hrandfield(b"h", count), Err(OUT_OF_RANGE));
+            assert_eq!(storage.hrandfield(b"missing", count), Err(OUT_OF_RANGE));
+        }
+    }
+
+    fn fields(names: &[&str]) -> Vec<Vec<u8>> {
+        names.iter().map(|name| name.as_bytes().to_vec()).collect()
+    }
+
+    // Makes `field` look like it expired a moment ago, which HEXPIRE itself
+    // can't do: a time in the past deletes the field right away.
+    fn backdate_field(storage: &mut Storage, key: &[u8], field: &[u8]) {
+        let Some(StoreValue {
+            data: Value::Hash(hash),
+            ..
+        }) = storage.data.get_mut(key).map(Arc::make_mut)
+        else {
+            panic!("Expected a hash");
+        };
+        let before = hash.next_expiry();
+        hash.set_field_expiry(field, Some(1));
+        storage.reindex_hash(key, before);
     }
 
     #[test]
-    fn test_set_with_expiry() {
+    fn test_hexpire() {
         let mut storage = Storage::new();
-        let key = "expiring_key".to_string();
-        let value = "expiring_value".to_string();
-        let expiry = Duration::from_secs(1);
+        storage
+            .hset(b"h".to_vec(), vec![(b"a".to_vec(), b"1".to_vec())])
+            .unwrap();
+        storage
+            .hset(b"h".to_vec(), vec![(b"b".to_vec(), b"2".to_vec())])
+            .unwrap();
+        let at = now_millis() as i64 + 60_000;
 
-        storage.set(key.clone(), value.clone(), Some(expiry));
-        let stored_value = storage.get(&key).unwrap();
-        
-        match &stored_value.data {
-            Value::String(s) => assert_eq!(s, &value),
-            _ => panic!("Expected string value"),
-        }
-        assert!(stored_value.expiry.is_some());
+        assert_eq!(
+            storage.hexpire(
+                b"h",
+                &fields(&["a", "nope"]),
+                at,
+                ExpireCondition::default()
+            ),
+            Ok(vec![1, -2])
+        );
+        let nx = ExpireCondition {
+            nx: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            storage.hexpire(b"h", &fields(&["a", "b"]), at, nx),
+            Ok(vec![0, 1])
+        );
+        assert_eq!(
+            storage.hexpiry(b"h", &fields(&["a", "nope"])),
+            Ok(vec![Some(Some(at as u64)), None])
+        );
+        assert_eq!(storage.hashes_with_expiring_fields(), 1);
+
+        assert_eq!(
+            storage.hpersist(b"h", &fields(&["a", "a", "nope"])),
+            Ok(vec![1, -1, -2])
+        );
+        // HSET drops the expiry of the fields it sets, HINCRBY keeps it.
+        storage
+            .hset(b"h".to_vec(), vec![(b"b".to_vec(), b"3".to_vec())])
+            .unwrap();
+        assert_eq!(storage.hexpiry(b"h", &fields(&["b"])), Ok(vec![Some(None)]));
+        storage
+            .hexpire(b"h", &fields(&["a"]), at, ExpireCondition::default())
+            .unwrap();
+        storage.hincrby(b"h", b"a", 1).unwrap();
+        assert_eq!(
+            storage.hexpiry(b"h", &fields(&["a"])),
+            Ok(vec![Some(Some(at as u64))])
+        );
+
+        // A time in the past deletes the fields, and the hash with them.
+        assert_eq!(
+            storage.hexpire(b"h", &fields(&["a", "b"]), 0, ExpireCondition::default()),
+            Ok(vec![2, 2])
+        );
+        assert!(storage.get(b"h").is_none());
+        assert_eq!(storage.hashes_with_expiring_fields(), 0);
+        assert_eq!(
+            storage.hexpire(b"h", &fields(&["a"]), at, ExpireCondition::default()),
+            Ok(vec![-2])
+        );
     }
 
     #[test]
-    fn test_set_overwrite_value() {
+    fn test_expired_fields_are_never_seen() {
         let mut storage = Storage::new();
-        let key = "test_key".to_string();
-        let initial_value = "initial".to_string();
-        let new_value = "overwritten".to_string();
+        storage
+            .hset(
+                b"h".to_vec(),
+                vec![
+                    (b"a".to_vec(), b"1".to_vec()),
+                    (b"b".to_vec(), b"2".to_vec()),
+                ],
+            )
+            .unwrap();
 
-        storage.set(key.clone(), initial_value.clone(), None);
-        storage.set(key.clone(), new_value.clone(), None);
+        backdate_field(&mut storage, b"h", b"a");
+        assert_eq!(storage.hlen(b"h"), Ok(1));
+        assert_eq!(storage.hget(b"h", b"a"), Ok(None));
+        assert_eq!(storage.hashes_with_expiring_fields(), 0);
 
-        let stored_value = storage.get(&key).unwrap();
-        match &stored_value.data {
-            Value::String(s) => assert_eq!(s, &new_value),
-            _ => panic!("Expected string value"),
-        }
+        backdate_field(&mut storage, b"h", b"b");
+        assert!(storage.hgetall(b"h").unwrap().is_none());
+        assert!(storage.is_empty());
     }
 
     #[test]
-    fn test_get_non_existent_key() {
+    fn test_remove_expired_fields() {
         let mut storage = Storage::new();
-        assert!(storage.get("non_existent_key").is_none());
+        storage
+            .hset(
+                b"h".to_vec(),
+                vec![
+                    (b"a".to_vec(), b"1".to_vec()),
+                    (b"b".to_vec(), b"2".to_vec()),
+                ],
+            )
+            .unwrap();
+        storage
+            .hset(b"gone".to_vec(), vec![(b"a".to_vec(), b"1".to_vec())])
+            .unwrap();
+        let later = now_millis() as i64 + 60_000;
+        storage
+            .hexpire(b"h", &fields(&["b"]), later, ExpireCondition::default())
+            .unwrap();
+        backdate_field(&mut storage, b"h", b"a");
+        backdate_field(&mut storage, b"gone", b"a");
+        assert_eq!(storage.hashes_with_expiring_fields(), 2);
+
+        assert_eq!(storage.remove_expired(Duration::from_millis(1)), 2);
+        assert_eq!(storage.len(), 1);
+        assert_eq!(storage.hashes_with_expiring_fields(), 1);
+        assert_eq!(storage.hlen(b"h"), Ok(1));
+        assert_eq!(storage.remove_expired(Duration::from_millis(1)), 0);
     }
 
     #[test]
-    fn test_remove_key() {
+    fn test_expired_keys_and_fields_are_logged() {
         let mut storage = Storage::new();
-        let key = "to_remove".to_string();
-        let value = "some_value".to_string();
+        insert_expired(&mut storage, b"k", Value::String(b"v".to_vec()));
+        storage
+            .hset(b"h".to_vec(), vec![(b"f".to_vec(), b"1".to_vec())])
+            .unwrap();
+        backdate_field(&mut storage, b"h", b"f");
 
-        storage.set(key.clone(), value.clone(), None);
-        assert!(storage.get(&key).is_some());
-
-        let removed_value = storage.remove(&key);
-        assert!(removed_value.is_some());
-        
-        if let Some(store_value) = removed_value {
-            match store_value.data {
-                Value::String(s) => assert_eq!(s, value),
-                _ => panic!("Expected string value"),
-            }
-        }
+        // Nothing expires while loading, not even through an expiry that has
+        // already passed.
+        storage.set_loading(true);
+        assert!(storage.get(b"k").is_some());
+        assert_eq!(storage.hlen(b"h"), Ok(1));
+        assert!(storage.expire_at(b"k", 1, ExpireCondition::default()));
+        assert!(storage.take_expired().is_empty());
 
-        assert!(storage.get(&key).is_none());
-        assert!(storage.is_dirty());
+        storage.set_loading(false);
+        assert!(storage.get(b"k").is_none());
+        storage.remove_expired(Duration::from_millis(1));
+        assert_eq!(
+            storage.take_expired(),
+            vec![fields(&["DEL", "k"]), fields(&["HDEL", "h", "f"])]
+        );
+        assert!(storage.take_expired().is_empty());
     }
 
     #[test]
-    fn test_remove_non_existent_key() {
+    fn test_expired_fields_are_dropped_on_load() {
         let mut storage = Storage::new();
-        assert!(storage.remove("non_existent_key").is_none());
+        storage
+            .hset(
+                b"h".to_vec(),
+                vec![
+                    (b"a".to_vec(), b"1".to_vec()),
+                    (b"b".to_vec(), b"2".to_vec()),
+                ],
+            )
+            .unwrap();
+        storage
+            .hset(b"gone".to_vec(), vec![(b"a".to_vec(), b"1".to_vec())])
+            .unwrap();
+        backdate_field(&mut storage, b"h", b"a");
+        backdate_field(&mut storage, b"gone", b"a");
+
+        let mut loaded: Storage =
+            bincode::deserialize(&bincode::serialize(&storage).unwrap()).unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded.hashes_with_expiring_fields(), 0);
+        assert_eq!(
+            loaded.hmget(b"h", &fields(&["a", "b"])),
+            Ok(vec![None, Some(b"2".to_vec())])
+        );
     }
 
     #[test]
-    fn test_dirty_flag() {
+    fn test_hdel_nonexistent_key() {
         let mut storage = Storage::new();
-        assert!(!storage.is_dirty());
-        
-        storage.set("key".to_string(), "value".to_string(), None);
-        assert!(storage.is_dirty());
-        
-        storage.clear_dirty_flag();
-        assert!(!storage.is_dirty());
+        let result = storage.hdel(b"nonexistent", vec![b"field".to_vec()]);
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap(), 0);
     }
 
-    // List operations tests
     #[test]
-    fn test_lpush_new_list() {
+    fn test_hlen() {
         let mut storage = Storage::new();
-        let key = "mylist";
-        let values = vec!["value1".to_string(), "value2".to_string()];
+        let key = b"myhash".to_vec();
 
-        let result = storage.lpush(key, values.clone());
+        // Empty/nonexistent hash
+        let result = storage.hlen(&key);
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap(), 0);
+
+        // Add some fields
+        storage
+            .hset(key.clone(), vec![(b"field1".to_vec(), b"value1".to_vec())])
+            .unwrap();
+        storage
+            .hset(key.clone(), vec![(b"field2".to_vec(), b"value2".to_vec())])
+            .unwrap();
+
+        let result = storage.hlen(&key);
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), 2);
-        assert!(storage.is_dirty());
 
-        // Check that values were inserted in reverse order (left push)
-        let range_result = storage.lrange(key, 0, -1).unwrap().unwrap();
-        assert_eq!(range_result, vec!["value2", "value1"]);
+        // Delete a field
+        storage.hdel(&key, vec![b"field1".to_vec()]).unwrap();
+        let result = storage.hlen(&key);
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap(), 1);
     }
 
     #[test]
-    fn test_rpush_new_list() {
+    fn test_hlen_wrong_type() {
         let mut storage = Storage::new();
-        let key = "mylist";
-        let values = vec!["value1".to_string(), "value2".to_string()];
+        let key = b"stringkey".to_vec();
 
-        let result = storage.rpush(key, values.clone());
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap(), 2);
+        storage
+            .set(key.clone(), b"stringvalue".to_vec(), None)
+            .unwrap();
+        let result = storage.hlen(&key);
 
-        // Check that values were inserted in order (right push)
-        let range_result = storage.lrange(key, 0, -1).unwrap().unwrap();
-        assert_eq!(range_result, vec!["value1", "value2"]);
+        assert!(result.is_err());
+        assert_eq!(
+            result.unwrap_err(),
+            "WRONGTYPE Operation against a key holding the wrong kind of value"
+        );
     }
 
     #[test]
-    fn test_lpush_existing_list() {
+    fn test_hgetall() {
         let mut storage = Storage::new();
-        let key = "mylist";
+        let key = b"myhash".to_vec();
 
-        storage.rpush(key, vec!["existing".to_string()]).unwrap();
-        let result = storage.lpush(key, vec!["new".to_string()]);
-        
+        // Empty/nonexistent hash
+        let result = storage.hgetall(&key);
         assert!(result.is_ok());
-        assert_eq!(result.unwrap(), 2);
+        assert!(result.unwrap().is_none());
 
-        let range_result = storage.lrange(key, 0, -1).unwrap().unwrap();
-        assert_eq!(range_result, vec!["new", "existing"]);
+        // Add some fields
+        storage
+            .hset(key.clone(), vec![(b"field1".to_vec(), b"value1".to_vec())])
+            .unwrap();
+        storage
+            .hset(key.clone(), vec![(b"field2".to_vec(), b"value2".to_vec())])
+            .unwrap();
+
+        let result = storage.hgetall(&key).unwrap().unwrap();
+        assert_eq!(result.len(), 2);
+        assert_eq!(result.get(b"field1".as_slice()).unwrap(), b"value1");
+        assert_eq!(result.get(b"field2".as_slice()).unwrap(), b"value2");
     }
 
     #[test]
-    fn test_lpush_wrong_type() {
+    fn test_hgetall_wrong_type() {
         let mut storage = Storage::new();
-        let key = "stringkey";
-        
-        storage.set(key.to_string(), "stringvalue".to_string(), None);
-        let result = storage.lpush(key, vec!["value".to_string()]);
-        
+        let key = b"stringkey".to_vec();
+
+        storage
+            .set(key.clone(), b"stringvalue".to_vec(), None)
+            .unwrap();
+        let result = storage.hgetall(&key);
+
         assert!(result.is_err());
-        assert_eq!(result.unwrap_err(), "WRONGTYPE Operation against a key holding the wrong kind of value");
+        assert_eq!(
+            result.unwrap_err(),
+            "WRONGTYPE Operation against a key holding the wrong kind of value"
+        );
     }
 
-    #[test]
-    fn test_lrange_empty_list() {
-        let mut storage = Storage::new();
-        let result = storage.lrange("nonexistent", 0, -1);
-        assert!(result.is_ok());
-        assert!(result.unwrap().is_none());
+    fn sorted(mut members: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        members.sort();
+        members
     }
 
     #[test]
-    fn test_lrange_with_indices() {
-        let mut storage = Storage::new();
-        let key = "mylist";
-        let values = vec!["a".to_string(), "b".to_string(), "c".to_string(), "d".to_string()];
-        
-        storage.rpush(key, values).unwrap();
-
-        // Test positive indices
-        let result = storage.lrange(key, 1, 2).unwrap().unwrap();
-        assert_eq!(result, vec!["b", "c"]);
+    fn test_members_index() {
+        let mut set: Members = fields(&["a", "b", "c", "d"]).into();
+        assert!(set.remove(b"a"));
+        assert!(!set.remove(b"a"));
+        // "d" took the place of "a", and can still be found and removed.
+        assert!(set.contains(b"d"));
+        assert!(set.remove(b"d"));
+        assert!(set.insert(b"e".to_vec()));
+        assert!(!set.insert(b"b".to_vec()));
+        assert_eq!(
+            sorted(set.iter().map(<[u8]>::to_vec).collect()),
+            fields(&["b", "c", "e"])
+        );
 
-        // Test negative indices
-        let result = storage.lrange(key, -2, -1).unwrap().unwrap();
-        assert_eq!(result, vec!["c", "d"]);
+        let mut popped = Vec::new();
+        while let Some(member) = set.pop_random() {
+            assert!(!set.contains(&member));
+            popped.push(member);
+        }
+        assert_eq!(sorted(popped), fields(&["b", "c", "e"]));
+    }
 
-        // Test full range
-        let result = storage.lrange(key, 0, -1).unwrap().unwrap();
-        assert_eq!(result, vec!["a", "b", "c", "d"]);
+    #[test]
+    fn test_members_serialize_like_a_hash_set() {
+        let old: HashSet<Vec<u8>> = fields(&["a", "b"]).into_iter().collect();
+        let set: Members = bincode::deserialize(&bincode::serialize(&old).unwrap()).unwrap();
+        assert_eq!(set, fields(&["a", "b"]).into());
 
-        // Test out of range
-        let result = storage.lrange(key, 10, 20).unwrap().unwrap();
-        assert!(result.is_empty());
+        let bytes = bincode::serialize(&set).unwrap();
+        assert_eq!(
+            bincode::deserialize::<HashSet<Vec<u8>>>(&bytes).unwrap(),
+            old
+        );
     }
 
     #[test]
-    fn test_lrange_wrong_type() {
+    fn test_set_membership() {
         let mut storage = Storage::new();
-        let key = "stringkey";
-        
-        storage.set(key.to_string(), "stringvalue".to_string(), None);
-        let result = storage.lrange(key, 0, -1);
-        
-        assert!(result.is_err());
-        assert_eq!(result.unwrap_err(), "WRONGTYPE Operation against a key holding the wrong kind of value");
+
+        assert_eq!(storage.sadd(b"s", fields(&["a", "b", "a"])), Ok(2));
+        assert_eq!(storage.sadd(b"s", fields(&["b", "c"])), Ok(1));
+        assert_eq!(storage.dirty(), 3);
+        // Adding members already there changes nothing to save.
+        assert_eq!(storage.sadd(b"s", fields(&["a"])), Ok(0));
+        assert_eq!(storage.dirty(), 3);
+        assert_eq!(storage.scard(b"s"), Ok(3));
+        assert_eq!(storage.sismember(b"s", b"c"), Ok(true));
+        assert_eq!(storage.sismember(b"missing", b"c"), Ok(false));
+        assert_eq!(
+            sorted(storage.smembers(b"s").unwrap()),
+            fields(&["a", "b", "c"])
+        );
+
+        assert_eq!(storage.srem(b"s", &fields(&["a", "x"])), Ok(1));
+        assert_eq!(storage.srem(b"s", &fields(&["b", "c"])), Ok(2));
+        assert!(storage.get(b"s").is_none());
+
+        storage.set(b"str".to_vec(), b"v".to_vec(), None).unwrap();
+        assert!(storage.sadd(b"str", fields(&["a"])).is_err());
+        assert!(storage.scard(b"str").is_err());
     }
 
-    // Hash operations tests
     #[test]
-    fn test_hset_new_hash() {
+    fn test_spop_and_srandmember() {
         let mut storage = Storage::new();
-        let key = "myhash".to_string();
-        let field = "field1".to_string();
-        let value = "value1".to_string();
+        storage.sadd(b"s", fields(&["a", "b", "c", "d"])).unwrap();
 
-        let result = storage.hset(key.clone(), field.clone(), value.clone());
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap(), 1); // New field
-        assert!(storage.is_dirty());
+        let mut picked = sorted(storage.srandmember(b"s", 3).unwrap());
+        picked.dedup();
+        assert_eq!(picked.len(), 3);
+        assert_eq!(storage.srandmember(b"s", -10).unwrap().len(), 10);
+        assert_eq!(storage.srandmember(b"s", 10).unwrap().len(), 4);
+        assert_eq!(storage.scard(b"s"), Ok(4));
 
-        let get_result = storage.hget(&key, &field).unwrap();
-        assert_eq!(get_result.unwrap(), &value);
+        let popped = storage.spop(b"s", 3).unwrap();
+        assert_eq!(popped.len(), 3);
+        assert_eq!(storage.scard(b"s"), Ok(1));
+        for member in &popped {
+            assert_eq!(storage.sismember(b"s", member), Ok(false));
+        }
+        assert_eq!(storage.spop(b"s", 5).unwrap().len(), 1);
+        assert!(storage.get(b"s").is_none());
+        assert!(storage.spop(b"s", 1).unwrap().is_empty());
+        assert!(storage.srandmember(b"s", -3).unwrap().is_empty());
+
+        // Counts past `i64::MAX` pop every member, once.
+        for count in [1 << 63, usize::MAX] {
+            storage.sadd(b"s", fields(&["a", "b", "c"])).unwrap();
+            assert_eq!(
+                sorted(storage.spop(b"s", count).unwrap()),
+                fields(&["a", "b", "c"])
+            );
+            assert!(storage.get(b"s").is_none());
+        }
+        for count in [i64::MIN, -3_000_000_000] {
+            assert_eq!(storage.srandmember(b"s", count), Err(OUT_OF_RANGE));
+        }
     }
 
     #[test]
-    fn test_hset_existing_field() {
+    fn test_smove() {
         let mut storage = Storage::new();
-        let key = "myhash".to_string();
-        let field = "field1".to_string();
+        storage.sadd(b"from", fields(&["a"])).unwrap();
+        storage.set(b"str".to_vec(), b"v".to_vec(), None).unwrap();
 
-        storage.hset(key.clone(), field.clone(), "old_value".to_string()).unwrap();
-        let result = storage.hset(key.clone(), field.clone(), "new_value".to_string());
-        
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap(), 0); // Existing field
+        assert!(storage.smove(b"from", b"str", b"a".to_vec()).is_err());
+        assert_eq!(storage.smove(b"from", b"to", b"x".to_vec()), Ok(false));
+        assert_eq!(storage.smove(b"from", b"from", b"a".to_vec()), Ok(true));
+        assert_eq!(storage.smove(b"from", b"to", b"a".to_vec()), Ok(true));
+        assert!(storage.get(b"from").is_none());
+        assert_eq!(storage.smembers(b"to"), Ok(fields(&["a"])));
 
-        let get_result = storage.hget(&key, &field).unwrap();
-        assert_eq!(get_result.unwrap(), "new_value");
+        // Moving a member the destination already has still removes it from
+        // the source.
+        storage.sadd(b"from", fields(&["a"])).unwrap();
+        let dirty = storage.dirty();
+        assert_eq!(storage.smove(b"from", b"to", b"a".to_vec()), Ok(true));
+        assert_eq!(storage.dirty(), dirty + 1);
     }
 
     #[test]
-    fn test_hset_wrong_type() {
+    fn test_set_algebra() {
         let mut storage = Storage::new();
-        let key = "stringkey".to_string();
-        
-        storage.set(key.clone(), "stringvalue".to_string(), None);
-        let result = storage.hset(key, "field".to_string(), "value".to_string());
-        
-        assert!(result.is_err());
-        assert_eq!(result.unwrap_err(), "WRONGTYPE Operation against a key holding the wrong kind of value");
+        storage.sadd(b"x", fields(&["a", "b", "c"])).unwrap();
+        storage.sadd(b"y", fields(&["b", "c", "d"])).unwrap();
+        storage.sadd(b"z", fields(&["c"])).unwrap();
+        let keys = |names: &[&str]| fields(names);
+        let op = |storage: &mut Storage, op, names: &[&str]| {
+            sorted(
+                storage
+                    .set_op(op, &keys(names))
+                    .unwrap()
+                    .iter()
+                    .map(<[u8]>::to_vec)
+                    .collect(),
+            )
+        };
+
+        assert_eq!(
+            op(&mut storage, SetOp::Inter, &["x", "y", "z"]),
+            fields(&["c"])
+        );
+        assert!(op(&mut storage, SetOp::Inter, &["x", "missing"]).is_empty());
+        assert_eq!(
+            op(&mut storage, SetOp::Union, &["x", "y", "missing"]),
+            fields(&["a", "b", "c", "d"])
+        );
+        assert_eq!(op(&mut storage, SetOp::Diff, &["x", "y"]), fields(&["a"]));
+        assert!(op(&mut storage, SetOp::Diff, &["missing", "x"]).is_empty());
+
+        storage.set(b"str".to_vec(), b"v".to_vec(), None).unwrap();
+        assert!(storage.set_op(SetOp::Union, &keys(&["x", "str"])).is_err());
+
+        assert_eq!(
+            storage.set_op_store(SetOp::Union, b"str".to_vec(), &keys(&["x", "z"])),
+            Ok(3)
+        );
+        assert_eq!(storage.scard(b"str"), Ok(3));
+        assert_eq!(
+            storage.set_op_store(SetOp::Inter, b"str".to_vec(), &keys(&["x", "missing"])),
+            Ok(0)
+        );
+        assert!(storage.get(b"str").is_none());
     }
 
     #[test]
-    fn test_hget_nonexistent_key() {
+    fn test_type_name() {
         let mut storage = Storage::new();
-        let result = storage.hget("nonexistent", "field");
-        assert!(result.is_ok());
-        assert!(result.unwrap().is_none());
+        storage.set(b"s".to_vec(), b"v".to_vec(), None).unwrap();
+        storage.rpush(b"l", fields(&["a"])).unwrap();
+        storage
+            .hset(b"h".to_vec(), vec![(b"f".to_vec(), b"v".to_vec())])
+            .unwrap();
+        storage.sadd(b"set", fields(&["a"])).unwrap();
+        storage
+            .zadd(b"z", vec![(1.0, b"a".to_vec())], &ZAddOptions::default())
+            .unwrap();
+        storage
+            .xgroup_create(b"x", b"g", ReadFrom::Last, true)
+            .unwrap();
+
+        let names: Vec<_> = ["s", "l", "h", "set", "z", "x"]
+            .iter()
+            .map(|key| storage.get(key.as_bytes()).unwrap().data.type_name())
+            .collect();
+        assert_eq!(names, ["string", "list", "hash", "set", "zset", "stream"]);
+    }
+
+    fn scored(pairs: &[(&str, f64)]) -> Vec<(Vec<u8>, f64)> {
+        pairs
+            .iter()
+            .map(|&(member, score)| (member.as_bytes().to_vec(), score))
+            .collect()
     }
 
     #[test]
-    fn test_hget_nonexistent_field() {
+    fn test_zadd_options() {
         let mut storage = Storage::new();
-        let key = "myhash".to_string();
-        
-        storage.hset(key.clone(), "field1".to_string(), "value1".to_string()).unwrap();
-        let result = storage.hget(&key, "nonexistent_field");
-        
-        assert!(result.is_ok());
-        assert!(result.unwrap().is_none());
+        let pairs = |pairs: &[(f64, &str)]| {
+            pairs
+                .iter()
+                .map(|&(score, member)| (score, member.as_bytes().to_vec()))
+                .collect::<Vec<_>>()
+        };
+        let add = |storage: &mut Storage, options: ZAddOptions, list: &[(f64, &str)]| {
+            storage.zadd(b"z", pairs(list), &options).unwrap()
+        };
+        let plain = ZAddOptions::default();
+
+        assert_eq!(add(&mut storage, plain, &[(1.0, "a"), (2.0, "b")]), 2);
+        assert_eq!(add(&mut storage, plain, &[(3.0, "a"), (1.0, "c")]), 1);
+        assert_eq!(storage.zscore(b"z", b"a"), Ok(Some(3.0)));
+
+        let nx = ZAddOptions {
+            only_if_exists: Some(false),
+            ..plain
+        };
+        assert_eq!(add(&mut storage, nx, &[(9.0, "a"), (4.0, "d")]), 1);
+        assert_eq!(storage.zscore(b"z", b"a"), Ok(Some(3.0)));
+
+        let xx_ch = ZAddOptions {
+            only_if_exists: Some(true),
+            changed: true,
+            ..plain
+        };
+        assert_eq!(add(&mut storage, xx_ch, &[(5.0, "a"), (1.0, "e")]), 1);
+        assert_eq!(storage.zscore(b"z", b"e"), Ok(None));
+
+        let gt = ZAddOptions {
+            gt: true,
+            changed: true,
+            ..plain
+        };
+        assert_eq!(add(&mut storage, gt, &[(1.0, "a"), (0.5, "f")]), 1);
+        assert_eq!(storage.zscore(b"z", b"a"), Ok(Some(5.0)));
+        assert_eq!(storage.zscore(b"z", b"f"), Ok(Some(0.5)));
+
+        // XX on a missing key doesn't leave an empty sorted set behind.
+        assert_eq!(storage.zadd(b"none", pairs(&[(1.0, "a")]), &xx_ch), Ok(0));
+        assert!(storage.get(b"none").is_none());
+
+        storage.set(b"s".to_vec(), b"v".to_vec(), None).unwrap();
+        assert!(storage.zadd(b"s", pairs(&[(1.0, "a")]), &plain).is_err());
     }
 
     #[test]
-    fn test_hdel() {
+    fn test_zincrby() {
         let mut storage = Storage::new();
-        let key = "myhash".to_string();
-        
-        storage.hset(key.clone(), "field1".to_string(), "value1".to_string()).unwrap();
-        storage.hset(key.clone(), "field2".to_string(), "value2".to_string()).unwrap();
-        storage.hset(key.clone(), "field3".to_string(), "value3".to_string()).unwrap();
+        let plain = ZAddOptions::default();
 
-        // Delete existing fields
-        let result = storage.hdel(&key, vec!["field1".to_string(), "field3".to_string()]);
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap(), 2);
+        assert_eq!(
+            storage.zincrby(b"z", b"a".to_vec(), 2.5, &plain),
+            Ok(Some(2.5))
+        );
+        assert_eq!(
+            storage.zincrby(b"z", b"a".to_vec(), -1.0, &plain),
+            Ok(Some(1.5))
+        );
 
-        // Check that field2 still exists
-        assert!(storage.hget(&key, "field2").unwrap().is_some());
-        assert!(storage.hget(&key, "field1").unwrap().is_none());
-        assert!(storage.hget(&key, "field3").unwrap().is_none());
+        let lt = ZAddOptions { lt: true, ..plain };
+        assert_eq!(storage.zincrby(b"z", b"a".to_vec(), 1.0, &lt), Ok(None));
+        assert_eq!(storage.zscore(b"z", b"a"), Ok(Some(1.5)));
 
-        // Delete non-existent field
-        let result = storage.hdel(&key, vec!["nonexistent".to_string()]);
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap(), 0);
+        storage
+            .zincrby(b"z", b"inf".to_vec(), f64::INFINITY, &plain)
+            .unwrap();
+        assert!(
+            storage
+                .zincrby(b"z", b"inf".to_vec(), f64::NEG_INFINITY, &plain)
+                .is_err()
+        );
+        assert_eq!(storage.zscore(b"z", b"inf"), Ok(Some(f64::INFINITY)));
     }
 
     #[test]
-    fn test_hdel_nonexistent_key() {
+    fn test_zrange_and_zpop() {
         let mut storage = Storage::new();
-        let result = storage.hdel("nonexistent", vec!["field".to_string()]);
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap(), 0);
+        let members = (0..10)
+            .map(|i| (i as f64, format!("m{}", i).into_bytes()))
+            .collect();
+        storage
+            .zadd(b"z", members, &ZAddOptions::default())
+            .unwrap();
+        let by_score = RangeBy::Score(Bound::Excluded(2.0), Bound::Included(6.0));
+
+        assert_eq!(
+            storage.zrange(b"z", &by_score, false, Some((1, 2))),
+            Ok(scored(&[("m4", 4.0), ("m5", 5.0)]))
+        );
+        assert_eq!(
+            storage
+                .zrange(b"z", &by_score, true, Some((0, -1)))
+                .unwrap()
+                .len(),
+            4
+        );
+        assert!(
+            storage
+                .zrange(b"z", &by_score, false, Some((-1, 1)))
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(storage.zcount(b"z", &by_score), Ok(4));
+        assert_eq!(storage.zrank(b"z", b"m3", true), Ok(Some(6)));
+
+        assert_eq!(
+            storage.zpop(b"z", true, 2),
+            Ok(scored(&[("m9", 9.0), ("m8", 8.0)]))
+        );
+        assert_eq!(storage.zpop(b"z", false, 100).unwrap().len(), 8);
+        assert!(storage.get(b"z").is_none());
     }
 
     #[test]
-    fn test_hlen() {
+    fn test_zstore() {
         let mut storage = Storage::new();
-        let key = "myhash".to_string();
+        let plain = ZAddOptions::default();
+        storage
+            .zadd(
+                b"x",
+                vec![(1.0, b"a".to_vec()), (2.0, b"b".to_vec())],
+                &plain,
+            )
+            .unwrap();
+        storage
+            .zadd(
+                b"y",
+                vec![(10.0, b"b".to_vec()), (20.0, b"c".to_vec())],
+                &plain,
+            )
+            .unwrap();
+        storage.sadd(b"set", fields(&["b"])).unwrap();
+        let keys = |names: &[&str]| fields(names);
+        let all = RangeBy::Rank(0, -1);
 
-        // Empty/nonexistent hash
-        let result = storage.hlen(&key);
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap(), 0);
+        assert_eq!(
+            storage.zstore(
+                SetOp::Union,
+                b"out".to_vec(),
+                &keys(&["x", "y"]),
+                &[2.0],
+                Aggregate::Sum
+            ),
+            Ok(3)
+        );
+        assert_eq!(
+            storage.zrange(b"out", &all, false, None),
+            Ok(scored(&[("a", 2.0), ("b", 14.0), ("c", 20.0)]))
+        );
 
-        // Add some fields
-        storage.hset(key.clone(), "field1".to_string(), "value1".to_string()).unwrap();
-        storage.hset(key.clone(), "field2".to_string(), "value2".to_string()).unwrap();
+        assert_eq!(
+            storage.zstore(
+                SetOp::Inter,
+                b"out".to_vec(),
+                &keys(&["x", "y", "set"]),
+                &[],
+                Aggregate::Max
+            ),
+            Ok(1)
+        );
+        assert_eq!(storage.zscore(b"out", b"b"), Ok(Some(10.0)));
 
-        let result = storage.hlen(&key);
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap(), 2);
+        assert_eq!(
+            storage.zstore(
+                SetOp::Diff,
+                b"out".to_vec(),
+                &keys(&["x", "set"]),
+                &[],
+                Aggregate::Sum
+            ),
+            Ok(1)
+        );
+        assert_eq!(
+            storage.zrange(b"out", &all, false, None),
+            Ok(scored(&[("a", 1.0)]))
+        );
 
-        // Delete a field
-        storage.hdel(&key, vec!["field1".to_string()]).unwrap();
-        let result = storage.hlen(&key);
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap(), 1);
+        assert_eq!(
+            storage.zstore(
+                SetOp::Inter,
+                b"out".to_vec(),
+                &keys(&["x", "missing"]),
+                &[],
+                Aggregate::Sum
+            ),
+            Ok(0)
+        );
+        assert!(storage.get(b"out").is_none());
+    }
+
+    fn stream_ids(entries: &[(StreamId, impl Sized)]) -> Vec<StreamId> {
+        entries.iter().map(|(id, _)| *id).collect()
     }
 
     #[test]
-    fn test_hlen_wrong_type() {
+    fn test_xadd_and_xread() {
         let mut storage = Storage::new();
-        let key = "stringkey".to_string();
-        
-        storage.set(key.clone(), "stringvalue".to_string(), None);
-        let result = storage.hlen(&key);
-        
-        assert!(result.is_err());
-        assert_eq!(result.unwrap_err(), "WRONGTYPE Operation against a key holding the wrong kind of value");
+        let entry = || vec![(b"f".to_vec(), b"v".to_vec())];
+        let exact = |ms| NewId::Exact(StreamId::new(ms, 0));
+
+        assert_eq!(storage.xadd(b"x", exact(1), entry(), true, None), Ok(None));
+        assert!(storage.get(b"x").is_none());
+        // A bad ID doesn't leave an empty stream behind.
+        assert!(
+            storage
+                .xadd(b"x", NewId::Exact(StreamId::MIN), entry(), false, None)
+                .is_err()
+        );
+        assert!(storage.get(b"x").is_none());
+
+        for ms in 1..=5 {
+            storage.xadd(b"x", exact(ms), entry(), false, None).unwrap();
+        }
+        let trim = Trim {
+            by: TrimBy::MaxLen(3),
+            limit: None,
+        };
+        assert_eq!(
+            storage.xadd(b"x", exact(6), entry(), true, Some(&trim)),
+            Ok(Some(StreamId::new(6, 0)))
+        );
+        assert_eq!(storage.xlen(b"x"), Ok(3));
+
+        // Emptied streams are kept.
+        assert_eq!(storage.xdel(b"x", &[StreamId::new(4, 0)]), Ok(1));
+        assert_eq!(
+            storage.xtrim(
+                b"x",
+                &Trim {
+                    by: TrimBy::MaxLen(0),
+                    limit: None
+                }
+            ),
+            Ok(2)
+        );
+        assert_eq!(storage.xlen(b"x"), Ok(0));
+        assert_eq!(storage.xlast_id(b"x"), Ok(Some(StreamId::new(6, 0))));
+        assert_eq!(
+            storage.xsetid(b"missing", StreamId::MIN),
+            Err("ERR no such key")
+        );
+
+        storage.xadd(b"x", exact(7), entry(), false, None).unwrap();
+        storage.xadd(b"y", exact(1), entry(), false, None).unwrap();
+        let keys = [b"x".to_vec(), b"y".to_vec(), b"z".to_vec()];
+        let from = [
+            ReadFrom::After(StreamId::MIN),
+            ReadFrom::After(StreamId::new(1, 0)),
+            ReadFrom::After(StreamId::MIN),
+        ];
+        let read = storage.xread(&keys, &from, None).unwrap();
+        assert_eq!(read.len(), 1);
+        assert_eq!(read[0].0, b"x");
+        assert_eq!(stream_ids(&read[0].1), [StreamId::new(7, 0)]);
+
+        storage.set(b"s".to_vec(), b"v".to_vec(), None).unwrap();
+        assert!(storage.xread(&[b"s".to_vec()], &from[..1], None).is_err());
+        assert!(storage.xlen(b"s").is_err());
     }
 
     #[test]
-    fn test_hgetall() {
+    fn test_stream_groups() {
         let mut storage = Storage::new();
-        let key = "myhash".to_string();
+        let group_err = storage.xgroup_create(b"x", b"g", ReadFrom::Last, false);
+        assert!(group_err.unwrap_err().contains("MKSTREAM"));
+        storage
+            .xgroup_create(b"x", b"g", ReadFrom::Last, true)
+            .unwrap();
+        storage
+            .xgroup_create(b"y", b"g", ReadFrom::Last, true)
+            .unwrap();
+        for key in [b"x", b"y"] {
+            let fields = vec![(b"f".to_vec(), b"v".to_vec())];
+            storage.xadd(key, NewId::Auto, fields, false, None).unwrap();
+        }
 
-        // Empty/nonexistent hash
-        let result = storage.hgetall(&key);
-        assert!(result.is_ok());
-        assert!(result.unwrap().is_none());
+        let keys = [b"x".to_vec(), b"other".to_vec()];
+        let from = [ReadFrom::Undelivered; 2];
+        assert_eq!(
+            storage.xreadgroup(b"g", b"c", &keys, &from, None, false),
+            Err("NOGROUP No such key or consumer group")
+        );
+        // Nothing was read from any stream then.
+        assert_eq!(storage.xgroup(b"x", b"g").unwrap().pending().len(), 0);
+        storage.set(b"str".to_vec(), b"v".to_vec(), None).unwrap();
+        let keys = [b"x".to_vec(), b"str".to_vec()];
+        assert!(
+            storage
+                .xreadgroup(b"g", b"c", &keys, &from, None, false)
+                .unwrap_err()
+                .starts_with("WRONGTYPE")
+        );
+        assert_eq!(storage.xgroup(b"x", b"g").unwrap().pending().len(), 0);
 
-        // Add some fields
-        storage.hset(key.clone(), "field1".to_string(), "value1".to_string()).unwrap();
-        storage.hset(key.clone(), "field2".to_string(), "value2".to_string()).unwrap();
+        let keys = [b"x".to_vec(), b"y".to_vec()];
+        let dirty = storage.dirty();
+        let read = storage
+            .xreadgroup(b"g", b"c", &keys, &from, None, false)
+            .unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!(storage.dirty(), dirty + 1);
+        // Streams with nothing new are left out, but the history is always
+        // there. A read that delivers nothing changes nothing.
+        let read = storage
+            .xreadgroup(b"g", b"c", &keys, &from, None, false)
+            .unwrap();
+        assert!(read.is_empty());
+        assert_eq!(storage.dirty(), dirty + 1);
+        let history = [ReadFrom::After(StreamId::MIN), ReadFrom::Undelivered];
+        let read = storage
+            .xreadgroup(b"g", b"c", &keys, &history, None, false)
+            .unwrap();
+        assert_eq!(read.len(), 1);
+        assert_eq!(read[0].1.len(), 1);
+        assert_eq!(storage.dirty(), dirty + 2);
+        // Adding a consumer is a change even when nothing is delivered.
+        storage
+            .xreadgroup(b"g", b"e", &keys, &from, None, false)
+            .unwrap();
+        assert_eq!(storage.dirty(), dirty + 3);
 
-        let result = storage.hgetall(&key).unwrap().unwrap();
-        assert_eq!(result.len(), 2);
-        assert_eq!(result.get("field1").unwrap(), "value1");
-        assert_eq!(result.get("field2").unwrap(), "value2");
+        assert_eq!(storage.xgroup_createconsumer(b"x", b"g", b"c"), Ok(false));
+        assert_eq!(storage.xgroup_createconsumer(b"x", b"g", b"d"), Ok(true));
+        assert_eq!(storage.xgroup_delconsumer(b"x", b"g", b"c"), Ok(1));
+        assert_eq!(storage.xgroup_destroy(b"x", b"g"), Ok(true));
+        assert_eq!(storage.xgroup_destroy(b"x", b"g"), Ok(false));
+        assert!(storage.xgroup(b"x", b"g").is_err());
     }
 
     #[test]
-    fn test_hgetall_wrong_type() {
+    fn test_binary_keys_and_values() {
         let mut storage = Storage::new();
-        let key = "stringkey".to_string();
-        
-        storage.set(key.clone(), "stringvalue".to_string(), None);
-        let result = storage.hgetall(&key);
-        
-        assert!(result.is_err());
-        assert_eq!(result.unwrap_err(), "WRONGTYPE Operation against a key holding the wrong kind of value");
+        let key = vec![0u8, 0xff, b'\n'];
+        let value = vec![0x89, b'P', b'N', b'G', 0, 0];
+
+        storage.set(key.clone(), value.clone(), None).unwrap();
+        storage
+            .rpush(&[0xc3, 0x28], vec![vec![0xfe], vec![]])
+            .unwrap();
+
+        match &storage.get(&key).unwrap().data {
+            Value::String(s) => assert_eq!(s, &value),
+            _ => panic!("Expected string value"),
+        }
+        let list = storage.lrange(&[0xc3, 0x28], 0, -1).unwrap().unwrap();
+        assert_eq!(list, vec![vec![0xfe], vec![]]);
+
+        let serialized = bincode::serialize(&storage).unwrap();
+        let mut deserialized: Storage = bincode::deserialize(&serialized).unwrap();
+        assert!(deserialized.get(&key).is_some());
     }
 
     #[test]
     fn test_serialization() {
         let mut storage = Storage::new();
-        
+
         // Add some data
-        storage.set("string_key".to_string(), "string_value".to_string(), None);
-        storage.rpush("list_key", vec!["item1".to_string(), "item2".to_string()]).unwrap();
-        storage.hset("hash_key".to_string(), "field1".to_string(), "value1".to_string()).unwrap();
+        storage
+            .set(b"string_key".to_vec(), b"string_value".to_vec(), None)
+            .unwrap();
+        storage
+            .rpush(b"list_key", vec![b"item1".to_vec(), b"item2".to_vec()])
+            .unwrap();
+        storage
+            .hset(
+                b"hash_key".to_vec(),
+                vec![(b"field1".to_vec(), b"value1".to_vec())],
+            )
+            .unwrap();
 
         // Serialize
         let serialized = bincode::serialize(&storage).unwrap();
-        
+
         // Deserialize
         let mut deserialized: Storage = bincode::deserialize(&serialized).unwrap();
-        
+
         // Verify data integrity
-        let string_val = deserialized.get("string_key").unwrap();
+        let string_val = deserialized.get(b"string_key").unwrap();
         match &string_val.data {
-            Value::String(s) => assert_eq!(s, "string_value"),
+            Value::String(s) => assert_eq!(s, b"string_value"),
             _ => panic!("Expected string value"),
         }
 
-        let list_val = deserialized.lrange("list_key", 0, -1).unwrap().unwrap();
-        assert_eq!(list_val, vec!["item1", "item2"]);
+        let list_val = deserialized.lrange(b"list_key", 0, -1).unwrap().unwrap();
+        assert_eq!(list_val, vec![b"item1".to_vec(), b"item2".to_vec()]);
 
-        let hash_val = deserialized.hget("hash_key", "field1").unwrap().unwrap();
-        assert_eq!(hash_val, "value1");
+        let hash_val = deserialized.hget(b"hash_key", b"field1").unwrap().unwrap();
+        assert_eq!(hash_val, b"value1");
 
-        // Dirty flag should be reset after deserialization
+        // Loaded data has no unsaved changes
         assert!(!deserialized.is_dirty());
     }
 }
@@ -0,0 +1,851 @@
+//! The stream: an append-only log of entries, each a list of field-value
+//! pairs stored under an ID that only ever grows.
+//!
+//! Consumer groups share the entries of a stream out between their
+//! consumers. A group remembers the last entry it handed out, and keeps every
+//! entry it delivered in its pending entries list until the consumer that got
+//! it acknowledges it, so entries a consumer never processed can be claimed
+//! by another one.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Bound;
+
+const NO_GROUP: &str = "NOGROUP No such key or consumer group";
+const BUSY_GROUP: &str = "BUSYGROUP Consumer Group name already exists";
+const ID_TOO_SMALL: &str =
+    "ERR The ID specified in XADD is equal or smaller than the target stream top item";
+
+/// The ID of a stream entry: the Unix time in milliseconds it was added at,
+/// then a sequence number telling apart entries added in the same
+/// millisecond.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    pub fn new(ms: u64, seq: u64) -> Self {
+        StreamId { ms, seq }
+    }
+
+    /// Parses `<ms>-<seq>`, or a lone `<ms>` with `default_seq` as the
+    /// sequence number.
+    pub fn parse(arg: &[u8], default_seq: u64) -> Option<StreamId> {
+        let arg = std::str::from_utf8(arg).ok()?;
+        // Plain digits only, `parse` would take a leading `+` too.
+        let number = |s: &str| {
+            Some(s)
+                .filter(|s| s.bytes().all(|b| b.is_ascii_digit()))
+                .and_then(|s| s.parse().ok())
+        };
+
+        match arg.split_once('-') {
+            Some((ms, seq)) => Some(StreamId::new(number(ms)?, number(seq)?)),
+            None => Some(StreamId::new(number(arg)?, default_seq)),
+        }
+    }
+
+    /// The smallest ID greater than this one, if there is one.
+    pub fn next(self) -> Option<StreamId> {
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(StreamId { seq, ..self }),
+            None => Some(StreamId::new(self.ms.checked_add(1)?, 0)),
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// The field-value pairs of an entry, in the order they were given.
+pub type Fields = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// What XAUTOCLAIM did: the ID to carry on from, the entries it claimed and
+/// the IDs of the deleted ones it dropped.
+pub type AutoClaimed = (StreamId, Vec<(StreamId, Fields)>, Vec<StreamId>);
+
+/// The ID XADD gives a new entry.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NewId {
+    /// `*`: the current time, or one past the last ID if the clock is
+    /// behind it.
+    Auto,
+    /// `<ms>-*`: the next sequence number within that millisecond.
+    AutoSeq(u64),
+    Exact(StreamId),
+}
+
+/// Where XREAD and XREADGROUP start reading a stream.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReadFrom {
+    /// The entries after this ID.
+    After(StreamId),
+    /// `$`: only entries added from now on.
+    Last,
+    /// `>`: the entries the group hasn't delivered to anyone yet.
+    Undelivered,
+}
+
+/// How XTRIM, or XADD with MAXLEN or MINID, shortens a stream.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrimBy {
+    /// Down to at most this many entries, evicting the oldest.
+    MaxLen(usize),
+    /// Evicting every entry with a smaller ID.
+    MinId(StreamId),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Trim {
+    pub by: TrimBy,
+    /// Evict at most this many entries.
+    pub limit: Option<usize>,
+}
+
+/// When XCLAIM says the entries it claims were delivered. An idle time
+/// counts back from when the command runs rather than when it was parsed,
+/// which for one queued in a transaction can be a good while later.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeliveredAt {
+    /// This many milliseconds before now (IDLE).
+    Idle(u64),
+    /// At this Unix time in milliseconds (TIME).
+    At(u64),
+}
+
+impl DeliveredAt {
+    /// The Unix time in milliseconds this comes to, given the time now.
+    pub fn resolve(self, now: u64) -> u64 {
+        match self {
+            DeliveredAt::Idle(idle) => now.saturating_sub(idle),
+            DeliveredAt::At(at) => at,
+        }
+    }
+}
+
+/// The options of XCLAIM.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ClaimOptions {
+    /// When the claimed entries count as delivered, rather than now (IDLE or
+    /// TIME).
+    pub delivered_at: Option<DeliveredAt>,
+    /// The delivery count to give them, rather than one more (RETRYCOUNT).
+    pub deliveries: Option<u64>,
+    /// Also claim entries no consumer has pending, as long as they are
+    /// still in the stream (FORCE).
+    pub force: bool,
+    /// Don't count the claim as a delivery (JUSTID).
+    pub just_id: bool,
+    /// Move the group's last delivered ID up to this one (LASTID).
+    pub last_id: Option<StreamId>,
+}
+
+/// An entry a consumer has been delivered but hasn't acknowledged.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PendingEntry {
+    pub consumer: Vec<u8>,
+    /// When it was last delivered, in Unix milliseconds.
+    pub delivered_at: u64,
+    pub deliveries: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ConsumerGroup {
+    pub last_delivered: StreamId,
+    pending: BTreeMap<StreamId, PendingEntry>,
+    // Each consumer, with the last time it read or claimed anything.
+    consumers: BTreeMap<Vec<u8>, u64>,
+}
+
+impl ConsumerGroup {
+    fn new(last_delivered: StreamId) -> Self {
+        ConsumerGroup {
+            last_delivered,
+            ..ConsumerGroup::default()
+        }
+    }
+
+    pub fn pending(&self) -> &BTreeMap<StreamId, PendingEntry> {
+        &self.pending
+    }
+
+    pub fn consumers(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.consumers.keys()
+    }
+
+    pub fn has_consumer(&self, consumer: &[u8]) -> bool {
+        self.consumers.contains_key(consumer)
+    }
+
+    /// The pending entries with IDs between `start` and `end`.
+    pub fn pending_range(
+        &self,
+        start: Bound<StreamId>,
+        end: Bound<StreamId>,
+    ) -> impl Iterator<Item = (&StreamId, &PendingEntry)> {
+        let range = (!is_empty_range(start, end)).then(|| self.pending.range((start, end)));
+        range.into_iter().flatten()
+    }
+
+    /// How many entries each consumer with any has pending.
+    pub fn pending_per_consumer(&self) -> BTreeMap<&[u8], usize> {
+        let mut counts = BTreeMap::new();
+        for entry in self.pending.values() {
+            *counts.entry(entry.consumer.as_slice()).or_default() += 1;
+        }
+        counts
+    }
+
+    fn touch(&mut self, consumer: &[u8], now: u64) {
+        self.consumers.insert(consumer.to_vec(), now);
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Stream {
+    entries: BTreeMap<StreamId, Fields>,
+    // The ID of the last entry ever added, which new IDs must be above even
+    // once that entry is deleted.
+    last_id: StreamId,
+    groups: BTreeMap<Vec<u8>, ConsumerGroup>,
+}
+
+impl Stream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    /// Every entry, oldest first.
+    pub fn entries(&self) -> impl Iterator<Item = (&StreamId, &Fields)> {
+        self.entries.iter()
+    }
+
+    pub fn contains(&self, id: &StreamId) -> bool {
+        self.entries.contains_key(id)
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = (&Vec<u8>, &ConsumerGroup)> {
+        self.groups.iter()
+    }
+
+    pub fn group(&self, name: &[u8]) -> Option<&ConsumerGroup> {
+        self.groups.get(name)
+    }
+
+    /// Appends an entry. Returns the ID it was given.
+    pub fn add(&mut self, id: NewId, fields: Fields, now: u64) -> Result<StreamId, &'static str> {
+        let last = self.last_id;
+        let id = match id {
+            NewId::Auto if now > last.ms => StreamId::new(now, 0),
+            NewId::Auto => last.next().ok_or(
+                "ERR The stream has exhausted the last possible ID, unable to add more items",
+            )?,
+            NewId::AutoSeq(ms) if ms > last.ms => StreamId::new(ms, 0),
+            NewId::AutoSeq(ms) if ms == last.ms => {
+                StreamId::new(ms, last.seq.checked_add(1).ok_or(ID_TOO_SMALL)?)
+            }
+            NewId::AutoSeq(_) => return Err(ID_TOO_SMALL),
+            NewId::Exact(id) => id,
+        };
+
+        if id == StreamId::MIN {
+            return Err("ERR The ID specified in XADD must be greater than 0-0");
+        }
+        if id <= last {
+            return Err(ID_TOO_SMALL);
+        }
+        self.entries.insert(id, fields);
+        self.last_id = id;
+        Ok(id)
+    }
+
+    /// Evicts the oldest entries as `trim` says. Returns how many.
+    pub fn trim(&mut self, trim: &Trim) -> usize {
+        let limit = trim.limit.unwrap_or(usize::MAX);
+        let mut evicted = 0;
+
+        while evicted < limit {
+            let len = self.entries.len();
+            let Some(oldest) = self.entries.first_entry() else {
+                break;
+            };
+            let evict = match trim.by {
+                TrimBy::MaxLen(max) => len > max,
+                TrimBy::MinId(min) => *oldest.key() < min,
+            };
+            if !evict {
+                break;
+            }
+            oldest.remove();
+            evicted += 1;
+        }
+        evicted
+    }
+
+    /// Deletes the entries with these IDs. Returns how many there were. They
+    /// stay in the pending lists of the groups that delivered them.
+    pub fn delete(&mut self, ids: &[StreamId]) -> usize {
+        ids.iter()
+            .filter(|id| self.entries.remove(id).is_some())
+            .count()
+    }
+
+    /// Sets the ID new entries have to be above, which can't be below the
+    /// last entry's.
+    pub fn set_last_id(&mut self, id: StreamId) -> Result<(), &'static str> {
+        if self
+            .entries
+            .last_key_value()
+            .is_some_and(|(&last, _)| id < last)
+        {
+            return Err(
+                "ERR The ID specified in XSETID is smaller than the target stream top item",
+            );
+        }
+        self.last_id = id;
+        Ok(())
+    }
+
+    /// The entries between `start` and `end`, oldest first or newest first
+    /// with `rev`, at most `count` of them.
+    pub fn range(
+        &self,
+        start: Bound<StreamId>,
+        end: Bound<StreamId>,
+        rev: bool,
+        count: Option<usize>,
+    ) -> Vec<(StreamId, Fields)> {
+        if is_empty_range(start, end) {
+            return Vec::new();
+        }
+
+        let range = self.entries.range((start, end));
+        let count = count.unwrap_or(usize::MAX);
+        let clone = |(id, fields): (&StreamId, &Fields)| (*id, fields.clone());
+        if rev {
+            range.rev().take(count).map(clone).collect()
+        } else {
+            range.take(count).map(clone).collect()
+        }
+    }
+
+    /// Adds a group that will first deliver the entries after `from`.
+    pub fn create_group(&mut self, name: &[u8], from: ReadFrom) -> Result<(), &'static str> {
+        if self.groups.contains_key(name) {
+            return Err(BUSY_GROUP);
+        }
+        let last_delivered = self.resolve(from);
+        self.groups
+            .insert(name.to_vec(), ConsumerGroup::new(last_delivered));
+        Ok(())
+    }
+
+    /// Makes a group deliver the entries after `from` next.
+    pub fn set_group_id(&mut self, name: &[u8], from: ReadFrom) -> Result<(), &'static str> {
+        let last_delivered = self.resolve(from);
+        self.group_mut(name)?.last_delivered = last_delivered;
+        Ok(())
+    }
+
+    pub fn destroy_group(&mut self, name: &[u8]) -> bool {
+        self.groups.remove(name).is_some()
+    }
+
+    /// Adds a consumer to a group. Returns false if it was already in it.
+    pub fn create_consumer(
+        &mut self,
+        group: &[u8],
+        consumer: &[u8],
+        now: u64,
+    ) -> Result<bool, &'static str> {
+        let group = self.group_mut(group)?;
+        if group.consumers.contains_key(consumer) {
+            return Ok(false);
+        }
+        group.touch(consumer, now);
+        Ok(true)
+    }
+
+    /// Removes a consumer from a group, along with the entries it had
+    /// pending. Returns how many those were.
+    pub fn delete_consumer(
+        &mut self,
+        group: &[u8],
+        consumer: &[u8],
+    ) -> Result<usize, &'static str> {
+        let group = self.group_mut(group)?;
+        group.consumers.remove(consumer);
+        let before = group.pending.len();
+        group.pending.retain(|_, entry| entry.consumer != consumer);
+        Ok(before - group.pending.len())
+    }
+
+    /// Reads on behalf of `consumer` of `group`, for XREADGROUP. Reading the
+    /// undelivered entries hands them to the consumer and, unless `no_ack`,
+    /// adds them to its pending entries. Reading from an ID instead goes
+    /// over the consumer's own pending entries after it, delivering them
+    /// again. Entries deleted since have no fields.
+    pub fn read_group(
+        &mut self,
+        group: &[u8],
+        consumer: &[u8],
+        from: ReadFrom,
+        count: Option<usize>,
+        no_ack: bool,
+        now: u64,
+    ) -> Result<Vec<(StreamId, Option<Fields>)>, &'static str> {
+        let group = self.groups.get_mut(group).ok_or(NO_GROUP)?;
+        group.touch(consumer, now);
+        let count = count.unwrap_or(usize::MAX);
+
+        let ReadFrom::After(after) = from else {
+            let read: Vec<_> = self
+                .entries
+                .range((Bound::Excluded(group.last_delivered), Bound::Unbounded))
+                .take(count)
+                .map(|(&id, fields)| (id, Some(fields.clone())))
+                .collect();
+            if let Some(&(last, _)) = read.last() {
+                group.last_delivered = last;
+            }
+            if !no_ack {
+                for &(id, _) in &read {
+                    let entry = PendingEntry {
+                        consumer: consumer.to_vec(),
+                        delivered_at: now,
+                        deliveries: 1,
+                    };
+                    group.pending.insert(id, entry);
+                }
+            }
+            return Ok(read);
+        };
+
+        let mut read = Vec::new();
+        let pending = group
+            .pending
+            .range_mut((Bound::Excluded(after), Bound::Unbounded))
+            .filter(|(_, entry)| entry.consumer == consumer)
+            .take(count);
+        for (&id, entry) in pending {
+            entry.delivered_at = now;
+            entry.deliveries += 1;
+            read.push((id, self.entries.get(&id).cloned()));
+        }
+        Ok(read)
+    }
+
+    /// Acknowledges entries, removing them from the group's pending entries.
+    /// Returns how many were pending.
+    pub fn ack(&mut self, group: &[u8], ids: &[StreamId]) -> usize {
+        let Some(group) = self.groups.get_mut(group) else {
+            return 0;
+        };
+        ids.iter()
+            .filter(|id| group.pending.remove(id).is_some())
+            .count()
+    }
+
+    /// Hands the pending entries with these IDs that have been idle for at
+    /// least `min_idle` milliseconds over to `consumer`, for XCLAIM. Returns
+    /// the ones it now has, with no fields for those deleted since.
+    pub fn claim(
+        &mut self,
+        group: &[u8],
+        consumer: &[u8],
+        min_idle: u64,
+        ids: &[StreamId],
+        options: &ClaimOptions,
+        now: u64,
+    ) -> Result<Vec<(StreamId, Option<Fields>)>, &'static str> {
+        let group = self.groups.get_mut(group).ok_or(NO_GROUP)?;
+        group.touch(consumer, now);
+        if let Some(last_id) = options.last_id
+            && last_id > group.last_delivered
+        {
+            group.last_delivered = last_id;
+        }
+
+        let mut claimed = Vec::new();
+        for &id in ids {
+            let fields = self.entries.get(&id).cloned();
+            let entry = match group.pending.get_mut(&id) {
+                Some(entry) if now.saturating_sub(entry.delivered_at) < min_idle => continue,
+                Some(entry) => entry,
+                None if options.force && fields.is_some() => {
+                    group.pending.entry(id).or_insert(PendingEntry {
+                        consumer: Vec::new(),
+                        delivered_at: now,
+                        deliveries: 1,
+                    })
+                }
+                None => continue,
+            };
+
+            entry.consumer = consumer.to_vec();
+            entry.delivered_at = options.delivered_at.map_or(now, |at| at.resolve(now));
+            if let Some(deliveries) = options.deliveries {
+                entry.deliveries = deliveries;
+            } else if !options.just_id {
+                entry.deliveries += 1;
+            }
+            claimed.push((id, fields));
+        }
+        Ok(claimed)
+    }
+
+    /// Claims up to `count` pending entries from `start` on that have been
+    /// idle for at least `min_idle` milliseconds, for XAUTOCLAIM. Looks at no
+    /// more than ten times `count` entries. Entries deleted since are dropped
+    /// from the pending entries instead. Returns where to carry on from, 0-0
+    /// once every entry was looked at, what was claimed and what was
+    /// dropped.
+    #[allow(clippy::too_many_arguments)]
+    pub fn auto_claim(
+        &mut self,
+        group: &[u8],
+        consumer: &[u8],
+        min_idle: u64,
+        start: StreamId,
+        count: usize,
+        just_id: bool,
+        now: u64,
+    ) -> Result<AutoClaimed, &'static str> {
+        let group = self.groups.get_mut(group).ok_or(NO_GROUP)?;
+        group.touch(consumer, now);
+
+        let mut candidates: Vec<StreamId> = group
+            .pending
+            .range(start..)
+            .map(|(&id, _)| id)
+            .take(count.saturating_mul(10).saturating_add(1))
+            .collect();
+        let mut next = StreamId::MIN;
+        if candidates.len() > count.saturating_mul(10) {
+            next = candidates.pop().expect("there is one more than looked at");
+        }
+
+        let (mut claimed, mut deleted) = (Vec::new(), Vec::new());
+        for id in &candidates {
+            if claimed.len() == count {
+                next = *id;
+                break;
+            }
+            let Some(fields) = self.entries.get(id) else {
+                group.pending.remove(id);
+                deleted.push(*id);
+                continue;
+            };
+            let entry = group.pending.get_mut(id).expect("candidates are pending");
+            if now.saturating_sub(entry.delivered_at) < min_idle {
+                continue;
+            }
+
+            entry.consumer = consumer.to_vec();
+            entry.delivered_at = now;
+            if !just_id {
+                entry.deliveries += 1;
+            }
+            claimed.push((*id, fields.clone()));
+        }
+        Ok((next, claimed, deleted))
+    }
+
+    fn group_mut(&mut self, name: &[u8]) -> Result<&mut ConsumerGroup, &'static str> {
+        self.groups.get_mut(name).ok_or(NO_GROUP)
+    }
+
+    // The ID reading `from` starts after.
+    fn resolve(&self, from: ReadFrom) -> StreamId {
+        match from {
+            ReadFrom::After(id) => id,
+            ReadFrom::Last | ReadFrom::Undelivered => self.last_id,
+        }
+    }
+}
+
+// Whether no ID can lie between the two bounds. `BTreeMap::range` panics on
+// some of those.
+fn is_empty_range(start: Bound<StreamId>, end: Bound<StreamId>) -> bool {
+    match (start, end) {
+        (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        (
+            Bound::Included(start) | Bound::Excluded(start),
+            Bound::Included(end) | Bound::Excluded(end),
+        ) => start > end,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(ms: u64, seq: u64) -> StreamId {
+        StreamId::new(ms, seq)
+    }
+
+    fn fields(value: &str) -> Fields {
+        vec![(b"f".to_vec(), value.as_bytes().to_vec())]
+    }
+
+    // A stream with entries 1-0 to `n`-0.
+    fn stream_of(n: u64) -> Stream {
+        let mut stream = Stream::new();
+        for ms in 1..=n {
+            stream.add(NewId::Exact(id(ms, 0)), fields("v"), 0).unwrap();
+        }
+        stream
+    }
+
+    fn ids(entries: &[(StreamId, impl Sized)]) -> Vec<StreamId> {
+        entries.iter().map(|(id, _)| *id).collect()
+    }
+
+    #[test]
+    fn test_parse_id() {
+        assert_eq!(StreamId::parse(b"5-3", 0), Some(id(5, 3)));
+        assert_eq!(StreamId::parse(b"5", u64::MAX), Some(id(5, u64::MAX)));
+        assert_eq!(StreamId::parse(b"+5", 0), None);
+        assert_eq!(StreamId::parse(b"5-", 0), None);
+        assert_eq!(StreamId::parse(b"5-3-1", 0), None);
+        assert_eq!(id(5, u64::MAX).next(), Some(id(6, 0)));
+        assert_eq!(StreamId::MAX.next(), None);
+    }
+
+    #[test]
+    fn test_add_ids() {
+        let mut stream = Stream::new();
+        assert_eq!(
+            stream.add(NewId::Exact(StreamId::MIN), fields("a"), 100),
+            Err("ERR The ID specified in XADD must be greater than 0-0")
+        );
+        assert_eq!(stream.add(NewId::Auto, fields("a"), 100), Ok(id(100, 0)));
+        assert_eq!(stream.add(NewId::Auto, fields("b"), 100), Ok(id(100, 1)));
+        // The clock went back, the IDs keep growing.
+        assert_eq!(stream.add(NewId::Auto, fields("c"), 50), Ok(id(100, 2)));
+        assert_eq!(
+            stream.add(NewId::AutoSeq(100), fields("d"), 0),
+            Ok(id(100, 3))
+        );
+        assert_eq!(
+            stream.add(NewId::AutoSeq(200), fields("e"), 0),
+            Ok(id(200, 0))
+        );
+        assert_eq!(
+            stream.add(NewId::AutoSeq(150), fields("f"), 0),
+            Err(ID_TOO_SMALL)
+        );
+        assert_eq!(
+            stream.add(NewId::Exact(id(200, 0)), fields("f"), 0),
+            Err(ID_TOO_SMALL)
+        );
+        assert_eq!(stream.len(), 5);
+
+        stream.delete(&[id(200, 0)]);
+        assert_eq!(stream.last_id(), id(200, 0));
+        assert_eq!(
+            stream.add(NewId::Exact(id(200, 0)), fields("f"), 0),
+            Err(ID_TOO_SMALL)
+        );
+        assert!(stream.set_last_id(id(100, 2)).is_err());
+        assert_eq!(stream.set_last_id(id(100, 3)), Ok(()));
+        assert_eq!(stream.add(NewId::Auto, fields("f"), 0), Ok(id(100, 4)));
+    }
+
+    #[test]
+    fn test_trim_and_range() {
+        let mut stream = stream_of(10);
+        let max_len = |max, limit| Trim {
+            by: TrimBy::MaxLen(max),
+            limit,
+        };
+        assert_eq!(stream.trim(&max_len(5, Some(2))), 2);
+        assert_eq!(stream.trim(&max_len(5, None)), 3);
+        let min_id = Trim {
+            by: TrimBy::MinId(id(7, 0)),
+            limit: None,
+        };
+        assert_eq!(stream.trim(&min_id), 1);
+        assert_eq!(stream.len(), 4);
+
+        let all = stream.range(Bound::Unbounded, Bound::Unbounded, false, None);
+        assert_eq!(ids(&all), [id(7, 0), id(8, 0), id(9, 0), id(10, 0)]);
+        let newest = stream.range(Bound::Excluded(id(7, 0)), Bound::Unbounded, true, Some(2));
+        assert_eq!(ids(&newest), [id(10, 0), id(9, 0)]);
+        let nothing = stream.range(
+            Bound::Excluded(id(8, 0)),
+            Bound::Excluded(id(8, 0)),
+            false,
+            None,
+        );
+        assert!(nothing.is_empty());
+        let backwards = stream.range(
+            Bound::Included(id(9, 0)),
+            Bound::Included(id(8, 0)),
+            false,
+            None,
+        );
+        assert!(backwards.is_empty());
+    }
+
+    #[test]
+    fn test_read_group_and_ack() {
+        let mut stream = stream_of(3);
+        stream
+            .create_group(b"g", ReadFrom::After(id(1, 0)))
+            .unwrap();
+        assert_eq!(stream.create_group(b"g", ReadFrom::Last), Err(BUSY_GROUP));
+
+        let read = stream
+            .read_group(b"g", b"alice", ReadFrom::Undelivered, Some(1), false, 10)
+            .unwrap();
+        assert_eq!(read, [(id(2, 0), Some(fields("v")))]);
+        let read = stream
+            .read_group(b"g", b"bob", ReadFrom::Undelivered, None, true, 10)
+            .unwrap();
+        assert_eq!(ids(&read), [id(3, 0)]);
+        let group = stream.group(b"g").unwrap();
+        assert_eq!(group.last_delivered, id(3, 0));
+        // Bob read without acknowledging.
+        assert_eq!(group.pending().keys().collect::<Vec<_>>(), [&id(2, 0)]);
+
+        // Reading the history delivers Alice's entries again, deleted or not.
+        stream.delete(&[id(2, 0)]);
+        let read = stream
+            .read_group(
+                b"g",
+                b"alice",
+                ReadFrom::After(StreamId::MIN),
+                None,
+                false,
+                20,
+            )
+            .unwrap();
+        assert_eq!(read, [(id(2, 0), None)]);
+        let entry = &stream.group(b"g").unwrap().pending()[&id(2, 0)];
+        assert_eq!((entry.delivered_at, entry.deliveries), (20, 2));
+        let read = stream
+            .read_group(
+                b"g",
+                b"bob",
+                ReadFrom::After(StreamId::MIN),
+                None,
+                false,
+                20,
+            )
+            .unwrap();
+        assert!(read.is_empty());
+
+        assert_eq!(stream.ack(b"g", &[id(2, 0), id(3, 0)]), 1);
+        assert_eq!(stream.ack(b"nope", &[id(2, 0)]), 0);
+        assert_eq!(
+            stream.read_group(b"nope", b"bob", ReadFrom::Undelivered, None, false, 0),
+            Err(NO_GROUP)
+        );
+    }
+
+    #[test]
+    fn test_claim() {
+        let mut stream = stream_of(3);
+        stream
+            .create_group(b"g", ReadFrom::After(StreamId::MIN))
+            .unwrap();
+        stream
+            .read_group(b"g", b"alice", ReadFrom::Undelivered, Some(2), false, 100)
+            .unwrap();
+
+        let options = ClaimOptions::default();
+        let ids_of = |claimed: Vec<(StreamId, Option<Fields>)>| ids(&claimed);
+        // Not idle for long enough yet.
+        let claimed = stream.claim(b"g", b"bob", 50, &[id(1, 0)], &options, 120);
+        assert_eq!(claimed.map(ids_of), Ok(vec![]));
+        let claimed = stream.claim(b"g", b"bob", 50, &[id(1, 0), id(3, 0)], &options, 150);
+        assert_eq!(claimed.map(ids_of), Ok(vec![id(1, 0)]));
+        let entry = &stream.group(b"g").unwrap().pending()[&id(1, 0)];
+        assert_eq!(
+            (entry.consumer.as_slice(), entry.deliveries),
+            (&b"bob"[..], 2)
+        );
+
+        let forced = ClaimOptions {
+            force: true,
+            just_id: true,
+            delivered_at: Some(DeliveredAt::At(7)),
+            ..ClaimOptions::default()
+        };
+        let claimed = stream.claim(b"g", b"bob", 0, &[id(3, 0), id(9, 0)], &forced, 150);
+        assert_eq!(claimed.map(ids_of), Ok(vec![id(3, 0)]));
+        let entry = &stream.group(b"g").unwrap().pending()[&id(3, 0)];
+        assert_eq!((entry.delivered_at, entry.deliveries), (7, 1));
+
+        let lifted = ClaimOptions {
+            last_id: Some(id(3, 0)),
+            ..ClaimOptions::default()
+        };
+        stream.claim(b"g", b"bob", 0, &[], &lifted, 150).unwrap();
+        assert_eq!(stream.group(b"g").unwrap().last_delivered, id(3, 0));
+    }
+
+    #[test]
+    fn test_auto_claim() {
+        let mut stream = stream_of(30);
+        stream
+            .create_group(b"g", ReadFrom::After(StreamId::MIN))
+            .unwrap();
+        stream
+            .read_group(b"g", b"alice", ReadFrom::Undelivered, None, false, 0)
+            .unwrap();
+        stream.delete(&[id(2, 0)]);
+
+        let (next, claimed, deleted) = stream
+            .auto_claim(b"g", b"bob", 10, StreamId::MIN, 2, false, 100)
+            .unwrap();
+        assert_eq!(ids(&claimed), [id(1, 0), id(3, 0)]);
+        assert_eq!(deleted, [id(2, 0)]);
+        assert_eq!(next, id(4, 0));
+        assert_eq!(stream.group(b"g").unwrap().pending().len(), 29);
+
+        // Only ten times the count of entries are looked at.
+        let (next, claimed, _) = stream
+            .auto_claim(b"g", b"bob", 10, id(4, 0), 1, false, 5)
+            .unwrap();
+        assert!(claimed.is_empty());
+        assert_eq!(next, id(14, 0));
+        let (next, claimed, _) = stream
+            .auto_claim(b"g", b"bob", 0, id(14, 0), 100, true, 5)
+            .unwrap();
+        assert_eq!(claimed.len(), 17);
+        assert_eq!(next, StreamId::MIN);
+        assert_eq!(
+            stream.group(b"g").unwrap().pending()[&id(30, 0)].deliveries,
+            1
+        );
+    }
+}
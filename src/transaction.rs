@@ -0,0 +1,126 @@
+//! MULTI/EXEC transactions. After MULTI the commands a client sends are
+//! queued instead of executed, and EXEC runs them all with the storage
+//! locked once, so no other client sees the database halfway through them.
+//!
+//! Commands are parsed as they are queued. One that fails to parse, or can't
+//! run inside a transaction, is refused, and EXEC then discards the whole
+//! transaction rather than running the rest of it.
+
+use crate::commands::{Command, ParseError};
+use crate::connection::Request;
+use crate::resp::Frame;
+
+/// The commands queued since MULTI.
+#[derive(Debug, Default)]
+pub struct Transaction {
+    queued: Vec<(Command, Request)>,
+    // Set once a command was refused.
+    aborted: bool,
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of commands queued so far.
+    pub fn len(&self) -> usize {
+        self.queued.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queued.is_empty()
+    }
+
+    /// Queues the command parsed from `args`. Replies `QUEUED`, or with why
+    /// it was refused.
+    pub fn queue(&mut self, command: Result<Command, ParseError>, args: Request) -> Frame {
+        let command = match command {
+            // Like in Redis, this doesn't spoil the transaction.
+            Ok(Command::Multi) => {
+                return Frame::Error("ERR MULTI calls can not be nested".to_string());
+            }
+            // These change how the connection works, which can't be done in
+            // the middle of EXEC.
+            Ok(Command::Subscribe { .. } | Command::Unsubscribe { .. }) => {
+                Err("ERR Command not allowed inside a transaction".to_string())
+            }
+            Ok(command) => Ok(command),
+            Err(e) => Err(e.to_string()),
+        };
+
+        match command {
+            Ok(command) => {
+                self.queued.push((command, args));
+                Frame::Simple("QUEUED".to_string())
+            }
+            Err(msg) => {
+                self.aborted = true;
+                Frame::Error(msg)
+            }
+        }
+    }
+
+    /// The commands to run on EXEC, in the order they were queued, or the
+    /// error to reply with if one was refused.
+    pub fn into_commands(self) -> Result<Vec<(Command, Request)>, Frame> {
+        if self.aborted {
+            return Err(Frame::Error(
+                "EXECABORT Transaction discarded because of previous errors.".to_string(),
+            ));
+        }
+        Ok(self.queued)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(transaction: &mut Transaction, request: &str) -> Frame {
+        let args: Request = request
+            .split_whitespace()
+            .map(|arg| arg.as_bytes().to_vec())
+            .collect();
+        transaction.queue(Command::from_args(&args), args)
+    }
+
+    #[test]
+    fn test_queue_and_exec() {
+        let mut transaction = Transaction::new();
+        let queued = Frame::Simple("QUEUED".to_string());
+        assert_eq!(queue(&mut transaction, "SET k v"), queued);
+        assert_eq!(queue(&mut transaction, "INCR k"), queued);
+        assert_eq!(queue(&mut transaction, "BGSAVE"), queued);
+        assert!(matches!(queue(&mut transaction, "MULTI"), Frame::Error(_)));
+        assert_eq!(transaction.len(), 3);
+
+        // Failing to run, like INCR on a string that isn't a number, is only
+        // found out on EXEC and doesn't stop the others.
+        let commands = transaction.into_commands().unwrap();
+        assert!(matches!(commands[0].0, Command::Set { .. }));
+        assert_eq!(commands[1].1, [b"INCR".to_vec(), b"k".to_vec()]);
+    }
+
+    #[test]
+    fn test_refused_commands_abort() {
+        let mut transaction = Transaction::new();
+        assert!(matches!(queue(&mut transaction, "SET k"), Frame::Error(_)));
+        assert_eq!(
+            queue(&mut transaction, "GET k"),
+            Frame::Simple("QUEUED".to_string())
+        );
+        match transaction.into_commands() {
+            Err(Frame::Error(msg)) => assert!(msg.starts_with("EXECABORT")),
+            other => panic!("Expected EXECABORT, got {:?}", other),
+        }
+
+        let mut transaction = Transaction::new();
+        assert_eq!(
+            queue(&mut transaction, "SUBSCRIBE news"),
+            Frame::Error("ERR Command not allowed inside a transaction".to_string())
+        );
+        assert!(transaction.is_empty());
+        assert!(transaction.into_commands().is_err());
+    }
+}